@@ -0,0 +1,15 @@
+fn main() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    // the C header is checked in, so C callers have it without building; builds with the ffi feature
+    // keep it in step with src/ffi.rs
+    #[cfg(feature = "ffi")]
+    {
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let config = cbindgen::Config::from_file(format!("{dir}/cbindgen.toml")).unwrap();
+        cbindgen::generate_with_config(&dir, config)
+            .expect("generating the C header")
+            .write_to_file(format!("{dir}/include/image_encryption.h"));
+    }
+}
@@ -0,0 +1,19 @@
+#![no_main]
+
+use image_encryption::{parse_header, EncryptionHeader, HeaderError};
+use libfuzzer_sys::fuzz_target;
+
+// a written header is read back exactly as it was, unless it has a value the parser rejects,
+// like chunks of no pixels that no encryption writes; and whatever the bytes, parsing them
+// doesn't panic and what they parse to is written and read back the same way
+fuzz_target!(|input: (EncryptionHeader, &[u8])| {
+    let (header, bytes) = input;
+    match parse_header(&header.to_bytes()) {
+        Ok(parsed) => assert_eq!(parsed, Some(header)),
+        Err(HeaderError::InvalidField(_)) => {}
+        Err(err) => panic!("a written header failed to parse: {}", err),
+    }
+    if let Ok(Some(parsed)) = parse_header(bytes) {
+        assert_eq!(parse_header(&parsed.to_bytes()), Ok(Some(parsed)));
+    }
+});
@@ -0,0 +1,27 @@
+#![no_main]
+
+use image_encryption::{
+    compare_images, decrypt_image, encode_image, encrypt_image_with, load_image_from_bytes,
+    EncryptOptions, Image,
+};
+use libfuzzer_sys::fuzz_target;
+
+// encrypting with any options decrypts back to the image, unless the options change the plaintext
+// with a fingerprint or a color conversion; writing and loading the ciphertext never panics
+fuzz_target!(|input: (Image, EncryptOptions, u64)| {
+    let (mut original, options, key) = input;
+    original.set_header(None);
+    let mut img = original.clone();
+    encrypt_image_with(&mut img, key, &options);
+
+    if let Ok(bytes) = encode_image(&img) {
+        if let Ok(mut loaded) = load_image_from_bytes(&bytes) {
+            let _ = decrypt_image(&mut loaded, key);
+        }
+    }
+
+    if options.fingerprint.is_none() && options.normalize.is_none() {
+        decrypt_image(&mut img, key).expect("the ciphertext authenticates");
+        assert!(compare_images(&original, &img).identical);
+    }
+});
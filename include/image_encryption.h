@@ -0,0 +1,115 @@
+#ifndef IMAGE_ENCRYPTION_H
+#define IMAGE_ENCRYPTION_H
+
+/* generated by cbindgen from src/ffi.rs, don't edit */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct IeImage IeImage;
+
+/**
+ * Loads an image file. Returns null on failure, see `ie_last_error`.
+ *
+ * # Safety
+ * `path` must be a nul-terminated string.
+ */
+IeImage *ie_load(const char *path);
+
+/**
+ * Encrypts an image in place with the default options, like `enc` does.
+ *
+ * # Safety
+ * `img` must come from `ie_load` or `ie_from_raw_frame` and not have been freed.
+ */
+int ie_encrypt(IeImage *img, uint64_t key);
+
+/**
+ * Decrypts an image in place. Fails when the key is wrong or the image isn't encrypted.
+ *
+ * # Safety
+ * `img` must come from `ie_load` or `ie_from_raw_frame` and not have been freed.
+ */
+int ie_decrypt(IeImage *img, uint64_t key);
+
+/**
+ * Writes an image to a file, in the format it was loaded from whatever the extension, like `enc` does.
+ *
+ * # Safety
+ * `img` must come from `ie_load` or `ie_from_raw_frame` and not have been freed, and `path` must be
+ * a nul-terminated string.
+ */
+int ie_write(const IeImage *img,
+             const char *path);
+
+/**
+ * Encrypts a frame in a borrowed buffer in place, like a screenshot or a framebuffer: `height` rows of
+ * `width` pixels of `bytes_per_pixel` bytes, each row `stride` bytes after the one before it. The padding
+ * after each row is left as it is. Every frame must be encrypted with a key of its own.
+ *
+ * # Safety
+ * `buffer` must point to `len` bytes that can be written.
+ */
+int ie_encrypt_raw_frame(uint8_t *buffer,
+                         size_t len,
+                         uint32_t width,
+                         uint32_t height,
+                         size_t stride,
+                         uint32_t bytes_per_pixel,
+                         uint64_t key);
+
+/**
+ * Decrypts a frame encrypted with `ie_encrypt_raw_frame`, in place.
+ *
+ * # Safety
+ * `buffer` must point to `len` bytes that can be written.
+ */
+int ie_decrypt_raw_frame(uint8_t *buffer,
+                         size_t len,
+                         uint32_t width,
+                         uint32_t height,
+                         size_t stride,
+                         uint32_t bytes_per_pixel,
+                         uint64_t key);
+
+/**
+ * Copies a frame out of a buffer into a new image, which the other functions work on like one from
+ * `ie_load`; `ie_write` writes it as a PNG. The layout is that of `ie_encrypt_raw_frame`, with the
+ * bottom row first if `bottom_up` is set, like a Windows DIB or `glReadPixels`. Returns null on failure.
+ *
+ * # Safety
+ * `buffer` must point to `len` bytes that can be read.
+ */
+IeImage *ie_from_raw_frame(const uint8_t *buffer,
+                           size_t len,
+                           uint32_t width,
+                           uint32_t height,
+                           size_t stride,
+                           uint32_t bytes_per_pixel,
+                           bool bottom_up);
+
+/**
+ * Copies an image back into a frame with the same width, height and bytes per pixel, laid out as
+ * `ie_from_raw_frame` describes; the padding after each row is left as it is.
+ *
+ * # Safety
+ * `img` must not have been freed, and `buffer` must point to `len` bytes that can be written.
+ */
+int ie_to_raw_frame(const IeImage *img, uint8_t *buffer, size_t len, size_t stride, bool bottom_up);
+
+/**
+ * Frees an image from `ie_load` or `ie_from_raw_frame`. Null is ignored.
+ *
+ * # Safety
+ * `img` must come from `ie_load` or `ie_from_raw_frame` and not have been freed already.
+ */
+void ie_free(IeImage *img);
+
+/**
+ * The message of the last failure on this thread, or null if nothing failed yet. The string is
+ * valid until the next failing call on the same thread.
+ */
+const char *ie_last_error(void);
+
+#endif  /* IMAGE_ENCRYPTION_H */
@@ -0,0 +1,152 @@
+use crate::Image;
+
+// the chi-square statistic of a uniform 256 bin histogram stays below this
+// for 95% of truly random data (255 degrees of freedom)
+const CHI_SQUARE_CRITICAL: f64 = 293.25;
+// good ciphertexts have next to no correlation between neighbouring pixels,
+// while natural images are usually above 0.9
+const MAX_CORRELATION: f64 = 0.05;
+// the share of the keystream a single known plaintext may give away
+const MAX_RECOVERED: f64 = 0.1;
+// pixels with less correlation than this to their neighbours are taken for noise rather than an image
+const MIN_IMAGE_CORRELATION: f64 = 0.3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditResult {
+    pub name: String,
+    pub value: f64,
+    pub threshold: f64,
+    pub passed: bool,
+}
+
+fn result(name: &str, value: f64, threshold: f64) -> AuditResult {
+    AuditResult {
+        name: name.to_string(),
+        value,
+        threshold,
+        passed: value < threshold,
+    }
+}
+
+// every byte of a pixel is looked at on its own, so 16-bit samples are split into two lanes
+fn lanes(img: &Image) -> usize {
+    img.color.bytes_per_pixel() as usize
+}
+
+// the worst chi-square statistic over the lanes, against a uniform distribution of byte values
+fn histogram(img: &Image) -> f64 {
+    let lanes = lanes(img);
+    let expected = (img.pixels.len() / lanes) as f64 / 256.0;
+    (0..lanes)
+        .map(|lane| {
+            let mut counts = [0usize; 256];
+            for &value in img.pixels.iter().skip(lane).step_by(lanes) {
+                counts[value as usize] += 1;
+            }
+            counts
+                .iter()
+                .map(|&count| (count as f64 - expected).powi(2) / expected)
+                .sum::<f64>()
+        })
+        .fold(0.0, f64::max)
+}
+
+// the worst absolute Pearson correlation over the lanes between every pixel and its neighbour at (dx, dy)
+fn correlation(img: &Image, dx: usize, dy: usize) -> f64 {
+    let lanes = lanes(img);
+    let (width, height) = (img.width as usize, img.height as usize);
+    (0..lanes)
+        .map(|lane| {
+            let sample = |x: usize, y: usize| img.pixels[(y * width + x) * lanes + lane] as f64;
+            let (mut n, mut sum_a, mut sum_b, mut sum_ab, mut sum_aa, mut sum_bb) =
+                (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in 0..height.saturating_sub(dy) {
+                for x in 0..width.saturating_sub(dx) {
+                    let (a, b) = (sample(x, y), sample(x + dx, y + dy));
+                    n += 1.0;
+                    sum_a += a;
+                    sum_b += b;
+                    sum_ab += a * b;
+                    sum_aa += a * a;
+                    sum_bb += b * b;
+                }
+            }
+            let covariance = n * sum_ab - sum_a * sum_b;
+            let variance = (n * sum_aa - sum_a * sum_a) * (n * sum_bb - sum_b * sum_b);
+            // a constant lane carries no correlation to speak of
+            if variance > 0.0 {
+                (covariance / variance.sqrt()).abs()
+            } else {
+                0.0
+            }
+        })
+        .fold(0.0, f64::max)
+}
+
+// whether the pixels look like an image rather than noise: a ciphertext, and what a wrong key decrypts one to,
+// have no more correlation between neighbouring pixels than chance gives, a few times 1 / sqrt(pixels),
+// so an image too small to tell never looks like one
+pub(crate) fn looks_like_image(img: &Image) -> bool {
+    let chance = 5.0 / (img.width as f64 * img.height as f64).sqrt();
+    let correlation = correlation(img, 1, 0).max(correlation(img, 0, 1));
+    correlation > chance.max(MIN_IMAGE_CORRELATION)
+}
+
+// the legacy cipher chains every sample with the previous one, so an attacker who knows
+// the plaintext gets c[i] ^ c[i - 1] = p[perm[i]] ^ k[i]; guessing the most common plaintext value
+// for every p[perm[i]] recovers k[i] wherever the guess is right, whatever the permutation.
+// the keystream only depends on the key and the image size, so the recovered part
+// decrypts the same share of any other image of that size encrypted with the same key
+fn known_plaintext(plaintext: &Image) -> f64 {
+    let lanes = lanes(plaintext);
+    let samples = (plaintext.pixels.len() / lanes).max(1);
+    let recovered = (0..lanes)
+        .map(|lane| {
+            let mut counts = [0usize; 256];
+            for &value in plaintext.pixels.iter().skip(lane).step_by(lanes) {
+                counts[value as usize] += 1;
+            }
+            counts.into_iter().max().unwrap_or_default()
+        })
+        .sum::<usize>();
+    recovered as f64 / (samples * lanes) as f64
+}
+
+// run the statistical attacks on a ciphertext, and the known-plaintext attack
+// if the plaintext it was made from is given
+pub fn audit(ciphertext: &Image, plaintext: Option<&Image>) -> Vec<AuditResult> {
+    if ciphertext.pixels.is_empty() {
+        return Vec::new();
+    }
+
+    let mut results = vec![
+        result(
+            "histogram chi-square",
+            histogram(ciphertext),
+            CHI_SQUARE_CRITICAL,
+        ),
+        result(
+            "horizontal correlation",
+            correlation(ciphertext, 1, 0),
+            MAX_CORRELATION,
+        ),
+        result(
+            "vertical correlation",
+            correlation(ciphertext, 0, 1),
+            MAX_CORRELATION,
+        ),
+        result(
+            "diagonal correlation",
+            correlation(ciphertext, 1, 1),
+            MAX_CORRELATION,
+        ),
+    ];
+    if let Some(plaintext) = plaintext.filter(|plaintext| !plaintext.pixels.is_empty()) {
+        results.push(result(
+            "known-plaintext keystream recovery",
+            known_plaintext(plaintext),
+            MAX_RECOVERED,
+        ));
+    }
+    results
+}
@@ -0,0 +1,181 @@
+use std::{error::Error, fmt};
+
+use image::{ColorType, ImageFormat};
+
+use crate::{
+    blake3, kdf::Hmac, sha256::Sha256, swap_samples, EncryptionHeader, Image, SampleOrder,
+};
+
+pub const AUTH_TAG_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecryptError {
+    // the authentication tag doesn't match the ciphertext: the key is wrong or the file was modified
+    AuthenticationFailed,
+    // the key doesn't match the fingerprint in the header, for images without an authentication tag
+    WrongKey,
+    // the image isn't the size the header says it was encrypted at, so it was resized or cropped
+    DimensionMismatch {
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+    // the file was encrypted in the DCT domain, and only `decrypt_jpeg_dct` can decrypt it
+    DctDomain,
+    // the image is a share of a split image, and only `combine_images` with the other shares gives it back
+    Share,
+    // the header describes something the image can't hold, like a region outside of it,
+    // so either of them was damaged
+    Malformed(&'static str),
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptError::AuthenticationFailed => write!(
+                f,
+                "authentication failed, the key is wrong or the image was modified"
+            ),
+            DecryptError::WrongKey => write!(f, "encrypted with a different key"),
+            DecryptError::DimensionMismatch { expected, found } => write!(
+                f,
+                "encrypted at {}x{} but the image is {}x{}, it was resized or cropped",
+                expected.0, expected.1, found.0, found.1
+            ),
+            DecryptError::DctDomain => write!(
+                f,
+                "encrypted in the DCT domain, the JPEG file has to be decrypted as it is"
+            ),
+            DecryptError::Share => write!(
+                f,
+                "a share of a split image, it is combined with the other shares instead of decrypted"
+            ),
+            DecryptError::Malformed(what) => write!(f, "damaged encryption header: {}", what),
+        }
+    }
+}
+
+impl Error for DecryptError {}
+
+// the HMAC key is kept apart from the cipher key, so the tag says nothing about the keystream
+fn mac_key(key: u64) -> [u8; 32] {
+    blake3::Hasher::new()
+        .update(b"image_encryption auth key\0")
+        .update(&key.to_le_bytes())
+        .finalize()
+}
+
+// HMAC-SHA256 over the pixels of the file as they are written, banner and all,
+// along with the size and layout they are read back with and the header that says how to decrypt them
+pub(crate) fn auth_tag(img: &Image, header: &EncryptionHeader, key: u64) -> [u8; AUTH_TAG_LEN] {
+    let mut tag = StreamingTag::new(key, img.width, img.height, img.color);
+    tag.update(&img.pixels);
+    tag.finish(header)
+}
+
+// the tag of a JPEG encrypted in the DCT domain, over the tables and scrambled coefficients its pixels are
+// decoded from, since no decoder has to give back the same pixels
+pub(crate) fn dct_tag(
+    key: u64,
+    coefficients: &[u8],
+    header: &EncryptionHeader,
+) -> [u8; AUTH_TAG_LEN] {
+    let hmac = Hmac::new(&mac_key(key));
+    let mut inner = hmac.start();
+    inner
+        .update(b"image_encryption dct tag\0")
+        .update(coefficients)
+        .update(&header.authenticated_bytes());
+    hmac.finish(&inner)
+}
+
+// the same tag fed the pixels a band at a time, for images that are never in memory as a whole
+pub(crate) struct StreamingTag {
+    hmac: Hmac,
+    inner: Sha256,
+}
+
+impl StreamingTag {
+    pub(crate) fn new(key: u64, width: u32, height: u32, color: ColorType) -> Self {
+        let hmac = Hmac::new(&mac_key(key));
+        let mut inner = hmac.start();
+        inner
+            .update(b"image_encryption auth tag\0")
+            .update(&width.to_le_bytes())
+            .update(&height.to_le_bytes())
+            .update(&[color.bytes_per_pixel(), color.channel_count()]);
+        StreamingTag { hmac, inner }
+    }
+
+    pub(crate) fn update(&mut self, pixels: &[u8]) {
+        self.inner.update(pixels);
+    }
+
+    pub(crate) fn finish(mut self, header: &EncryptionHeader) -> [u8; AUTH_TAG_LEN] {
+        if !header.pixel_tag_only {
+            self.inner.update(&header.authenticated_bytes());
+        }
+        self.hmac.finish(&self.inner)
+    }
+}
+
+// lossy JPEGs don't decode to the pixels that were encoded, so no tag could ever match them
+pub(crate) fn can_authenticate(img: &Image) -> bool {
+    img.format != ImageFormat::Jpeg
+        || img
+            .header
+            .as_ref()
+            .is_some_and(|header| header.jpeg_container)
+}
+
+// whether the image has to carry a tag: every header since the tag covers it is written with one
+// wherever it can be checked, so one without was stripped of it
+pub(crate) fn tag_expected(img: &Image) -> bool {
+    img.header
+        .as_ref()
+        .is_some_and(|header| !header.pixel_tag_only)
+        && can_authenticate(img)
+}
+
+// tag the ciphertext and its header as they are now, or drop a stale tag if it can't be checked once written
+pub(crate) fn authenticate(img: &mut Image, key: u64) {
+    let can_authenticate = can_authenticate(img);
+    let Some(mut header) = img.header.take() else {
+        return;
+    };
+    header.auth_tag = can_authenticate.then(|| auth_tag(img, &header, key));
+    img.header = Some(header);
+}
+
+// images encrypted before tags were added are let through
+pub(crate) fn verify(img: &Image, key: u64) -> Result<(), DecryptError> {
+    let Some(header) = &img.header else {
+        return Ok(());
+    };
+    let Some(expected) = header.auth_tag else {
+        if tag_expected(img) {
+            return Err(DecryptError::AuthenticationFailed);
+        }
+        return Ok(());
+    };
+    // the tag is of the samples in the order the cipher ran over them
+    let tag = match header.sample_order {
+        Some(SampleOrder::BigEndian) => {
+            let mut pixels = img.pixels.clone();
+            swap_samples(img.color, &mut pixels);
+            let mut tag = StreamingTag::new(key, img.width, img.height, img.color);
+            tag.update(&pixels);
+            tag.finish(header)
+        }
+        _ => auth_tag(img, header, key),
+    };
+    if tags_match(&tag, &expected) {
+        Ok(())
+    } else {
+        Err(DecryptError::AuthenticationFailed)
+    }
+}
+
+// compare every byte, so how long the comparison takes doesn't leak how much of the tag matched
+pub(crate) fn tags_match(a: &[u8; AUTH_TAG_LEN], b: &[u8; AUTH_TAG_LEN]) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,252 @@
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+
+use crate::{
+    font::{draw_text, text_size},
+    Image, QrCode, Rect,
+};
+
+// the rest of an image of the given size once a strip along one of its edges is taken out,
+// or None if the rectangle isn't such a strip
+pub(crate) fn rest(strip: Rect, width: u32, height: u32) -> Option<Rect> {
+    let rect = if strip.x == 0 && strip.width == width && strip.height < height {
+        if strip.y == 0 {
+            Rect {
+                x: 0,
+                y: strip.height,
+                width,
+                height: height - strip.height,
+            }
+        } else if strip.y + strip.height == height {
+            Rect {
+                x: 0,
+                y: 0,
+                width,
+                height: strip.y,
+            }
+        } else {
+            return None;
+        }
+    } else if strip.y == 0 && strip.height == height && strip.width < width {
+        if strip.x == 0 {
+            Rect {
+                x: strip.width,
+                y: 0,
+                width: width - strip.width,
+                height,
+            }
+        } else if strip.x + strip.width == width {
+            Rect {
+                x: 0,
+                y: 0,
+                width: strip.x,
+                height,
+            }
+        } else {
+            return None;
+        }
+    } else {
+        return None;
+    };
+    Some(rect)
+}
+
+// cut a strip off the edge of the image, returning its pixels
+pub(crate) fn remove_strip(img: &mut Image, strip: Rect) -> Option<Vec<u8>> {
+    let rest = rest(strip, img.width, img.height)?;
+    let pixels = img.crop_pixels(strip);
+    img.pixels = img.crop_pixels(rest);
+    img.width = rest.width;
+    img.height = rest.height;
+    Some(pixels)
+}
+
+// the inverse of `remove_strip`, grow the image to the given size with the strip along its edge
+pub(crate) fn attach_strip(img: &mut Image, strip: Rect, pixels: &[u8], width: u32, height: u32) {
+    let Some(rest) = rest(strip, width, height) else {
+        return;
+    };
+    if (rest.width, rest.height) != (img.width, img.height) {
+        return;
+    }
+
+    let old = std::mem::take(&mut img.pixels);
+    img.pixels = vec![0; width as usize * height as usize * img.color.bytes_per_pixel() as usize];
+    img.width = width;
+    img.height = height;
+    img.paste_pixels(rest, &old);
+    img.paste_pixels(strip, pixels);
+}
+
+// the edge of the image a banner goes along
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BannerEdge {
+    Top,
+    #[default]
+    Bottom,
+    Left,
+    Right,
+}
+
+// a strip added along an edge of the encrypted image that stays plaintext, so whoever gets the file
+// can see what it is and how to decrypt it; the strip is recorded in the header and removed on decryption
+#[derive(Debug, Clone, Default)]
+pub struct Banner {
+    pub edge: BannerEdge,
+    // how thick the strip is in pixels; by default just enough for the text, or a tenth of the image for a logo
+    pub size: Option<u32>,
+    // a line of light text on the dark strip
+    pub text: Option<String>,
+    // an image like a logo, scaled down to fit the strip, drawn before the text
+    pub logo: Option<Image>,
+    // a QR code at the far end of the strip, e.g. with a link to the decryption tool or the key fingerprint;
+    // it is plaintext like the rest of the strip, so it must never hold the key
+    pub qr: Option<QrCode>,
+}
+
+// a QR code needs a light margin of four modules around it to be scanned
+const QR_QUIET_ZONE: usize = 4;
+
+// render the banner as a horizontal RGBA strip of the given length
+fn render(banner: &Banner, length: u32, image_size: u32) -> RgbaImage {
+    let text = banner.text.as_deref().unwrap_or_default();
+    let (text_width, _) = text_size(text, 1);
+    // text about a twenty-fifth of the image, as thick as the strip allows, and never longer than it
+    let scale = match banner.size {
+        Some(size) => size / 12,
+        None => image_size / 25 / 8,
+    }
+    .min(length / (text_width + 4).max(1))
+    .max(1);
+    let padding = 2 * scale;
+    let qr_modules = banner
+        .qr
+        .as_ref()
+        .map_or(0, |qr| (qr.size() + 2 * QR_QUIET_ZONE) as u32);
+    let thickness = banner.size.unwrap_or_else(|| {
+        let content = match banner.text {
+            Some(_) => text_size(text, scale).1 + 2 * padding,
+            None if banner.qr.is_some() => 0,
+            None => (image_size / 10).max(16),
+        };
+        // scanners want modules of a few pixels
+        content.max(qr_modules * 3)
+    });
+    let qr_module = (thickness / qr_modules.max(1)).max(1);
+    // the logo and the text go before the QR code
+    let end = length.saturating_sub(qr_modules * qr_module);
+
+    let mut canvas = RgbaImage::from_pixel(length, thickness.max(1), Rgba([32, 32, 32, 255]));
+
+    let mut x = padding;
+    if let Some(logo) = banner.logo.as_ref().and_then(Image::to_dynamic) {
+        let room = thickness.saturating_sub(2 * padding).max(1);
+        let logo = logo.thumbnail(end, room).to_rgba8();
+        let y = (thickness as i64 - logo.height() as i64) / 2;
+        imageops::overlay(&mut canvas, &logo, x as i64, y);
+        x += logo.width() + padding;
+    }
+    // whatever the logo and the QR code leave of the strip may need smaller text
+    let scale = scale
+        .min(end.saturating_sub(x + padding) / text_width.max(1))
+        .max(1);
+    let (_, text_height) = text_size(text, scale);
+    draw_text(
+        &mut canvas,
+        text,
+        x as i64,
+        (thickness as i64 - text_height as i64) / 2,
+        scale,
+        Rgba([255, 255, 255, 255]),
+    );
+
+    if let Some(qr) = &banner.qr {
+        let side = qr_modules * qr_module;
+        let top = thickness.saturating_sub(side) / 2;
+        for y in 0..side.min(thickness) {
+            for x in 0..side.min(length) {
+                let (qx, qy) = ((x / qr_module) as usize, (y / qr_module) as usize);
+                let inside = QR_QUIET_ZONE..QR_QUIET_ZONE + qr.size();
+                let dark = inside.contains(&qx)
+                    && inside.contains(&qy)
+                    && qr.is_dark(qx - QR_QUIET_ZONE, qy - QR_QUIET_ZONE);
+                let value = if dark { 0 } else { 255 };
+                canvas.put_pixel(end + x, top + y, Rgba([value, value, value, 255]));
+            }
+        }
+    }
+    canvas
+}
+
+// add the banner along its edge of the image, returning where the strip ended up;
+// None if the color type of the image can't be drawn on
+pub(crate) fn add_banner(img: &mut Image, banner: &Banner) -> Option<Rect> {
+    let (width, height) = (img.width, img.height);
+    let vertical = matches!(banner.edge, BannerEdge::Left | BannerEdge::Right);
+    let canvas = if vertical {
+        // drawn along the edge, reading upwards
+        imageops::rotate270(&render(banner, height, width))
+    } else {
+        render(banner, width, height)
+    };
+
+    let mut strip = Image {
+        format: img.format,
+        pixels: Vec::new(),
+        color: img.color,
+        width: 0,
+        height: 0,
+        header: None,
+        metadata: Vec::new(),
+        jpeg_segments: Vec::new(),
+    };
+    strip.set_dynamic(DynamicImage::ImageRgba8(canvas));
+    strip.convert_color(img.color);
+    if strip.color != img.color {
+        return None;
+    }
+
+    let (rect, full_width, full_height) = match banner.edge {
+        BannerEdge::Top => (
+            Rect {
+                x: 0,
+                y: 0,
+                width,
+                height: strip.height,
+            },
+            width,
+            height + strip.height,
+        ),
+        BannerEdge::Bottom => (
+            Rect {
+                x: 0,
+                y: height,
+                width,
+                height: strip.height,
+            },
+            width,
+            height + strip.height,
+        ),
+        BannerEdge::Left => (
+            Rect {
+                x: 0,
+                y: 0,
+                width: strip.width,
+                height,
+            },
+            width + strip.width,
+            height,
+        ),
+        BannerEdge::Right => (
+            Rect {
+                x: width,
+                y: 0,
+                width: strip.width,
+                height,
+            },
+            width + strip.width,
+            height,
+        ),
+    };
+    attach_strip(img, rect, &strip.pixels, full_width, full_height);
+    Some(rect)
+}
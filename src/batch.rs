@@ -0,0 +1,546 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        mpsc::{self, Sender},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use image::ImageFormat;
+
+use crate::{
+    decrypt_image_with_progress, decrypt_stream, encode_reported, encrypt_image_with_progress,
+    encrypt_stream, estimate_working_set, load_image_with_progress, path_for_format, read_header,
+    report, write_file_atomic_with, Cipher, EncryptOptions, Image, ImageEncryptionError,
+    LimitError, LoadOptions, OperationReport, OperationWarning, Phase, Progress, TempLocation,
+    WriteOptions,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Enc,
+    Dec,
+}
+
+// how to process a directory of images and where the results go
+#[derive(Debug, Clone, Default)]
+pub struct DirectoryOptions {
+    // the directory the tree is mirrored into; None to replace every image where it is
+    pub output: Option<PathBuf>,
+    // also process the images in all subdirectories
+    pub recursive: bool,
+    pub load: LoadOptions,
+    // ignored when decrypting
+    pub encrypt: EncryptOptions,
+    pub write: WriteOptions,
+    // bytes per second to read and write at most, so a job in the background doesn't keep the disk busy;
+    // None for as fast as it goes
+    pub max_throughput: Option<u64>,
+    // bytes of memory an image may take; a TIFF that would take more is streamed a band at a time,
+    // and anything else fails instead of running the machine out of memory
+    pub max_memory: Option<u64>,
+}
+
+// what happened to one image of the directory
+#[derive(Debug)]
+pub struct FileOutcome {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub result: Result<OperationReport, ImageEncryptionError>,
+}
+
+// how far processing a directory has got, passed to its progress callback with every step of every image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectoryProgress {
+    // images finished, successfully or not, out of `files`
+    pub files_done: usize,
+    pub files: usize,
+    // how far the image being processed has got
+    pub current: Progress,
+}
+
+// paces reading and writing: every byte takes a token, tokens come back at the rate allowed, and
+// running out of them sleeps until enough are back; up to a second's worth can be saved up for a burst
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second.max(1) as f64;
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    // the bytes were just read or written; a block bigger than the bucket leaves it in debt,
+    // which is slept off at once
+    fn take(&mut self, bytes: u64) {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refilled).min(self.rate) - bytes as f64;
+        self.last = now;
+        if self.tokens < 0.0 {
+            thread::sleep(Duration::from_secs_f64(-self.tokens / self.rate));
+        }
+    }
+}
+
+// files whose extension names a format the image crate can decode; everything else is left alone
+fn is_supported(path: &Path) -> bool {
+    ImageFormat::from_path(path).is_ok_and(|format| format.can_read())
+}
+
+fn collect_images(
+    dir: &Path,
+    skip: Option<&Path>,
+    recursive: bool,
+    images: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            // an output directory inside the input one doesn't get processed again
+            if recursive && (skip.is_none() || fs::canonicalize(&path).ok().as_deref() != skip) {
+                collect_images(&path, skip, recursive, images)?;
+            }
+        } else if is_supported(&path) {
+            images.push(path);
+        }
+    }
+    Ok(())
+}
+
+// an image on its way through the stages, with what the last one made of it
+struct Job<T> {
+    input: PathBuf,
+    output: PathBuf,
+    result: Result<T, ImageEncryptionError>,
+}
+
+// what decoding hands on: the image, or for one over the memory budget, the budget it is over
+enum Decoded {
+    Image(Box<Image>),
+    OverBudget(LimitError),
+}
+
+// what the cipher hands on: the image and its report so far, or the report of one already streamed
+// to the output a band at a time
+enum Crypted {
+    Image(Box<Image>, OperationReport),
+    Written(OperationReport),
+}
+
+// what the stages tell the thread the caller's callback runs on
+enum Event {
+    Progress(Progress),
+    Done(FileOutcome),
+}
+
+// the progress callback of a stage: reading and writing report how far they have got as every block
+// is done, which is where they are held back, and all of it goes to the caller's thread
+fn stage_progress<'a>(
+    bucket: &'a Mutex<Option<TokenBucket>>,
+    events: &'a Sender<Event>,
+) -> impl FnMut(Progress) + 'a {
+    let mut last = (Phase::Read, 0);
+    move |current: Progress| {
+        if let (Some(bucket), Phase::Read | Phase::Write) =
+            (bucket.lock().unwrap().as_mut(), current.phase)
+        {
+            let done = match last {
+                (phase, done) if phase == current.phase => current.done.saturating_sub(done),
+                _ => current.done,
+            };
+            last = (current.phase, current.done);
+            bucket.take(done);
+        }
+        let _ = events.send(Event::Progress(current));
+    }
+}
+
+fn decode(
+    input: &Path,
+    options: &DirectoryOptions,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Decoded, ImageEncryptionError> {
+    if let Some(budget) = options.max_memory {
+        let needed = estimate_working_set(input)?;
+        if needed > budget {
+            return Ok(Decoded::OverBudget(LimitError::MemoryBudget {
+                needed,
+                budget,
+            }));
+        }
+    }
+    let img = load_image_with_progress(input, &options.load, progress)?;
+    Ok(Decoded::Image(Box::new(img)))
+}
+
+fn crypt(
+    input: &Path,
+    output: &Path,
+    decoded: Decoded,
+    mode: Mode,
+    key: u64,
+    options: &DirectoryOptions,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Crypted, ImageEncryptionError> {
+    let mut img = match decoded {
+        Decoded::Image(img) => img,
+        Decoded::OverBudget(over_budget) => {
+            let report = stream_file(input, output, mode, key, over_budget, progress)?;
+            return Ok(Crypted::Written(report));
+        }
+    };
+    let report = match mode {
+        Mode::Enc => encrypt_image_with_progress(&mut img, key, &options.encrypt, &mut *progress),
+        Mode::Dec => decrypt_image_with_progress(&mut img, key, &mut *progress)?,
+    };
+    Ok(Crypted::Image(img, report))
+}
+
+// writes the image under the extension of the format it is written in, and returns the path it went to
+fn encode(
+    input: &Path,
+    output: &Path,
+    crypted: Crypted,
+    options: &DirectoryOptions,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<(PathBuf, OperationReport), ImageEncryptionError> {
+    let (img, report) = match crypted {
+        Crypted::Image(img, report) => (img, report),
+        Crypted::Written(report) => return Ok((output.to_path_buf(), report)),
+    };
+    let output = path_for_format(output, img.format);
+    // an image replaced where it is under another name must not take the place of another image
+    let renamed_in_place = options.output.is_none() && output != input;
+    if renamed_in_place && output.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", output.display()),
+        )
+        .into());
+    }
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // written atomically, so an image replaced where it is is never left half written
+    let (bytes, written) = encode_reported(&img, &options.write, progress)?;
+    let mut report = report.then(written);
+    let total = bytes.len() as u64;
+    let started = report::start_phase(progress, Phase::Write, total);
+    write_file_atomic_with(&output, &bytes, &TempLocation::default())?;
+    if renamed_in_place {
+        fs::remove_file(input)?;
+    }
+    report.end_phase(progress, Phase::Write, total, started);
+    Ok((output, report))
+}
+
+// an image too big for the memory budget: a TIFF is encrypted a band at a time, and an image encrypted
+// that way is decrypted the same way; anything else can't be, and fails with the budget it is over
+fn stream_file(
+    input: &Path,
+    output: &Path,
+    mode: Mode,
+    key: u64,
+    over_budget: LimitError,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<OperationReport, ImageEncryptionError> {
+    let streamable = match mode {
+        Mode::Enc => ImageFormat::from_path(input).ok() == Some(ImageFormat::Tiff),
+        Mode::Dec => read_header(input)?.is_some_and(|header| header.band_rows.is_some()),
+    };
+    if !streamable {
+        return Err(over_budget.into());
+    }
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temp_name = OsString::from(".");
+    temp_name.push(output.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.tmp", process::id()));
+    let temp = output.with_file_name(temp_name);
+
+    let mut report = OperationReport {
+        bytes_in: fs::metadata(input)?.len(),
+        cipher: Some(Cipher::Legacy),
+        ..Default::default()
+    };
+    let phase = match mode {
+        Mode::Enc => Phase::Encrypt,
+        Mode::Dec => Phase::Decrypt,
+    };
+    let started = report::start_phase(progress, phase, report.bytes_in);
+    let result = (|| {
+        let reader = BufReader::new(File::open(input)?);
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        match mode {
+            Mode::Enc => encrypt_stream(reader, &mut writer, key)?,
+            Mode::Dec => decrypt_stream(reader, &mut writer, key)?,
+        }
+        writer.flush()?;
+        drop(writer);
+        Ok::<_, ImageEncryptionError>(fs::rename(&temp, output)?)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+    report.end_phase(progress, phase, report.bytes_in, started);
+    report.bytes_out = fs::metadata(output)?.len();
+    if mode == Mode::Enc {
+        report.warnings.push(OperationWarning::Streamed);
+    }
+    Ok(report)
+}
+
+// encrypt or decrypt every supported image in a directory, writing each one to the same relative path
+// under the output directory, with the extension of the format it is written in; a failure with one image doesn't stop the others, every outcome is returned
+// in path order, and only failing to list the directory is an error
+pub fn process_directory(
+    path: impl AsRef<Path>,
+    mode: Mode,
+    key: u64,
+    options: &DirectoryOptions,
+) -> io::Result<Vec<FileOutcome>> {
+    process_directory_with_progress(path, mode, key, options, |_| {})
+}
+
+// the same, telling the callback how far the directory and the image being processed have got
+pub fn process_directory_with_progress(
+    path: impl AsRef<Path>,
+    mode: Mode,
+    key: u64,
+    options: &DirectoryOptions,
+    mut progress: impl FnMut(DirectoryProgress),
+) -> io::Result<Vec<FileOutcome>> {
+    let input_dir = path.as_ref();
+    let output_dir = options.output.as_deref().unwrap_or(input_dir);
+
+    // the whole tree is listed before anything is written, so new outputs are never picked up as inputs
+    let skip = fs::canonicalize(output_dir)
+        .ok()
+        .filter(|dir| fs::canonicalize(input_dir).ok().as_ref() != Some(dir));
+    let mut images = Vec::new();
+    collect_images(input_dir, skip.as_deref(), options.recursive, &mut images)?;
+
+    let files = images.len();
+    let bucket = &Mutex::new(options.max_throughput.map(TokenBucket::new));
+    let jobs = images.into_iter().map(|input| {
+        let relative = input.strip_prefix(input_dir).unwrap_or(&input);
+        let output = output_dir.join(relative);
+        (input, output)
+    });
+
+    // decoding the next image, encrypting the current one and encoding the one before run on threads
+    // of their own, so the codecs take hardly any time of their own on big batches; a channel of one
+    // between every two stages keeps no more than a few images in memory at once, and every image goes
+    // through each stage in turn, so the outcomes come out in path order
+    let (decoded_tx, decoded_rx) = mpsc::sync_channel::<Job<Decoded>>(1);
+    let (crypted_tx, crypted_rx) = mpsc::sync_channel::<Job<Crypted>>(1);
+    let (events, events_rx) = mpsc::channel();
+    Ok(thread::scope(|scope| {
+        let decode_events = events.clone();
+        scope.spawn(move || {
+            for (input, output) in jobs {
+                let result = decode(&input, options, &mut stage_progress(bucket, &decode_events));
+                let job = Job {
+                    input,
+                    output,
+                    result,
+                };
+                if decoded_tx.send(job).is_err() {
+                    break;
+                }
+            }
+        });
+        let crypt_events = events.clone();
+        scope.spawn(move || {
+            for job in decoded_rx {
+                let result = job.result.and_then(|decoded| {
+                    crypt(
+                        &job.input,
+                        &job.output,
+                        decoded,
+                        mode,
+                        key,
+                        options,
+                        &mut stage_progress(bucket, &crypt_events),
+                    )
+                });
+                let job = Job {
+                    input: job.input,
+                    output: job.output,
+                    result,
+                };
+                if crypted_tx.send(job).is_err() {
+                    break;
+                }
+            }
+        });
+        scope.spawn(move || {
+            for job in crypted_rx {
+                let result = job.result.and_then(|crypted| {
+                    encode(
+                        &job.input,
+                        &job.output,
+                        crypted,
+                        options,
+                        &mut stage_progress(bucket, &events),
+                    )
+                });
+                let (output, result) = match result {
+                    Ok((output, report)) => (output, Ok(report)),
+                    Err(err) => (job.output, Err(err)),
+                };
+                let outcome = FileOutcome {
+                    input: job.input,
+                    output,
+                    result,
+                };
+                if events.send(Event::Done(outcome)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut outcomes = Vec::with_capacity(files);
+        for event in events_rx {
+            match event {
+                Event::Progress(current) => progress(DirectoryProgress {
+                    files_done: outcomes.len(),
+                    files,
+                    current,
+                }),
+                Event::Done(outcome) => outcomes.push(outcome),
+            }
+        }
+        outcomes
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ColorType;
+
+    use super::*;
+    use crate::{
+        load_image,
+        test_util::{random_image, rng},
+        write_image_to_vec,
+    };
+
+    // the images come out of the stages in path order, one that fails among them, and decrypt back
+    #[test]
+    fn pipelined_in_order() {
+        let dir = std::env::temp_dir().join(format!("image_encryption-batch-{}", process::id()));
+        let (input, encrypted, decrypted) = (dir.join("in"), dir.join("enc"), dir.join("dec"));
+        fs::create_dir_all(&input).unwrap();
+        let mut rng = rng();
+        let images = (0..5)
+            .map(|i| random_image(&mut rng, 20 + i, 9, ColorType::Rgb8))
+            .collect::<Vec<_>>();
+        for (i, img) in images.iter().enumerate() {
+            let bytes = write_image_to_vec(img).unwrap();
+            fs::write(input.join(format!("{}.png", i)), bytes).unwrap();
+        }
+        fs::write(input.join("2b.png"), b"not a png").unwrap();
+
+        let options = DirectoryOptions {
+            output: Some(encrypted.clone()),
+            ..Default::default()
+        };
+        let outcomes = process_directory(&input, Mode::Enc, 7, &options).unwrap();
+        let names = outcomes
+            .iter()
+            .map(|outcome| outcome.input.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["0.png", "1.png", "2.png", "2b.png", "3.png", "4.png"]
+        );
+        let failed = outcomes.iter().map(|outcome| outcome.result.is_err());
+        assert!(failed.eq([false, false, false, true, false, false]));
+
+        let options = DirectoryOptions {
+            output: Some(decrypted.clone()),
+            ..Default::default()
+        };
+        let outcomes = process_directory(&encrypted, Mode::Dec, 7, &options).unwrap();
+        assert_eq!(outcomes.len(), images.len());
+        for (i, img) in images.iter().enumerate() {
+            let output = load_image(decrypted.join(format!("{}.png", i))).unwrap();
+            assert_eq!(output.pixels, img.pixels);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // an encrypted JPEG is written as a PNG under a .png name, in place of the JPEG or mirrored, and back
+    #[test]
+    fn extension_of_the_written_format() {
+        let dir = std::env::temp_dir().join(format!("image_encryption-rename-{}", process::id()));
+        let decrypted = dir.join("dec");
+        fs::create_dir_all(&dir).unwrap();
+        let mut jpeg = Vec::new();
+        image::DynamicImage::new_rgb8(16, 8)
+            .write_to(
+                &mut io::Cursor::new(&mut jpeg),
+                image::ImageOutputFormat::Jpeg(90),
+            )
+            .unwrap();
+        fs::write(dir.join("a.jpg"), &jpeg).unwrap();
+        // a.png would replace an image of its own
+        fs::write(dir.join("b.jpg"), &jpeg).unwrap();
+        fs::write(dir.join("b.png"), b"another image").unwrap();
+
+        let outcomes = process_directory(&dir, Mode::Enc, 7, &DirectoryOptions::default()).unwrap();
+        let outputs = outcomes
+            .iter()
+            .map(|outcome| outcome.output.strip_prefix(&dir).unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, ["a.png", "b.jpg", "b.png"]);
+        assert!(outcomes[0].result.is_ok());
+        assert!(matches!(
+            &outcomes[1].result,
+            Err(ImageEncryptionError::Io(err)) if err.kind() == io::ErrorKind::AlreadyExists
+        ));
+        assert!(!dir.join("a.jpg").exists());
+        assert_eq!(fs::read(dir.join("b.jpg")).unwrap(), jpeg);
+        assert_eq!(fs::read(dir.join("b.png")).unwrap(), b"another image");
+        assert_eq!(
+            load_image(dir.join("a.png")).unwrap().format,
+            ImageFormat::Png
+        );
+
+        fs::remove_file(dir.join("b.jpg")).unwrap();
+        fs::remove_file(dir.join("b.png")).unwrap();
+        let options = DirectoryOptions {
+            output: Some(decrypted.clone()),
+            ..Default::default()
+        };
+        let outcomes = process_directory(&dir, Mode::Dec, 7, &options).unwrap();
+        assert_eq!(outcomes[0].output, decrypted.join("a.jpg"));
+        assert_eq!(
+            load_image(decrypted.join("a.jpg")).unwrap().format,
+            ImageFormat::Jpeg
+        );
+        assert!(dir.join("a.png").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
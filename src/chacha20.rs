@@ -0,0 +1,237 @@
+use rand::{Error, RngCore};
+
+use crate::{blake3, rng::SeekableRng};
+
+// the ChaCha20 stream cipher (RFC 8439) as a generator, for the secure cipher:
+// unlike xoshiro its output can't be predicted from earlier output,
+// and a nonce stored with every image keeps two images encrypted with the same key
+// from sharing a keystream
+
+pub const NONCE_LEN: usize = 12;
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+// the 64 keystream bytes of one block
+pub(crate) fn block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u8; 64] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+    input[13..].copy_from_slice(nonce);
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut out = [0; 64];
+    for ((chunk, word), input) in out.chunks_exact_mut(4).zip(state).zip(input) {
+        chunk.copy_from_slice(&word.wrapping_add(input).to_le_bytes());
+    }
+    out
+}
+
+fn words<const N: usize>(bytes: &[u8]) -> [u32; N] {
+    std::array::from_fn(|i| u32::from_le_bytes(bytes[4 * i..][..4].try_into().unwrap()))
+}
+
+pub struct ChaCha20Rng {
+    key: [u32; 8],
+    nonce: [u32; 3],
+    counter: u32,
+    buffer: [u8; 64],
+    // how much of the buffer has been handed out
+    used: usize,
+}
+
+impl ChaCha20Rng {
+    pub fn new(key: [u8; 32], nonce: [u8; NONCE_LEN]) -> Self {
+        ChaCha20Rng {
+            key: words(&key),
+            nonce: words(&nonce),
+            counter: 0,
+            buffer: [0; 64],
+            used: 64,
+        }
+    }
+
+    // the 256-bit cipher key is derived from the 64-bit image key, so it is only as hard to guess,
+    // but nothing about the key can be worked out from the keystream
+    pub fn from_key(key: u64, nonce: [u8; NONCE_LEN]) -> Self {
+        let hash = blake3::Hasher::new()
+            .update(b"image_encryption chacha20\0")
+            .update(&key.to_le_bytes())
+            .finalize();
+        Self::new(hash, nonce)
+    }
+}
+
+impl RngCore for ChaCha20Rng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, mut dest: &mut [u8]) {
+        while !dest.is_empty() {
+            if self.used == self.buffer.len() {
+                self.buffer = block(&self.key, self.counter, &self.nonce);
+                // 2^32 blocks are 256 GiB of keystream, more than any image needs
+                self.counter = self.counter.wrapping_add(1);
+                self.used = 0;
+            }
+            let take = (self.buffer.len() - self.used).min(dest.len());
+            dest[..take].copy_from_slice(&self.buffer[self.used..][..take]);
+            self.used += take;
+            dest = &mut dest[take..];
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl SeekableRng for ChaCha20Rng {
+    // every block is 16 outputs, so the block to carry on in is worked out directly
+    fn skip_u32(&mut self, count: u64) {
+        let position = self.counter as u64 * 64 - (self.buffer.len() - self.used) as u64;
+        let position = position + count * 4;
+        self.counter = (position / 64) as u32;
+        self.used = self.buffer.len();
+        let offset = (position % 64) as usize;
+        if offset > 0 {
+            self.buffer = block(&self.key, self.counter, &self.nonce);
+            self.counter = self.counter.wrapping_add(1);
+            self.used = offset;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::to_hex;
+
+    fn bytes<const N: usize>(hex: &str) -> [u8; N] {
+        std::array::from_fn(|i| u8::from_str_radix(&hex[2 * i..][..2], 16).unwrap())
+    }
+
+    // RFC 8439, section 2.3.2
+    #[test]
+    fn block_vector() {
+        let key = words(&bytes::<32>(
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        ));
+        let nonce = words(&bytes::<NONCE_LEN>("000000090000004a00000000"));
+        assert_eq!(
+            to_hex(&block(&key, 1, &nonce)),
+            "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e\
+             d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e"
+        );
+    }
+
+    // RFC 8439, section 2.4.2, which starts at block 1
+    #[test]
+    fn encryption_vector() {
+        let mut rng = ChaCha20Rng::new(
+            bytes("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"),
+            bytes("000000000000004a00000000"),
+        );
+        rng.skip_u32(16);
+        let mut text = b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+            tip for the future, sunscreen would be it."
+            .to_vec();
+        let mut keystream = vec![0; text.len()];
+        rng.fill_bytes(&mut keystream);
+        for (byte, key_byte) in text.iter_mut().zip(keystream) {
+            *byte ^= key_byte;
+        }
+        assert_eq!(
+            to_hex(&text),
+            "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b\
+             f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8\
+             07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736\
+             5af90bbf74a35be6b40b8eedf2785e42874d"
+        );
+    }
+
+    // RFC 8439, appendix A.1, test vectors 1, 2, 3 and 5
+    #[test]
+    fn keystream_vectors() {
+        let mut key = [0; 32];
+        let mut zeros = ChaCha20Rng::new(key, [0; NONCE_LEN]);
+        let mut two_blocks = [0; 128];
+        zeros.fill_bytes(&mut two_blocks);
+        assert_eq!(
+            to_hex(&two_blocks),
+            "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+             da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586\
+             9f07e7be5551387a98ba977c732d080dcb0f29a048e3656912c6533e32ee7aed\
+             29b721769ce64e43d57133b074d839d531ed1f28510afb45ace10a1f4b794d6f"
+        );
+
+        key[31] = 1;
+        assert_eq!(
+            to_hex(&block(&words(&key), 1, &[0; 3])),
+            "3aeb5224ecf849929b9d828db1ced4dd832025e8018b8160b82284f3c949aa5a\
+             8eca00bbb4a73bdad192b5c42f73f2fd4e273644c8b36125a64addeb006c13a0"
+        );
+        let mut nonce = [0; NONCE_LEN];
+        nonce[11] = 2;
+        let mut block = [0; 64];
+        ChaCha20Rng::new([0; 32], nonce).fill_bytes(&mut block);
+        assert_eq!(
+            to_hex(&block),
+            "c2c64d378cd536374ae204b9ef933fcd1a8b2288b3dfa49672ab765b54ee27c7\
+             8a970e0e955c14f3a88e741b97c286f75f8fc299e8148362fa198a39531bed6d"
+        );
+    }
+
+    // skipping ahead in the keystream has to land on the same numbers as drawing up to there
+    #[test]
+    fn skip_ahead() {
+        for offset in [0, 1, 15, 16, 17, 1000, 123_457] {
+            let mut drawn = ChaCha20Rng::from_key(offset, [7; NONCE_LEN]);
+            let mut skipped = ChaCha20Rng::from_key(offset, [7; NONCE_LEN]);
+            // starting partway into a block
+            drawn.next_u32();
+            skipped.next_u32();
+            for _ in 0..offset {
+                drawn.next_u32();
+            }
+            skipped.skip_u32(offset);
+            assert!(
+                (0..20).all(|_| drawn.next_u32() == skipped.next_u32()),
+                "{}",
+                offset
+            );
+        }
+    }
+}
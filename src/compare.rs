@@ -0,0 +1,63 @@
+use crate::Image;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Difference {
+    // same size, same color type and the exact same pixels
+    pub identical: bool,
+    // whether the images have the same size and color type; if not, nothing else can be compared,
+    // every pixel counts as differing and the maximum delta is infinite
+    pub same_layout: bool,
+    // the largest absolute difference between two corresponding samples, in the units of the color type
+    pub max_delta: f64,
+    // the number of pixels with at least one differing sample
+    pub differing_pixels: usize,
+}
+
+// the samples of a pixel as numbers, decoded the same way `Image::to_dynamic` does
+fn samples(pixel: &[u8], sample_size: usize) -> impl Iterator<Item = f64> + '_ {
+    pixel
+        .chunks_exact(sample_size)
+        .map(move |s| match sample_size {
+            2 => u16::from_le_bytes([s[0], s[1]]) as f64,
+            4 => f32::from_le_bytes([s[0], s[1], s[2], s[3]]) as f64,
+            _ => s[0] as f64,
+        })
+}
+
+pub fn compare_images(a: &Image, b: &Image) -> Difference {
+    if a.width != b.width || a.height != b.height || a.color != b.color {
+        return Difference {
+            identical: false,
+            same_layout: false,
+            max_delta: f64::INFINITY,
+            differing_pixels: (a.width as usize * a.height as usize)
+                .max(b.width as usize * b.height as usize),
+        };
+    }
+
+    let pixel_size = (a.color.bytes_per_pixel() as usize).max(1);
+    let sample_size = pixel_size / (a.color.channel_count() as usize).max(1);
+
+    let mut max_delta = 0.0f64;
+    let mut differing_pixels = 0;
+    for (pa, pb) in a
+        .pixels
+        .chunks_exact(pixel_size)
+        .zip(b.pixels.chunks_exact(pixel_size))
+    {
+        if pa == pb {
+            continue;
+        }
+        differing_pixels += 1;
+        for (sa, sb) in samples(pa, sample_size).zip(samples(pb, sample_size)) {
+            max_delta = max_delta.max((sa - sb).abs());
+        }
+    }
+
+    Difference {
+        identical: differing_pixels == 0,
+        same_layout: true,
+        max_delta,
+        differing_pixels,
+    }
+}
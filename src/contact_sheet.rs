@@ -0,0 +1,63 @@
+use image::{imageops, ColorType, DynamicImage, ImageFormat, Rgba, RgbaImage};
+
+use crate::Image;
+
+const SPACING: u32 = 4;
+const BACKGROUND: Rgba<u8> = Rgba([32, 32, 32, 255]);
+
+// a copy of the image scaled down to fit in a size x size square, keeping the aspect ratio
+pub fn thumbnail(img: &Image, size: u32) -> Image {
+    let rgba = match img.to_dynamic() {
+        Some(image) => image.thumbnail(size, size).to_rgba8(),
+        None => RgbaImage::from_pixel(size, size, BACKGROUND),
+    };
+
+    Image {
+        format: ImageFormat::Png,
+        width: rgba.width(),
+        height: rgba.height(),
+        color: ColorType::Rgba8,
+        pixels: rgba.into_raw(),
+        header: None,
+        metadata: Vec::new(),
+        jpeg_segments: Vec::new(),
+    }
+}
+
+// lay the images out on a grid with the given number of columns,
+// each one scaled down to fit in a tile_size x tile_size cell
+pub fn contact_sheet(images: &[Image], columns: u32, tile_size: u32) -> Image {
+    let columns = columns.clamp(1, images.len().max(1) as u32);
+    let rows = (images.len() as u32).div_ceil(columns);
+    let cell = tile_size + SPACING;
+
+    let mut sheet = RgbaImage::from_pixel(
+        columns * cell + SPACING,
+        rows.max(1) * cell + SPACING,
+        BACKGROUND,
+    );
+
+    for (i, img) in images.iter().enumerate() {
+        let tile = thumbnail(img, tile_size);
+        let Some(DynamicImage::ImageRgba8(tile)) = tile.to_dynamic() else {
+            continue;
+        };
+
+        // center each tile in its cell
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        let x = SPACING + column * cell + (tile_size - tile.width()) / 2;
+        let y = SPACING + row * cell + (tile_size - tile.height()) / 2;
+        imageops::replace(&mut sheet, &tile, x as i64, y as i64);
+    }
+
+    Image {
+        format: ImageFormat::Png,
+        width: sheet.width(),
+        height: sheet.height(),
+        color: ColorType::Rgba8,
+        pixels: sheet.into_raw(),
+        header: None,
+        metadata: Vec::new(),
+        jpeg_segments: Vec::new(),
+    }
+}
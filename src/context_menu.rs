@@ -0,0 +1,141 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::ImageEncryptionError;
+
+// "Encrypt with image_encryption" and "Decrypt with image_encryption" entries in the file manager's
+// menu for images, which run the CLI on the file in place in a terminal that prompts for the key
+const ACTIONS: [(&str, &str, &str); 2] = [
+    ("encrypt", "Encrypt with image_encryption", "enc"),
+    ("decrypt", "Decrypt with image_encryption", "dec"),
+];
+
+// the image types the Linux entries are offered for
+const MIME_TYPES: &str = "image/png;image/jpeg;image/bmp;image/tiff;image/gif;image/webp;";
+
+// the Windows entries go on every image type, for the current user only
+const WINDOWS_KEY: &str = r"HKCU\Software\Classes\SystemFileAssociations\image\shell";
+
+// where user .desktop files go, per the XDG base directory spec
+fn applications_dir() -> Result<PathBuf, ImageEncryptionError> {
+    let data_home = match env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(
+            env::var_os("HOME")
+                .ok_or_else(|| ImageEncryptionError::Unsupported("HOME isn't set".to_string()))?,
+        )
+        .join(".local/share"),
+    };
+    Ok(data_home.join("applications"))
+}
+
+fn desktop_file(dir: &Path, action: &str) -> PathBuf {
+    dir.join(format!("image_encryption-{}.desktop", action))
+}
+
+// a quoted argument of a desktop entry's Exec key
+fn desktop_quote(arg: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        if matches!(c, '"' | '`' | '$' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    // backslashes are escaped once more by the desktop entry format itself
+    quoted.replace('\\', "\\\\")
+}
+
+// run reg.exe quietly, with what it printed as the error if it fails
+fn reg(args: &[&str]) -> Result<(), ImageEncryptionError> {
+    let output = Command::new("reg").args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "reg {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+// install the menu entries for this executable, returning where they were installed
+pub fn register_context_menu(exe: impl AsRef<Path>) -> Result<Vec<String>, ImageEncryptionError> {
+    let exe = exe.as_ref().to_str().ok_or_else(|| {
+        ImageEncryptionError::Unsupported("the executable path isn't valid UTF-8".to_string())
+    })?;
+    let mut installed = Vec::new();
+    if cfg!(windows) {
+        for (action, name, command) in ACTIONS {
+            let key = format!(r"{}\image_encryption.{}", WINDOWS_KEY, action);
+            reg(&["add", &key, "/ve", "/d", name, "/f"])?;
+            let command = format!("\"{}\" {} - \"%1\"", exe, command);
+            reg(&[
+                "add",
+                &format!(r"{}\command", key),
+                "/ve",
+                "/d",
+                &command,
+                "/f",
+            ])?;
+            installed.push(key);
+        }
+    } else if cfg!(target_os = "linux") {
+        let dir = applications_dir()?;
+        fs::create_dir_all(&dir)?;
+        for (action, name, command) in ACTIONS {
+            // hidden from the application menu, but listed under "Open With" for images
+            let entry = format!(
+                "[Desktop Entry]\nType=Application\nName={}\nExec={} {} - %f\nTerminal=true\nNoDisplay=true\nMimeType={}\n",
+                name,
+                desktop_quote(exe),
+                command,
+                MIME_TYPES
+            );
+            let path = desktop_file(&dir, action);
+            fs::write(&path, entry)?;
+            installed.push(path.display().to_string());
+        }
+        // the desktop database caches which entries handle which types; without it they show up on next login
+        let _ = Command::new("update-desktop-database").arg(&dir).status();
+    } else {
+        return Err(ImageEncryptionError::Unsupported(
+            "context menu entries can only be installed on Windows and Linux".to_string(),
+        ));
+    }
+    Ok(installed)
+}
+
+// remove the menu entries, returning the ones that were there
+pub fn unregister_context_menu() -> Result<Vec<String>, ImageEncryptionError> {
+    let mut removed = Vec::new();
+    if cfg!(windows) {
+        for (action, _, _) in ACTIONS {
+            let key = format!(r"{}\image_encryption.{}", WINDOWS_KEY, action);
+            if reg(&["query", &key]).is_ok() {
+                reg(&["delete", &key, "/f"])?;
+                removed.push(key);
+            }
+        }
+    } else if cfg!(target_os = "linux") {
+        let dir = applications_dir()?;
+        for (action, _, _) in ACTIONS {
+            let path = desktop_file(&dir, action);
+            if path.exists() {
+                fs::remove_file(&path)?;
+                removed.push(path.display().to_string());
+            }
+        }
+        let _ = Command::new("update-desktop-database").arg(&dir).status();
+    } else {
+        return Err(ImageEncryptionError::Unsupported(
+            "context menu entries can only be installed on Windows and Linux".to_string(),
+        ));
+    }
+    Ok(removed)
+}
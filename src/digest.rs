@@ -0,0 +1,101 @@
+use image::ColorType;
+use rand::RngCore;
+
+use crate::{blake3, Image};
+
+pub const DIGEST_LEN: usize = 16;
+
+// a digest of the plaintext stored in the header, encrypted with the key under a random salt,
+// so holders of the key can tell which files hold the same image without decrypting them,
+// while to everyone else equal images still look unrelated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct SealedDigest {
+    pub salt: [u8; DIGEST_LEN],
+    pub sealed: [u8; DIGEST_LEN],
+}
+
+// what identifies an image: its size, color type and pixels
+pub(crate) fn plaintext_digest(img: &Image) -> [u8; DIGEST_LEN] {
+    let mut hasher = digest_hasher(img.width, img.height, img.color);
+    hasher.update(&img.pixels);
+    finish_digest(&hasher)
+}
+
+// the hasher of `plaintext_digest` before any pixel, so the pixels can be fed a band at a time
+pub(crate) fn digest_hasher(width: u32, height: u32, color: ColorType) -> blake3::Hasher {
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update(b"image_encryption plaintext digest\0")
+        .update(&width.to_le_bytes())
+        .update(&height.to_le_bytes())
+        .update(&[color.bytes_per_pixel(), color.channel_count()]);
+    hasher
+}
+
+pub(crate) fn finish_digest(hasher: &blake3::Hasher) -> [u8; DIGEST_LEN] {
+    hasher.finalize()[..DIGEST_LEN].try_into().unwrap()
+}
+
+// the plaintext digest and the content hash of the ciphertext, fed a block at a time by the pass
+// that encrypts the image, so neither buffer has to be read again for them
+pub(crate) struct PassHashes {
+    plaintext: blake3::Hasher,
+    ciphertext: Option<blake3::Hasher>,
+}
+
+impl PassHashes {
+    // the content hash of the ciphertext is only worked out if asked for
+    pub(crate) fn new(img: &Image, ciphertext: bool) -> Self {
+        PassHashes {
+            plaintext: digest_hasher(img.width, img.height, img.color),
+            ciphertext: ciphertext.then(|| crate::content_hasher(img.width, img.height)),
+        }
+    }
+
+    pub(crate) fn update(&mut self, plaintext: &[u8], ciphertext: &[u8]) {
+        self.plaintext.update(plaintext);
+        if let Some(hasher) = &mut self.ciphertext {
+            hasher.update(ciphertext);
+        }
+    }
+
+    pub(crate) fn digest(&self) -> [u8; DIGEST_LEN] {
+        finish_digest(&self.plaintext)
+    }
+
+    // the same as `content_hash` of the ciphertext
+    pub(crate) fn content_hash(&self) -> Option<String> {
+        let hasher = self.ciphertext.as_ref()?;
+        Some(crate::to_hex(&hasher.finalize()))
+    }
+}
+
+fn pad(key: u64, salt: &[u8; DIGEST_LEN]) -> [u8; DIGEST_LEN] {
+    let hash = blake3::Hasher::new()
+        .update(b"image_encryption digest pad\0")
+        .update(&key.to_le_bytes())
+        .update(salt)
+        .finalize();
+    hash[..DIGEST_LEN].try_into().unwrap()
+}
+
+fn xor(a: [u8; DIGEST_LEN], b: [u8; DIGEST_LEN]) -> [u8; DIGEST_LEN] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+impl SealedDigest {
+    pub(crate) fn seal(digest: [u8; DIGEST_LEN], key: u64) -> Self {
+        let mut salt = [0; DIGEST_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        SealedDigest {
+            salt,
+            sealed: xor(digest, pad(key, &salt)),
+        }
+    }
+
+    // the digest of the plaintext; with the wrong key this is just as well formed, but random
+    pub fn open(&self, key: u64) -> [u8; DIGEST_LEN] {
+        xor(self.sealed, pad(key, &self.salt))
+    }
+}
@@ -0,0 +1,188 @@
+use std::{fs, io::Cursor, path::Path};
+
+use image::{ColorType, DynamicImage, ImageFormat, ImageResult};
+use rand::{Rng, RngCore};
+
+use crate::{encoder::encode_pixels, parse_header, EncryptionHeader, WriteOptions};
+
+// an experimental disguise for ciphertext: the whole encrypted file is hidden in the two low bits
+// of every sample of a generated picture of clouds, written as a PNG, so the output looks like
+// an ordinary photo of the sky instead of static. It only fools a casual look: anyone who reads
+// the low bits finds the file, and anything that re-encodes the picture lossily destroys it
+//
+//     [file length: u32 le][file...][random bits up to the end of the picture]
+const LOW_BITS: u8 = 2;
+const LOW_MASK: u8 = (1 << LOW_BITS) - 1;
+const SAMPLES_PER_BYTE: usize = 8 / LOW_BITS as usize;
+const CHANNELS: usize = 3;
+// an 8-bit RGB PNG starts with its signature and IHDR, which has the bit depth and color type at these offsets
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const IHDR_DEPTH: usize = 24;
+const IHDR_COLOR_TYPE: usize = 25;
+// how many layers of ever finer noise the clouds are made of, each half as strong as the one before
+const OCTAVES: u32 = 6;
+
+const SKY_TOP: [f64; 3] = [62.0, 118.0, 196.0];
+const SKY_HORIZON: [f64; 3] = [156.0, 192.0, 228.0];
+const CLOUD_LIGHT: [f64; 3] = [250.0, 250.0, 252.0];
+const CLOUD_SHADE: [f64; 3] = [196.0, 200.0, 210.0];
+
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn mix(a: [f64; 3], b: [f64; 3], t: f64) -> [f64; 3] {
+    std::array::from_fn(|c| a[c] + (b[c] - a[c]) * t)
+}
+
+// fractal value noise in [0, 1]: random values on ever finer lattices, smoothly interpolated and summed
+fn clouds(width: usize, height: usize, rng: &mut impl RngCore) -> Vec<f64> {
+    let mut field = vec![0.0; width * height];
+    let (mut cell, mut amplitude, mut total) = (width.max(height) as f64 / 6.0, 1.0, 0.0);
+    for _ in 0..OCTAVES {
+        let cell_size = cell.max(1.0);
+        let columns = (width as f64 / cell_size) as usize + 2;
+        let rows = (height as f64 / cell_size) as usize + 2;
+        let lattice = (0..columns * rows)
+            .map(|_| rng.gen::<f64>())
+            .collect::<Vec<_>>();
+        for y in 0..height {
+            let fy = y as f64 / cell_size;
+            let (row, ty) = (fy as usize, smoothstep(0.0, 1.0, fy.fract()));
+            for x in 0..width {
+                let fx = x as f64 / cell_size;
+                let (column, tx) = (fx as usize, smoothstep(0.0, 1.0, fx.fract()));
+                let at = |column: usize, row: usize| lattice[row * columns + column];
+                let top = at(column, row) + (at(column + 1, row) - at(column, row)) * tx;
+                let bottom =
+                    at(column, row + 1) + (at(column + 1, row + 1) - at(column, row + 1)) * tx;
+                field[y * width + x] += amplitude * (top + (bottom - top) * ty);
+            }
+        }
+        total += amplitude;
+        amplitude /= 2.0;
+        cell /= 2.0;
+    }
+    field.iter_mut().for_each(|value| *value /= total);
+    field
+}
+
+// the pixels of a sky with clouds, as RGB
+fn sky(width: usize, height: usize, rng: &mut impl RngCore) -> Vec<u8> {
+    let field = clouds(width, height, rng);
+    let mut pixels = Vec::with_capacity(width * height * CHANNELS);
+    for (i, &density) in field.iter().enumerate() {
+        let sky = mix(
+            SKY_TOP,
+            SKY_HORIZON,
+            (i / width) as f64 / height.max(2) as f64,
+        );
+        // the thickest parts of a cloud are in its own shadow
+        let cloud = mix(CLOUD_LIGHT, CLOUD_SHADE, smoothstep(0.6, 0.9, density));
+        let color = mix(sky, cloud, smoothstep(0.5, 0.72, density));
+        pixels.extend(color.iter().map(|&value| value.round() as u8));
+    }
+    pixels
+}
+
+// a generated picture hiding the file in its low bits, encoded as a PNG
+pub(crate) fn cover(file: &[u8]) -> ImageResult<Vec<u8>> {
+    let mut payload = (file.len() as u32).to_le_bytes().to_vec();
+    payload.extend_from_slice(file);
+
+    // a picture of about 4:3 with room for every bit of the payload
+    let pixels = (payload.len() * SAMPLES_PER_BYTE).div_ceil(CHANNELS);
+    let width = ((pixels as f64 * 4.0 / 3.0).sqrt().ceil() as usize).max(1);
+    let height = pixels.div_ceil(width).max(1);
+
+    let mut rng = rand::thread_rng();
+    let mut samples = sky(width, height, &mut rng);
+    // the samples past the payload get random bits, so the low bits look the same all over
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let bits = match payload.get(i / SAMPLES_PER_BYTE) {
+            Some(byte) => {
+                let shift = 8 - LOW_BITS as usize * (i % SAMPLES_PER_BYTE + 1);
+                byte >> shift & LOW_MASK
+            }
+            None => rng.gen::<u8>() & LOW_MASK,
+        };
+        *sample = *sample & !LOW_MASK | bits;
+    }
+
+    let mut bytes = Cursor::new(Vec::new());
+    encode_pixels(
+        &mut bytes,
+        &samples,
+        (width as u32, height as u32),
+        ColorType::Rgb8,
+        ImageFormat::Png,
+        &WriteOptions::default(),
+    )?;
+    Ok(bytes.into_inner())
+}
+
+// the file hidden in the low bits of the RGB samples of a cover, if they hold one that fits;
+// any picture gives some bytes, so whether they are an encrypted file is for the caller to check
+pub(crate) fn reveal(samples: &[u8]) -> Option<Vec<u8>> {
+    let byte = |i: usize| {
+        samples[i * SAMPLES_PER_BYTE..][..SAMPLES_PER_BYTE]
+            .iter()
+            .fold(0, |byte, sample| byte << LOW_BITS | sample & LOW_MASK)
+    };
+    if samples.len() < 4 * SAMPLES_PER_BYTE {
+        return None;
+    }
+    let len = u32::from_le_bytes(std::array::from_fn(byte)) as usize;
+    if (4 + len) * SAMPLES_PER_BYTE > samples.len() {
+        return None;
+    }
+    Some((4..4 + len).map(byte).collect())
+}
+
+// the header of the encrypted file hidden in the picture at this path, if it is a cover;
+// only 8-bit RGB PNGs are decoded to look
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn hidden_header(path: &Path) -> Option<EncryptionHeader> {
+    let bytes = fs::read(path).ok()?;
+    let rgb8 = bytes.starts_with(PNG_SIGNATURE)
+        && bytes.get(IHDR_DEPTH) == Some(&8)
+        && bytes.get(IHDR_COLOR_TYPE) == Some(&2);
+    if !rgb8 {
+        return None;
+    }
+    let DynamicImage::ImageRgb8(cover) =
+        image::load_from_memory_with_format(&bytes, ImageFormat::Png).ok()?
+    else {
+        return None;
+    };
+    parse_header(&reveal(cover.as_raw())?).ok().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compare_images, decrypt_image, encrypt_image_with, load_image_from_bytes,
+        test_util::{random_image, rng},
+        write_image_to_vec, EncryptOptions,
+    };
+
+    use super::*;
+
+    // a disguised file has to be found again in its cover when it is loaded
+    #[test]
+    fn found_in_cover() {
+        let mut rng = rng();
+        let key = rng.next_u64();
+        let original = random_image(&mut rng, 37, 23, ColorType::Rgb8);
+        let mut img = original.clone();
+        let options = EncryptOptions {
+            disguise: true,
+            ..Default::default()
+        };
+        encrypt_image_with(&mut img, key, &options);
+        let mut found = load_image_from_bytes(&write_image_to_vec(&img).unwrap()).unwrap();
+        assert!(decrypt_image(&mut found, key).is_ok());
+        assert!(compare_images(&original, &found).identical);
+    }
+}
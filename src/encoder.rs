@@ -0,0 +1,243 @@
+use std::io::{Cursor, Write};
+
+use crate::{png_store::encode_stored, Image};
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType, PngEncoder},
+    },
+    error::{EncodingError, ImageFormatHint, UnsupportedError, UnsupportedErrorKind},
+    ColorType, ImageEncoder, ImageError, ImageFormat, ImageResult,
+};
+use tiff::encoder::{
+    colortype,
+    compression::{Compression, Deflate, Lzw, Packbits, Uncompressed},
+    TiffEncoder,
+};
+
+// how hard the PNG encoder tries to shrink the image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PngCompression {
+    // no compression at all, which is all ciphertext is worth; the filter is ignored
+    Store,
+    #[default]
+    Fast,
+    Default,
+    Best,
+}
+
+// the filter the PNG encoder runs over each row before compressing it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    // pick the best of the others for each row
+    #[default]
+    Adaptive,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TiffCompression {
+    #[default]
+    None,
+    Lzw,
+    Deflate,
+    PackBits,
+}
+
+// how to encode each output format; the options for other formats than the one written are ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    // None to store images that are encrypted as a whole, which don't compress,
+    // and compress the rest with the default level
+    pub png_compression: Option<PngCompression>,
+    pub png_filter: PngFilter,
+    pub tiff_compression: TiffCompression,
+    // from 1 to 100; the encoder's own default is too low for images that are meant to be decrypted again
+    pub jpeg_quality: u8,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            png_compression: None,
+            png_filter: PngFilter::default(),
+            tiff_compression: TiffCompression::default(),
+            jpeg_quality: 100,
+        }
+    }
+}
+
+impl WriteOptions {
+    // the options with the PNG compression settled for this image
+    pub(crate) fn for_image(&self, img: &Image) -> WriteOptions {
+        // a region or a banner leaves plaintext in the image, which still compresses
+        let ciphertext = img
+            .header
+            .as_ref()
+            .is_some_and(|header| header.regions.is_empty() && header.reserved.is_none());
+        WriteOptions {
+            png_compression: Some(self.png_compression.unwrap_or(if ciphertext {
+                PngCompression::Store
+            } else {
+                PngCompression::default()
+            })),
+            ..*self
+        }
+    }
+}
+
+fn png_encoder<W: Write>(
+    w: W,
+    compression: PngCompression,
+    options: &WriteOptions,
+) -> PngEncoder<W> {
+    let compression = match compression {
+        // handled before getting here
+        PngCompression::Store | PngCompression::Fast => CompressionType::Fast,
+        PngCompression::Default => CompressionType::Default,
+        PngCompression::Best => CompressionType::Best,
+    };
+    let filter = match options.png_filter {
+        PngFilter::None => FilterType::NoFilter,
+        PngFilter::Sub => FilterType::Sub,
+        PngFilter::Up => FilterType::Up,
+        PngFilter::Avg => FilterType::Avg,
+        PngFilter::Paeth => FilterType::Paeth,
+        PngFilter::Adaptive => FilterType::Adaptive,
+    };
+    PngEncoder::new_with_quality(w, compression, filter)
+}
+
+fn tiff_error(err: tiff::TiffError) -> ImageError {
+    match err {
+        tiff::TiffError::IoError(err) => ImageError::IoError(err),
+        err => ImageError::Encoding(EncodingError::new(ImageFormat::Tiff.into(), err)),
+    }
+}
+
+// 16-bit and float pixels are kept as little-endian bytes, see `dynamic_bytes`
+fn u16_samples(pixels: &[u8]) -> Vec<u16> {
+    pixels
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect()
+}
+
+fn f32_samples(pixels: &[u8]) -> Vec<f32> {
+    pixels
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+        .collect()
+}
+
+fn write_tiff<D: Compression>(
+    bytes: &mut Cursor<Vec<u8>>,
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+    compression: D,
+) -> ImageResult<()> {
+    let mut encoder = TiffEncoder::new(bytes).map_err(tiff_error)?;
+    match color {
+        ColorType::L8 => encoder.write_image_with_compression::<colortype::Gray8, _>(
+            width,
+            height,
+            compression,
+            pixels,
+        ),
+        ColorType::Rgb8 => encoder.write_image_with_compression::<colortype::RGB8, _>(
+            width,
+            height,
+            compression,
+            pixels,
+        ),
+        ColorType::Rgba8 => encoder.write_image_with_compression::<colortype::RGBA8, _>(
+            width,
+            height,
+            compression,
+            pixels,
+        ),
+        ColorType::L16 => encoder.write_image_with_compression::<colortype::Gray16, _>(
+            width,
+            height,
+            compression,
+            &u16_samples(pixels),
+        ),
+        ColorType::Rgb16 => encoder.write_image_with_compression::<colortype::RGB16, _>(
+            width,
+            height,
+            compression,
+            &u16_samples(pixels),
+        ),
+        ColorType::Rgba16 => encoder.write_image_with_compression::<colortype::RGBA16, _>(
+            width,
+            height,
+            compression,
+            &u16_samples(pixels),
+        ),
+        ColorType::Rgb32F => encoder.write_image_with_compression::<colortype::RGB32Float, _>(
+            width,
+            height,
+            compression,
+            &f32_samples(pixels),
+        ),
+        ColorType::Rgba32F => encoder.write_image_with_compression::<colortype::RGBA32Float, _>(
+            width,
+            height,
+            compression,
+            &f32_samples(pixels),
+        ),
+        _ => {
+            return Err(ImageError::Unsupported(
+                UnsupportedError::from_format_and_kind(
+                    ImageFormatHint::Exact(ImageFormat::Tiff),
+                    UnsupportedErrorKind::Color(color.into()),
+                ),
+            ))
+        }
+    }
+    .map_err(tiff_error)
+}
+
+// encode raw pixels in the given format with the knobs for that format,
+// which are expected to be settled for the image with `WriteOptions::for_image`
+pub(crate) fn encode_pixels(
+    bytes: &mut Cursor<Vec<u8>>,
+    pixels: &[u8],
+    (width, height): (u32, u32),
+    color: ColorType,
+    format: ImageFormat,
+    options: &WriteOptions,
+) -> ImageResult<()> {
+    match format {
+        ImageFormat::Png => match options.png_compression.unwrap_or_default() {
+            PngCompression::Store => {
+                bytes
+                    .get_mut()
+                    .extend(encode_stored(pixels, width, height, color)?);
+                Ok(())
+            }
+            compression => {
+                png_encoder(bytes, compression, options).write_image(pixels, width, height, color)
+            }
+        },
+        ImageFormat::Jpeg => {
+            JpegEncoder::new_with_quality(bytes, options.jpeg_quality.clamp(1, 100))
+                .write_image(pixels, width, height, color)
+        }
+        ImageFormat::Tiff => match options.tiff_compression {
+            TiffCompression::None => write_tiff(bytes, pixels, width, height, color, Uncompressed),
+            TiffCompression::Lzw => write_tiff(bytes, pixels, width, height, color, Lzw),
+            TiffCompression::Deflate => {
+                write_tiff(bytes, pixels, width, height, color, Deflate::default())
+            }
+            TiffCompression::PackBits => write_tiff(bytes, pixels, width, height, color, Packbits),
+        },
+        _ => image::write_buffer_with_format(bytes, pixels, width, height, color, format),
+    }
+}
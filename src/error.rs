@@ -0,0 +1,215 @@
+use std::{error::Error, fmt, io};
+
+use image::{
+    error::{DecodingError, ImageFormatHint},
+    ColorType, ImageError, ImageFormat,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::UploadError;
+use crate::{
+    DctError, DecryptError, HeaderError, KeyError, LimitError, RekeyError, ShareError, StegoError,
+};
+
+// the error of everything that loads, writes or streams images. The narrower errors of functions that
+// can only fail one way, like `DecryptError` of `decrypt_image`, convert into it, so a caller can
+// handle all of them in one place, and the decryption failures become variants of their own
+#[derive(Debug)]
+pub enum ImageEncryptionError {
+    Io(io::Error),
+    // the image crate couldn't decode or encode the file
+    Decode(ImageError),
+    // pixels of a color type the operation doesn't handle
+    UnsupportedColor(ColorType),
+    // a file or a platform the operation doesn't handle, e.g. a TIFF with separate color planes to stream
+    Unsupported(String),
+    // arguments that can't be acted on, e.g. an animation without frames to write
+    Invalid(String),
+    // a file that decodes but doesn't hold together, e.g. a TIFF strip shorter than its rows
+    Malformed(String),
+    BadKey(KeyError),
+    Header(HeaderError),
+    Limit(LimitError),
+    NotEncrypted,
+    // the authentication tag doesn't match the ciphertext: the key is wrong or the file was modified
+    AuthFailure,
+    // the key doesn't match the fingerprint in the header, for images without an authentication tag
+    WrongKey,
+    // the image isn't the size the header says it was encrypted at, so it was resized or cropped
+    DimensionMismatch {
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+    // the file was encrypted in the DCT domain, and only `decrypt_jpeg_dct` can decrypt it
+    DctDomain,
+    // the image is a share of a split image, and only `combine_images` with the other shares gives it back
+    Share,
+    Dct(DctError),
+    Rekey(RekeyError),
+    Shares(ShareError),
+    Stego(StegoError),
+    // the server didn't take an upload
+    #[cfg(not(target_arch = "wasm32"))]
+    Upload(UploadError),
+}
+
+impl fmt::Display for ImageEncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageEncryptionError::Io(err) => write!(f, "{}", err),
+            ImageEncryptionError::Decode(err) => write!(f, "{}", err),
+            ImageEncryptionError::UnsupportedColor(color) => {
+                write!(f, "unsupported color type {:?}", color)
+            }
+            ImageEncryptionError::Unsupported(what)
+            | ImageEncryptionError::Invalid(what)
+            | ImageEncryptionError::Malformed(what) => write!(f, "{}", what),
+            ImageEncryptionError::BadKey(err) => write!(f, "{}", err),
+            ImageEncryptionError::Header(err) => write!(f, "{}", err),
+            ImageEncryptionError::Limit(err) => write!(f, "{}", err),
+            ImageEncryptionError::NotEncrypted => write!(f, "not encrypted"),
+            ImageEncryptionError::AuthFailure => {
+                write!(f, "{}", DecryptError::AuthenticationFailed)
+            }
+            ImageEncryptionError::WrongKey => write!(f, "{}", DecryptError::WrongKey),
+            &ImageEncryptionError::DimensionMismatch { expected, found } => {
+                write!(f, "{}", DecryptError::DimensionMismatch { expected, found })
+            }
+            ImageEncryptionError::DctDomain => write!(f, "{}", DecryptError::DctDomain),
+            ImageEncryptionError::Share => write!(f, "{}", DecryptError::Share),
+            ImageEncryptionError::Dct(err) => write!(f, "{}", err),
+            ImageEncryptionError::Rekey(err) => write!(f, "{}", err),
+            ImageEncryptionError::Shares(err) => write!(f, "{}", err),
+            ImageEncryptionError::Stego(err) => write!(f, "{}", err),
+            #[cfg(not(target_arch = "wasm32"))]
+            ImageEncryptionError::Upload(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ImageEncryptionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ImageEncryptionError::Io(err) => Some(err),
+            ImageEncryptionError::Decode(err) => Some(err),
+            ImageEncryptionError::BadKey(err) => Some(err),
+            ImageEncryptionError::Header(err) => Some(err),
+            ImageEncryptionError::Limit(err) => Some(err),
+            ImageEncryptionError::Dct(err) => Some(err),
+            ImageEncryptionError::Rekey(err) => Some(err),
+            ImageEncryptionError::Shares(err) => Some(err),
+            ImageEncryptionError::Stego(err) => Some(err),
+            #[cfg(not(target_arch = "wasm32"))]
+            ImageEncryptionError::Upload(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImageEncryptionError {
+    fn from(err: io::Error) -> Self {
+        ImageEncryptionError::Io(err)
+    }
+}
+
+// the image crate wraps the IO errors of reading and writing files, which are told apart here
+impl From<ImageError> for ImageEncryptionError {
+    fn from(err: ImageError) -> Self {
+        match err {
+            ImageError::IoError(err) => ImageEncryptionError::Io(err),
+            err => ImageEncryptionError::Decode(err),
+        }
+    }
+}
+
+impl From<tiff::TiffError> for ImageEncryptionError {
+    fn from(err: tiff::TiffError) -> Self {
+        match err {
+            tiff::TiffError::IoError(err) => ImageEncryptionError::Io(err),
+            err => ImageEncryptionError::Decode(ImageError::Decoding(DecodingError::new(
+                ImageFormatHint::Exact(ImageFormat::Tiff),
+                err,
+            ))),
+        }
+    }
+}
+
+impl From<KeyError> for ImageEncryptionError {
+    fn from(err: KeyError) -> Self {
+        ImageEncryptionError::BadKey(err)
+    }
+}
+
+impl From<HeaderError> for ImageEncryptionError {
+    fn from(err: HeaderError) -> Self {
+        ImageEncryptionError::Header(err)
+    }
+}
+
+impl From<LimitError> for ImageEncryptionError {
+    fn from(err: LimitError) -> Self {
+        ImageEncryptionError::Limit(err)
+    }
+}
+
+impl From<DecryptError> for ImageEncryptionError {
+    fn from(err: DecryptError) -> Self {
+        match err {
+            DecryptError::AuthenticationFailed => ImageEncryptionError::AuthFailure,
+            DecryptError::WrongKey => ImageEncryptionError::WrongKey,
+            DecryptError::DimensionMismatch { expected, found } => {
+                ImageEncryptionError::DimensionMismatch { expected, found }
+            }
+            DecryptError::DctDomain => ImageEncryptionError::DctDomain,
+            DecryptError::Share => ImageEncryptionError::Share,
+            DecryptError::Malformed(_) => ImageEncryptionError::Malformed(err.to_string()),
+        }
+    }
+}
+
+impl From<DctError> for ImageEncryptionError {
+    fn from(err: DctError) -> Self {
+        match err {
+            DctError::Header(err) => ImageEncryptionError::Header(err),
+            DctError::NotEncrypted => ImageEncryptionError::NotEncrypted,
+            DctError::WrongKey => ImageEncryptionError::WrongKey,
+            DctError::AuthenticationFailed => ImageEncryptionError::AuthFailure,
+            err => ImageEncryptionError::Dct(err),
+        }
+    }
+}
+
+impl From<RekeyError> for ImageEncryptionError {
+    fn from(err: RekeyError) -> Self {
+        match err {
+            RekeyError::NotEncrypted => ImageEncryptionError::NotEncrypted,
+            RekeyError::WrongKey => ImageEncryptionError::WrongKey,
+            err => ImageEncryptionError::Rekey(err),
+        }
+    }
+}
+
+impl From<ShareError> for ImageEncryptionError {
+    fn from(err: ShareError) -> Self {
+        ImageEncryptionError::Shares(err)
+    }
+}
+
+impl From<StegoError> for ImageEncryptionError {
+    fn from(err: StegoError) -> Self {
+        ImageEncryptionError::Stego(err)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<UploadError> for ImageEncryptionError {
+    fn from(err: UploadError) -> Self {
+        match err {
+            UploadError::Io(err) => ImageEncryptionError::Io(err),
+            UploadError::InvalidUrl(_) | UploadError::UnsupportedScheme(_) => {
+                ImageEncryptionError::Invalid(err.to_string())
+            }
+            err => ImageEncryptionError::Upload(err),
+        }
+    }
+}
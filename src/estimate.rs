@@ -0,0 +1,135 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::{fs::File, io::BufReader, path::Path};
+use std::{io::Cursor, ops::Range};
+
+#[cfg(not(target_arch = "wasm32"))]
+use image::{
+    codecs::{jpeg::JpegDecoder, png::PngDecoder},
+    io::Reader,
+    ImageDecoder,
+};
+use image::{ColorType, ImageFormat, ImageResult};
+use rand::RngCore;
+#[cfg(not(target_arch = "wasm32"))]
+use tiff::decoder::Decoder;
+
+use crate::{
+    encoder::encode_pixels, lossless_format, png_store::stored_len, Cipher, EncryptionHeader,
+    Image, ImageEncryptionError, KeyFingerprint, PermutationUnit, PngCompression, WriteOptions,
+};
+
+// the side of the square of noise that stands in for the ciphertext when estimating compressed sizes
+const SAMPLE_SIDE: u32 = 64;
+// how far the size of encoded noise strays from the estimate made from the sample
+const SAMPLE_SPREAD: f64 = 0.05;
+
+// the size random pixels of this size encode to
+fn noise_len(
+    width: u32,
+    height: u32,
+    color: ColorType,
+    format: ImageFormat,
+    options: &WriteOptions,
+) -> ImageResult<u64> {
+    let mut pixels = vec![0; width as usize * height as usize * color.bytes_per_pixel() as usize];
+    rand::thread_rng().fill_bytes(&mut pixels);
+    let mut bytes = Cursor::new(Vec::new());
+    encode_pixels(&mut bytes, &pixels, (width, height), color, format, options)?;
+    Ok(bytes.into_inner().len() as u64)
+}
+
+// the range the size of the file will fall in once the whole image is encrypted with the default options
+// and written with these; ciphertext is indistinguishable from noise, so it takes about as much space
+// as noise does, which for a compressed format is measured on a small sample; a JPEG is written as a PNG,
+// which comes out many times the size of its source. Not covered are the extra header fields
+// and plaintext parts that some encryption options add, or the metadata of a JPEG container
+pub fn estimate_output_size(
+    img: &Image,
+    options: &WriteOptions,
+) -> Result<Range<u64>, ImageEncryptionError> {
+    let header = EncryptionHeader {
+        cipher: Some(Cipher::Legacy),
+        original_format: Some(img.format),
+        dimensions: Some((img.width, img.height)),
+        permutation_unit: Some(PermutationUnit::default()),
+        key_fingerprint: Some(KeyFingerprint([0; 8])),
+        key_check_iterations: Some(0),
+        key_id: Some(KeyFingerprint([0; 8])),
+        ..Default::default()
+    };
+    let header_len = header.to_bytes().len() as u64;
+    let format = lossless_format(img.format);
+
+    // left to the default, the PNG compression is settled as it is for ciphertext
+    let options = &WriteOptions {
+        png_compression: Some(options.png_compression.unwrap_or(PngCompression::Store)),
+        ..*options
+    };
+    if format == ImageFormat::Png && options.png_compression == Some(PngCompression::Store) {
+        let len = stored_len(img.width, img.height, img.color) + header_len;
+        return Ok(len..len + 1);
+    }
+
+    let pixels = img.width as u64 * img.height as u64;
+    let side = SAMPLE_SIDE.min(img.width).min(img.height);
+    let estimate = if pixels <= (2 * side * side) as u64 {
+        noise_len(img.width, img.height, img.color, format, options)? as f64
+    } else {
+        // two samples tell the fixed overhead of the format apart from the cost of every pixel
+        let small = noise_len(side, side, img.color, format, options)? as f64;
+        let large = noise_len(2 * side, side, img.color, format, options)? as f64;
+        let per_pixel = (large - small).max(0.0) / (side * side) as f64;
+        let fixed = (small - per_pixel * (side * side) as f64).max(0.0);
+        fixed + per_pixel * pixels as f64
+    };
+    Ok((estimate * (1.0 - SAMPLE_SPREAD)) as u64 + header_len
+        ..(estimate * (1.0 + SAMPLE_SPREAD)).ceil() as u64 + header_len + 1)
+}
+
+// about the most memory encrypting or decrypting an image of this many pixels takes in memory:
+// the decoded pixels, the random numbers and the permutation at 4 bytes a pixel each,
+// the ciphertext, and the file it is encoded to, which for noise is about as big as the pixels
+fn working_set(width: u32, height: u32, bytes_per_pixel: u64) -> u64 {
+    let pixels = width as u64 * height as u64;
+    3 * pixels * bytes_per_pixel + 8 * pixels
+}
+
+// the same for an image file, of which only the header is read for its dimensions and color type;
+// the color type of formats other than PNG, JPEG and TIFF isn't looked at, they are taken to decode
+// to 8-bit RGBA like most of them do
+#[cfg(not(target_arch = "wasm32"))]
+pub fn estimate_working_set(path: impl AsRef<Path>) -> Result<u64, ImageEncryptionError> {
+    let path = path.as_ref();
+    let reader = Reader::open(path)?.with_guessed_format()?;
+    let file = || File::open(path).map(BufReader::new);
+    let ((width, height), bytes_per_pixel) = match reader.format() {
+        Some(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(file()?)?;
+            (
+                decoder.dimensions(),
+                decoder.color_type().bytes_per_pixel() as u64,
+            )
+        }
+        Some(ImageFormat::Jpeg) => {
+            let decoder = JpegDecoder::new(file()?)?;
+            (
+                decoder.dimensions(),
+                decoder.color_type().bytes_per_pixel() as u64,
+            )
+        }
+        // read with the TIFF decoder itself, which knows the float TIFFs image doesn't
+        Some(ImageFormat::Tiff) => {
+            let mut decoder = Decoder::new(file()?)?;
+            let bits = match decoder.colortype()? {
+                tiff::ColorType::Gray(bits) => bits as u64,
+                tiff::ColorType::GrayA(bits) => 2 * bits as u64,
+                tiff::ColorType::RGB(bits) => 3 * bits as u64,
+                tiff::ColorType::RGBA(bits) | tiff::ColorType::CMYK(bits) => 4 * bits as u64,
+                _ => 128,
+            };
+            (decoder.dimensions()?, bits.div_ceil(8))
+        }
+        _ => (reader.into_dimensions()?, 4),
+    };
+    Ok(working_set(width, height, bytes_per_pixel))
+}
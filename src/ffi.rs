@@ -0,0 +1,290 @@
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CStr, CString},
+    ptr, slice,
+};
+
+use image::ColorType;
+
+use crate::{
+    decrypt_image, decrypt_raw_frame, encrypt_image, encrypt_raw_frame, load_image, read_raw_frame,
+    write_image, write_raw_frame, FrameDesc, Image, ImageEncryptionError, RawFrameError, RowOrder,
+};
+
+// the C API, for calling the cipher from C, C++ or Python's ctypes; the header is include/image_encryption.h,
+// which cbindgen regenerates from this file when building with the ffi feature. From Python:
+//
+//     lib = ctypes.CDLL("target/release/libimage_encryption.so")
+//     lib.ie_load.restype = ctypes.c_void_p
+//     img = lib.ie_load(b"photo.png")
+//     lib.ie_encrypt(ctypes.c_void_p(img), ctypes.c_uint64(key))
+//     lib.ie_write(ctypes.c_void_p(img), b"photo.enc.png")
+//     lib.ie_free(ctypes.c_void_p(img))
+//
+// images are opaque pointers owned by the caller until ie_free. Functions that can fail return 0 on
+// success, or -1 (or null) with the message kept for ie_last_error, like errno, per thread
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(err: impl ToString) {
+    // the messages come from Display impls, which never contain nul bytes, but a path might
+    let message = err.to_string().replace('\0', "\\0");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).ok());
+}
+
+unsafe fn path_arg<'a>(path: *const c_char) -> Result<&'a str, ImageEncryptionError> {
+    if path.is_null() {
+        return Err(ImageEncryptionError::Invalid(
+            "the path is null".to_string(),
+        ));
+    }
+    CStr::from_ptr(path)
+        .to_str()
+        .map_err(|_| ImageEncryptionError::Invalid("the path isn't valid UTF-8".to_string()))
+}
+
+// the reports of operations aren't passed on, C callers only get whether they succeeded
+fn status<T>(result: Result<T, ImageEncryptionError>) -> c_int {
+    match result {
+        Ok(_) => 0,
+        Err(err) => {
+            set_error(err);
+            -1
+        }
+    }
+}
+
+unsafe fn image_arg<'a>(img: *mut Image) -> Result<&'a mut Image, ImageEncryptionError> {
+    img.as_mut()
+        .ok_or_else(|| ImageEncryptionError::Invalid("the image is null".to_string()))
+}
+
+/// Loads an image file. Returns null on failure, see `ie_last_error`.
+///
+/// # Safety
+/// `path` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ie_load(path: *const c_char) -> *mut Image {
+    match path_arg(path).and_then(load_image) {
+        Ok(img) => Box::into_raw(Box::new(img)),
+        Err(err) => {
+            set_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Encrypts an image in place with the default options, like `enc` does.
+///
+/// # Safety
+/// `img` must come from `ie_load` or `ie_from_raw_frame` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn ie_encrypt(img: *mut Image, key: u64) -> c_int {
+    status(image_arg(img).map(|img| encrypt_image(img, key)))
+}
+
+/// Decrypts an image in place. Fails when the key is wrong or the image isn't encrypted.
+///
+/// # Safety
+/// `img` must come from `ie_load` or `ie_from_raw_frame` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn ie_decrypt(img: *mut Image, key: u64) -> c_int {
+    status(image_arg(img).and_then(|img| Ok(decrypt_image(img, key)?)))
+}
+
+/// Writes an image to a file, in the format it was loaded from whatever the extension, like `enc` does.
+///
+/// # Safety
+/// `img` must come from `ie_load` or `ie_from_raw_frame` and not have been freed, and `path` must be
+/// a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ie_write(img: *const Image, path: *const c_char) -> c_int {
+    status((|| {
+        let img = img
+            .as_ref()
+            .ok_or_else(|| ImageEncryptionError::Invalid("the image is null".to_string()))?;
+        write_image(path_arg(path)?, img.clone())
+    })())
+}
+
+// only the size of a pixel matters to the cipher, so C callers give that instead of a color type
+fn frame_desc(
+    width: u32,
+    height: u32,
+    stride: usize,
+    bytes_per_pixel: u32,
+    bottom_up: bool,
+) -> Result<FrameDesc, ImageEncryptionError> {
+    let color = match bytes_per_pixel {
+        1 => ColorType::L8,
+        2 => ColorType::La8,
+        3 => ColorType::Rgb8,
+        4 => ColorType::Rgba8,
+        6 => ColorType::Rgb16,
+        8 => ColorType::Rgba16,
+        _ => {
+            return Err(ImageEncryptionError::Invalid(format!(
+                "{} bytes per pixel isn't a pixel format",
+                bytes_per_pixel
+            )))
+        }
+    };
+    Ok(FrameDesc {
+        width,
+        height,
+        stride,
+        color,
+        row_order: match bottom_up {
+            true => RowOrder::BottomUp,
+            false => RowOrder::TopDown,
+        },
+    })
+}
+
+fn buffer_null() -> ImageEncryptionError {
+    ImageEncryptionError::Invalid("the buffer is null".to_string())
+}
+
+unsafe fn frame_args<'a>(
+    buffer: *mut u8,
+    len: usize,
+    width: u32,
+    height: u32,
+    stride: usize,
+    bytes_per_pixel: u32,
+) -> Result<(&'a mut [u8], FrameDesc), ImageEncryptionError> {
+    let desc = frame_desc(width, height, stride, bytes_per_pixel, false)?;
+    if buffer.is_null() {
+        return Err(buffer_null());
+    }
+    Ok((slice::from_raw_parts_mut(buffer, len), desc))
+}
+
+fn frame_status(result: Result<(), RawFrameError>) -> c_int {
+    status(result.map_err(|err| ImageEncryptionError::Invalid(err.to_string())))
+}
+
+/// Encrypts a frame in a borrowed buffer in place, like a screenshot or a framebuffer: `height` rows of
+/// `width` pixels of `bytes_per_pixel` bytes, each row `stride` bytes after the one before it. The padding
+/// after each row is left as it is. Every frame must be encrypted with a key of its own.
+///
+/// # Safety
+/// `buffer` must point to `len` bytes that can be written.
+#[no_mangle]
+pub unsafe extern "C" fn ie_encrypt_raw_frame(
+    buffer: *mut u8,
+    len: usize,
+    width: u32,
+    height: u32,
+    stride: usize,
+    bytes_per_pixel: u32,
+    key: u64,
+) -> c_int {
+    match frame_args(buffer, len, width, height, stride, bytes_per_pixel) {
+        Ok((buffer, desc)) => frame_status(encrypt_raw_frame(buffer, desc, key)),
+        Err(err) => status::<()>(Err(err)),
+    }
+}
+
+/// Decrypts a frame encrypted with `ie_encrypt_raw_frame`, in place.
+///
+/// # Safety
+/// `buffer` must point to `len` bytes that can be written.
+#[no_mangle]
+pub unsafe extern "C" fn ie_decrypt_raw_frame(
+    buffer: *mut u8,
+    len: usize,
+    width: u32,
+    height: u32,
+    stride: usize,
+    bytes_per_pixel: u32,
+    key: u64,
+) -> c_int {
+    match frame_args(buffer, len, width, height, stride, bytes_per_pixel) {
+        Ok((buffer, desc)) => frame_status(decrypt_raw_frame(buffer, desc, key)),
+        Err(err) => status::<()>(Err(err)),
+    }
+}
+
+/// Copies a frame out of a buffer into a new image, which the other functions work on like one from
+/// `ie_load`; `ie_write` writes it as a PNG. The layout is that of `ie_encrypt_raw_frame`, with the
+/// bottom row first if `bottom_up` is set, like a Windows DIB or `glReadPixels`. Returns null on failure.
+///
+/// # Safety
+/// `buffer` must point to `len` bytes that can be read.
+#[no_mangle]
+pub unsafe extern "C" fn ie_from_raw_frame(
+    buffer: *const u8,
+    len: usize,
+    width: u32,
+    height: u32,
+    stride: usize,
+    bytes_per_pixel: u32,
+    bottom_up: bool,
+) -> *mut Image {
+    let img = frame_desc(width, height, stride, bytes_per_pixel, bottom_up).and_then(|desc| {
+        if buffer.is_null() {
+            return Err(buffer_null());
+        }
+        read_raw_frame(slice::from_raw_parts(buffer, len), desc)
+            .map_err(|err| ImageEncryptionError::Invalid(err.to_string()))
+    });
+    match img {
+        Ok(img) => Box::into_raw(Box::new(img)),
+        Err(err) => {
+            set_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Copies an image back into a frame with the same width, height and bytes per pixel, laid out as
+/// `ie_from_raw_frame` describes; the padding after each row is left as it is.
+///
+/// # Safety
+/// `img` must not have been freed, and `buffer` must point to `len` bytes that can be written.
+#[no_mangle]
+pub unsafe extern "C" fn ie_to_raw_frame(
+    img: *const Image,
+    buffer: *mut u8,
+    len: usize,
+    stride: usize,
+    bottom_up: bool,
+) -> c_int {
+    status((|| {
+        let img = img
+            .as_ref()
+            .ok_or_else(|| ImageEncryptionError::Invalid("the image is null".to_string()))?;
+        let bytes_per_pixel = img.color.bytes_per_pixel() as u32;
+        let desc = frame_desc(img.width, img.height, stride, bytes_per_pixel, bottom_up)?;
+        if buffer.is_null() {
+            return Err(buffer_null());
+        }
+        write_raw_frame(img, slice::from_raw_parts_mut(buffer, len), desc)
+            .map_err(|err| ImageEncryptionError::Invalid(err.to_string()))
+    })())
+}
+
+/// Frees an image from `ie_load` or `ie_from_raw_frame`. Null is ignored.
+///
+/// # Safety
+/// `img` must come from `ie_load` or `ie_from_raw_frame` and not have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn ie_free(img: *mut Image) {
+    if !img.is_null() {
+        drop(Box::from_raw(img));
+    }
+}
+
+/// The message of the last failure on this thread, or null if nothing failed yet. The string is
+/// valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn ie_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
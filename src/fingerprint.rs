@@ -0,0 +1,62 @@
+use rand::RngCore;
+
+use crate::{blake3, rng::Xoshiro256PlusPlus, Image};
+
+// an invisible per-recipient mark: the least significant bit of every 8-bit sample is replaced
+// with a pseudorandom bit pattern keyed by the recipient, spread over the whole image;
+// a decrypted copy can then be matched against the list of recipients it was sent to
+fn pattern(recipient: &str) -> Xoshiro256PlusPlus {
+    let hash = blake3::Hasher::new()
+        .update(b"image_encryption recipient fingerprint\0")
+        .update(recipient.as_bytes())
+        .finalize();
+    Xoshiro256PlusPlus::seed_from_u64(u64::from_le_bytes(hash[..8].try_into().unwrap()))
+}
+
+// the pattern bits, one per sample, 64 at a time
+fn pattern_bits(recipient: &str, len: usize) -> impl Iterator<Item = u8> {
+    let mut rng = pattern(recipient);
+    (0..len.div_ceil(64))
+        .flat_map(move |_| {
+            let bits = rng.next_u64();
+            (0..64).map(move |i| (bits >> i) as u8 & 1)
+        })
+        .take(len)
+}
+
+// only images with 8-bit samples carry the fingerprint
+fn has_byte_samples(img: &Image) -> bool {
+    img.color.bytes_per_pixel() == img.color.channel_count()
+}
+
+pub fn embed_fingerprint(img: &mut Image, recipient: &str) {
+    if !has_byte_samples(img) {
+        return;
+    }
+    let len = img.pixels.len();
+    for (sample, bit) in img.pixels.iter_mut().zip(pattern_bits(recipient, len)) {
+        *sample = (*sample & !1) | bit;
+    }
+}
+
+// the fraction of samples whose least significant bit matches the recipient's pattern:
+// close to 1.0 for the recipient the copy was made for, around 0.5 for everyone else
+pub fn fingerprint_score(img: &Image, recipient: &str) -> f64 {
+    if !has_byte_samples(img) || img.pixels.is_empty() {
+        return 0.0;
+    }
+    let matches = img
+        .pixels
+        .iter()
+        .zip(pattern_bits(recipient, img.pixels.len()))
+        .filter(|&(&sample, bit)| sample & 1 == bit)
+        .count();
+    matches as f64 / img.pixels.len() as f64
+}
+
+// whether the score is too far above chance to be a coincidence: the score of an unrelated
+// recipient is binomial around 0.5, so require it to be 8 standard deviations away
+pub fn fingerprint_detected(img: &Image, recipient: &str) -> bool {
+    let deviation = 0.5 / (img.pixels.len().max(1) as f64).sqrt();
+    fingerprint_score(img, recipient) > 0.5 + 8.0 * deviation
+}
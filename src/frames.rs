@@ -0,0 +1,304 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Cursor},
+    path::Path,
+    time::Duration,
+};
+
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        png::PngDecoder,
+        webp::WebPDecoder,
+    },
+    AnimationDecoder, ColorType, Delay, Frame, ImageBuffer, ImageFormat,
+};
+
+use crate::{
+    auth, blake3, decrypt_image, encrypt_image_with, parse_header,
+    png_store::{chunks, encode_stored_animation, StoredFrame},
+    DecryptError, EncryptOptions, EncryptionHeader, Image, ImageEncryptionError, KeyFingerprint,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::load_image;
+
+// a private PNG chunk holding the encryption header of a frame, since a file has room for only one trailer;
+// the lowercase second letter marks it private, the last that it is safe to copy
+const HEADER_CHUNK: [u8; 4] = *b"imHd";
+
+// an animation as a list of images of the same size, each shown for its delay;
+// every frame is a whole picture, already composed over the ones before it
+#[derive(Debug, Clone)]
+pub struct AnimatedImage {
+    pub images: Vec<Image>,
+    // as many as there are images
+    pub delays: Vec<Duration>,
+}
+
+fn frame_image(frame: Frame, format: ImageFormat) -> (Image, Duration) {
+    let delay = Duration::from(frame.delay());
+    let buffer = frame.into_buffer();
+    let image = Image {
+        format,
+        width: buffer.width(),
+        height: buffer.height(),
+        color: ColorType::Rgba8,
+        pixels: buffer.into_raw(),
+        header: None,
+        metadata: Vec::new(),
+        jpeg_segments: Vec::new(),
+    };
+    (image, delay)
+}
+
+// the frames of an animated GIF, PNG or WebP; any other image is a single frame shown forever
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_animation(path: impl AsRef<Path>) -> Result<AnimatedImage, ImageEncryptionError> {
+    let path = path.as_ref();
+    let bytes = fs::read(path)?;
+    let format = ImageFormat::from_path(path).or_else(|_| image::guess_format(&bytes))?;
+    match decode_frames(&bytes, format)? {
+        Some(animation) => Ok(animation),
+        None => single_frame(path),
+    }
+}
+
+// the frames of an animation in memory, or None if the format has no frames or the PNG isn't animated
+pub(crate) fn decode_frames(
+    bytes: &[u8],
+    format: ImageFormat,
+) -> Result<Option<AnimatedImage>, ImageEncryptionError> {
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+        ImageFormat::WebP => WebPDecoder::new(Cursor::new(bytes))?.into_frames(),
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(bytes))?;
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+            decoder.apng().into_frames()
+        }
+        _ => return Ok(None),
+    };
+
+    let (mut images, delays): (Vec<_>, _) = frames
+        .collect_frames()?
+        .into_iter()
+        .map(|frame| frame_image(frame, format))
+        .unzip();
+    if format == ImageFormat::Png {
+        for (image, header) in images.iter_mut().zip(frame_headers(bytes)?) {
+            image.header = header;
+        }
+    }
+    Ok(Some(AnimatedImage { images, delays }))
+}
+
+// whether the file is an animation of more than one frame, reading no more than it takes to tell
+#[cfg(not(target_arch = "wasm32"))]
+pub fn is_animated(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    let Ok(bytes) = fs::read(path) else {
+        return false;
+    };
+    let two_frames = |frames: image::Frames| frames.take(2).count() == 2;
+    match ImageFormat::from_path(path).or_else(|_| image::guess_format(&bytes)) {
+        Ok(ImageFormat::Gif) => GifDecoder::new(Cursor::new(&bytes))
+            .is_ok_and(|decoder| two_frames(decoder.into_frames())),
+        Ok(ImageFormat::WebP) => WebPDecoder::new(Cursor::new(&bytes))
+            .is_ok_and(|decoder| two_frames(decoder.into_frames())),
+        Ok(ImageFormat::Png) => chunks(&bytes)
+            .iter()
+            .find(|(kind, _)| kind == b"acTL")
+            // the first field of acTL is the number of frames
+            .and_then(|(_, data)| data.get(..4))
+            .is_some_and(|count| u32::from_be_bytes(count.try_into().unwrap()) > 1),
+        _ => false,
+    }
+}
+
+// the header in the private chunk of every frame of an animated PNG, if it has one;
+// a frame starts at its fcTL, and the default image only counts if it has one too
+fn frame_headers(png: &[u8]) -> Result<Vec<Option<EncryptionHeader>>, ImageEncryptionError> {
+    let mut headers = Vec::new();
+    for (kind, data) in chunks(png) {
+        match &kind {
+            b"fcTL" => headers.push(None),
+            kind if kind == &HEADER_CHUNK => {
+                if let Some(last) = headers.last_mut() {
+                    *last = parse_header(data)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(headers)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn single_frame(path: &Path) -> Result<AnimatedImage, ImageEncryptionError> {
+    Ok(AnimatedImage {
+        images: vec![load_image(path)?],
+        delays: vec![Duration::ZERO],
+    })
+}
+
+// write the animation with its timing, looping forever: as a GIF if the path ends in .gif,
+// or else as an animated PNG that keeps the ciphertext exactly, with the encryption header
+// of every frame in a private chunk. A GIF must be plaintext, since its palette can't hold
+// the colors of ciphertext, which would never decrypt again
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_animation(
+    path: impl AsRef<Path>,
+    animation: &AnimatedImage,
+) -> Result<(), ImageEncryptionError> {
+    let path = path.as_ref();
+    if animation.images.is_empty() {
+        return Err(ImageEncryptionError::Invalid(
+            "no frames to write".to_string(),
+        ));
+    }
+    if ImageFormat::from_path(path).ok() != Some(ImageFormat::Gif) {
+        fs::write(path, encode_apng(animation)?)?;
+        return Ok(());
+    }
+    if animation.images.iter().any(|image| image.header.is_some()) {
+        return Err(ImageEncryptionError::Unsupported(
+            "encrypted frames can't be written as a GIF, write them to a .png instead".to_string(),
+        ));
+    }
+    let mut encoder = GifEncoder::new(BufWriter::new(File::create(path)?));
+    encoder.set_repeat(Repeat::Infinite)?;
+    for (image, delay) in animation.images.iter().zip(&animation.delays) {
+        let mut image = image.clone();
+        image.convert_color(ColorType::Rgba8);
+        let buffer =
+            ImageBuffer::from_raw(image.width, image.height, image.pixels).ok_or_else(|| {
+                ImageEncryptionError::Malformed("a frame isn't 8-bit RGBA".to_string())
+            })?;
+        let delay = Delay::from_saturating_duration(*delay);
+        encoder.encode_frame(Frame::from_parts(buffer, 0, 0, delay))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn encode_apng(animation: &AnimatedImage) -> Result<Vec<u8>, ImageEncryptionError> {
+    let first = &animation.images[0];
+    let (width, height, color) = (first.width, first.height, first.color);
+    if animation
+        .images
+        .iter()
+        .any(|image| (image.width, image.height, image.color) != (width, height, color))
+    {
+        return Err(ImageEncryptionError::Invalid(
+            "the frames of an animated PNG must all be of the same size and color type".to_string(),
+        ));
+    }
+    let frames = animation
+        .images
+        .iter()
+        .zip(&animation.delays)
+        .map(|(image, &delay)| StoredFrame {
+            pixels: &image.pixels,
+            delay,
+            chunk: image
+                .header
+                .as_ref()
+                .map(|header| (HEADER_CHUNK, header.to_bytes())),
+        })
+        .collect::<Vec<_>>();
+    Ok(encode_stored_animation(&frames, width, height, color)?)
+}
+
+// the key a frame is encrypted with, so no two frames share a keystream or a permutation;
+// a single frame decrypts with `decrypt_image` and the key of its index, which its header records
+pub fn frame_key(key: u64, index: u32) -> u64 {
+    let hash = blake3::Hasher::new()
+        .update(b"image_encryption frame key\0")
+        .update(&key.to_le_bytes())
+        .update(&(index as u64).to_le_bytes())
+        .finalize();
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+// encrypt every frame on its own with the same options; with per-frame keys every frame gets
+// the key of its index, or else they all share the key, and with it their keystream
+pub fn encrypt_animation(
+    animation: &mut AnimatedImage,
+    key: u64,
+    options: &EncryptOptions,
+    per_frame_keys: bool,
+) {
+    for (index, image) in (0..).zip(&mut animation.images) {
+        if !per_frame_keys {
+            encrypt_image_with(image, key, options);
+            continue;
+        }
+        encrypt_image_with(image, frame_key(key, index), options);
+        if let Some(header) = &mut image.header {
+            header.frame_index = Some(index);
+            header.key_id = Some(KeyFingerprint::id(key));
+        }
+        auth::authenticate(image, frame_key(key, index));
+    }
+}
+
+// decrypt every frame, failing on the first one that doesn't authenticate; a frame with an index
+// in its header goes by the key of that index, so frames that were dropped or reordered still decrypt,
+// and one without uses the key itself
+pub fn decrypt_animation(animation: &mut AnimatedImage, key: u64) -> Result<(), DecryptError> {
+    for image in &mut animation.images {
+        let key = match image.header.as_ref().and_then(|header| header.frame_index) {
+            Some(index) => frame_key(key, index),
+            None => key,
+        };
+        decrypt_image(image, key)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        compare_images,
+        test_util::{random_image, rng},
+    };
+
+    // an encrypted animation has to keep its frames, their timing and their headers in an animated PNG,
+    // with a key for every frame and with one for them all
+    #[test]
+    fn apng_round_trip() {
+        let mut rng = rng();
+        for per_frame_keys in [true, false] {
+            let key = rng.next_u64();
+            let original = AnimatedImage {
+                images: (0..3)
+                    .map(|_| random_image(&mut rng, 37, 23, ColorType::Rgba8))
+                    .collect(),
+                delays: (1..=3).map(|i| Duration::from_millis(i * 40)).collect(),
+            };
+            let mut animation = original.clone();
+            encrypt_animation(
+                &mut animation,
+                key,
+                &EncryptOptions::default(),
+                per_frame_keys,
+            );
+            let bytes = encode_apng(&animation).unwrap();
+            let mut found = decode_frames(&bytes, ImageFormat::Png).unwrap().unwrap();
+            assert_eq!(found.delays, original.delays);
+            assert!(decrypt_animation(&mut found, key).is_ok());
+            for (found, original) in found.images.iter().zip(&original.images) {
+                assert!(
+                    compare_images(original, found).identical,
+                    "{}",
+                    per_frame_keys
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use arbitrary::{Arbitrary, Result, Unstructured};
+use image::{ColorType, ImageFormat};
+
+use crate::{EncryptOptions, EncryptionHeader, Image, PermutationUnit, Pipeline};
+
+// arbitrary values for fuzzing, see the targets in fuzz/; the image crate's color types and formats
+// don't implement `Arbitrary`, so everything holding one is put together by hand here
+
+// generated images are kept small, so a fuzzer spends its time on the cipher and not on allocating
+const MAX_SIDE: u32 = 64;
+
+const COLORS: &[ColorType] = &[
+    ColorType::L8,
+    ColorType::La8,
+    ColorType::Rgb8,
+    ColorType::Rgba8,
+    ColorType::L16,
+    ColorType::La16,
+    ColorType::Rgb16,
+    ColorType::Rgba16,
+    ColorType::Rgb32F,
+    ColorType::Rgba32F,
+];
+
+// the formats images are loaded from and written to
+const FORMATS: &[ImageFormat] = &[
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+    ImageFormat::Tiff,
+    ImageFormat::Bmp,
+    ImageFormat::Ico,
+];
+
+fn color(u: &mut Unstructured) -> Result<ColorType> {
+    u.choose(COLORS).copied()
+}
+
+fn format(u: &mut Unstructured) -> Result<ImageFormat> {
+    u.choose(FORMATS).copied()
+}
+
+fn option<T>(
+    u: &mut Unstructured,
+    f: impl FnOnce(&mut Unstructured) -> Result<T>,
+) -> Result<Option<T>> {
+    Ok(if u.arbitrary()? { Some(f(u)?) } else { None })
+}
+
+// blocks and tiles of no pixels aren't a unit, and the header rejects them
+impl<'a> Arbitrary<'a> for PermutationUnit {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=5)? {
+            0 => PermutationUnit::Pixel,
+            1 => PermutationUnit::Row,
+            2 => PermutationUnit::Column,
+            3 => PermutationUnit::Block(u.int_in_range(1..=MAX_SIDE)?),
+            4 => PermutationUnit::Tile(u.int_in_range(1..=MAX_SIDE)?),
+            _ => PermutationUnit::Bijection,
+        })
+    }
+}
+
+// like any image a decoder gives, there is at least one pixel; the pixels always fill the image,
+// topped up with zeros when the fuzzer's data runs out. Float images only come from TIFFs,
+// with finite samples: the image crate panics converting NaN to integer samples
+impl<'a> Arbitrary<'a> for Image {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let color = color(u)?;
+        let float = matches!(color, ColorType::Rgb32F | ColorType::Rgba32F);
+        let format = if float { ImageFormat::Tiff } else { format(u)? };
+        let width = u.int_in_range(1..=MAX_SIDE)?;
+        let height = u.int_in_range(1..=MAX_SIDE)?;
+        let len = (width * height) as usize * color.bytes_per_pixel() as usize;
+        let pixels = if float {
+            let mut pixels = Vec::with_capacity(len);
+            for _ in 0..len / 4 {
+                let sample = u.arbitrary::<u16>()? as f32 / u16::MAX as f32;
+                pixels.extend_from_slice(&sample.to_le_bytes());
+            }
+            pixels
+        } else {
+            let mut pixels = u.bytes(len.min(u.len()))?.to_vec();
+            pixels.resize(len, 0);
+            pixels
+        };
+        Ok(Image {
+            format,
+            pixels,
+            color,
+            width,
+            height,
+            header: u.arbitrary()?,
+            metadata: u.arbitrary()?,
+            jpeg_segments: Vec::new(),
+        })
+    }
+}
+
+// a watermark, a banner and preprocessing only change the plaintext, which fuzzing has no use for
+impl<'a> Arbitrary<'a> for EncryptOptions {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(EncryptOptions {
+            pipeline: Pipeline::default(),
+            normalize: option(u, color)?,
+            watermark: None,
+            fingerprint: u.arbitrary()?,
+            convergent: u.arbitrary()?,
+            permutation_unit: u.arbitrary()?,
+            regions: u.arbitrary()?,
+            banner: None,
+            jpeg_container: u.arbitrary()?,
+            kdf: u.arbitrary()?,
+            cipher: u.arbitrary()?,
+            tags: u.arbitrary()?,
+            parallel: u.arbitrary()?,
+            keep_lossy_format: u.arbitrary()?,
+            permute_only: u.arbitrary()?,
+            noise: u.arbitrary()?,
+            disguise: u.arbitrary()?,
+            skip_channels: u.arbitrary()?,
+            scramble_algorithm: u.arbitrary()?,
+            rounds: u.int_in_range(0..=4)?,
+            hash_ciphertext: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for EncryptionHeader {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(EncryptionHeader {
+            original_color: option(u, color)?,
+            convergent_key: u.arbitrary()?,
+            cipher: u.arbitrary()?,
+            original_format: option(u, format)?,
+            dimensions: u.arbitrary()?,
+            permutation_unit: u.arbitrary()?,
+            key_fingerprint: u.arbitrary()?,
+            key_check_iterations: u.arbitrary()?,
+            key_id: u.arbitrary()?,
+            regions: u.arbitrary()?,
+            reserved: u.arbitrary()?,
+            jpeg_container: u.arbitrary()?,
+            kdf: u.arbitrary()?,
+            nonce: u.arbitrary()?,
+            plaintext_digest: u.arbitrary()?,
+            auth_tag: u.arbitrary()?,
+            pixel_tag_only: u.arbitrary()?,
+            search_tags: u.arbitrary()?,
+            chunk_len: u.arbitrary()?,
+            band_rows: u.arbitrary()?,
+            frame_index: u.arbitrary()?,
+            layer_index: u.arbitrary()?,
+            permute_only: u.arbitrary()?,
+            noise: u.arbitrary()?,
+            disguised: u.arbitrary()?,
+            skipped_channels: u.arbitrary()?,
+            sample_order: u.arbitrary()?,
+            scramble_algorithm: u.arbitrary()?,
+            rounds: u.arbitrary()?,
+            share: u.arbitrary()?,
+        })
+    }
+}
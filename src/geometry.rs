@@ -0,0 +1,525 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use crate::{
+    json::{self, Json, JsonError},
+    shape::Shape,
+};
+
+// a rectangle of pixels inside an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub(crate) fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        for (i, value) in [self.x, self.y, self.width, self.height].iter().enumerate() {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let value = |i: usize| {
+            Some(u32::from_le_bytes(
+                bytes.get(i * 4..i * 4 + 4)?.try_into().ok()?,
+            ))
+        };
+        Some(Rect {
+            x: value(0)?,
+            y: value(1)?,
+            width: value(2)?,
+            height: value(3)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Length {
+    Pixels(i64),
+    // a percentage of the image width or height
+    Percent(f64),
+}
+
+impl Length {
+    pub(crate) fn resolve(self, size: u32) -> i64 {
+        match self {
+            Length::Pixels(pixels) => pixels,
+            Length::Percent(percent) => (size as f64 * percent / 100.0).round() as i64,
+        }
+    }
+}
+
+// an ImageMagick-style geometry: `WxH+X+Y`, where sizes can be percentages of the image (`50%x25%+10+10`),
+// a single percentage applies to both sides (`50%`), and the offsets are optional
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Geometry {
+    pub width: Length,
+    pub height: Length,
+    pub x: Length,
+    pub y: Length,
+}
+
+// a shape to encrypt, with an optional label for the tools that pick the regions
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Region {
+    pub shape: Shape,
+    pub label: Option<String>,
+}
+
+impl From<Shape> for Region {
+    fn from(shape: Shape) -> Self {
+        Region { shape, label: None }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeometryError {
+    Invalid(String),
+    // the geometry lies entirely outside the image
+    Empty(String),
+    Json(JsonError),
+}
+
+impl fmt::Display for GeometryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeometryError::Invalid(geometry) => write!(f, "invalid geometry {}", geometry),
+            GeometryError::Json(err) => write!(f, "{}", err),
+            GeometryError::Empty(geometry) => {
+                write!(
+                    f,
+                    "geometry {} doesn't cover any pixel of the image",
+                    geometry
+                )
+            }
+        }
+    }
+}
+
+impl Error for GeometryError {}
+
+pub(crate) fn parse_length(s: &str) -> Option<Length> {
+    match s.strip_suffix('%') {
+        Some(percent) => percent
+            .parse::<f64>()
+            .ok()
+            .filter(|percent| percent.is_finite() && *percent >= 0.0)
+            .map(Length::Percent),
+        None => s.parse().ok().map(Length::Pixels),
+    }
+}
+
+impl FromStr for Geometry {
+    type Err = GeometryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || GeometryError::Invalid(s.to_string());
+
+        // the plain form "x,y,w,h", in the order rectangles are usually written in code
+        if let [x, y, width, height] = s.split(',').map(str::trim).collect::<Vec<_>>()[..] {
+            return Ok(Geometry {
+                x: parse_length(x).ok_or_else(invalid)?,
+                y: parse_length(y).ok_or_else(invalid)?,
+                width: parse_length(width).ok_or_else(invalid)?,
+                height: parse_length(height).ok_or_else(invalid)?,
+            });
+        }
+
+        // the size ends where the first offset sign starts
+        let (size, offsets) = s.split_at(s.find(['+', '-']).unwrap_or(s.len()));
+        let (width, height) = match size.split_once('x') {
+            Some((width, height)) => (parse_length(width), parse_length(height)),
+            // a lone size only makes sense as a percentage of both sides
+            None if size.ends_with('%') => (parse_length(size), parse_length(size)),
+            None => (None, None),
+        };
+        let (width, height) = width.zip(height).ok_or_else(invalid)?;
+
+        // each offset keeps its sign, "+10-5" is x = 10, y = -5
+        let signs = offsets
+            .match_indices(['+', '-'])
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let (x, y) = match signs[..] {
+            [] => (Length::Pixels(0), Length::Pixels(0)),
+            [x, y] => (
+                parse_length(offsets[x..y].trim_start_matches('+')).ok_or_else(invalid)?,
+                parse_length(offsets[y..].trim_start_matches('+')).ok_or_else(invalid)?,
+            ),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Geometry {
+            width,
+            height,
+            x,
+            y,
+        })
+    }
+}
+
+impl From<Rect> for Geometry {
+    fn from(rect: Rect) -> Self {
+        Geometry {
+            width: Length::Pixels(rect.width as i64),
+            height: Length::Pixels(rect.height as i64),
+            x: Length::Pixels(rect.x as i64),
+            y: Length::Pixels(rect.y as i64),
+        }
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Length::Pixels(pixels) => write!(f, "{}", pixels),
+            Length::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl fmt::Display for Geometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)?;
+        // offsets carry their own sign
+        for offset in [self.x, self.y] {
+            if !offset.to_string().starts_with('-') {
+                f.write_str("+")?;
+            }
+            write!(f, "{}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Rect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}+{}+{}", self.width, self.height, self.x, self.y)
+    }
+}
+
+impl Geometry {
+    // the geometry in pixels as (x, y, width, height), which may extend past the image
+    pub(crate) fn resolve_unclipped(
+        &self,
+        image_width: u32,
+        image_height: u32,
+    ) -> (i64, i64, i64, i64) {
+        (
+            self.x.resolve(image_width),
+            self.y.resolve(image_height),
+            self.width.resolve(image_width),
+            self.height.resolve(image_height),
+        )
+    }
+
+    // the pixels the geometry covers in an image of the given size, clipped to the image
+    pub fn resolve(&self, image_width: u32, image_height: u32) -> Option<Rect> {
+        let (x, y, width, height) = self.resolve_unclipped(image_width, image_height);
+
+        let (left, top) = (x.max(0), y.max(0));
+        let right = (x + width).min(image_width as i64);
+        let bottom = (y + height).min(image_height as i64);
+        (right > left && bottom > top).then(|| Rect {
+            x: left as u32,
+            y: top as u32,
+            width: (right - left) as u32,
+            height: (bottom - top) as u32,
+        })
+    }
+}
+
+// a length in a JSON region, either a number of pixels or a percentage string like "50%"
+fn json_length(value: Option<&Json>) -> Option<Length> {
+    match value {
+        Some(Json::Number(number)) if number.fract() == 0.0 => Some(Length::Pixels(*number as i64)),
+        Some(Json::String(string)) => parse_length(string),
+        // a missing offset is 0
+        None => Some(Length::Pixels(0)),
+        _ => None,
+    }
+}
+
+impl Length {
+    fn to_json(self) -> Json {
+        match self {
+            Length::Pixels(pixels) => Json::Number(pixels as f64),
+            Length::Percent(_) => Json::String(self.to_string()),
+        }
+    }
+}
+
+// parse a JSON list of regions, each either a shape string like "20x10+5+5" or "ellipse:20x10+5+5",
+// or an object like {"x": 5, "y": 5, "width": 20, "height": "10%", "label": "face"};
+// objects can also be {"shape": "ellipse", ...} with the same fields,
+// or {"shape": "polygon", "points": [[10, 10], [50, 10], ["50%", 40]]}
+pub fn parse_regions_json(text: &str) -> Result<Vec<Region>, GeometryError> {
+    let Json::Array(items) = json::parse(text).map_err(GeometryError::Json)? else {
+        return Err(GeometryError::Invalid(text.to_string()));
+    };
+
+    items
+        .iter()
+        .map(|item| match item {
+            Json::String(shape) => shape.parse::<Shape>().map(Region::from),
+            Json::Object(_) => {
+                let invalid = || GeometryError::Invalid(format!("{:?}", item));
+                let size = |key| item.get(key).ok_or_else(invalid).map(Some);
+                let label = match item.get("label") {
+                    Some(Json::String(label)) => Some(label.clone()),
+                    None | Some(Json::Null) => None,
+                    Some(_) => return Err(invalid()),
+                };
+                let geometry = || -> Result<_, GeometryError> {
+                    Ok(Geometry {
+                        x: json_length(item.get("x")).ok_or_else(invalid)?,
+                        y: json_length(item.get("y")).ok_or_else(invalid)?,
+                        width: json_length(size("width")?).ok_or_else(invalid)?,
+                        height: json_length(size("height")?).ok_or_else(invalid)?,
+                    })
+                };
+                let shape = match item.get("shape") {
+                    None => Shape::Rect(geometry()?),
+                    Some(Json::String(shape)) if shape == "rect" => Shape::Rect(geometry()?),
+                    Some(Json::String(shape)) if shape == "ellipse" => Shape::Ellipse(geometry()?),
+                    Some(Json::String(shape)) if shape == "polygon" => {
+                        let Some(Json::Array(points)) = item.get("points") else {
+                            return Err(invalid());
+                        };
+                        let points = points
+                            .iter()
+                            .map(|point| match point {
+                                Json::Array(point) if point.len() == 2 => {
+                                    Some((json_length(point.first())?, json_length(point.get(1))?))
+                                }
+                                _ => None,
+                            })
+                            .collect::<Option<Vec<_>>>()
+                            .filter(|points| points.len() >= 3)
+                            .ok_or_else(invalid)?;
+                        Shape::Polygon(points)
+                    }
+                    Some(_) => return Err(invalid()),
+                };
+                Ok(Region { shape, label })
+            }
+            _ => Err(GeometryError::Invalid(format!("{:?}", item))),
+        })
+        .collect()
+}
+
+// the JSON list `parse_regions_json` reads, one region object per line,
+// so the same file can be edited by annotation tools and fed back in
+pub fn regions_json(regions: &[Region]) -> String {
+    let mut json = String::from("[");
+    for (i, region) in regions.iter().enumerate() {
+        let geometry_fields = |geometry: &Geometry| {
+            vec![
+                ("x".to_string(), geometry.x.to_json()),
+                ("y".to_string(), geometry.y.to_json()),
+                ("width".to_string(), geometry.width.to_json()),
+                ("height".to_string(), geometry.height.to_json()),
+            ]
+        };
+        let shape = |name: &str| ("shape".to_string(), Json::String(name.to_string()));
+        let mut fields = match &region.shape {
+            Shape::Rect(geometry) => geometry_fields(geometry),
+            Shape::Ellipse(geometry) => {
+                let mut fields = vec![shape("ellipse")];
+                fields.extend(geometry_fields(geometry));
+                fields
+            }
+            Shape::Polygon(points) => vec![
+                shape("polygon"),
+                (
+                    "points".to_string(),
+                    Json::Array(
+                        points
+                            .iter()
+                            .map(|(x, y)| Json::Array(vec![x.to_json(), y.to_json()]))
+                            .collect(),
+                    ),
+                ),
+            ],
+        };
+        if let Some(label) = &region.label {
+            fields.push(("label".to_string(), Json::String(label.clone())));
+        }
+        json += if i == 0 { "\n  " } else { ",\n  " };
+        json += &Json::Object(fields).to_string();
+    }
+    json += "\n]\n";
+    json
+}
+
+// parse a geometry and resolve it against an image size
+pub fn parse_geometry(s: &str, image_width: u32, image_height: u32) -> Result<Rect, GeometryError> {
+    s.parse::<Geometry>()?
+        .resolve(image_width, image_height)
+        .ok_or_else(|| GeometryError::Empty(s.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn parses_geometries() {
+        for (geometry, expected) in [
+            ("20x10+5+6", rect(5, 6, 20, 10)),
+            ("20x10", rect(0, 0, 20, 10)),
+            ("5,6,20,10", rect(5, 6, 20, 10)),
+            (" 5, 6 ,20 , 10", rect(5, 6, 20, 10)),
+            ("50%", rect(0, 0, 100, 50)),
+            ("50%x10%+25%+0", rect(50, 0, 100, 10)),
+            ("100%x100%", rect(0, 0, 200, 100)),
+            // clipped to the image
+            ("20x20-10-5", rect(0, 0, 10, 15)),
+            ("300x300+190+90", rect(190, 90, 10, 10)),
+            ("0,0,1000%,1000%", rect(0, 0, 200, 100)),
+        ] {
+            assert_eq!(
+                parse_geometry(geometry, 200, 100),
+                Ok(expected),
+                "{}",
+                geometry
+            );
+        }
+    }
+
+    #[test]
+    fn geometry_errors() {
+        for geometry in [
+            "",
+            "20",
+            "20x",
+            "x10",
+            "20x10+5",
+            "20x10+5+6+7",
+            "20x10+5+",
+            "20x10++5+6",
+            "20X10",
+            "20x10+a+b",
+            "20.5x10",
+            "-20x10",
+            "20x-10",
+            "-5%",
+            "nan%",
+            "inf%x10",
+            "20x10%%",
+            "1,2,3",
+            "1,2,3,4,5",
+            "1,2,x,4",
+            "ellipse",
+        ] {
+            assert_eq!(
+                parse_geometry(geometry, 200, 100),
+                Err(GeometryError::Invalid(geometry.to_string())),
+                "{}",
+                geometry
+            );
+        }
+
+        for geometry in ["20x10+200+0", "20x10+0+100", "20x10-20-10", "0x10", "20x0%"] {
+            assert_eq!(
+                parse_geometry(geometry, 200, 100),
+                Err(GeometryError::Empty(geometry.to_string())),
+                "{}",
+                geometry
+            );
+        }
+    }
+
+    // what a geometry is shown as parses back to the same geometry
+    #[test]
+    fn display_round_trips() {
+        for geometry in ["20x10+5+6", "20x10-5-6", "50%x10.5%+25%+1%", "5,6,20,10"] {
+            let parsed = geometry.parse::<Geometry>().unwrap();
+            assert_eq!(parsed.to_string().parse::<Geometry>(), Ok(parsed));
+        }
+        assert_eq!(
+            Geometry::from(rect(1, 2, 3, 4)).to_string(),
+            rect(1, 2, 3, 4).to_string()
+        );
+        assert_eq!(
+            Rect::from_bytes(&rect(1, 2, u32::MAX, 4).to_bytes()),
+            Some(rect(1, 2, u32::MAX, 4))
+        );
+        assert_eq!(Rect::from_bytes(&[0; 15]), None);
+    }
+
+    #[test]
+    fn regions_json_round_trips() {
+        let geometry = |s: &str| s.parse::<Geometry>().unwrap();
+        let regions = vec![
+            Region::from(Shape::Rect(geometry("20x10+5+6"))),
+            Region {
+                shape: Shape::Ellipse(geometry("50%x10%-5+25%")),
+                label: Some("a \"quoted\" \\ label\n\u{1}".to_string()),
+            },
+            Region {
+                shape: "polygon:10,10;50%,10;50,40".parse().unwrap(),
+                label: Some("visage 😀".to_string()),
+            },
+        ];
+        let json = regions_json(&regions);
+        assert!(json.contains(r#""label": "a \"quoted\" \\ label\n\u0001""#));
+        assert_eq!(parse_regions_json(&json), Ok(regions));
+
+        let parsed = parse_regions_json(
+            r#"["20x10+5+6", "ellipse:5,6,20,10", {"width": 20, "height": "10%", "label": null}]"#,
+        )
+        .unwrap();
+        assert_eq!(parsed[0].shape, Shape::Rect(geometry("20x10+5+6")));
+        assert_eq!(parsed[1].shape, Shape::Ellipse(geometry("20x10+5+6")));
+        assert_eq!(parsed[2], Region::from(Shape::Rect(geometry("20x10%"))));
+    }
+
+    #[test]
+    fn regions_json_errors() {
+        assert_eq!(
+            parse_regions_json("[\"20x10\""),
+            Err(GeometryError::Json(JsonError { offset: 8 }))
+        );
+        for json in [
+            "{}",
+            "\"20x10\"",
+            "[1]",
+            "[\"20\"]",
+            // a missing size, a fractional one, a label that isn't a string, an unknown shape
+            r#"[{"width": 20}]"#,
+            r#"[{"width": 20.5, "height": 10}]"#,
+            r#"[{"width": 20, "height": 10, "x": true}]"#,
+            r#"[{"width": 20, "height": 10, "label": 1}]"#,
+            r#"[{"shape": "circle", "width": 20, "height": 10}]"#,
+            // polygons need at least three points of two coordinates each
+            r#"[{"shape": "polygon"}]"#,
+            r#"[{"shape": "polygon", "points": [[0, 0], [1, 1]]}]"#,
+            r#"[{"shape": "polygon", "points": [[0, 0], [1, 1], [2]]}]"#,
+        ] {
+            assert!(
+                matches!(parse_regions_json(json), Err(GeometryError::Invalid(_))),
+                "{}",
+                json
+            );
+        }
+    }
+}
@@ -0,0 +1,770 @@
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use image::{ColorType, ImageFormat};
+
+use crate::{
+    auth::AUTH_TAG_LEN, chacha20::NONCE_LEN, digest::DIGEST_LEN, disguise, kdf::SALT_LEN,
+    tags::TOKEN_LEN, GuessCost, ImageEncryptionError, KdfParams, KeyFingerprint, PermutationUnit,
+    PixelShape, Rect, ScrambleAlgorithm, SealedDigest, SearchTags, ShapedNoise, ShareInfo,
+    KEY_ID_ITERATIONS,
+};
+
+// encrypted images carry a small trailer after the encoded image data, which image decoders
+// ignore, so the output stays a regular viewable file:
+//
+//     [version: u8][fields...][payload length: u32 le][MAGIC]
+//
+// every field is encoded as [tag: u8][length: u16 le][value], so readers can skip tags
+// they don't know about; since version 2 a length of 0xffff is followed by the real one as a u32,
+// for lists of regions too long for the u16 to count. Since version 2 the authentication tag covers the rest of the header as this
+// writes it, which a reader can only do for the fields it knows, so new fields need a version bump
+const MAGIC: &[u8; 8] = b"IMGENCv\0";
+const VERSION: u8 = 2;
+// the version before the tag covered the header, when it only covered the pixels
+const PIXEL_TAG_VERSION: u8 = 1;
+
+const TAG_ORIGINAL_COLOR: u8 = 1;
+const TAG_CONVERGENT_KEY: u8 = 2;
+const TAG_CIPHER: u8 = 3;
+const TAG_ORIGINAL_FORMAT: u8 = 4;
+const TAG_DIMENSIONS: u8 = 5;
+const TAG_PERMUTATION_UNIT: u8 = 6;
+const TAG_KEY_FINGERPRINT: u8 = 7;
+const TAG_REGIONS: u8 = 8;
+const TAG_SHAPES: u8 = 9;
+const TAG_RESERVED: u8 = 10;
+const TAG_KEY_CHECK_ITERATIONS: u8 = 11;
+const TAG_JPEG_CONTAINER: u8 = 12;
+const TAG_KDF: u8 = 13;
+const TAG_NONCE: u8 = 14;
+const TAG_PLAINTEXT_DIGEST: u8 = 15;
+const TAG_AUTH_TAG: u8 = 16;
+const TAG_SEARCH_TAGS: u8 = 17;
+const TAG_CHUNK_LEN: u8 = 18;
+const TAG_BAND_ROWS: u8 = 19;
+const TAG_FRAME_INDEX: u8 = 20;
+const TAG_LAYER_INDEX: u8 = 21;
+const TAG_PERMUTE_ONLY: u8 = 22;
+const TAG_NOISE: u8 = 23;
+const TAG_DISGUISED: u8 = 24;
+const TAG_SKIPPED_CHANNELS: u8 = 25;
+const TAG_SAMPLE_ORDER: u8 = 26;
+const TAG_SCRAMBLE_ALGORITHM: u8 = 27;
+const TAG_ROUNDS: u8 = 29;
+const TAG_SHARE: u8 = 30;
+const TAG_KEY_ID: u8 = 31;
+
+// the u16 length of a field whose u32 length follows
+const LONG_FIELD: u16 = u16::MAX;
+
+// the size of the fixed part at the very end of the file: the payload length and the magic
+const TRAILER_LEN: usize = 4 + MAGIC.len();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Cipher {
+    // the original xor chain over a key-derived pixel permutation
+    #[default]
+    Legacy,
+    // the quantized coefficients of a baseline JPEG shuffled and scrambled, see `encrypt_jpeg_dct`
+    Dct,
+    // the same xor chain and permutation, driven by ChaCha20 with a nonce stored in the header
+    // instead of a fast but predictable generator
+    ChaCha20,
+}
+
+impl Cipher {
+    fn to_u8(self) -> u8 {
+        match self {
+            Cipher::Legacy => 0,
+            Cipher::Dct => 1,
+            Cipher::ChaCha20 => 2,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Cipher::Legacy),
+            1 => Some(Cipher::Dct),
+            2 => Some(Cipher::ChaCha20),
+            _ => None,
+        }
+    }
+}
+
+// the byte order of the 16-bit and float samples the cipher ran over
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum SampleOrder {
+    // what the cipher always works in, whatever machine it runs on
+    #[default]
+    LittleEndian,
+    // for images encrypted on big-endian machines before the order was fixed, which ran the cipher
+    // over the samples in the machine's own order
+    BigEndian,
+}
+
+// the parameters an encrypted image needs in order to be decrypted and restored
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncryptionHeader {
+    // the color type of the image before it was normalized for encryption
+    pub original_color: Option<ColorType>,
+    // in convergent mode, the key derived from the plaintext, masked with the user's key
+    pub convergent_key: Option<u64>,
+    pub cipher: Option<Cipher>,
+    // the format of the file the image was encrypted from
+    pub original_format: Option<ImageFormat>,
+    // width and height of the encrypted image
+    pub dimensions: Option<(u32, u32)>,
+    // images encrypted before this was recorded were always permuted by pixel
+    pub permutation_unit: Option<PermutationUnit>,
+    // which key the image was encrypted with, so it can be matched to a key without trying to decrypt it
+    pub key_fingerprint: Option<KeyFingerprint>,
+    // how many hashes the fingerprint is stretched through; images encrypted before this was recorded
+    // carry the cheap fingerprint of the key instead
+    pub key_check_iterations: Option<u32>,
+    // the fingerprint of the key that is the same for every image, see `KeyFingerprint::id`
+    pub key_id: Option<KeyFingerprint>,
+    // the encrypted regions, in the order they were encrypted; empty if the whole image was
+    pub regions: Vec<PixelShape>,
+    // a plaintext strip along an edge of the file that isn't part of the ciphertext,
+    // added after encrypting and removed before decrypting
+    pub reserved: Option<Rect>,
+    // the file is a JPEG placeholder carrying the encrypted image, see `EncryptOptions::jpeg_container`
+    pub jpeg_container: bool,
+    // the salt and iterations the key was derived from a passphrase with, if it was
+    pub kdf: Option<KdfParams>,
+    // the nonce of the ChaCha20 cipher
+    pub nonce: Option<[u8; NONCE_LEN]>,
+    // the digest of the image as it was before encrypting, sealed with the key, see `SealedDigest`
+    pub plaintext_digest: Option<SealedDigest>,
+    // HMAC-SHA256 of the ciphertext pixels and the rest of the header, checked before decrypting;
+    // none for lossy outputs
+    pub auth_tag: Option<[u8; AUTH_TAG_LEN]>,
+    // the header was written as version 1, whose tag only covers the pixels and may be missing,
+    // as it is for images encrypted before tags were added
+    pub pixel_tag_only: bool,
+    // the tags the image was encrypted with, as tokens that can only be matched with the key
+    pub search_tags: Option<SearchTags>,
+    // the pixels were chained in independently keyed chunks of this many pixels, see `EncryptOptions::parallel`
+    pub chunk_len: Option<u32>,
+    // the image was encrypted as a stream, in bands of this many rows, see `encrypt_stream`
+    pub band_rows: Option<u32>,
+    // the image is this frame of an animation, encrypted with its own key, see `frame_key`
+    pub frame_index: Option<u32>,
+    // the image is this layer of a multi-layer file, encrypted with its own key, see `layer_key`
+    pub layer_index: Option<u32>,
+    // the pixels were only permuted, not chained, see `EncryptOptions::permute_only`
+    pub permute_only: bool,
+    // the ciphertext was shaped into noise, see `EncryptOptions::noise`
+    pub noise: Option<ShapedNoise>,
+    // the file is hidden in a generated picture, see `EncryptOptions::disguise`
+    pub disguised: bool,
+    // the positions of the channels left as they were, see `EncryptOptions::skip_channels`
+    pub skipped_channels: Vec<u8>,
+    // only recorded for images with samples wider than a byte; images encrypted before this was
+    // recorded are little-endian, as every machine they were encrypted on was
+    pub sample_order: Option<SampleOrder>,
+    // only recorded for schemes other than the XOR chain, which every image was encrypted with before
+    pub scramble_algorithm: Option<ScrambleAlgorithm>,
+    // only recorded for more than the single round every image was encrypted with before
+    pub rounds: Option<u32>,
+    // the image is a share of one split with `split_image`, which has no key and can only be
+    // put back together with the other shares by `combine_images`
+    pub share: Option<ShareInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    Truncated,
+    UnsupportedVersion(u8),
+    InvalidField(u8),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Truncated => write!(f, "encryption header is truncated"),
+            HeaderError::UnsupportedVersion(version) => {
+                write!(f, "unsupported encryption header version {}", version)
+            }
+            HeaderError::InvalidField(tag) => {
+                write!(f, "invalid value for encryption header field {}", tag)
+            }
+        }
+    }
+}
+
+impl Error for HeaderError {}
+
+pub(crate) fn color_to_u8(color: ColorType) -> u8 {
+    match color {
+        ColorType::L8 => 0,
+        ColorType::La8 => 1,
+        ColorType::Rgb8 => 2,
+        ColorType::Rgba8 => 3,
+        ColorType::L16 => 4,
+        ColorType::La16 => 5,
+        ColorType::Rgb16 => 6,
+        ColorType::Rgba16 => 7,
+        ColorType::Rgb32F => 8,
+        ColorType::Rgba32F => 9,
+        _ => u8::MAX,
+    }
+}
+
+pub(crate) fn color_from_u8(value: u8) -> Option<ColorType> {
+    Some(match value {
+        0 => ColorType::L8,
+        1 => ColorType::La8,
+        2 => ColorType::Rgb8,
+        3 => ColorType::Rgba8,
+        4 => ColorType::L16,
+        5 => ColorType::La16,
+        6 => ColorType::Rgb16,
+        7 => ColorType::Rgba16,
+        8 => ColorType::Rgb32F,
+        9 => ColorType::Rgba32F,
+        _ => return None,
+    })
+}
+
+fn push_field(out: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    out.push(tag);
+    match u16::try_from(value.len()) {
+        Ok(len) if len != LONG_FIELD => out.extend_from_slice(&len.to_le_bytes()),
+        _ => {
+            out.extend_from_slice(&LONG_FIELD.to_le_bytes());
+            out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        }
+    }
+    out.extend_from_slice(value);
+}
+
+impl EncryptionHeader {
+    // the trailer bytes to append after the encoded image
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = vec![if self.pixel_tag_only {
+            PIXEL_TAG_VERSION
+        } else {
+            VERSION
+        }];
+        if let Some(color) = self.original_color {
+            push_field(&mut payload, TAG_ORIGINAL_COLOR, &[color_to_u8(color)]);
+        }
+        if let Some(key) = self.convergent_key {
+            push_field(&mut payload, TAG_CONVERGENT_KEY, &key.to_le_bytes());
+        }
+        if let Some(cipher) = self.cipher {
+            push_field(&mut payload, TAG_CIPHER, &[cipher.to_u8()]);
+        }
+        // formats are stored by their usual extension, which stays stable across image versions
+        if let Some(format) = self.original_format {
+            push_field(
+                &mut payload,
+                TAG_ORIGINAL_FORMAT,
+                format.extensions_str()[0].as_bytes(),
+            );
+        }
+        if let Some((width, height)) = self.dimensions {
+            let mut value = width.to_le_bytes().to_vec();
+            value.extend_from_slice(&height.to_le_bytes());
+            push_field(&mut payload, TAG_DIMENSIONS, &value);
+        }
+        if let Some(unit) = self.permutation_unit {
+            push_field(&mut payload, TAG_PERMUTATION_UNIT, &unit.to_bytes());
+        }
+        if let Some(fingerprint) = self.key_fingerprint {
+            push_field(&mut payload, TAG_KEY_FINGERPRINT, &fingerprint.0);
+        }
+        if let Some(iterations) = self.key_check_iterations {
+            push_field(
+                &mut payload,
+                TAG_KEY_CHECK_ITERATIONS,
+                &iterations.to_le_bytes(),
+            );
+        }
+        if let Some(id) = self.key_id {
+            push_field(&mut payload, TAG_KEY_ID, &id.0);
+        }
+        // lists of rectangles keep the more compact encoding older readers understand
+        let rects = self
+            .regions
+            .iter()
+            .map(|region| match region {
+                PixelShape::Rect(rect) => Some(rect.to_bytes()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>();
+        match rects {
+            _ if self.regions.is_empty() => {}
+            Some(rects) => push_field(&mut payload, TAG_REGIONS, &rects.concat()),
+            None => {
+                let value = self
+                    .regions
+                    .iter()
+                    .flat_map(PixelShape::to_bytes)
+                    .collect::<Vec<_>>();
+                push_field(&mut payload, TAG_SHAPES, &value);
+            }
+        }
+
+        if let Some(strip) = self.reserved {
+            push_field(&mut payload, TAG_RESERVED, &strip.to_bytes());
+        }
+        if self.jpeg_container {
+            push_field(&mut payload, TAG_JPEG_CONTAINER, &[]);
+        }
+        if let Some(kdf) = self.kdf {
+            let mut value = kdf.iterations.to_le_bytes().to_vec();
+            value.extend_from_slice(&kdf.salt);
+            push_field(&mut payload, TAG_KDF, &value);
+        }
+        if let Some(nonce) = self.nonce {
+            push_field(&mut payload, TAG_NONCE, &nonce);
+        }
+        if let Some(digest) = self.plaintext_digest {
+            push_field(
+                &mut payload,
+                TAG_PLAINTEXT_DIGEST,
+                &[digest.salt, digest.sealed].concat(),
+            );
+        }
+        if let Some(tag) = self.auth_tag {
+            push_field(&mut payload, TAG_AUTH_TAG, &tag);
+        }
+        if let Some(tags) = &self.search_tags {
+            push_field(
+                &mut payload,
+                TAG_SEARCH_TAGS,
+                &[&tags.salt[..], &tags.tokens.concat()].concat(),
+            );
+        }
+        if let Some(len) = self.chunk_len {
+            push_field(&mut payload, TAG_CHUNK_LEN, &len.to_le_bytes());
+        }
+        if let Some(rows) = self.band_rows {
+            push_field(&mut payload, TAG_BAND_ROWS, &rows.to_le_bytes());
+        }
+        if let Some(index) = self.frame_index {
+            push_field(&mut payload, TAG_FRAME_INDEX, &index.to_le_bytes());
+        }
+        if let Some(index) = self.layer_index {
+            push_field(&mut payload, TAG_LAYER_INDEX, &index.to_le_bytes());
+        }
+        if self.permute_only {
+            push_field(&mut payload, TAG_PERMUTE_ONLY, &[]);
+        }
+        if let Some(noise) = self.noise {
+            push_field(&mut payload, TAG_NOISE, &noise.to_bytes());
+        }
+        if self.disguised {
+            push_field(&mut payload, TAG_DISGUISED, &[]);
+        }
+        if !self.skipped_channels.is_empty() {
+            push_field(&mut payload, TAG_SKIPPED_CHANNELS, &self.skipped_channels);
+        }
+        if let Some(order) = self.sample_order {
+            let value = match order {
+                SampleOrder::LittleEndian => 0,
+                SampleOrder::BigEndian => 1,
+            };
+            push_field(&mut payload, TAG_SAMPLE_ORDER, &[value]);
+        }
+        if let Some(algorithm) = self.scramble_algorithm {
+            push_field(&mut payload, TAG_SCRAMBLE_ALGORITHM, &[algorithm.to_u8()]);
+        }
+        if let Some(rounds) = self.rounds {
+            push_field(&mut payload, TAG_ROUNDS, &rounds.to_le_bytes());
+        }
+        if let Some(share) = self.share {
+            push_field(&mut payload, TAG_SHARE, &share.to_bytes());
+        }
+
+        let len = payload.len() as u32;
+        payload.extend_from_slice(&len.to_le_bytes());
+        payload.extend_from_slice(MAGIC);
+        payload
+    }
+
+    // what the authentication tag covers besides the pixels
+    pub(crate) fn authenticated_bytes(&self) -> Vec<u8> {
+        EncryptionHeader {
+            auth_tag: None,
+            ..self.clone()
+        }
+        .to_bytes()
+    }
+
+    // the dimensions of the ciphertext, which the Arnold cat map pads to a square
+    pub(crate) fn scrambled_dimensions(&self) -> Option<(u32, u32)> {
+        let (width, height) = self.dimensions?;
+        Some(match self.scramble_algorithm {
+            Some(ScrambleAlgorithm::ArnoldCat) => (width.max(height), width.max(height)),
+            _ => (width, height),
+        })
+    }
+
+    // whether the key matches the fingerprint, or None if there is no fingerprint to check it against
+    pub fn check_key(&self, key: u64) -> Option<bool> {
+        let fingerprint = self.key_fingerprint?;
+        let expected = match self.key_check_iterations {
+            Some(iterations) => KeyFingerprint::hardened(key, iterations),
+            None => KeyFingerprint::of(key),
+        };
+        Some(fingerprint == expected)
+    }
+
+    // what guessing the key of the image offline costs, or None if the header doesn't say how big the image is
+    pub fn guess_cost(&self) -> Option<GuessCost> {
+        let (width, height) = self.dimensions?;
+        let check = self.key_fingerprint.map(|_| {
+            self.key_check_iterations
+                .map_or(1, |iterations| iterations as u64 + 1)
+        });
+        // a guess can be checked against whichever fingerprint is cheaper
+        let id = self.key_id.map(|_| KEY_ID_ITERATIONS as u64 + 1);
+        Some(GuessCost {
+            check_hashes: check.into_iter().chain(id).min(),
+            decryption_steps: width as u64 * height as u64,
+        })
+    }
+
+    fn from_payload(payload: &[u8]) -> Result<Self, HeaderError> {
+        let (&version, mut fields) = payload.split_first().ok_or(HeaderError::Truncated)?;
+        if version != VERSION && version != PIXEL_TAG_VERSION {
+            return Err(HeaderError::UnsupportedVersion(version));
+        }
+
+        let mut header = EncryptionHeader {
+            pixel_tag_only: version == PIXEL_TAG_VERSION,
+            ..Default::default()
+        };
+        while !fields.is_empty() {
+            if fields.len() < 3 {
+                return Err(HeaderError::Truncated);
+            }
+            let tag = fields[0];
+            let len = u16::from_le_bytes([fields[1], fields[2]]);
+            fields = &fields[3..];
+            let len = if len == LONG_FIELD && version != PIXEL_TAG_VERSION {
+                let (len, rest) = fields.split_first_chunk().ok_or(HeaderError::Truncated)?;
+                fields = rest;
+                u32::from_le_bytes(*len) as usize
+            } else {
+                len as usize
+            };
+            let value = fields.get(..len).ok_or(HeaderError::Truncated)?;
+            fields = &fields[len..];
+
+            match tag {
+                TAG_ORIGINAL_COLOR => {
+                    header.original_color = Some(
+                        value
+                            .first()
+                            .copied()
+                            .and_then(color_from_u8)
+                            .ok_or(HeaderError::InvalidField(tag))?,
+                    );
+                }
+                TAG_CONVERGENT_KEY => {
+                    header.convergent_key = Some(u64::from_le_bytes(
+                        value
+                            .try_into()
+                            .map_err(|_| HeaderError::InvalidField(tag))?,
+                    ));
+                }
+                TAG_CIPHER => {
+                    header.cipher = Some(
+                        value
+                            .first()
+                            .copied()
+                            .and_then(Cipher::from_u8)
+                            .ok_or(HeaderError::InvalidField(tag))?,
+                    );
+                }
+                TAG_ORIGINAL_FORMAT => {
+                    header.original_format = Some(
+                        std::str::from_utf8(value)
+                            .ok()
+                            .and_then(ImageFormat::from_extension)
+                            .ok_or(HeaderError::InvalidField(tag))?,
+                    );
+                }
+                TAG_DIMENSIONS => {
+                    let value: [u8; 8] = value
+                        .try_into()
+                        .map_err(|_| HeaderError::InvalidField(tag))?;
+                    header.dimensions = Some((
+                        u32::from_le_bytes(value[..4].try_into().unwrap()),
+                        u32::from_le_bytes(value[4..].try_into().unwrap()),
+                    ));
+                }
+                TAG_PERMUTATION_UNIT => {
+                    header.permutation_unit = Some(
+                        PermutationUnit::from_bytes(value).ok_or(HeaderError::InvalidField(tag))?,
+                    );
+                }
+                TAG_KEY_FINGERPRINT => {
+                    header.key_fingerprint = Some(KeyFingerprint(
+                        value
+                            .try_into()
+                            .map_err(|_| HeaderError::InvalidField(tag))?,
+                    ));
+                }
+                TAG_KEY_ID => {
+                    header.key_id = Some(KeyFingerprint(
+                        value
+                            .try_into()
+                            .map_err(|_| HeaderError::InvalidField(tag))?,
+                    ));
+                }
+                TAG_REGIONS => {
+                    if value.len() % 16 != 0 {
+                        return Err(HeaderError::InvalidField(tag));
+                    }
+                    header.regions = value
+                        .chunks_exact(16)
+                        .map(|bytes| Rect::from_bytes(bytes).map(PixelShape::Rect))
+                        .collect::<Option<_>>()
+                        .ok_or(HeaderError::InvalidField(tag))?;
+                }
+                TAG_SHAPES => {
+                    let mut rest = value;
+                    header.regions.clear();
+                    while !rest.is_empty() {
+                        let (shape, next) =
+                            PixelShape::from_bytes(rest).ok_or(HeaderError::InvalidField(tag))?;
+                        header.regions.push(shape);
+                        rest = next;
+                    }
+                }
+                TAG_KEY_CHECK_ITERATIONS => {
+                    header.key_check_iterations = Some(u32::from_le_bytes(
+                        value
+                            .try_into()
+                            .map_err(|_| HeaderError::InvalidField(tag))?,
+                    ));
+                }
+                TAG_RESERVED => {
+                    header.reserved = Some(
+                        Some(value)
+                            .filter(|value| value.len() == 16)
+                            .and_then(Rect::from_bytes)
+                            .ok_or(HeaderError::InvalidField(tag))?,
+                    );
+                }
+                TAG_JPEG_CONTAINER => header.jpeg_container = true,
+                TAG_PERMUTE_ONLY => header.permute_only = true,
+                TAG_DISGUISED => header.disguised = true,
+                TAG_SKIPPED_CHANNELS => header.skipped_channels = value.to_vec(),
+                TAG_SAMPLE_ORDER => {
+                    header.sample_order = Some(match value {
+                        [0] => SampleOrder::LittleEndian,
+                        [1] => SampleOrder::BigEndian,
+                        _ => return Err(HeaderError::InvalidField(tag)),
+                    });
+                }
+                TAG_SCRAMBLE_ALGORITHM => {
+                    header.scramble_algorithm = Some(match value {
+                        &[value] => ScrambleAlgorithm::from_u8(value)
+                            .ok_or(HeaderError::InvalidField(tag))?,
+                        _ => return Err(HeaderError::InvalidField(tag)),
+                    });
+                }
+                TAG_ROUNDS => {
+                    let rounds = u32::from_le_bytes(
+                        value
+                            .try_into()
+                            .map_err(|_| HeaderError::InvalidField(tag))?,
+                    );
+                    if rounds == 0 {
+                        return Err(HeaderError::InvalidField(tag));
+                    }
+                    header.rounds = Some(rounds);
+                }
+                TAG_SHARE => {
+                    header.share =
+                        Some(ShareInfo::from_bytes(value).ok_or(HeaderError::InvalidField(tag))?);
+                }
+                TAG_NOISE => {
+                    header.noise =
+                        Some(ShapedNoise::from_bytes(value).ok_or(HeaderError::InvalidField(tag))?);
+                }
+                TAG_KDF => {
+                    if value.len() != 4 + SALT_LEN {
+                        return Err(HeaderError::InvalidField(tag));
+                    }
+                    header.kdf = Some(KdfParams {
+                        iterations: u32::from_le_bytes(value[..4].try_into().unwrap()),
+                        salt: value[4..].try_into().unwrap(),
+                    });
+                }
+                TAG_PLAINTEXT_DIGEST => {
+                    if value.len() != 2 * DIGEST_LEN {
+                        return Err(HeaderError::InvalidField(tag));
+                    }
+                    header.plaintext_digest = Some(SealedDigest {
+                        salt: value[..DIGEST_LEN].try_into().unwrap(),
+                        sealed: value[DIGEST_LEN..].try_into().unwrap(),
+                    });
+                }
+                TAG_NONCE => {
+                    header.nonce = Some(
+                        value
+                            .try_into()
+                            .map_err(|_| HeaderError::InvalidField(tag))?,
+                    );
+                }
+                TAG_AUTH_TAG => {
+                    header.auth_tag = Some(
+                        value
+                            .try_into()
+                            .map_err(|_| HeaderError::InvalidField(tag))?,
+                    );
+                }
+                TAG_SEARCH_TAGS => {
+                    if value.len() < TOKEN_LEN || value.len() % TOKEN_LEN != 0 {
+                        return Err(HeaderError::InvalidField(tag));
+                    }
+                    let (salt, tokens) = value.split_at(TOKEN_LEN);
+                    header.search_tags = Some(SearchTags {
+                        salt: salt.try_into().unwrap(),
+                        tokens: tokens
+                            .chunks_exact(TOKEN_LEN)
+                            .map(|token| token.try_into().unwrap())
+                            .collect(),
+                    });
+                }
+                TAG_CHUNK_LEN => {
+                    let len = u32::from_le_bytes(
+                        value
+                            .try_into()
+                            .map_err(|_| HeaderError::InvalidField(tag))?,
+                    );
+                    if len == 0 {
+                        return Err(HeaderError::InvalidField(tag));
+                    }
+                    header.chunk_len = Some(len);
+                }
+                TAG_BAND_ROWS => {
+                    let rows = u32::from_le_bytes(
+                        value
+                            .try_into()
+                            .map_err(|_| HeaderError::InvalidField(tag))?,
+                    );
+                    if rows == 0 {
+                        return Err(HeaderError::InvalidField(tag));
+                    }
+                    header.band_rows = Some(rows);
+                }
+                TAG_FRAME_INDEX => {
+                    header.frame_index = Some(u32::from_le_bytes(
+                        value
+                            .try_into()
+                            .map_err(|_| HeaderError::InvalidField(tag))?,
+                    ));
+                }
+                TAG_LAYER_INDEX => {
+                    header.layer_index = Some(u32::from_le_bytes(
+                        value
+                            .try_into()
+                            .map_err(|_| HeaderError::InvalidField(tag))?,
+                    ));
+                }
+                // fields from newer writers are skipped
+                _ => {}
+            }
+        }
+        Ok(header)
+    }
+}
+
+// split a file into its image data and, if it has one, its encryption header
+pub fn split_header(bytes: &[u8]) -> Result<(&[u8], Option<EncryptionHeader>), HeaderError> {
+    let Some(rest) = bytes.strip_suffix(MAGIC.as_slice()) else {
+        return Ok((bytes, None));
+    };
+    let len_start = rest.len().checked_sub(4).ok_or(HeaderError::Truncated)?;
+    let len = u32::from_le_bytes(rest[len_start..].try_into().unwrap()) as usize;
+    let payload_start = len_start.checked_sub(len).ok_or(HeaderError::Truncated)?;
+
+    let header = EncryptionHeader::from_payload(&rest[payload_start..len_start])?;
+    Ok((&bytes[..payload_start], Some(header)))
+}
+
+// the encryption header of an encrypted file's contents, if it has one
+pub fn parse_header(bytes: &[u8]) -> Result<Option<EncryptionHeader>, HeaderError> {
+    split_header(bytes).map(|(_, header)| header)
+}
+
+// the encryption header of a file, without reading or decoding the image data before it,
+// unless the file is a picture that may be a disguise, see `EncryptOptions::disguise`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn read_header(
+    path: impl AsRef<Path>,
+) -> Result<Option<EncryptionHeader>, ImageEncryptionError> {
+    let header = read_header_from(&mut File::open(&path)?)?;
+    if header.is_some() {
+        return Ok(header);
+    }
+    Ok(disguise::hidden_header(path.as_ref()))
+}
+
+// the same from anything that can seek to its end
+pub(crate) fn read_header_from(
+    file: &mut (impl Read + Seek),
+) -> Result<Option<EncryptionHeader>, ImageEncryptionError> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    if file_len < TRAILER_LEN as u64 {
+        return Ok(None);
+    }
+
+    let mut trailer = [0; TRAILER_LEN];
+    file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    file.read_exact(&mut trailer)?;
+    if &trailer[4..] != MAGIC {
+        return Ok(None);
+    }
+
+    let len = u32::from_le_bytes(trailer[..4].try_into().unwrap()) as u64;
+    if len + TRAILER_LEN as u64 > file_len {
+        return Err(HeaderError::Truncated.into());
+    }
+    let mut payload = vec![0; len as usize];
+    file.seek(SeekFrom::End(-((len as usize + TRAILER_LEN) as i64)))?;
+    file.read_exact(&mut payload)?;
+
+    Ok(Some(EncryptionHeader::from_payload(&payload)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a list of regions too long for a u16 to count its bytes is read back whole
+    #[test]
+    fn long_field() {
+        let rect = |i| {
+            PixelShape::Rect(Rect {
+                x: i,
+                y: i,
+                width: 1,
+                height: 1,
+            })
+        };
+        for count in [LONG_FIELD as u32 / 16, 5000] {
+            let header = EncryptionHeader {
+                regions: (0..count).map(rect).collect(),
+                ..Default::default()
+            };
+            assert_eq!(parse_header(&header.to_bytes()), Ok(Some(header)));
+        }
+    }
+}
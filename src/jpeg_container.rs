@@ -0,0 +1,116 @@
+use std::io::Cursor;
+
+use image::{codecs::jpeg::JpegEncoder, ColorType, ImageFormat, ImageResult};
+
+use crate::{encoder::encode_pixels, Image, WriteOptions};
+
+// a JPEG whose pixels are a flat gray placeholder, with the metadata segments of the source file
+// and the encrypted image, losslessly encoded as PNG, split over APP9 segments after them:
+//
+//     [SOI][source APPn and COM segments...][APP9 "IMGENC\0" payload chunk...][placeholder image]
+//
+// so tools that index JPEG metadata still see the original EXIF, XMP and comments,
+// while the ciphertext survives exactly instead of going through the lossy JPEG encoder
+const PAYLOAD_MARKER: u8 = 0xE9;
+const PAYLOAD_ID: &[u8] = b"IMGENC\0";
+// a segment length includes its own two bytes and can't go over u16::MAX
+const CHUNK_LEN: usize = u16::MAX as usize - 2 - PAYLOAD_ID.len();
+
+const SOI: &[u8] = &[0xFF, 0xD8];
+const PLACEHOLDER_GRAY: u8 = 128;
+
+fn is_app(marker: u8) -> bool {
+    (0xE0..=0xEF).contains(&marker)
+}
+
+fn is_payload(marker: u8, segment: &[u8]) -> bool {
+    marker == PAYLOAD_MARKER
+        && segment
+            .get(4..)
+            .is_some_and(|data| data.starts_with(PAYLOAD_ID))
+}
+
+// the marker segments of a JPEG up to the start of scan, each with its marker and length bytes,
+// and the rest of the file from the start of scan on; empty if the bytes aren't a JPEG
+pub(crate) fn jpeg_segments(bytes: &[u8]) -> (Vec<(u8, &[u8])>, &[u8]) {
+    let mut segments = Vec::new();
+    let Some(mut rest) = bytes.strip_prefix(SOI) else {
+        return (segments, &[]);
+    };
+    // every segment is followed by a big endian length including itself
+    while rest.len() >= 4 && rest[0] == 0xFF && rest[1] != 0xDA {
+        let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+        let Some(segment) = rest.get(..2 + len).filter(|_| len >= 2) else {
+            break;
+        };
+        segments.push((rest[1], segment));
+        rest = &rest[2 + len..];
+    }
+    (segments, rest)
+}
+
+// the APPn and COM segments of a JPEG source file, which carry its metadata;
+// a container's own payload isn't part of them
+pub(crate) fn metadata_segments(bytes: &[u8]) -> Vec<Vec<u8>> {
+    jpeg_segments(bytes)
+        .0
+        .into_iter()
+        .filter(|&(marker, segment)| {
+            (is_app(marker) || marker == 0xFE) && !is_payload(marker, segment)
+        })
+        .map(|(_, segment)| segment.to_vec())
+        .collect()
+}
+
+// the encoded image carried by a container, or None if the bytes aren't one
+pub(crate) fn payload(bytes: &[u8]) -> Option<Vec<u8>> {
+    let payload = jpeg_segments(bytes)
+        .0
+        .into_iter()
+        .filter(|&(marker, segment)| is_payload(marker, segment))
+        .flat_map(|(_, segment)| &segment[4 + PAYLOAD_ID.len()..])
+        .copied()
+        .collect::<Vec<_>>();
+    (!payload.is_empty()).then_some(payload)
+}
+
+// the encrypted image wrapped in a JPEG with a placeholder of the same size and the metadata of its source
+// the payload is written with the PNG options, the placeholder with the encoder's defaults
+pub(crate) fn encode_container(img: &Image, options: &WriteOptions) -> ImageResult<Vec<u8>> {
+    let mut payload = Cursor::new(Vec::new());
+    encode_pixels(
+        &mut payload,
+        &img.pixels,
+        (img.width, img.height),
+        img.color,
+        ImageFormat::Png,
+        options,
+    )?;
+
+    // a flat image compresses to almost nothing whatever its size
+    let mut placeholder = Vec::new();
+    JpegEncoder::new(&mut placeholder).encode(
+        &vec![PLACEHOLDER_GRAY; img.width as usize * img.height as usize],
+        img.width,
+        img.height,
+        ColorType::L8,
+    )?;
+    let (segments, scan) = jpeg_segments(&placeholder);
+
+    let mut bytes = SOI.to_vec();
+    for segment in &img.jpeg_segments {
+        bytes.extend_from_slice(segment);
+    }
+    for chunk in payload.into_inner().chunks(CHUNK_LEN) {
+        bytes.extend_from_slice(&[0xFF, PAYLOAD_MARKER]);
+        bytes.extend_from_slice(&((2 + PAYLOAD_ID.len() + chunk.len()) as u16).to_be_bytes());
+        bytes.extend_from_slice(PAYLOAD_ID);
+        bytes.extend_from_slice(chunk);
+    }
+    // the encoder's JFIF segment would end up after the metadata, where it doesn't belong
+    for (_, segment) in segments.into_iter().filter(|&(marker, _)| !is_app(marker)) {
+        bytes.extend_from_slice(segment);
+    }
+    bytes.extend_from_slice(scan);
+    Ok(bytes)
+}
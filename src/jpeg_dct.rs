@@ -0,0 +1,823 @@
+use std::{error::Error, fmt};
+
+use rand::RngCore;
+
+use crate::{
+    auth::{dct_tag, tags_match},
+    blake3,
+    jpeg_container::jpeg_segments,
+    key_check_iterations,
+    rng::shuffle,
+    split_header, Cipher, EncryptionHeader, HeaderError, KeyFingerprint, Xoshiro256PlusPlus,
+};
+
+// encryption of baseline JPEGs in the DCT domain: the quantized coefficients are entropy decoded,
+// the blocks of every component are shuffled among themselves, and the AC coefficients of every block
+// are shuffled and have their signs flipped; the result is entropy coded again, so the output is
+// a valid JPEG with the same quantization tables that decrypts to exactly the same coefficients.
+//
+// only the Huffman tables and restart markers change: the scrambled coefficients are coded with the
+// standard tables of annex K of the JPEG spec, which can code every value a baseline JPEG can hold.
+// The header after the file has a tag of the scrambled coefficients, the tables they are read with
+// and the header itself, which is checked before anything is decrypted
+
+#[derive(Debug)]
+pub enum DctError {
+    NotJpeg,
+    // a kind of JPEG the coefficients can't be read from, e.g. progressive
+    Unsupported(&'static str),
+    Invalid,
+    Header(HeaderError),
+    // the file has no header saying it was encrypted in the DCT domain
+    NotEncrypted,
+    // the key doesn't match the fingerprint in the header
+    WrongKey,
+    // the authentication tag doesn't match the coefficients: the key is wrong or the file was modified
+    AuthenticationFailed,
+}
+
+impl fmt::Display for DctError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DctError::NotJpeg => write!(f, "not a JPEG file"),
+            DctError::Unsupported(kind) => write!(
+                f,
+                "{} JPEGs can't be encrypted in the DCT domain, only baseline ones",
+                kind
+            ),
+            DctError::Invalid => write!(f, "invalid JPEG data"),
+            DctError::Header(err) => write!(f, "{}", err),
+            DctError::NotEncrypted => write!(f, "not encrypted in the DCT domain"),
+            DctError::WrongKey => write!(f, "encrypted with a different key"),
+            DctError::AuthenticationFailed => write!(
+                f,
+                "authentication failed, the key is wrong or the file was modified"
+            ),
+        }
+    }
+}
+
+impl Error for DctError {}
+
+impl From<HeaderError> for DctError {
+    fn from(err: HeaderError) -> Self {
+        DctError::Header(err)
+    }
+}
+
+const DHT: u8 = 0xC4;
+const DQT: u8 = 0xDB;
+const DRI: u8 = 0xDD;
+const EOI: u8 = 0xD9;
+
+// (number of codes of every length from 1 to 16, symbols in order of their codes)
+type TableSpec = (&'static [u8; 16], &'static [u8]);
+
+const DC_LUMA: TableSpec = (
+    &[0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+    &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+);
+const DC_CHROMA: TableSpec = (
+    &[0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0],
+    &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+);
+const AC_LUMA: TableSpec = (
+    &[0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d],
+    &[
+        0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61,
+        0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52,
+        0xd1, 0xf0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25,
+        0x26, 0x27, 0x28, 0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45,
+        0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64,
+        0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83,
+        0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99,
+        0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6,
+        0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3,
+        0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8,
+        0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+    ],
+);
+const AC_CHROMA: TableSpec = (
+    &[0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77],
+    &[
+        0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61,
+        0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33,
+        0x52, 0xf0, 0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18,
+        0x19, 0x1a, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44,
+        0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63,
+        0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a,
+        0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97,
+        0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4,
+        0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca,
+        0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7,
+        0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+    ],
+);
+
+// a Huffman table, with the canonical codes assigned to its symbols in order
+struct Huffman {
+    // for every code length, the first code, the last code (or -1 if there are none) and the index
+    // of the first symbol with codes of that length
+    min_code: [i32; 17],
+    max_code: [i32; 17],
+    first: [usize; 17],
+    symbols: Vec<u8>,
+    // the code and its length for every symbol
+    codes: [(u16, u8); 256],
+}
+
+impl Huffman {
+    fn new(counts: &[u8], symbols: &[u8]) -> Result<Self, DctError> {
+        let total = counts.iter().map(|&count| count as usize).sum::<usize>();
+        if counts.len() != 16 || symbols.len() != total {
+            return Err(DctError::Invalid);
+        }
+        let mut table = Huffman {
+            min_code: [0; 17],
+            max_code: [-1; 17],
+            first: [0; 17],
+            symbols: symbols.to_vec(),
+            codes: [(0, 0); 256],
+        };
+        let (mut code, mut index) = (0i32, 0);
+        for len in 1..=16 {
+            let count = counts[len - 1] as usize;
+            table.first[len] = index;
+            table.min_code[len] = code;
+            for &symbol in &symbols[index..index + count] {
+                table.codes[symbol as usize] = (code as u16, len as u8);
+                code += 1;
+            }
+            index += count;
+            if count > 0 {
+                table.max_code[len] = code - 1;
+            }
+            // a code that doesn't fit in its length means the counts are wrong
+            if code > 1 << len {
+                return Err(DctError::Invalid);
+            }
+            code <<= 1;
+        }
+        Ok(table)
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8, DctError> {
+        let mut code = reader.bit() as i32;
+        for len in 1..=16 {
+            if code <= self.max_code[len] {
+                let index = self.first[len] + (code - self.min_code[len]) as usize;
+                return self.symbols.get(index).copied().ok_or(DctError::Invalid);
+            }
+            code = (code << 1) | reader.bit() as i32;
+        }
+        Err(DctError::Invalid)
+    }
+
+    fn encode(&self, writer: &mut BitWriter, symbol: u8) {
+        let (code, len) = self.codes[symbol as usize];
+        writer.write(code as u32, len as u32);
+    }
+}
+
+// reads the bits of unstuffed entropy-coded data, most significant first;
+// past the end it reads ones, like the padding of the last byte
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    byte: u8,
+    left: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader {
+            bytes,
+            pos: 0,
+            byte: 0,
+            left: 0,
+        }
+    }
+
+    fn bit(&mut self) -> u32 {
+        if self.left == 0 {
+            self.byte = self.bytes.get(self.pos).copied().unwrap_or(0xFF);
+            self.pos += 1;
+            self.left = 8;
+        }
+        self.left -= 1;
+        (self.byte >> self.left) as u32 & 1
+    }
+
+    fn bits(&mut self, count: u32) -> u32 {
+        (0..count).fold(0, |value, _| (value << 1) | self.bit())
+    }
+
+    // whether more bits were read than there are, so the data was cut short
+    fn overrun(&self) -> bool {
+        self.pos > self.bytes.len()
+    }
+
+    // a coefficient of the given size category, from its low bits
+    fn coefficient(&mut self, size: u8) -> Result<i16, DctError> {
+        if size > 15 {
+            return Err(DctError::Invalid);
+        }
+        let value = self.bits(size as u32) as i32;
+        let value = if size > 0 && value < 1 << (size - 1) {
+            value - (1 << size) + 1
+        } else {
+            value
+        };
+        Ok(value as i16)
+    }
+}
+
+// writes entropy-coded data, stuffing a zero byte after every 0xFF
+struct BitWriter {
+    bytes: Vec<u8>,
+    byte: u32,
+    used: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.byte = (self.byte << 1) | (value >> i) & 1;
+            self.used += 1;
+            if self.used == 8 {
+                self.push_byte();
+            }
+        }
+    }
+
+    fn push_byte(&mut self) {
+        self.bytes.push(self.byte as u8);
+        if self.byte == 0xFF {
+            self.bytes.push(0);
+        }
+        self.byte = 0;
+        self.used = 0;
+    }
+
+    // pad the last byte with ones
+    fn finish(mut self) -> Vec<u8> {
+        if self.used > 0 {
+            self.write(0xFF, 8 - self.used);
+        }
+        self.bytes
+    }
+
+    // a coefficient as its size category and its low bits
+    fn coefficient(value: i16) -> (u8, u32) {
+        let value = value as i32;
+        let size = 32 - value.unsigned_abs().leading_zeros();
+        let bits = if value < 0 { value - 1 } else { value };
+        (size as u8, bits as u32 & ((1 << size) - 1))
+    }
+}
+
+struct Component {
+    id: u8,
+    horizontal: u32,
+    vertical: u32,
+}
+
+// the quantized coefficients of a baseline JPEG, in zigzag order, block by block in the order
+// they are coded, with the segments of the file that aren't about the entropy coding
+struct Coefficients<'a> {
+    segments: Vec<(u8, &'a [u8])>,
+    components: Vec<Component>,
+    width: u32,
+    height: u32,
+    blocks: Vec<[i16; 64]>,
+    // the component of every block
+    owners: Vec<usize>,
+}
+
+fn u16_at(bytes: &[u8], i: usize) -> Result<u16, DctError> {
+    match bytes.get(i..i + 2) {
+        Some(&[high, low]) => Ok(u16::from_be_bytes([high, low])),
+        _ => Err(DctError::Invalid),
+    }
+}
+
+// the entropy-coded data after a start of scan, unstuffed and split at the restart markers,
+// and what follows it
+fn entropy_coded(data: &[u8]) -> (Vec<Vec<u8>>, &[u8]) {
+    let mut intervals = vec![Vec::new()];
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != 0xFF {
+            intervals.last_mut().unwrap().push(data[i]);
+            i += 1;
+            continue;
+        }
+        match data.get(i + 1) {
+            Some(0x00) => intervals.last_mut().unwrap().push(0xFF),
+            Some(0xD0..=0xD7) => intervals.push(Vec::new()),
+            // fill bytes before a marker
+            Some(0xFF) => {
+                i += 1;
+                continue;
+            }
+            _ => return (intervals, &data[i..]),
+        }
+        i += 2;
+    }
+    (intervals, &[])
+}
+
+impl<'a> Coefficients<'a> {
+    fn decode(bytes: &'a [u8]) -> Result<Self, DctError> {
+        let (segments, scan) = jpeg_segments(bytes);
+        if segments.is_empty() {
+            return Err(DctError::NotJpeg);
+        }
+
+        let mut frame = None;
+        let mut dc_tables: [Option<Huffman>; 4] = Default::default();
+        let mut ac_tables: [Option<Huffman>; 4] = Default::default();
+        let mut restart_interval = 0;
+        for &(marker, segment) in &segments {
+            let data = &segment[4..];
+            match marker {
+                0xC0 | 0xC1 => frame = Some(data),
+                0xC2 | 0xC6 | 0xCA | 0xCE => return Err(DctError::Unsupported("progressive")),
+                0xC3 | 0xC7 | 0xCB | 0xCF => return Err(DctError::Unsupported("lossless")),
+                0xC5 => return Err(DctError::Unsupported("hierarchical")),
+                0xC9 | 0xCD => return Err(DctError::Unsupported("arithmetic coded")),
+                DHT => {
+                    let mut rest = data;
+                    while let Some((&class_id, tail)) = rest.split_first() {
+                        let counts = tail.get(..16).ok_or(DctError::Invalid)?;
+                        let total = counts.iter().map(|&count| count as usize).sum::<usize>();
+                        let symbols = tail.get(16..16 + total).ok_or(DctError::Invalid)?;
+                        let table = Some(Huffman::new(counts, symbols)?);
+                        match (class_id >> 4, class_id & 0x0F) {
+                            (0, id @ 0..=3) => dc_tables[id as usize] = table,
+                            (1, id @ 0..=3) => ac_tables[id as usize] = table,
+                            _ => return Err(DctError::Invalid),
+                        }
+                        rest = &tail[16 + total..];
+                    }
+                }
+                DRI => restart_interval = u16_at(data, 0)? as usize,
+                _ => {}
+            }
+        }
+
+        let frame = frame.ok_or(DctError::Invalid)?;
+        if frame.first() != Some(&8) {
+            return Err(DctError::Unsupported("12-bit"));
+        }
+        let height = u16_at(frame, 1)? as u32;
+        let width = u16_at(frame, 3)? as u32;
+        let count = *frame.get(5).ok_or(DctError::Invalid)? as usize;
+        let components = (0..count)
+            .map(|i| {
+                let spec = frame.get(6 + i * 3..9 + i * 3).ok_or(DctError::Invalid)?;
+                let (horizontal, vertical) = ((spec[1] >> 4) as u32, (spec[1] & 0x0F) as u32);
+                if !(1..=4).contains(&horizontal) || !(1..=4).contains(&vertical) {
+                    return Err(DctError::Invalid);
+                }
+                Ok(Component {
+                    id: spec[0],
+                    horizontal,
+                    vertical,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if width == 0 || height == 0 || components.is_empty() {
+            return Err(DctError::Invalid);
+        }
+
+        // the start of scan header: the tables of every component in the scan
+        if scan.len() < 4 || scan[..2] != [0xFF, 0xDA] {
+            return Err(DctError::Invalid);
+        }
+        let len = u16_at(scan, 2)? as usize;
+        let header = scan.get(4..2 + len).ok_or(DctError::Invalid)?;
+        let scan_count = *header.first().ok_or(DctError::Invalid)? as usize;
+        if scan_count != components.len() {
+            return Err(DctError::Unsupported("multi-scan"));
+        }
+        let mut tables = Vec::new();
+        for (i, component) in components.iter().enumerate() {
+            let spec = header.get(1 + i * 2..3 + i * 2).ok_or(DctError::Invalid)?;
+            if spec[0] != component.id {
+                return Err(DctError::Unsupported("multi-scan"));
+            }
+            let dc = dc_tables[(spec[1] >> 4) as usize & 3].as_ref();
+            let ac = ac_tables[(spec[1] & 0x0F) as usize & 3].as_ref();
+            tables.push((dc.ok_or(DctError::Invalid)?, ac.ok_or(DctError::Invalid)?));
+        }
+
+        // which component every block of a minimum coded unit belongs to; a scan of a single component
+        // isn't interleaved, and its units are single blocks
+        let max_horizontal = components.iter().map(|c| c.horizontal).max().unwrap();
+        let max_vertical = components.iter().map(|c| c.vertical).max().unwrap();
+        let (unit, units) = if components.len() == 1 {
+            let columns = (width * components[0].horizontal).div_ceil(max_horizontal);
+            let rows = (height * components[0].vertical).div_ceil(max_vertical);
+            (
+                vec![0],
+                columns.div_ceil(8) as usize * rows.div_ceil(8) as usize,
+            )
+        } else {
+            let unit = components
+                .iter()
+                .enumerate()
+                .flat_map(|(i, c)| std::iter::repeat_n(i, (c.horizontal * c.vertical) as usize))
+                .collect::<Vec<_>>();
+            let columns = width.div_ceil(8 * max_horizontal) as usize;
+            let rows = height.div_ceil(8 * max_vertical) as usize;
+            (unit, columns * rows)
+        };
+
+        let (intervals, after) = entropy_coded(&scan[2 + len..]);
+        if !after.is_empty() && after.get(1) != Some(&EOI) {
+            return Err(DctError::Unsupported("multi-scan"));
+        }
+        let per_interval = if restart_interval == 0 {
+            units
+        } else {
+            restart_interval
+        };
+        if intervals.len() < units.div_ceil(per_interval) {
+            return Err(DctError::Invalid);
+        }
+
+        let mut blocks = Vec::with_capacity(units * unit.len());
+        let mut owners = Vec::with_capacity(units * unit.len());
+        for (interval, data) in intervals.iter().enumerate() {
+            let mut reader = BitReader::new(data);
+            let mut predictions = vec![0i16; components.len()];
+            let start = interval * per_interval;
+            for _ in start..units.min(start + per_interval) {
+                for &c in &unit {
+                    let (dc, ac) = tables[c];
+                    let mut block = [0i16; 64];
+                    let size = dc.decode(&mut reader)?;
+                    predictions[c] = predictions[c].wrapping_add(reader.coefficient(size)?);
+                    // the range of 8-bit samples, so any two can be coded next to each other
+                    if !(-1024..1024).contains(&predictions[c]) {
+                        return Err(DctError::Invalid);
+                    }
+                    block[0] = predictions[c];
+                    let mut k = 1;
+                    while k < 64 {
+                        let symbol = ac.decode(&mut reader)?;
+                        let (run, size) = ((symbol >> 4) as usize, symbol & 0x0F);
+                        if size == 0 {
+                            if run != 15 {
+                                break;
+                            }
+                            k += 16;
+                            continue;
+                        }
+                        // larger ones don't come from 8-bit samples, and the standard tables can't code them
+                        if size > 10 {
+                            return Err(DctError::Invalid);
+                        }
+                        k += run;
+                        *block.get_mut(k).ok_or(DctError::Invalid)? = reader.coefficient(size)?;
+                        k += 1;
+                    }
+                    blocks.push(block);
+                    owners.push(c);
+                }
+            }
+            if reader.overrun() {
+                return Err(DctError::Invalid);
+            }
+        }
+
+        Ok(Coefficients {
+            segments,
+            components,
+            width,
+            height,
+            blocks,
+            owners,
+        })
+    }
+
+    // the JPEG with the coefficients coded with the standard tables, in a single interval
+    fn encode(&self) -> Vec<u8> {
+        let chroma = self.components.len() > 1;
+        let specs = [
+            (0x00, DC_LUMA),
+            (0x10, AC_LUMA),
+            (0x01, DC_CHROMA),
+            (0x11, AC_CHROMA),
+        ];
+        let specs = &specs[..if chroma { 4 } else { 2 }];
+        let table = |(counts, symbols): TableSpec| Huffman::new(counts, symbols).unwrap();
+        let luma = (table(DC_LUMA), table(AC_LUMA));
+        let chroma_tables = (table(DC_CHROMA), table(AC_CHROMA));
+
+        let mut writer = BitWriter {
+            bytes: Vec::new(),
+            byte: 0,
+            used: 0,
+        };
+        let mut predictions = vec![0i16; self.components.len()];
+        for (block, &c) in self.blocks.iter().zip(&self.owners) {
+            let (dc, ac) = if c == 0 { &luma } else { &chroma_tables };
+
+            let (size, bits) = BitWriter::coefficient(block[0].wrapping_sub(predictions[c]));
+            predictions[c] = block[0];
+            dc.encode(&mut writer, size);
+            writer.write(bits, size as u32);
+
+            let mut run = 0;
+            for &value in &block[1..] {
+                if value == 0 {
+                    run += 1;
+                    continue;
+                }
+                while run >= 16 {
+                    ac.encode(&mut writer, 0xF0);
+                    run -= 16;
+                }
+                let (size, bits) = BitWriter::coefficient(value);
+                ac.encode(&mut writer, (run << 4) | size);
+                writer.write(bits, size as u32);
+                run = 0;
+            }
+            if run > 0 {
+                ac.encode(&mut writer, 0x00);
+            }
+        }
+
+        let mut bytes = vec![0xFF, 0xD8];
+        for &(marker, segment) in &self.segments {
+            if marker != DHT && marker != DRI {
+                bytes.extend_from_slice(segment);
+            }
+        }
+        let mut tables = Vec::new();
+        for &(class_id, (counts, symbols)) in specs {
+            tables.push(class_id);
+            tables.extend_from_slice(counts);
+            tables.extend_from_slice(symbols);
+        }
+        push_segment(&mut bytes, DHT, &tables);
+        let mut scan = vec![self.components.len() as u8];
+        for (i, component) in self.components.iter().enumerate() {
+            scan.extend_from_slice(&[component.id, if i == 0 { 0x00 } else { 0x11 }]);
+        }
+        scan.extend_from_slice(&[0, 63, 0]);
+        push_segment(&mut bytes, 0xDA, &scan);
+        bytes.extend_from_slice(&writer.finish());
+        bytes.extend_from_slice(&[0xFF, EOI]);
+        bytes
+    }
+
+    // what the authentication tag covers: the quantization tables and the frame the coefficients are read with,
+    // and the coefficients themselves, little-endian in the order they are coded
+    fn authenticated_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.blocks.len() * 128);
+        for &(marker, segment) in &self.segments {
+            if matches!(marker, DQT | 0xC0 | 0xC1) {
+                bytes.extend_from_slice(segment);
+            }
+        }
+        for block in &self.blocks {
+            bytes.extend(block.iter().flat_map(|value| value.to_le_bytes()));
+        }
+        bytes
+    }
+
+    // shuffle the blocks of every component and scramble the coefficients within them, or undo it
+    fn scramble(&mut self, key: u64, decrypt: bool) {
+        let seed = blake3::Hasher::new()
+            .update(b"image_encryption dct\0")
+            .update(&key.to_le_bytes())
+            .finalize();
+        let mut rng = Xoshiro256PlusPlus::from_seed(seed);
+
+        // every random number is drawn in the same order either way
+        let permutations = (0..self.components.len())
+            .map(|c| {
+                let positions = (0..self.blocks.len())
+                    .filter(|&i| self.owners[i] == c)
+                    .collect::<Vec<_>>();
+                let mut shuffled = positions.clone();
+                shuffle(&mut shuffled, &mut rng);
+                (positions, shuffled)
+            })
+            .collect::<Vec<_>>();
+        let shuffle_blocks = |blocks: &mut Vec<[i16; 64]>| {
+            let old = blocks.clone();
+            for (positions, shuffled) in &permutations {
+                for (&to, &from) in positions.iter().zip(shuffled) {
+                    if decrypt {
+                        blocks[from] = old[to];
+                    } else {
+                        blocks[to] = old[from];
+                    }
+                }
+            }
+        };
+
+        if !decrypt {
+            shuffle_blocks(&mut self.blocks);
+        }
+        // the DC coefficient stays, so the differences between neighbours stay within what can be coded
+        let mut order = (1..64).collect::<Vec<usize>>();
+        for block in &mut self.blocks {
+            order.sort_unstable();
+            shuffle(&mut order, &mut rng);
+            let signs = rng.next_u64();
+            let old = *block;
+            if decrypt {
+                for (k, &from) in order.iter().enumerate() {
+                    let flip = signs >> (k + 1) & 1 == 1;
+                    block[from] = if flip { -old[k + 1] } else { old[k + 1] };
+                }
+            } else {
+                for (k, &from) in order.iter().enumerate() {
+                    let flip = signs >> (k + 1) & 1 == 1;
+                    block[k + 1] = if flip { -old[from] } else { old[from] };
+                }
+            }
+        }
+        if decrypt {
+            shuffle_blocks(&mut self.blocks);
+        }
+    }
+}
+
+fn push_segment(bytes: &mut Vec<u8>, marker: u8, data: &[u8]) {
+    bytes.extend_from_slice(&[0xFF, marker]);
+    bytes.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+    bytes.extend_from_slice(data);
+}
+
+// encrypt a baseline JPEG in the DCT domain, with an encryption header after it like other encrypted images
+pub fn encrypt_jpeg_dct(bytes: &[u8], key: u64) -> Result<Vec<u8>, DctError> {
+    let (data, _) = split_header(bytes)?;
+    let mut coefficients = Coefficients::decode(data)?;
+    coefficients.scramble(key, false);
+
+    let (width, height) = (coefficients.width, coefficients.height);
+    let iterations = key_check_iterations(width, height);
+    let mut header = EncryptionHeader {
+        cipher: Some(Cipher::Dct),
+        original_format: Some(image::ImageFormat::Jpeg),
+        dimensions: Some((width, height)),
+        key_fingerprint: Some(KeyFingerprint::hardened(key, iterations)),
+        key_check_iterations: Some(iterations),
+        key_id: Some(KeyFingerprint::id(key)),
+        ..Default::default()
+    };
+    header.auth_tag = Some(dct_tag(key, &coefficients.authenticated_bytes(), &header));
+    let mut bytes = coefficients.encode();
+    bytes.extend_from_slice(&header.to_bytes());
+    Ok(bytes)
+}
+
+// the inverse of `encrypt_jpeg_dct`, giving back a JPEG with the original coefficients;
+// a wrong key or a modified file is an error instead of a JPEG of noise
+pub fn decrypt_jpeg_dct(bytes: &[u8], key: u64) -> Result<Vec<u8>, DctError> {
+    let (data, header) = split_header(bytes)?;
+    let header = header
+        .filter(|header| header.cipher == Some(Cipher::Dct))
+        .ok_or(DctError::NotEncrypted)?;
+    if header.check_key(key) == Some(false) {
+        return Err(DctError::WrongKey);
+    }
+    let mut coefficients = Coefficients::decode(data)?;
+    // every header since the tag was added is written with one, so one without was stripped of it
+    let tag = dct_tag(key, &coefficients.authenticated_bytes(), &header);
+    if !header
+        .auth_tag
+        .is_some_and(|expected| tags_match(&tag, &expected))
+    {
+        return Err(DctError::AuthenticationFailed);
+    }
+    coefficients.scramble(key, true);
+    Ok(coefficients.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{codecs::jpeg::JpegEncoder, ColorType};
+
+    use super::*;
+    use crate::{
+        load_image_from_bytes,
+        test_util::{random_image, rng},
+        ImageEncryptionError,
+    };
+
+    // a baseline JPEG as the image crate writes it, of a smooth gradient with some noise on it
+    fn baseline_jpeg(width: u32, height: u32, color: ColorType) -> Vec<u8> {
+        let mut img = random_image(&mut rng(), width, height, color);
+        let pixel_size = color.bytes_per_pixel() as usize;
+        for (i, pixel) in img.pixels.chunks_exact_mut(pixel_size).enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            for sample in pixel {
+                *sample = (x * 3 + y * 2) as u8 ^ (*sample & 0x0F);
+            }
+        }
+        let mut bytes = Vec::new();
+        JpegEncoder::new_with_quality(&mut bytes, 90)
+            .encode(&img.pixels, width, height, color)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = rng();
+        for (width, height, color) in [(67, 41, ColorType::Rgb8), (33, 70, ColorType::L8)] {
+            let original = baseline_jpeg(width, height, color);
+            let key = rng.next_u64();
+            let encrypted = encrypt_jpeg_dct(&original, key).unwrap();
+            let decrypted = decrypt_jpeg_dct(&encrypted, key).unwrap();
+
+            let blocks = |bytes| Coefficients::decode(bytes).unwrap().blocks;
+            let (scrambled, _) = split_header(&encrypted).unwrap();
+            assert_ne!(blocks(scrambled), blocks(&original));
+            assert_eq!(blocks(&decrypted), blocks(&original));
+            // both are JPEGs any decoder reads, the size they were
+            for bytes in [scrambled, &decrypted] {
+                let img = image::load_from_memory_with_format(bytes, image::ImageFormat::Jpeg);
+                assert_eq!(img.unwrap().width(), width);
+            }
+        }
+    }
+
+    #[test]
+    fn progressive_or_truncated() {
+        let original = baseline_jpeg(40, 24, ColorType::Rgb8);
+        let frame = jpeg_segments(&original)
+            .0
+            .iter()
+            .find(|&&(marker, _)| marker == 0xC0)
+            .map(|(_, segment)| segment.as_ptr() as usize - original.as_ptr() as usize)
+            .unwrap();
+        let mut progressive = original.clone();
+        progressive[frame + 1] = 0xC2;
+        assert!(matches!(
+            encrypt_jpeg_dct(&progressive, 1),
+            Err(DctError::Unsupported("progressive"))
+        ));
+
+        // cut in the headers, in the scan, and just before the end of the image
+        for len in [frame + 5, original.len() / 2, original.len() - 3] {
+            assert!(matches!(
+                encrypt_jpeg_dct(&original[..len], 1),
+                Err(DctError::Invalid)
+            ));
+        }
+        // which is a damaged file when it is decoded to pixels, too
+        assert!(matches!(
+            load_image_from_bytes(&original[..original.len() / 2]),
+            Err(ImageEncryptionError::Malformed(_))
+        ));
+        // a ciphertext cut short loses the header after it
+        let encrypted = encrypt_jpeg_dct(&original, 1).unwrap();
+        assert!(matches!(
+            decrypt_jpeg_dct(&encrypted[..encrypted.len() / 2], 1),
+            Err(DctError::NotEncrypted)
+        ));
+    }
+
+    #[test]
+    fn wrong_key_or_modified() {
+        let mut rng = rng();
+        let key = rng.next_u64();
+        let encrypted = encrypt_jpeg_dct(&baseline_jpeg(48, 32, ColorType::Rgb8), key).unwrap();
+        assert!(matches!(
+            decrypt_jpeg_dct(&encrypted, key ^ 1),
+            Err(DctError::WrongKey)
+        ));
+
+        let (data, header) = split_header(&encrypted).unwrap();
+        let header = header.unwrap();
+        let rewritten = |change: &dyn Fn(&mut Coefficients, &mut EncryptionHeader)| {
+            let mut coefficients = Coefficients::decode(data).unwrap();
+            let mut header = header.clone();
+            change(&mut coefficients, &mut header);
+            let mut bytes = coefficients.encode();
+            bytes.extend_from_slice(&header.to_bytes());
+            decrypt_jpeg_dct(&bytes, key)
+        };
+        assert!(rewritten(&|_, _| {}).is_ok());
+        assert!(matches!(
+            rewritten(&|coefficients, _| coefficients.blocks[3][5] += 1),
+            Err(DctError::AuthenticationFailed)
+        ));
+        assert!(matches!(
+            rewritten(&|_, header| header.original_format = Some(image::ImageFormat::Png)),
+            Err(DctError::AuthenticationFailed)
+        ));
+        assert!(matches!(
+            rewritten(&|_, header| header.auth_tag = None),
+            Err(DctError::AuthenticationFailed)
+        ));
+    }
+}
@@ -0,0 +1,377 @@
+use std::{error::Error, fmt};
+
+// just enough JSON for region lists and sidecar files
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    // fields in the order they appear
+    Object(Vec<(String, Json)>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonError {
+    // byte offset of the error in the input
+    pub offset: usize,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid JSON at byte {}", self.offset)
+    }
+}
+
+impl Error for JsonError {}
+
+impl Json {
+    pub(crate) fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+// compact JSON, with the fields in their original order
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            // JSON has no representation for infinities and NaN
+            Json::Number(number) if !number.is_finite() => f.write_str("null"),
+            Json::Number(number) => write!(f, "{}", number),
+            Json::String(string) => write_string(f, string),
+            Json::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ": {}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in string.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error<T>(&self) -> Result<T, JsonError> {
+        Err(JsonError { offset: self.pos })
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, literal: &str) -> bool {
+        let found = self.bytes[self.pos..].starts_with(literal.as_bytes());
+        if found {
+            self.pos += literal.len();
+        }
+        found
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+        let value = match self.bytes.get(self.pos) {
+            Some(b'{') => self.object()?,
+            Some(b'[') => self.array()?,
+            Some(b'"') => Json::String(self.string()?),
+            Some(b'-' | b'0'..=b'9') => self.number()?,
+            _ if self.eat("true") => Json::Bool(true),
+            _ if self.eat("false") => Json::Bool(false),
+            _ if self.eat("null") => Json::Null,
+            _ => return self.error(),
+        };
+        self.skip_whitespace();
+        Ok(value)
+    }
+
+    // a comma separated list of items up to the closing delimiter, the opening one already consumed
+    fn list(
+        &mut self,
+        close: u8,
+        mut item: impl FnMut(&mut Self) -> Result<(), JsonError>,
+    ) -> Result<(), JsonError> {
+        self.pos += 1;
+        self.skip_whitespace();
+        if self.bytes.get(self.pos) == Some(&close) {
+            self.pos += 1;
+            return Ok(());
+        }
+        loop {
+            item(self)?;
+            match self.bytes.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(&c) if c == close => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                _ => return self.error(),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, JsonError> {
+        let mut fields = Vec::new();
+        self.list(b'}', |parser| {
+            parser.skip_whitespace();
+            let key = parser.string()?;
+            parser.skip_whitespace();
+            if !parser.eat(":") {
+                return parser.error();
+            }
+            fields.push((key, parser.value()?));
+            Ok(())
+        })?;
+        Ok(Json::Object(fields))
+    }
+
+    fn array(&mut self) -> Result<Json, JsonError> {
+        let mut items = Vec::new();
+        self.list(b']', |parser| {
+            items.push(parser.value()?);
+            Ok(())
+        })?;
+        Ok(Json::Array(items))
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        if !self.eat("\"") {
+            return self.error();
+        }
+        let mut out = String::new();
+        loop {
+            let start = self.pos;
+            while !matches!(self.bytes.get(self.pos), None | Some(b'"' | b'\\')) {
+                self.pos += 1;
+            }
+            // the input is a str, and the run stops at ASCII characters, so it is valid UTF-8
+            out.push_str(std::str::from_utf8(&self.bytes[start..self.pos]).unwrap());
+
+            match self.bytes.get(self.pos) {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = match self.bytes.get(self.pos) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let code = self.hex4()?;
+                            // a high surrogate must be followed by an escaped low surrogate
+                            let code = if (0xD800..0xDC00).contains(&code) {
+                                if !self.bytes[self.pos + 1..].starts_with(b"\\u") {
+                                    return self.error();
+                                }
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return self.error();
+                                }
+                                0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00)
+                            } else {
+                                code
+                            };
+                            char::from_u32(code).map_or_else(|| self.error(), Ok)?
+                        }
+                        _ => return self.error(),
+                    };
+                    out.push(escaped);
+                    self.pos += 1;
+                }
+                _ => return self.error(),
+            }
+        }
+    }
+
+    // the four hex digits after a \u, leaving the position on the last one
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self
+            .bytes
+            .get(self.pos + 1..self.pos + 5)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok());
+        match digits {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            None => self.error(),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(|c| matches!(c, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'))
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos])
+            .unwrap()
+            .parse()
+            .map(Json::Number)
+            .or_else(|_| {
+                self.pos = start;
+                self.error()
+            })
+    }
+}
+
+pub(crate) fn parse(text: &str) -> Result<Json, JsonError> {
+    let mut parser = Parser {
+        bytes: text.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.value()?;
+    if parser.pos != text.len() {
+        return parser.error();
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_strings() {
+        for (string, json) in [
+            ("plain", r#""plain""#),
+            (r#"say "hi""#, r#""say \"hi\"""#),
+            (r"C:\images\a.png", r#""C:\\images\\a.png""#),
+            ("a\nb\r\tc", r#""a\nb\r\tc""#),
+            (
+                "\u{0}\u{1}\u{8}\u{c}\u{1f}\u{7f}\u{85}",
+                r#""\u0000\u0001\u0008\u000c\u001f\u007f\u0085""#,
+            ),
+            // everything else is written as it is, JSON text being UTF-8
+            ("café 猫 😀 \u{2028}", "\"café 猫 😀 \u{2028}\""),
+            ("/", r#""/""#),
+        ] {
+            let value = Json::String(string.to_string());
+            assert_eq!(value.to_string(), json);
+            assert_eq!(parse(json), Ok(value));
+        }
+        // object keys are escaped the same way
+        let object = Json::Object(vec![("a\"b".to_string(), Json::Null)]);
+        assert_eq!(object.to_string(), r#"{"a\"b": null}"#);
+        assert_eq!(parse(&object.to_string()), Ok(object));
+    }
+
+    #[test]
+    fn unescapes_strings() {
+        for (json, string) in [
+            (r#""\/\b\f""#, "/\u{8}\u{c}"),
+            (r#""\u00e9\u00E9""#, "éé"),
+            (r#""\ud83d\ude00""#, "😀"),
+            (r#""\uD83D\uDE00x""#, "😀x"),
+        ] {
+            assert_eq!(parse(json), Ok(Json::String(string.to_string())));
+        }
+        for (json, offset) in [
+            (r#""unterminated"#, 13),
+            (r#""\x""#, 2),
+            (r#""\u12""#, 2),
+            (r#""\u12g4""#, 2),
+            // a low surrogate alone, a high one alone, and a high one followed by something else
+            (r#""\udc00""#, 6),
+            (r#""\ud83d""#, 6),
+            (r#""\ud83dx""#, 6),
+            (r#""\ud83d\u0041""#, 12),
+        ] {
+            assert_eq!(parse(json), Err(JsonError { offset }), "{}", json);
+        }
+    }
+
+    #[test]
+    fn values() {
+        let value = parse(r#" { "a" : [1, -2.5e1, true, false, null, {}], "b": [] } "#).unwrap();
+        assert_eq!(
+            value,
+            Json::Object(vec![
+                (
+                    "a".to_string(),
+                    Json::Array(vec![
+                        Json::Number(1.0),
+                        Json::Number(-25.0),
+                        Json::Bool(true),
+                        Json::Bool(false),
+                        Json::Null,
+                        Json::Object(Vec::new()),
+                    ])
+                ),
+                ("b".to_string(), Json::Array(Vec::new())),
+            ])
+        );
+        assert_eq!(value.get("b"), Some(&Json::Array(Vec::new())));
+        assert_eq!(value.get("c"), None);
+        assert_eq!(parse(&value.to_string()), Ok(value));
+        assert_eq!(Json::Number(f64::NAN).to_string(), "null");
+
+        for (json, offset) in [
+            ("", 0),
+            ("[1,]", 3),
+            ("[1 2]", 3),
+            ("{\"a\" 1}", 5),
+            ("1 1", 2),
+            ("-", 0),
+        ] {
+            assert_eq!(parse(json), Err(JsonError { offset }), "{}", json);
+        }
+    }
+}
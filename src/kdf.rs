@@ -0,0 +1,180 @@
+use rand::RngCore;
+
+use crate::sha256::Sha256;
+
+// how many rounds of PBKDF2-HMAC-SHA256 a passphrase goes through, as recommended for it by OWASP
+pub const PASSPHRASE_ITERATIONS: u32 = 600_000;
+pub const SALT_LEN: usize = 16;
+
+// what a key was derived from a passphrase with, stored in the header so it can be derived again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct KdfParams {
+    pub salt: [u8; SALT_LEN],
+    pub iterations: u32,
+}
+
+impl KdfParams {
+    // a fresh random salt, so the same passphrase gives a different key for every image
+    pub fn random() -> Self {
+        let mut salt = [0; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        KdfParams {
+            salt,
+            iterations: PASSPHRASE_ITERATIONS,
+        }
+    }
+
+    pub fn derive_key(&self, passphrase: &str) -> u64 {
+        pbkdf2_key(passphrase.as_bytes(), &self.salt, self.iterations)
+    }
+}
+
+// HMAC-SHA256 with the key already absorbed into the inner and outer hashers,
+// which every PBKDF2 round would otherwise hash again
+pub(crate) struct Hmac {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl Hmac {
+    pub(crate) fn new(key: &[u8]) -> Self {
+        let mut block = [0u8; 64];
+        if key.len() > block.len() {
+            block[..32].copy_from_slice(&Sha256::new().update(key).finalize());
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let mut inner = Sha256::new();
+        inner.update(&block.map(|byte| byte ^ 0x36));
+        let mut outer = Sha256::new();
+        outer.update(&block.map(|byte| byte ^ 0x5c));
+        Hmac { inner, outer }
+    }
+
+    pub(crate) fn mac(&self, parts: &[&[u8]]) -> [u8; 32] {
+        let mut inner = self.start();
+        for part in parts {
+            inner.update(part);
+        }
+        self.finish(&inner)
+    }
+
+    // the inner hasher to feed a message too long to have in memory at once
+    pub(crate) fn start(&self) -> Sha256 {
+        self.inner.clone()
+    }
+
+    pub(crate) fn finish(&self, inner: &Sha256) -> [u8; 32] {
+        self.outer.clone().update(&inner.finalize()).finalize()
+    }
+}
+
+// the first 8 bytes of PBKDF2-HMAC-SHA256
+pub(crate) fn pbkdf2_key(passphrase: &[u8], salt: &[u8], iterations: u32) -> u64 {
+    let block = pbkdf2_block(passphrase, salt, iterations);
+    u64::from_le_bytes(block[..8].try_into().unwrap())
+}
+
+// the first block of PBKDF2-HMAC-SHA256 (RFC 8018), all a key of up to 32 bytes takes
+fn pbkdf2_block(passphrase: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let hmac = Hmac::new(passphrase);
+    let mut u = hmac.mac(&[salt, &1u32.to_be_bytes()]);
+    let mut block = u;
+    for _ in 1..iterations {
+        u = hmac.mac(&[&u]);
+        for (b, u) in block.iter_mut().zip(u) {
+            *b ^= u;
+        }
+    }
+    block
+}
+
+// the key for a passphrase and a salt, with the default number of iterations
+pub fn derive_key(passphrase: &str, salt: &[u8]) -> u64 {
+    pbkdf2_key(passphrase.as_bytes(), salt, PASSPHRASE_ITERATIONS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::to_hex;
+
+    // RFC 4231, test cases 1 to 4, 6 and 7; 5 truncates the output, which nothing here does
+    #[test]
+    fn hmac_vectors() {
+        let long_key = [0xaa; 131];
+        let vectors: [(&[u8], &[u8], &str); 6] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                &[0xaa; 20],
+                &[0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                &[
+                    1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22,
+                    23, 24, 25,
+                ],
+                &[0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+            (
+                &long_key,
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                &long_key,
+                b"This is a test using a larger than block-size key and a larger than block-size \
+                  data. The key needs to be hashed before being used by the HMAC algorithm.",
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, message, mac) in vectors {
+            assert_eq!(to_hex(&Hmac::new(key).mac(&[message])), mac);
+            // the same message given in parts
+            let (start, end) = message.split_at(message.len() / 3);
+            assert_eq!(to_hex(&Hmac::new(key).mac(&[start, end])), mac);
+        }
+    }
+
+    // RFC 7914, section 11, and the 4096 iterations of RFC 6070 with SHA-256 in place of SHA-1;
+    // only the first block is ever taken, so only its 32 bytes are checked
+    #[test]
+    fn pbkdf2_vectors() {
+        let vectors: [(&[u8], &[u8], u32, &str); 3] = [
+            (
+                b"passwd",
+                b"salt",
+                1,
+                "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc",
+            ),
+            (
+                b"Password",
+                b"NaCl",
+                80000,
+                "4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56",
+            ),
+            (
+                b"password",
+                b"salt",
+                4096,
+                "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a",
+            ),
+        ];
+        for (passphrase, salt, iterations, block) in vectors {
+            assert_eq!(to_hex(&pbkdf2_block(passphrase, salt, iterations)), block);
+        }
+        assert_eq!(pbkdf2_key(b"password", b"salt", 4096), 0x41c88892d578e4c5);
+    }
+}
@@ -0,0 +1,382 @@
+use std::{
+    env,
+    error::Error,
+    fmt,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{base64, blake3, ImageEncryptionError};
+
+// a short hash of a key that tells keys apart without revealing them,
+// shown as four groups of four hex digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct KeyFingerprint(pub [u8; 8]);
+
+// checking a guess against a hardened fingerprint takes at least this many hashes,
+// so even small images don't make keys cheap to guess
+pub const MIN_KEY_CHECK_ITERATIONS: u32 = 1 << 16;
+// the key id is stretched through this many hashes whatever the size of the image, so it stays
+// no cheaper to guess against than decrypting images of up to 16 million pixels
+#[cfg(not(test))]
+pub const KEY_ID_ITERATIONS: u32 = 1 << 22;
+// the unit tests encrypt with thousands of keys, which would take minutes to stretch that far
+#[cfg(test)]
+pub const KEY_ID_ITERATIONS: u32 = 1 << 10;
+// a BLAKE3 hash costs more than this many steps of a trial decryption
+// (a random number and a pixel moved to its place in the permutation)
+const STEPS_PER_HASH: u64 = 4;
+
+impl KeyFingerprint {
+    // cheap to compute, for telling keys apart on screen; don't publish it next to ciphertexts,
+    // because guessing keys against it is faster than trying to decrypt
+    pub fn of(key: u64) -> Self {
+        let hash = blake3::Hasher::new()
+            .update(b"image_encryption key fingerprint\0")
+            .update(&key.to_le_bytes())
+            .finalize();
+        KeyFingerprint(hash[..8].try_into().unwrap())
+    }
+
+    // the fingerprint stored in headers, stretched through a chain of hashes
+    // so checking a guessed key against it is as slow as decrypting with it
+    pub fn hardened(key: u64, iterations: u32) -> Self {
+        let mut hash = blake3::Hasher::new()
+            .update(b"image_encryption key check\0")
+            .update(&key.to_le_bytes())
+            .update(&iterations.to_le_bytes())
+            .finalize();
+        for _ in 0..iterations {
+            hash = blake3::Hasher::new().update(&hash).finalize();
+        }
+        KeyFingerprint(hash[..8].try_into().unwrap())
+    }
+
+    // the fingerprint `enc`, `info` and `keygen` show, which is the same for every image the key
+    // encrypts, unlike the key check stretched for the size of each one
+    pub fn id(key: u64) -> Self {
+        // a batch encrypts every file with the same key, which is stretched once
+        static LAST: Mutex<Option<(u64, KeyFingerprint)>> = Mutex::new(None);
+        let mut last = LAST.lock().unwrap_or_else(PoisonError::into_inner);
+        match *last {
+            Some((last_key, id)) if last_key == key => id,
+            _ => {
+                let id = KeyFingerprint::hardened(key, KEY_ID_ITERATIONS);
+                *last = Some((key, id));
+                id
+            }
+        }
+    }
+}
+
+// how long to stretch the fingerprint of a key for an image of this size
+pub fn key_check_iterations(width: u32, height: u32) -> u32 {
+    let steps = width as u64 * height as u64;
+    (steps.div_ceil(STEPS_PER_HASH).min(u32::MAX as u64) as u32).max(MIN_KEY_CHECK_ITERATIONS)
+}
+
+// what a single offline guess at the key of an encrypted image costs, for auditing headers:
+// checking it against the fingerprint takes `check_hashes` hashes (None if there is no fingerprint to check),
+// and a trial decryption takes `decryption_steps` steps, one per pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuessCost {
+    pub check_hashes: Option<u64>,
+    pub decryption_steps: u64,
+}
+
+impl GuessCost {
+    // whether the fingerprint is no shortcut: guessing against it costs at least as much as decrypting
+    pub fn check_is_hardened(&self) -> bool {
+        self.check_hashes
+            .is_none_or(|hashes| hashes * STEPS_PER_HASH >= self.decryption_steps)
+    }
+}
+
+impl fmt::Display for KeyFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, pair) in self.0.chunks(2).enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:02x}{:02x}", pair[0], pair[1])?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyError(pub String);
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid key {:?}, expected a decimal number, 0x followed by up to 16 hex digits, \
+             or base64: followed by 8 base64-encoded bytes",
+            self.0
+        )
+    }
+}
+
+impl Error for KeyError {}
+
+// parse a key as decimal digits, `0x` and hex digits, or `base64:` and 8 bytes of base64;
+// hex and base64 keys are big-endian, so `0x01` and `base64:AAAAAAAAAAE=` are both the key 1
+pub fn parse_key(s: &str) -> Result<u64, KeyError> {
+    let error = || KeyError(s.to_string());
+    let key = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        // from_str_radix would also take a sign, and leading zeros past the 16 digits of a key
+        if hex.is_empty() || hex.len() > 16 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(error());
+        }
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(encoded) = s.strip_prefix("base64:") {
+        base64::decode(encoded)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_be_bytes)
+    } else if !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    };
+    key.ok_or_else(error)
+}
+
+// where a key comes from, so callers can take it from somewhere other than the command line,
+// which other users of the machine can see
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    Key(u64),
+    // in any of the forms `parse_key` takes, e.g. typed in at a prompt
+    Text(String),
+    // the name of an environment variable holding the key as text
+    Env(String),
+}
+
+impl KeySource {
+    pub fn resolve(&self) -> Result<u64, ImageEncryptionError> {
+        match self {
+            &KeySource::Key(key) => Ok(key),
+            KeySource::Text(text) => Ok(parse_key(text.trim())?),
+            KeySource::Env(name) => {
+                let text = env::var(name).map_err(|_| {
+                    ImageEncryptionError::Invalid(format!(
+                        "the environment variable {} isn't set",
+                        name
+                    ))
+                })?;
+                Ok(parse_key(text.trim())?)
+            }
+        }
+    }
+}
+
+impl From<u64> for KeySource {
+    fn from(key: u64) -> Self {
+        KeySource::Key(key)
+    }
+}
+
+// why a key is easy to guess
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyWeakness {
+    // below 2^40, so trying every smaller key takes hours at most
+    Small,
+    // a calendar date or a unix timestamp, in seconds or milliseconds
+    DateLike,
+    // the same few digits or bytes over and over
+    Repetitive,
+    // a passphrase with only this many bits to guess, estimated from its length and the kinds of characters in it
+    ShortPassphrase(u32),
+}
+
+impl fmt::Display for KeyWeakness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyWeakness::Small => write!(
+                f,
+                "the key is a small number, with at most 40 bits to guess"
+            ),
+            KeyWeakness::DateLike => write!(f, "the key looks like a date or a timestamp"),
+            KeyWeakness::Repetitive => write!(f, "the key repeats a short pattern"),
+            KeyWeakness::ShortPassphrase(bits) => write!(
+                f,
+                "the passphrase is short or simple, with around {} bits to guess",
+                bits
+            ),
+        }
+    }
+}
+
+fn is_date(year: u32, month: u32, day: u32) -> bool {
+    (1900..2200).contains(&year) && (1..=12).contains(&month) && (1..=31).contains(&day)
+}
+
+// whether the digits are a date like 20240131, 31012024 or 01312024, optionally followed by a time
+fn looks_like_date(digits: &str) -> bool {
+    let number = |range: std::ops::Range<usize>| digits[range].parse::<u32>().unwrap_or(0);
+    match digits.len() {
+        8 | 10 | 12 | 14 => {
+            is_date(number(0..4), number(4..6), number(6..8))
+                || is_date(number(4..8), number(2..4), number(0..2))
+                || is_date(number(4..8), number(0..2), number(2..4))
+        }
+        _ => false,
+    }
+}
+
+// whether the text is made of a pattern of at most 4 characters repeated at least twice
+fn is_repetitive(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    (1..=4).any(|period| {
+        bytes.len() >= period * 2
+            && bytes
+                .iter()
+                .enumerate()
+                .all(|(i, &c)| c == bytes[i % period])
+    })
+}
+
+// the ways a key is guessable, the kind of keys people type by hand; empty for a good random key
+pub fn key_weakness(key: u64) -> Vec<KeyWeakness> {
+    let decimal = key.to_string();
+    let hex = format!("{:x}", key);
+    let mut weaknesses = Vec::new();
+    if key < 1 << 40 {
+        weaknesses.push(KeyWeakness::Small);
+    }
+    // unix timestamps from 2001 to 2033, in seconds and in milliseconds
+    let timestamp = (1_000_000_000..2_000_000_000).contains(&key)
+        || (1_000_000_000_000..2_000_000_000_000).contains(&key);
+    if timestamp || looks_like_date(&decimal) {
+        weaknesses.push(KeyWeakness::DateLike);
+    }
+    if is_repetitive(&decimal) || is_repetitive(&hex) {
+        weaknesses.push(KeyWeakness::Repetitive);
+    }
+    weaknesses
+}
+
+// passphrases under this many bits are worth a warning even after the key derivation slows guessing down
+const MIN_PASSPHRASE_BITS: u32 = 60;
+
+// the bits it takes to guess a passphrase if every character were picked at random from the kinds
+// of characters it uses; people don't pick them at random, so this is the most it could be
+fn passphrase_bits(passphrase: &str) -> u32 {
+    let has = |kind: fn(&char) -> bool| passphrase.chars().any(|c| kind(&c));
+    let alphabet = [
+        (has(char::is_ascii_lowercase), 26),
+        (has(char::is_ascii_uppercase), 26),
+        (has(char::is_ascii_digit), 10),
+        (has(|c| c.is_ascii_punctuation() || *c == ' '), 33),
+        (has(|c| !c.is_ascii()), 100),
+    ]
+    .iter()
+    .filter(|(used, _)| *used)
+    .map(|(_, size)| size)
+    .sum::<u32>();
+    (passphrase.chars().count() as f64 * (alphabet.max(1) as f64).log2()) as u32
+}
+
+// the ways a passphrase is guessable; empty for a long one
+pub fn passphrase_weakness(passphrase: &str) -> Vec<KeyWeakness> {
+    let mut weaknesses = Vec::new();
+    let bits = passphrase_bits(passphrase);
+    if bits < MIN_PASSPHRASE_BITS {
+        weaknesses.push(KeyWeakness::ShortPassphrase(bits));
+    }
+    if passphrase.bytes().all(|c| c.is_ascii_digit()) && looks_like_date(passphrase) {
+        weaknesses.push(KeyWeakness::DateLike);
+    }
+    if is_repetitive(passphrase) {
+        weaknesses.push(KeyWeakness::Repetitive);
+    }
+    weaknesses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(parse_key("0"), Ok(0));
+        assert_eq!(parse_key("2246800662264969608"), Ok(0x1f2e3d4c5b6a7988));
+        assert_eq!(parse_key("18446744073709551615"), Ok(u64::MAX));
+        assert_eq!(parse_key("0x1f2e3d4c5b6a7988"), Ok(0x1f2e3d4c5b6a7988));
+        assert_eq!(parse_key("0X1F2E3D4C5B6A7988"), Ok(0x1f2e3d4c5b6a7988));
+        assert_eq!(parse_key("0x0000000000000001"), Ok(1));
+        assert_eq!(parse_key("base64:Hy49TFtqeYg="), Ok(0x1f2e3d4c5b6a7988));
+        assert_eq!(parse_key("base64:AAAAAAAAAAA="), Ok(0));
+    }
+
+    #[test]
+    fn rejects_keys() {
+        for text in [
+            "",
+            "+1",
+            "-1",
+            "0x",
+            "0x+1",
+            "0x-1",
+            " 1",
+            "1.0",
+            "0xg",
+            // more than 16 hex digits or more than 64 bits
+            "0x10000000000000000",
+            "0x00000000000000001",
+            "18446744073709551616",
+            // 7 and 9 bytes, and not base64 at all
+            "base64:AAAAAAAAAA==",
+            "base64:AAAAAAAAAAAA",
+            "base64:",
+            "base64:!!!!!!!!!!!=",
+        ] {
+            assert_eq!(parse_key(text), Err(KeyError(text.to_string())), "{}", text);
+        }
+    }
+
+    #[test]
+    fn weak_keys() {
+        use KeyWeakness::*;
+        assert_eq!(key_weakness(0x1f2e3d4c5b6a7988), []);
+        assert_eq!(key_weakness(987654321), [Small]);
+        assert_eq!(key_weakness(1 << 41), []);
+        // dates, year first or last, and with a time after them
+        assert_eq!(key_weakness(20240131), [Small, DateLike]);
+        assert_eq!(key_weakness(31012024), [Small, DateLike]);
+        assert_eq!(key_weakness(12312024), [Small, DateLike]);
+        assert_eq!(key_weakness(20240131235959), [DateLike]);
+        assert_eq!(key_weakness(20241331235959), []);
+        // unix timestamps in seconds and in milliseconds
+        assert_eq!(key_weakness(1_700_000_000), [Small, DateLike]);
+        assert_eq!(key_weakness(1_700_000_000_123), [DateLike]);
+        assert_eq!(key_weakness(2_700_000_000_123), []);
+        // repeated in hex or in decimal
+        assert_eq!(key_weakness(0xabababababababab), [Repetitive]);
+        assert_eq!(key_weakness(0x1234123412341234), [Repetitive]);
+        assert_eq!(key_weakness(1212121212121212121), [Repetitive]);
+        assert_eq!(key_weakness(u64::MAX), [Repetitive]);
+    }
+
+    #[test]
+    fn weak_passphrases() {
+        use KeyWeakness::*;
+        assert_eq!(passphrase_weakness("correct horse battery staple"), []);
+        assert_eq!(passphrase_weakness("hunter2"), [ShortPassphrase(36)]);
+        assert_eq!(passphrase_weakness(""), [ShortPassphrase(0)]);
+        assert_eq!(
+            passphrase_weakness("20240131"),
+            [ShortPassphrase(26), DateLike]
+        );
+        assert_eq!(passphrase_weakness("31-01-2024 is the day"), []);
+        assert_eq!(
+            passphrase_weakness("abcabcabcabcabcabcabcabc"),
+            [Repetitive]
+        );
+        assert_eq!(
+            passphrase_weakness("aaaaaa"),
+            [ShortPassphrase(28), Repetitive]
+        );
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Cursor, Read, Seek, Write},
+    path::Path,
+};
+
+use image::{ColorType, DynamicImage, ImageBuffer, ImageFormat};
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    encoder::{colortype, TiffEncoder},
+    tags::{SampleFormat, Tag},
+};
+
+use crate::{
+    auth, blake3, decrypt_image, encoder::encode_pixels, encrypt_image_with,
+    load_image_from_bytes_with, parse_header, DecryptError, EncryptOptions, Image,
+    ImageEncryptionError, KeyFingerprint, LoadOptions, WriteOptions,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{decode_image, read_with_progress, Phase, Progress};
+
+// a private TIFF tag holding the encryption header of a page, since a file has room for only one trailer
+const HEADER_TAG: u16 = 65117;
+
+// the APP2 segment of a multi-picture (MPO) JPEG that lists where every image of the file is
+const MPF_SIGNATURE: &[u8] = b"MPF\0";
+const TAG_MP_VERSION: u16 = 0xB000;
+const TAG_NUMBER_OF_IMAGES: u16 = 0xB001;
+const TAG_MP_ENTRY: u16 = 0xB002;
+const MP_ENTRY_LEN: usize = 16;
+// the first image of a stereo pair is the primary one; both are of the disparity type
+const MP_PRIMARY: u32 = 0x2000_0000;
+const MP_DISPARITY: u32 = 0x0002_0002;
+
+// the key a layer is encrypted with, so no two layers share a keystream or a permutation;
+// a single layer decrypts with `decrypt_image` and the key of its index, which its header records
+pub fn layer_key(key: u64, index: u32) -> u64 {
+    let hash = blake3::Hasher::new()
+        .update(b"image_encryption layer key\0")
+        .update(&key.to_le_bytes())
+        .update(&(index as u64).to_le_bytes())
+        .finalize();
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+// the layers of an image: the pages of a multi-page TIFF, the views of an MPO stereo pair,
+// or the image itself for any other file
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_layers(path: impl AsRef<Path>) -> Result<Vec<Image>, ImageEncryptionError> {
+    load_layers_with(path, &LoadOptions::default())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_layers_with(
+    path: impl AsRef<Path>,
+    options: &LoadOptions,
+) -> Result<Vec<Image>, ImageEncryptionError> {
+    load_layers_with_progress(path, options, |_| {})
+}
+
+// the same, telling the callback how far reading and decoding the file have got
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_layers_with_progress(
+    path: impl AsRef<Path>,
+    options: &LoadOptions,
+    mut progress: impl FnMut(Progress),
+) -> Result<Vec<Image>, ImageEncryptionError> {
+    let path = path.as_ref();
+    let bytes = read_with_progress(path, &mut progress)?;
+    let total = bytes.len() as u64;
+    progress(Progress::started(Phase::Decode, total));
+    // a single image is loaded like `load_image_with` does, its format guessed from the extension first
+    let single = || decode_image(&bytes, ImageFormat::from_path(path).ok(), options);
+    let layers = match image::guess_format(&bytes) {
+        Ok(ImageFormat::Tiff) if is_multi_page(&bytes) => tiff_pages(&bytes, options)?,
+        Ok(ImageFormat::Jpeg) => match mpo_images(&bytes) {
+            Some(images) if images.len() > 1 => images
+                .into_iter()
+                .map(|bytes| load_image_from_bytes_with(bytes, options))
+                .collect::<Result<_, _>>()?,
+            _ => vec![single()?],
+        },
+        _ => vec![single()?],
+    };
+    progress(Progress::finished(Phase::Decode, total));
+    Ok(layers)
+}
+
+// encrypt every layer on its own, with the same options and the key of its index
+pub fn encrypt_layers(layers: &mut [Image], key: u64, options: &EncryptOptions) {
+    for (index, image) in (0..).zip(layers) {
+        encrypt_image_with(image, layer_key(key, index), options);
+        if let Some(header) = &mut image.header {
+            header.layer_index = Some(index);
+            header.key_id = Some(KeyFingerprint::id(key));
+        }
+        auth::authenticate(image, layer_key(key, index));
+    }
+}
+
+// decrypt every layer, failing on the first one that doesn't authenticate
+pub fn decrypt_layers(layers: &mut [Image], key: u64) -> Result<(), DecryptError> {
+    for (index, image) in (0..).zip(layers) {
+        let index = image
+            .header
+            .as_ref()
+            .and_then(|header| header.layer_index)
+            .unwrap_or(index);
+        decrypt_image(image, layer_key(key, index))?;
+    }
+    Ok(())
+}
+
+// write the layers as an MPO if they are all plaintext JPEGs, or else as a multi-page TIFF,
+// which keeps the ciphertext exactly, with the encryption header of every page in a private tag
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_layers(path: impl AsRef<Path>, layers: &[Image]) -> Result<(), ImageEncryptionError> {
+    if layers.is_empty() {
+        return Err(ImageEncryptionError::Invalid(
+            "no layers to write".to_string(),
+        ));
+    }
+    let mpo = layers
+        .iter()
+        .all(|image| image.format == ImageFormat::Jpeg && image.header.is_none());
+    if mpo {
+        fs::write(path, encode_mpo(layers)?)?;
+        return Ok(());
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut encoder = TiffEncoder::new(&mut writer)?;
+    for image in layers {
+        write_page(&mut encoder, image)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn is_multi_page(bytes: &[u8]) -> bool {
+    Decoder::new(Cursor::new(bytes)).is_ok_and(|decoder| decoder.more_images())
+}
+
+fn tiff_pages(bytes: &[u8], options: &LoadOptions) -> Result<Vec<Image>, ImageEncryptionError> {
+    let mut decoder = Decoder::new(Cursor::new(bytes))?;
+    let mut pages = Vec::new();
+    loop {
+        let (width, height) = decoder.dimensions()?;
+        options.check_dimensions(width, height)?;
+        let header = match decoder.find_tag(Tag::Unknown(HEADER_TAG))? {
+            // the decoder reads a list of bytes as 64-bit values
+            Some(value) => {
+                let bytes = value.into_u64_vec()?.into_iter().map(|byte| byte as u8);
+                parse_header(&bytes.collect::<Vec<_>>())?
+            }
+            None => None,
+        };
+        let (color, pixels) = read_page(&mut decoder)?;
+        pages.push(Image {
+            format: ImageFormat::Tiff,
+            pixels,
+            color,
+            width,
+            height,
+            header,
+            metadata: Vec::new(),
+            jpeg_segments: Vec::new(),
+        });
+
+        if !decoder.more_images() {
+            return Ok(pages);
+        }
+        decoder.next_image()?;
+    }
+}
+
+// the color type and pixels of the current page; 16-bit and float samples are kept as little-endian bytes,
+// like everywhere else
+fn read_page<R: Read + Seek>(
+    decoder: &mut Decoder<R>,
+) -> Result<(ColorType, Vec<u8>), ImageEncryptionError> {
+    let colortype = decoder.colortype()?;
+    let (color, pixels) = match (colortype, decoder.read_image()?) {
+        (tiff::ColorType::Gray(8), DecodingResult::U8(samples)) => (ColorType::L8, samples),
+        (tiff::ColorType::RGB(8), DecodingResult::U8(samples)) => (ColorType::Rgb8, samples),
+        (tiff::ColorType::RGBA(8), DecodingResult::U8(samples)) => (ColorType::Rgba8, samples),
+        (tiff::ColorType::Gray(16), DecodingResult::U16(samples)) => (
+            ColorType::L16,
+            samples.iter().flat_map(|s| s.to_le_bytes()).collect(),
+        ),
+        (tiff::ColorType::RGB(16), DecodingResult::U16(samples)) => (
+            ColorType::Rgb16,
+            samples.iter().flat_map(|s| s.to_le_bytes()).collect(),
+        ),
+        (tiff::ColorType::RGBA(16), DecodingResult::U16(samples)) => (
+            ColorType::Rgba16,
+            samples.iter().flat_map(|s| s.to_le_bytes()).collect(),
+        ),
+        (tiff::ColorType::RGB(32), DecodingResult::F32(samples)) => (
+            ColorType::Rgb32F,
+            samples.iter().flat_map(|s| s.to_le_bytes()).collect(),
+        ),
+        (tiff::ColorType::RGBA(32), DecodingResult::F32(samples)) => (
+            ColorType::Rgba32F,
+            samples.iter().flat_map(|s| s.to_le_bytes()).collect(),
+        ),
+        (color, _) => {
+            return Err(ImageEncryptionError::Unsupported(format!(
+                "unsupported TIFF page color type {:?}",
+                color
+            )))
+        }
+    };
+    Ok((color, pixels))
+}
+
+// whether the TIFF has float samples, which image can't read
+pub(crate) fn is_float_tiff(bytes: &[u8]) -> bool {
+    Decoder::new(Cursor::new(bytes))
+        .ok()
+        .and_then(|mut decoder| decoder.find_tag(Tag::SampleFormat).ok().flatten())
+        .and_then(|value| value.into_u64_vec().ok())
+        .is_some_and(|formats| formats.first() == Some(&(SampleFormat::IEEEFP.to_u16() as u64)))
+}
+
+// the first page of a TIFF with float samples, read with the TIFF decoder itself
+pub(crate) fn float_tiff(
+    bytes: &[u8],
+    options: &LoadOptions,
+) -> Result<DynamicImage, ImageEncryptionError> {
+    let mut decoder = Decoder::new(Cursor::new(bytes))?;
+    let (width, height) = decoder.dimensions()?;
+    options.check_dimensions(width, height)?;
+    let (color, pixels) = read_page(&mut decoder)?;
+    let samples = pixels
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+        .collect();
+    let image = match color {
+        ColorType::Rgb32F => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb32F)
+        }
+        ColorType::Rgba32F => {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba32F)
+        }
+        _ => None,
+    };
+    image.ok_or_else(|| {
+        ImageEncryptionError::Unsupported("unsupported float TIFF layout".to_string())
+    })
+}
+
+fn write_page<W: Write + Seek>(
+    encoder: &mut TiffEncoder<W>,
+    image: &Image,
+) -> Result<(), ImageEncryptionError> {
+    let (width, height) = (image.width, image.height);
+    let header = image.header.as_ref().map(|header| header.to_bytes());
+    let u16s = || {
+        image
+            .pixels
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect::<Vec<_>>()
+    };
+    let f32s = || {
+        image
+            .pixels
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect::<Vec<_>>()
+    };
+    macro_rules! page {
+        ($color:ty, $pixels:expr) => {{
+            let mut page = encoder.new_image::<$color>(width, height)?;
+            if let Some(header) = &header {
+                page.encoder()
+                    .write_tag(Tag::Unknown(HEADER_TAG), &header[..])?;
+            }
+            page.write_data($pixels)?
+        }};
+    }
+    match image.color {
+        ColorType::L8 => page!(colortype::Gray8, &image.pixels),
+        ColorType::L16 => page!(colortype::Gray16, &u16s()),
+        ColorType::Rgb8 => page!(colortype::RGB8, &image.pixels),
+        ColorType::Rgb16 => page!(colortype::RGB16, &u16s()),
+        ColorType::Rgba8 => page!(colortype::RGBA8, &image.pixels),
+        ColorType::Rgba16 => page!(colortype::RGBA16, &u16s()),
+        ColorType::Rgb32F => page!(colortype::RGB32Float, &f32s()),
+        ColorType::Rgba32F => page!(colortype::RGBA32Float, &f32s()),
+        color => return Err(ImageEncryptionError::UnsupportedColor(color)),
+    }
+    Ok(())
+}
+
+fn u16_at(bytes: &[u8], at: usize, big_endian: bool) -> Option<u16> {
+    let pair = bytes.get(at..at + 2)?.try_into().ok()?;
+    Some(if big_endian {
+        u16::from_be_bytes(pair)
+    } else {
+        u16::from_le_bytes(pair)
+    })
+}
+
+fn u32_at(bytes: &[u8], at: usize, big_endian: bool) -> Option<u32> {
+    let quad = bytes.get(at..at + 4)?.try_into().ok()?;
+    Some(if big_endian {
+        u32::from_be_bytes(quad)
+    } else {
+        u32::from_le_bytes(quad)
+    })
+}
+
+// the JPEG files an MPO is made of, as listed by the MP index in the APP2 segment of the first one;
+// None if there is no index
+fn mpo_images(bytes: &[u8]) -> Option<Vec<&[u8]>> {
+    // the segments up to the start of the image data
+    let mut at = 2;
+    let index = loop {
+        if bytes.get(at) != Some(&0xFF) {
+            return None;
+        }
+        let marker = *bytes.get(at + 1)?;
+        let len = u16::from_be_bytes(bytes.get(at + 2..at + 4)?.try_into().ok()?) as usize;
+        if marker == 0xDA {
+            return None;
+        }
+        let data = bytes.get(at + 4..at + 2 + len)?;
+        if marker == 0xE2 && data.starts_with(MPF_SIGNATURE) {
+            break at + 4 + MPF_SIGNATURE.len();
+        }
+        at += 2 + len;
+    };
+
+    // offsets in the index are from the start of its TIFF-style header
+    let mp = &bytes[index..];
+    let big_endian = match mp.get(..4)? {
+        b"MM\0*" => true,
+        b"II*\0" => false,
+        _ => return None,
+    };
+    let ifd = u32_at(mp, 4, big_endian)? as usize;
+    let mut entries = None;
+    for i in 0..u16_at(mp, ifd, big_endian)? as usize {
+        let entry = ifd + 2 + i * 12;
+        if u16_at(mp, entry, big_endian)? == TAG_MP_ENTRY {
+            let len = u32_at(mp, entry + 4, big_endian)? as usize;
+            let offset = u32_at(mp, entry + 8, big_endian)? as usize;
+            entries = Some(mp.get(offset..offset + len)?);
+        }
+    }
+
+    entries?
+        .chunks_exact(MP_ENTRY_LEN)
+        .map(|entry| {
+            let size = u32_at(entry, 4, big_endian)? as usize;
+            // the first image starts at the start of the file, and its offset is 0
+            let start = match u32_at(entry, 8, big_endian)? as usize {
+                0 => 0,
+                offset => index + offset,
+            };
+            bytes.get(start..start + size)
+        })
+        .collect()
+}
+
+// the JPEGs one after another, with an MP index in the first one listing them all
+fn encode_mpo(layers: &[Image]) -> Result<Vec<u8>, ImageEncryptionError> {
+    let options = WriteOptions::default();
+    let jpegs = layers
+        .iter()
+        .map(|image| {
+            let mut bytes = Cursor::new(Vec::new());
+            encode_pixels(
+                &mut bytes,
+                &image.pixels,
+                (image.width, image.height),
+                image.color,
+                ImageFormat::Jpeg,
+                &options,
+            )?;
+            Ok(bytes.into_inner())
+        })
+        .collect::<Result<Vec<_>, ImageEncryptionError>>()?;
+
+    // the index is a little endian TIFF header and a single IFD with three entries, then the MP entries
+    let ifd_len = 2 + 3 * 12 + 4;
+    let entries_offset = 8 + ifd_len as u32;
+    let segment_len =
+        2 + MPF_SIGNATURE.len() + entries_offset as usize + MP_ENTRY_LEN * jpegs.len();
+    // where the index starts in the first image: after SOI, the APP2 marker, its length and the signature
+    let index_start = 2 + 2 + 2 + MPF_SIGNATURE.len();
+
+    let mut sizes = jpegs.iter().map(Vec::len).collect::<Vec<_>>();
+    sizes[0] += 2 + segment_len;
+    let mut mp_entries = Vec::new();
+    let mut start = 0;
+    for (i, &size) in sizes.iter().enumerate() {
+        let attributes = if i == 0 {
+            MP_PRIMARY | MP_DISPARITY
+        } else {
+            MP_DISPARITY
+        };
+        let offset = if i == 0 { 0 } else { start - index_start };
+        mp_entries.extend_from_slice(&attributes.to_le_bytes());
+        mp_entries.extend_from_slice(&(size as u32).to_le_bytes());
+        mp_entries.extend_from_slice(&(offset as u32).to_le_bytes());
+        mp_entries.extend_from_slice(&[0; 4]);
+        start += size;
+    }
+
+    let mut segment = vec![0xFF, 0xE2];
+    segment.extend_from_slice(&(segment_len as u16).to_be_bytes());
+    segment.extend_from_slice(MPF_SIGNATURE);
+    segment.extend_from_slice(b"II*\0");
+    segment.extend_from_slice(&8u32.to_le_bytes());
+    segment.extend_from_slice(&3u16.to_le_bytes());
+    let mut entry = |tag: u16, kind: u16, count: u32, value: [u8; 4]| {
+        segment.extend_from_slice(&tag.to_le_bytes());
+        segment.extend_from_slice(&kind.to_le_bytes());
+        segment.extend_from_slice(&count.to_le_bytes());
+        segment.extend_from_slice(&value);
+    };
+    // 7 is UNDEFINED and 4 is LONG
+    entry(TAG_MP_VERSION, 7, 4, *b"0100");
+    entry(
+        TAG_NUMBER_OF_IMAGES,
+        4,
+        1,
+        (jpegs.len() as u32).to_le_bytes(),
+    );
+    entry(
+        TAG_MP_ENTRY,
+        7,
+        mp_entries.len() as u32,
+        entries_offset.to_le_bytes(),
+    );
+    segment.extend_from_slice(&0u32.to_le_bytes());
+    segment.extend_from_slice(&mp_entries);
+
+    let mut mpo = Vec::with_capacity(sizes.iter().sum());
+    mpo.extend_from_slice(&jpegs[0][..2]);
+    mpo.extend_from_slice(&segment);
+    mpo.extend_from_slice(&jpegs[0][2..]);
+    for jpeg in &jpegs[1..] {
+        mpo.extend_from_slice(jpeg);
+    }
+    Ok(mpo)
+}
@@ -1,157 +1,2510 @@
-use std::{error::Error, fs::File, io::BufWriter, path::Path};
+// the APIs that work on files and paths are left out of wasm builds, which run in a browser without a filesystem;
+// the helpers and imports only they use are unused there
+#![cfg_attr(target_arch = "wasm32", allow(dead_code, unused_imports))]
+
+use std::{
+    fmt, fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+};
 
 use image::{
-    codecs::jpeg,
     error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind},
-    io::Reader,
-    ColorType, ImageEncoder, ImageFormat, ImageResult,
+    io::{Limits, Reader},
+    ColorType, DynamicImage, ImageBuffer, ImageError, ImageFormat,
 };
-use rand::{rngs::SmallRng, seq::SliceRandom, Rng, SeedableRng};
+use rand::RngCore;
+use rayon::prelude::*;
 
+mod audit;
+mod auth;
+mod banner;
+mod base64;
+#[cfg(not(target_arch = "wasm32"))]
+mod batch;
+mod blake3;
+mod chacha20;
+mod compare;
+mod contact_sheet;
+#[cfg(not(target_arch = "wasm32"))]
+mod context_menu;
+mod digest;
+mod disguise;
+mod encoder;
+mod error;
+mod estimate;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod fingerprint;
+mod font;
+mod frames;
+#[cfg(feature = "arbitrary")]
+mod fuzz;
+mod geometry;
+mod header;
+mod jpeg_container;
+mod jpeg_dct;
+mod json;
+mod kdf;
+mod key;
+mod layers;
+mod limits;
+mod loss;
+#[cfg(not(target_arch = "wasm32"))]
+mod manifest;
+mod metadata;
+mod noise;
+mod permutation;
+mod pipeline;
+mod png_store;
+mod qr;
+mod raw_frame;
+mod redact;
+mod rekey;
+mod report;
+mod rng;
+mod scramble;
+mod self_test;
+mod sha256;
+mod shape;
+mod share;
+mod stego;
+#[cfg(feature = "proptest")]
+mod strategies;
+mod stream;
+mod tags;
+mod terminal;
+#[cfg(test)]
+mod test_util;
+#[cfg(not(target_arch = "wasm32"))]
+mod thumbnail_cache;
+#[cfg(not(target_arch = "wasm32"))]
+mod upload;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod watermark;
+
+pub use audit::{audit, AuditResult};
+pub use auth::{DecryptError, AUTH_TAG_LEN};
+pub use banner::{Banner, BannerEdge};
+#[cfg(not(target_arch = "wasm32"))]
+pub use batch::{
+    process_directory, process_directory_with_progress, DirectoryOptions, DirectoryProgress,
+    FileOutcome, Mode,
+};
+pub use compare::{compare_images, Difference};
+pub use contact_sheet::{contact_sheet, thumbnail};
+#[cfg(not(target_arch = "wasm32"))]
+pub use context_menu::{register_context_menu, unregister_context_menu};
+pub use digest::{SealedDigest, DIGEST_LEN};
+pub use encoder::{PngCompression, PngFilter, TiffCompression, WriteOptions};
+pub use error::ImageEncryptionError;
+pub use estimate::estimate_output_size;
+#[cfg(not(target_arch = "wasm32"))]
+pub use estimate::estimate_working_set;
+pub use fingerprint::{embed_fingerprint, fingerprint_detected, fingerprint_score};
+pub use frames::{decrypt_animation, encrypt_animation, frame_key, AnimatedImage};
+#[cfg(not(target_arch = "wasm32"))]
+pub use frames::{is_animated, load_animation, write_animation};
+pub use geometry::{
+    parse_geometry, parse_regions_json, regions_json, Geometry, GeometryError, Length, Rect, Region,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use header::read_header;
+pub use header::{parse_header, split_header, Cipher, EncryptionHeader, HeaderError, SampleOrder};
+pub use jpeg_dct::{decrypt_jpeg_dct, encrypt_jpeg_dct, DctError};
+pub use json::JsonError;
+pub use kdf::{derive_key, KdfParams, PASSPHRASE_ITERATIONS};
+pub use key::{
+    key_check_iterations, key_weakness, parse_key, passphrase_weakness, GuessCost, KeyError,
+    KeyFingerprint, KeySource, KeyWeakness, KEY_ID_ITERATIONS, MIN_KEY_CHECK_ITERATIONS,
+};
+pub use layers::{decrypt_layers, encrypt_layers, layer_key};
+#[cfg(not(target_arch = "wasm32"))]
+pub use layers::{load_layers, load_layers_with, load_layers_with_progress, write_layers};
+pub use limits::{LimitError, LoadOptions};
+pub use loss::{check_exact, information_loss, CycleStep, Downgrade, InformationLoss};
+#[cfg(not(target_arch = "wasm32"))]
+pub use manifest::{add_manifest_entry, file_sha256, verify_manifest, ManifestStatus};
+pub use metadata::MetadataKind;
+pub use noise::{NoiseShape, ShapedNoise};
+pub use permutation::PermutationUnit;
+pub use pipeline::{Pipeline, PipelineError, PipelineStep};
+pub use qr::{QrCode, QrError};
+pub use raw_frame::{
+    decrypt_raw_frame, encrypt_raw_frame, read_raw_frame, write_raw_frame, FrameDesc,
+    RawFrameError, RowOrder,
+};
+pub use redact::{redact_image, Redaction};
+pub use rekey::{migrate_legacy, rekey_image, rekey_jpeg_dct, RekeyError};
+pub use report::{OperationReport, OperationWarning, Phase, Progress};
+pub use scramble::{ScrambleAlgorithm, Scrambler};
+pub use self_test::{run_cross_vectors, SelfTestResult};
+pub use shape::{PixelShape, Shape};
+pub use share::{combine_images, split_image, ShareError, ShareInfo, MAX_SHARES, SHARE_SET_LEN};
+pub use stego::{embed, extract, stego_capacity, StegoError};
+#[cfg(feature = "proptest")]
+pub use strategies::{arb_color, arb_image, arb_image_of, arb_key, MAX_SIDE};
+pub use stream::{decrypt_region, decrypt_stream, encrypt_stream, BAND_PIXELS};
+pub use tags::SearchTags;
+pub use terminal::{terminal_graphics, GraphicsProtocol};
+#[cfg(not(target_arch = "wasm32"))]
+pub use thumbnail_cache::{
+    thumbnail_path, update_thumbnail_cache, CacheEntry, CacheStatus, THUMBNAIL_DIR,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use upload::{upload, UploadError, UploadOptions};
+pub use watermark::{Watermark, WatermarkContent, WatermarkPosition};
+
+use chacha20::{ChaCha20Rng, NONCE_LEN};
+use digest::PassHashes;
+use permutation::{Indices, Permutation};
+use report::CipherSteps;
+use rng::{SeekableRng, Xoshiro256PlusPlus};
+
+#[derive(Clone)]
 pub struct Image {
     format: ImageFormat,
     pixels: Vec<u8>,
     color: ColorType,
     width: u32,
     height: u32,
+    header: Option<EncryptionHeader>,
+    // metadata found in the source file, which isn't written back out
+    metadata: Vec<MetadataKind>,
+    // the metadata segments of a JPEG source file, which are only written back out
+    // around the placeholder of `EncryptOptions::jpeg_container`
+    jpeg_segments: Vec<Vec<u8>>,
+}
+
+// the pixel buffer is summarized, dumping it would be unreadable
+impl fmt::Debug for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Image")
+            .field("format", &self.format)
+            .field("color", &self.color)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("header", &self.header)
+            .field("metadata", &self.metadata)
+            .field("pixels", &format_args!("[{} bytes]", self.pixels.len()))
+            .finish()
+    }
+}
+
+impl Image {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn color(&self) -> ColorType {
+        self.color
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    pub fn header(&self) -> Option<&EncryptionHeader> {
+        self.header.as_ref()
+    }
+
+    // replace the header, e.g. to decrypt with regions kept next to the image instead of in it
+    pub fn set_header(&mut self, header: Option<EncryptionHeader>) {
+        self.header = header;
+    }
+
+    // the pixels as a `DynamicImage`, for the operations that need the typed pixel buffers of image
+    fn to_dynamic(&self) -> Option<DynamicImage> {
+        let (width, height) = (self.width, self.height);
+        let bytes = self.pixels.clone();
+        let u16s = || {
+            self.pixels
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>()
+        };
+        let f32s = || {
+            self.pixels
+                .chunks_exact(4)
+                .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect::<Vec<_>>()
+        };
+
+        match self.color {
+            ColorType::L8 => {
+                ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageLuma8)
+            }
+            ColorType::La8 => {
+                ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageLumaA8)
+            }
+            ColorType::Rgb8 => {
+                ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageRgb8)
+            }
+            ColorType::Rgba8 => {
+                ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageRgba8)
+            }
+            ColorType::L16 => {
+                ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageLuma16)
+            }
+            ColorType::La16 => {
+                ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageLumaA16)
+            }
+            ColorType::Rgb16 => {
+                ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageRgb16)
+            }
+            ColorType::Rgba16 => {
+                ImageBuffer::from_raw(width, height, u16s()).map(DynamicImage::ImageRgba16)
+            }
+            ColorType::Rgb32F => {
+                ImageBuffer::from_raw(width, height, f32s()).map(DynamicImage::ImageRgb32F)
+            }
+            ColorType::Rgba32F => {
+                ImageBuffer::from_raw(width, height, f32s()).map(DynamicImage::ImageRgba32F)
+            }
+            _ => None,
+        }
+    }
+
+    // replace the pixels with the ones of a `DynamicImage`, keeping the format and header
+    fn set_dynamic(&mut self, image: DynamicImage) {
+        self.width = image.width();
+        self.height = image.height();
+        self.color = image.color();
+        self.pixels = dynamic_bytes(image);
+    }
+
+    // the pixels inside a rectangle, row by row
+    fn crop_pixels(&self, rect: Rect) -> Vec<u8> {
+        let pixel_size = self.color.bytes_per_pixel() as usize;
+        let row_len = rect.width as usize * pixel_size;
+        let mut pixels = Vec::with_capacity(row_len * rect.height as usize);
+        for y in rect.y..rect.y + rect.height {
+            let start = (y as usize * self.width as usize + rect.x as usize) * pixel_size;
+            pixels.extend_from_slice(&self.pixels[start..start + row_len]);
+        }
+        pixels
+    }
+
+    // the inverse of `crop_pixels`, put the pixels back inside the rectangle
+    fn paste_pixels(&mut self, rect: Rect, pixels: &[u8]) {
+        let pixel_size = self.color.bytes_per_pixel() as usize;
+        let row_len = rect.width as usize * pixel_size;
+        for (row, y) in pixels.chunks_exact(row_len).zip(rect.y..) {
+            let start = (y as usize * self.width as usize + rect.x as usize) * pixel_size;
+            self.pixels[start..start + row_len].copy_from_slice(row);
+        }
+    }
+
+    // run an operation on the part of the image inside a rectangle as if it were an image of its own
+    fn with_rect(&mut self, rect: Rect, f: impl FnOnce(&mut Image)) {
+        let mut region = Image {
+            format: self.format,
+            pixels: self.crop_pixels(rect),
+            color: self.color,
+            width: rect.width,
+            height: rect.height,
+            header: None,
+            metadata: Vec::new(),
+            jpeg_segments: Vec::new(),
+        };
+        f(&mut region);
+        self.paste_pixels(rect, &region.pixels);
+    }
+
+    // the same for the pixels inside any shape; rectangles keep their rows and columns,
+    // the pixels of other shapes are lined up in a single row in row-major order
+    fn with_shape(&mut self, shape: &PixelShape, f: impl FnOnce(&mut Image)) {
+        if let PixelShape::Rect(rect) = shape {
+            return self.with_rect(*rect, f);
+        }
+        let Some((rect, mask)) = shape.mask(self.width, self.height) else {
+            return;
+        };
+
+        let pixel_size = self.color.bytes_per_pixel() as usize;
+        let mut pixels = self.crop_pixels(rect);
+        let selected = pixels
+            .chunks_exact(pixel_size)
+            .zip(&mask)
+            .filter(|(_, &inside)| inside)
+            .flat_map(|(pixel, _)| pixel)
+            .copied()
+            .collect::<Vec<_>>();
+        let mut region = Image {
+            format: self.format,
+            width: (selected.len() / pixel_size) as u32,
+            height: 1,
+            pixels: selected,
+            color: self.color,
+            header: None,
+            metadata: Vec::new(),
+            jpeg_segments: Vec::new(),
+        };
+        f(&mut region);
+
+        let mut changed = region.pixels.chunks_exact(pixel_size);
+        for (pixel, _) in pixels
+            .chunks_exact_mut(pixel_size)
+            .zip(&mask)
+            .filter(|(_, &inside)| inside)
+        {
+            pixel.copy_from_slice(changed.next().unwrap());
+        }
+        self.paste_pixels(rect, &pixels);
+    }
+
+    // the same for some channels of every pixel, given by their positions, as an image of only those channels;
+    // its color type is any with pixels of their size, since the cipher only goes by the size of a pixel
+    fn with_channels(&mut self, channels: &[usize], f: impl FnOnce(&mut Image)) {
+        let pixel_size = self.color.bytes_per_pixel() as usize;
+        let sample_size = pixel_size / self.color.channel_count() as usize;
+        let Some(color) = color_of_size(channels.len() * sample_size) else {
+            return;
+        };
+        let selected = self
+            .pixels
+            .chunks_exact(pixel_size)
+            .flat_map(|pixel| {
+                channels
+                    .iter()
+                    .flat_map(move |&c| &pixel[c * sample_size..][..sample_size])
+            })
+            .copied()
+            .collect();
+        let mut part = Image {
+            format: self.format,
+            width: self.width,
+            height: self.height,
+            pixels: selected,
+            color,
+            header: None,
+            metadata: Vec::new(),
+            jpeg_segments: Vec::new(),
+        };
+        f(&mut part);
+
+        let mut changed = part.pixels.chunks_exact(sample_size);
+        for pixel in self.pixels.chunks_exact_mut(pixel_size) {
+            for &c in channels {
+                pixel[c * sample_size..][..sample_size].copy_from_slice(changed.next().unwrap());
+            }
+        }
+    }
+
+    // convert the pixels to another color type; color types image can't convert are left as they are
+    fn convert_color(&mut self, color: ColorType) {
+        if self.color == color {
+            return;
+        }
+        let Some(mut image) = self.to_dynamic() else {
+            return;
+        };
+        // the image crate panics converting NaN to integer samples, which damaged ciphertext decrypts to
+        if let DynamicImage::ImageRgb32F(buffer) = &mut image {
+            buffer
+                .iter_mut()
+                .filter(|s| !s.is_finite())
+                .for_each(|s| *s = 0.0);
+        } else if let DynamicImage::ImageRgba32F(buffer) = &mut image {
+            buffer
+                .iter_mut()
+                .filter(|s| !s.is_finite())
+                .for_each(|s| *s = 0.0);
+        }
+
+        let converted = match color {
+            ColorType::L8 => DynamicImage::ImageLuma8(image.to_luma8()),
+            ColorType::La8 => DynamicImage::ImageLumaA8(image.to_luma_alpha8()),
+            ColorType::Rgb8 => DynamicImage::ImageRgb8(image.to_rgb8()),
+            ColorType::Rgba8 => DynamicImage::ImageRgba8(image.to_rgba8()),
+            ColorType::L16 => DynamicImage::ImageLuma16(image.to_luma16()),
+            ColorType::La16 => DynamicImage::ImageLumaA16(image.to_luma_alpha16()),
+            ColorType::Rgb16 => DynamicImage::ImageRgb16(image.to_rgb16()),
+            ColorType::Rgba16 => DynamicImage::ImageRgba16(image.to_rgba16()),
+            ColorType::Rgb32F => DynamicImage::ImageRgb32F(image.to_rgb32f()),
+            ColorType::Rgba32F => DynamicImage::ImageRgba32F(image.to_rgba32f()),
+            _ => return,
+        };
+        self.set_dynamic(converted);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_image(path: impl AsRef<Path>) -> Result<Image, ImageEncryptionError> {
+    load_image_with(path, &LoadOptions::default())
+}
+
+// a reader for image data in a known format, with decoding limits
+fn format_reader(data: &[u8], format: ImageFormat, limits: Limits) -> Reader<Cursor<&[u8]>> {
+    let mut reader = Reader::new(Cursor::new(data));
+    reader.set_format(format);
+    reader.limits(limits);
+    reader
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_image_with(
+    path: impl AsRef<Path>,
+    options: &LoadOptions,
+) -> Result<Image, ImageEncryptionError> {
+    load_image_with_progress(path, options, |_| {})
+}
+
+// the same, telling the callback how far reading and decoding the file have got
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_image_with_progress(
+    path: impl AsRef<Path>,
+    options: &LoadOptions,
+    mut progress: impl FnMut(Progress),
+) -> Result<Image, ImageEncryptionError> {
+    let path = path.as_ref();
+    let bytes = read_with_progress(path, &mut progress)?;
+    let total = bytes.len() as u64;
+    progress(Progress::started(Phase::Decode, total));
+    // guess the format from the extension first, then from the contents, like `Reader::open` does
+    let img = decode_image(&bytes, ImageFormat::from_path(path).ok(), options)?;
+    progress(Progress::finished(Phase::Decode, total));
+    Ok(img)
+}
+
+// how much of a file is read or written between two calls of a progress callback
+#[cfg(not(target_arch = "wasm32"))]
+const PROGRESS_BLOCK: usize = 1 << 20;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn read_with_progress(
+    path: &Path,
+    progress: &mut dyn FnMut(Progress),
+) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut file = fs::File::open(path)?;
+    let total = file.metadata()?.len();
+    let mut bytes = Vec::with_capacity(total as usize);
+    progress(Progress::started(Phase::Read, total));
+    while (&mut file)
+        .take(PROGRESS_BLOCK as u64)
+        .read_to_end(&mut bytes)?
+        > 0
+    {
+        // the file may have grown since its size was taken
+        progress(Progress {
+            phase: Phase::Read,
+            done: bytes.len() as u64,
+            total: total.max(bytes.len() as u64),
+        });
+    }
+    progress(Progress::finished(Phase::Read, bytes.len() as u64));
+    Ok(bytes)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn write_with_progress(
+    path: &Path,
+    bytes: &[u8],
+    progress: &mut dyn FnMut(Progress),
+) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut file = fs::File::create(path)?;
+    let total = bytes.len() as u64;
+    progress(Progress::started(Phase::Write, total));
+    let mut done = 0;
+    for block in bytes.chunks(PROGRESS_BLOCK) {
+        file.write_all(block)?;
+        done += block.len() as u64;
+        progress(Progress {
+            phase: Phase::Write,
+            done,
+            total,
+        });
+    }
+    Ok(())
+}
+
+// the same for an image file that is already in memory, e.g. an upload, with the format guessed from the contents
+pub fn load_image_from_bytes(bytes: &[u8]) -> Result<Image, ImageEncryptionError> {
+    load_image_from_bytes_with(bytes, &LoadOptions::default())
 }
 
-pub fn load_image(path: impl AsRef<Path>) -> Result<Image, Box<dyn Error>> {
-    let reader = Reader::open(path)?.with_guessed_format()?;
+pub fn load_image_from_bytes_with(
+    bytes: &[u8],
+    options: &LoadOptions,
+) -> Result<Image, ImageEncryptionError> {
+    decode_image(bytes, None, options)
+}
+
+// the pixels of a `DynamicImage` with 16-bit and float samples little-endian, the byte order the cipher
+// works in on every machine, where `into_bytes` gives them in the machine's own
+pub(crate) fn dynamic_bytes(image: DynamicImage) -> Vec<u8> {
+    let color = image.color();
+    let mut bytes = image.into_bytes();
+    if cfg!(target_endian = "big") {
+        swap_samples(color, &mut bytes);
+    }
+    bytes
+}
+
+// reverse the bytes of every sample wider than a byte, turning little-endian samples into big-endian ones
+// and back
+pub(crate) fn swap_samples(color: ColorType, bytes: &mut [u8]) {
+    let sample_len = (color.bytes_per_pixel() / color.channel_count()) as usize;
+    if sample_len > 1 {
+        for sample in bytes.chunks_exact_mut(sample_len) {
+            sample.reverse();
+        }
+    }
+}
+
+// the data is all in memory, so a decoder that fails to read it has a truncated file, not a failing disk
+fn decoding_error(err: ImageError) -> ImageEncryptionError {
+    match err {
+        ImageError::IoError(err) => {
+            ImageEncryptionError::Malformed(format!("truncated or damaged image data: {}", err))
+        }
+        err => err.into(),
+    }
+}
+
+fn decode_image(
+    bytes: &[u8],
+    format: Option<ImageFormat>,
+    options: &LoadOptions,
+) -> Result<Image, ImageEncryptionError> {
+    let (source, header) = split_header(bytes)?;
+    // a JPEG container is read as the image it carries
+    let payload = jpeg_container::payload(source);
+    let data = payload.as_deref().unwrap_or(source);
+
+    let mut reader = Reader::new(Cursor::new(data));
+    if let Some(format) = format {
+        reader.set_format(format);
+    }
+    if payload.is_some() {
+        reader.set_format(ImageFormat::Png);
+    }
+    let reader = reader.with_guessed_format()?;
     let format = reader.format().ok_or_else(|| {
-        UnsupportedError::from_format_and_kind(
+        ImageError::Unsupported(UnsupportedError::from_format_and_kind(
             ImageFormatHint::Unknown,
             UnsupportedErrorKind::Format(ImageFormatHint::Unknown),
-        )
+        ))
     })?;
 
-    let image = reader.decode()?;
+    let image = if format == ImageFormat::Tiff && layers::is_float_tiff(data) {
+        layers::float_tiff(data, options)?
+    } else {
+        // only the image header is read to get the dimensions, so oversized images are rejected before allocating
+        // anything, and before a decoding thread is started that the timeout couldn't stop
+        if *options != LoadOptions::default() {
+            let (width, height) = reader.into_dimensions().map_err(decoding_error)?;
+            options.check_decoded_size(width, height, format)?;
+        }
+
+        match options.timeout {
+            Some(timeout) => {
+                let (sender, receiver) = mpsc::channel();
+                let data = data.to_vec();
+                let limits = options.decoder_limits();
+                thread::spawn(move || sender.send(format_reader(&data, format, limits).decode()));
+                receiver
+                    .recv_timeout(timeout)
+                    .map_err(|_| LimitError::Timeout(timeout))?
+                    .map_err(decoding_error)?
+            }
+            None => format_reader(data, format, options.decoder_limits())
+                .decode()
+                .map_err(decoding_error)?,
+        }
+    };
+
+    // a disguise is read as the encrypted file hidden in it
+    if let (None, DynamicImage::ImageRgb8(cover)) = (&header, &image) {
+        let hidden = disguise::reveal(cover.as_raw())
+            .filter(|file| matches!(split_header(file), Ok((_, Some(_)))));
+        if let Some(file) = hidden {
+            return decode_image(&file, None, options);
+        }
+    }
+
+    // a container is the JPEG it looks like, with its own metadata
+    let source_format = if payload.is_some() {
+        ImageFormat::Jpeg
+    } else {
+        format
+    };
+    let metadata = metadata::find_metadata(source, source_format);
+    let jpeg_segments = if source_format == ImageFormat::Jpeg {
+        jpeg_container::metadata_segments(source)
+    } else {
+        Vec::new()
+    };
     Ok(Image {
-        format,
+        format: source_format,
         height: image.height(),
         width: image.width(),
         color: image.color(),
-        pixels: image.into_bytes(),
+        pixels: dynamic_bytes(image),
+        header,
+        metadata,
+        jpeg_segments,
     })
 }
 
-pub fn write_image(path: impl AsRef<Path>, img: Image) -> ImageResult<()> {
-    // must handle Jpeg case on its own because the default quality is too low
-    if img.format == ImageFormat::Jpeg {
-        let writer = &mut BufWriter::new(File::create(path)?);
-        jpeg::JpegEncoder::new_with_quality(writer, 100).write_image(
-            &img.pixels,
-            img.width,
-            img.height,
-            img.color,
-        )
+// encode the image in its format, with the encryption header after the image data if it has one
+pub fn encode_image(img: &Image) -> Result<Vec<u8>, ImageEncryptionError> {
+    encode_image_with(img, &WriteOptions::default())
+}
+
+pub fn encode_image_with(
+    img: &Image,
+    options: &WriteOptions,
+) -> Result<Vec<u8>, ImageEncryptionError> {
+    let options = &options.for_image(img);
+    let mut bytes = Cursor::new(Vec::new());
+
+    let container = img
+        .header
+        .as_ref()
+        .is_some_and(|header| header.jpeg_container);
+    if container && img.format == ImageFormat::Jpeg {
+        bytes = Cursor::new(jpeg_container::encode_container(img, options)?);
     } else {
-        image::save_buffer_with_format(
-            path,
+        encoder::encode_pixels(
+            &mut bytes,
             &img.pixels,
-            img.width,
-            img.height,
+            (img.width, img.height),
             img.color,
             img.format,
-        )
+            options,
+        )?;
     }
+
+    // the header goes after the encoded image, where decoders don't look
+    let mut bytes = bytes.into_inner();
+    if let Some(header) = &img.header {
+        bytes.extend_from_slice(&header.to_bytes());
+        if header.disguised {
+            bytes = disguise::cover(&bytes)?;
+        }
+    }
+    Ok(bytes)
 }
 
-// get the byte of rank i from a u32
+// encode the image like `encode_image_with`, with the report of it for the write functions to add to
+pub(crate) fn encode_reported(
+    img: &Image,
+    options: &WriteOptions,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<(Vec<u8>, OperationReport), ImageEncryptionError> {
+    let total = img.pixels.len() as u64;
+    let started = report::start_phase(progress, Phase::Encode, total);
+    let bytes = encode_image_with(img, options)?;
+    let mut report = OperationReport {
+        bytes_in: img.pixels.len() as u64,
+        bytes_out: bytes.len() as u64,
+        ..Default::default()
+    };
+    // only a JPEG container carries the metadata of the source over, and keeps the ciphertext exact
+    let container = img
+        .header
+        .as_ref()
+        .is_some_and(|header| header.jpeg_container)
+        && img.format == ImageFormat::Jpeg;
+    if img.header.is_some() && !container && lossless_format(img.format) != img.format {
+        report
+            .warnings
+            .push(OperationWarning::LossyCiphertext(img.format));
+    }
+    if !container {
+        let dropped = img.metadata.iter().copied();
+        report
+            .warnings
+            .extend(dropped.map(OperationWarning::DroppedMetadata));
+    }
+    report.end_phase(progress, Phase::Encode, total, started);
+    Ok((bytes, report))
+}
+
+// the file `write_image` would write, kept in memory
+pub fn write_image_to_vec(img: &Image) -> Result<Vec<u8>, ImageEncryptionError> {
+    encode_image(img)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_image(
+    path: impl AsRef<Path>,
+    img: Image,
+) -> Result<OperationReport, ImageEncryptionError> {
+    write_image_with(path, img, &WriteOptions::default())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_image_with(
+    path: impl AsRef<Path>,
+    img: Image,
+    options: &WriteOptions,
+) -> Result<OperationReport, ImageEncryptionError> {
+    write_image_with_progress(path, img, options, |_| {})
+}
+
+// the same, telling the callback how far encoding and writing the file have got
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_image_with_progress(
+    path: impl AsRef<Path>,
+    img: Image,
+    options: &WriteOptions,
+    mut progress: impl FnMut(Progress),
+) -> Result<OperationReport, ImageEncryptionError> {
+    let (bytes, mut report) = encode_reported(&img, options, &mut progress)?;
+    let started = report::start();
+    write_with_progress(path.as_ref(), &bytes, &mut progress)?;
+    report.time(Phase::Write, started);
+    Ok(report)
+}
+
+// where atomic writes put the file before it is renamed into place
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TempLocation {
+    // a hidden file next to the destination, which can always be renamed over it
+    #[default]
+    NextToOutput,
+    // a file in this directory, e.g. a tmpfs mount; if it isn't on the same filesystem as the destination
+    // the file can't be renamed, and the destination is written directly instead
+    Dir(PathBuf),
+    // no temporary file at all: the file is encoded in memory and written directly,
+    // so nothing but the destination ever touches the disk, but a crash can leave it half written
+    Memory,
+}
+
+// write the image to a temporary file next to the destination and rename it into place,
+// so the destination always holds either the old file or the complete new one
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_image_atomic(
+    path: impl AsRef<Path>,
+    img: Image,
+) -> Result<OperationReport, ImageEncryptionError> {
+    write_image_atomic_with(path, img, &TempLocation::default())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_image_atomic_with(
+    path: impl AsRef<Path>,
+    img: Image,
+    temp: &TempLocation,
+) -> Result<OperationReport, ImageEncryptionError> {
+    let (bytes, mut report) = encode_reported(&img, &WriteOptions::default(), &mut |_| {})?;
+    let started = report::start();
+    write_file_atomic_with(path, &bytes, temp)?;
+    report.time(Phase::Write, started);
+    Ok(report)
+}
+
+// the same for a file that is already encoded
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_file_atomic_with(
+    path: impl AsRef<Path>,
+    bytes: &[u8],
+    temp: &TempLocation,
+) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp = match temp {
+        TempLocation::NextToOutput => path.with_file_name(temp_name),
+        TempLocation::Dir(dir) => dir.join(temp_name),
+        TempLocation::Memory => return fs::write(path, bytes),
+    };
+
+    let result = fs::write(&temp, bytes).and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    match result {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => fs::write(path, bytes),
+        result => result,
+    }
+}
+
+// lowercase hex representation of a byte slice
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// the BLAKE3 hash of the image contents (dimensions and pixels), as a hex string
+pub fn content_hash(img: &Image) -> String {
+    let hash = content_hasher(img.width, img.height)
+        .update(&img.pixels)
+        .finalize();
+    to_hex(&hash)
+}
+
+// the hasher of `content_hash` before any pixel
+fn content_hasher(width: u32, height: u32) -> blake3::Hasher {
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update(&width.to_le_bytes())
+        .update(&height.to_le_bytes());
+    hasher
+}
+
+// a file name made of the content hash and the extension of the image format,
+// so identical images always end up under the same name
+pub fn content_addressed_name(img: &Image) -> String {
+    format!("{}.{}", content_hash(img), img.format.extensions_str()[0])
+}
+
+// in convergent mode the image is encrypted with a key derived from the user's key and the plaintext,
+// so the same image encrypted with the same key always gives the same ciphertext
+fn convergent_key(img: &Image, key: u64) -> u64 {
+    let hash = blake3::Hasher::new()
+        .update(b"image_encryption convergent key\0")
+        .update(&key.to_le_bytes())
+        .update(&img.width.to_le_bytes())
+        .update(&img.height.to_le_bytes())
+        .update(&img.pixels)
+        .finalize();
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+// the derived key is stored in the header masked with this, so only the user's key can recover it
+fn convergent_key_mask(key: u64) -> u64 {
+    let hash = blake3::Hasher::new()
+        .update(b"image_encryption convergent key mask\0")
+        .update(&key.to_le_bytes())
+        .finalize();
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+// every region gets its own key, so same-sized regions don't share a keystream
+fn region_key(key: u64, index: usize) -> u64 {
+    let hash = blake3::Hasher::new()
+        .update(b"image_encryption region key\0")
+        .update(&key.to_le_bytes())
+        .update(&(index as u64).to_le_bytes())
+        .finalize();
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+// every round after the first gets its own key, so the rounds don't undo or repeat each other;
+// the first keeps the key, so a single round encrypts the way it always has
+fn round_key(key: u64, round: u32) -> u64 {
+    if round == 0 {
+        return key;
+    }
+    let hash = blake3::Hasher::new()
+        .update(b"image_encryption round key\0")
+        .update(&key.to_le_bytes())
+        .update(&round.to_le_bytes())
+        .finalize();
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+// every chunk of a parallel encryption gets its own key, so the chunks' XOR chains don't depend on each other
+fn chunk_key(key: u64, index: usize) -> u64 {
+    let hash = blake3::Hasher::new()
+        .update(b"image_encryption chunk key\0")
+        .update(&key.to_le_bytes())
+        .update(&(index as u64).to_le_bytes())
+        .finalize();
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+// get the byte of rank i from a u32, always in little-endian order so the keystream is the same on every platform
+// pixels wider than four bytes (16-bit and float samples) stretch the word through a mixing function,
+// so no two bytes of a pixel share a keystream byte
 fn byte(num: u32, i: usize) -> u8 {
-    num.to_le_bytes()[i]
+    if i < 4 {
+        return num.to_le_bytes()[i];
+    }
+    let mut x = num ^ ((i / 4) as u32).wrapping_mul(0x9E37_79B9);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x85EB_CA6B);
+    x ^= x >> 13;
+    x = x.wrapping_mul(0xC2B2_AE35);
+    x ^= x >> 16;
+    x.to_le_bytes()[i % 4]
 }
 
-pub fn encrypt_image(img: &mut Image, key: u64) {
-    let mut rng = SmallRng::seed_from_u64(key);
-    // this value is used in the first step of encrypting the pixels, so it must be obtained before other RNG calls
-    let start = rng.gen::<u32>();
+#[derive(Debug, Clone, Default)]
+pub struct EncryptOptions {
+    // preprocessing run on the plaintext before anything else, so the ciphertext holds what it gives
+    pub pipeline: Pipeline,
+    // convert the pixels to this color type before encrypting,
+    // so the cipher only ever sees one layout; the original color type is recorded in the header
+    pub normalize: Option<ColorType>,
+    // a visible mark applied to the plaintext right before encryption, so decrypted copies are traceable
+    pub watermark: Option<Watermark>,
+    // an invisible mark keyed by this recipient id, embedded in the plaintext right before encryption
+    pub fingerprint: Option<String>,
+    // derive the cipher key from the plaintext and the key, so identical images encrypted with the same key
+    // give identical ciphertexts that storage backends can deduplicate; this reveals which images are equal
+    pub convergent: bool,
+    // what the permutation moves around, recorded in the header
+    pub permutation_unit: PermutationUnit,
+    // only encrypt the parts of the image inside these regions, leaving the rest as it is;
+    // the regions are resolved against the image size and recorded in the header
+    pub regions: Vec<Region>,
+    // a plaintext strip added along an edge of the ciphertext, recorded in the header
+    pub banner: Option<Banner>,
+    // write encrypted JPEGs as a gray placeholder JPEG that keeps the metadata of the source,
+    // with the ciphertext losslessly encoded inside it, so tools that index metadata can still read it;
+    // ignored for other formats
+    pub jpeg_container: bool,
+    // the salt and iterations the key was derived from a passphrase with, recorded in the header
+    // so the key can be derived again from the passphrase
+    pub kdf: Option<KdfParams>,
+    // what generates the keystream and the permutation, recorded in the header;
+    // `Cipher::Dct` doesn't work on pixels, see `encrypt_jpeg_dct`, and is taken as `Cipher::Legacy`
+    pub cipher: Cipher,
+    // tags to find the image by with its key, recorded in the header as tokens, see `SearchTags`
+    pub tags: Vec<String>,
+    // chain the pixels in independently keyed chunks of `CHUNK_LEN` pixels instead of all in one,
+    // so encrypting and decrypting run on all cores; recorded in the header
+    pub parallel: bool,
+    // write the ciphertext of a JPEG as a JPEG, which changes its pixels so it never decrypts exactly;
+    // otherwise it is written as a PNG, and decrypting writes it back as a JPEG
+    pub keep_lossy_format: bool,
+    // only move whole pixels around with the permutation, leaving every value as it is, so the output
+    // has exactly the color histogram of the input, for analytics that need the histogram but not the picture;
+    // this hides where the colors are but not which colors there are, and overrides `parallel`
+    pub permute_only: bool,
+    // map the ciphertext onto samples of this distribution, so it looks like grain rather than uniform static;
+    // the image grows by a few rows to hold them, and the shaping is undone before decrypting;
+    // only the whole image is shaped, so this is ignored when only regions or channels are encrypted
+    pub noise: Option<NoiseShape>,
+    // experimental: hide the encrypted file in the low bits of a generated picture of clouds, so the output
+    // looks like an ordinary photo; the picture is a PNG, which loading sees through to the file inside.
+    // The ciphertext is then always written losslessly, so this overrides `keep_lossy_format` and `jpeg_container`
+    pub disguise: bool,
+    // channels to leave exactly as they are, like alpha for compositing; the others are permuted and chained
+    // as if they were the whole pixel. Channels the image doesn't have are ignored, and so is the whole list
+    // if it would leave nothing to encrypt
+    pub skip_channels: Vec<Channel>,
+    // the scheme the pixels are scrambled with; only the XOR chain can be split into parallel chunks,
+    // so the others ignore `parallel`, and `permute_only` overrides them all
+    pub scramble_algorithm: ScrambleAlgorithm,
+    // how many times the permutation and diffusion run over the pixels, each round with a key of its own
+    // derived from the key; recorded in the header when more than one. 0 is taken as 1, and more than
+    // `MAX_ROUNDS` as that many
+    pub rounds: u32,
+    // work out the `content_hash` of the ciphertext into `OperationReport::content_hash`, for naming
+    // the output by it; when the encryption pass can, it hashes the pixels as it writes them
+    pub hash_ciphertext: bool,
+}
+
+// the most rounds an image can be encrypted with, so a damaged header can't make decrypting run forever
+pub const MAX_ROUNDS: u32 = 64;
+
+// a color type with pixels of this many bytes, whatever their channels hold
+fn color_of_size(bytes: usize) -> Option<ColorType> {
+    [
+        ColorType::L8,
+        ColorType::La8,
+        ColorType::Rgb8,
+        ColorType::Rgba8,
+        ColorType::Rgb16,
+        ColorType::Rgba16,
+        ColorType::Rgb32F,
+        ColorType::Rgba32F,
+    ]
+    .into_iter()
+    .find(|color| color.bytes_per_pixel() as usize == bytes)
+}
+
+// a channel of a pixel by what it holds, see `EncryptOptions::skip_channels`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+    Luma,
+    Alpha,
+}
+
+impl Channel {
+    // where the channel is in a pixel of this color type, if it has one
+    pub fn index(self, color: ColorType) -> Option<usize> {
+        match (self, color.has_color()) {
+            (Channel::Red, true) => Some(0),
+            (Channel::Green, true) => Some(1),
+            (Channel::Blue, true) => Some(2),
+            (Channel::Luma, false) => Some(0),
+            (Channel::Alpha, _) if color.has_alpha() => Some(color.channel_count() as usize - 1),
+            _ => None,
+        }
+    }
+
+    // the channel at this position in a pixel of this color type
+    pub fn at(index: usize, color: ColorType) -> Option<Self> {
+        [
+            Channel::Red,
+            Channel::Green,
+            Channel::Blue,
+            Channel::Luma,
+            Channel::Alpha,
+        ]
+        .into_iter()
+        .find(|channel| channel.index(color) == Some(index))
+    }
+}
+
+// the format the ciphertext of an image in this format is written in, unless the lossy format is kept
+pub(crate) fn lossless_format(format: ImageFormat) -> ImageFormat {
+    match format {
+        ImageFormat::Jpeg => ImageFormat::Png,
+        format => format,
+    }
+}
+
+// the path an image in this format is written to: the given one, unless its extension names another format,
+// e.g. an encrypted photo.jpg is written as photo.png, and decrypting that gives photo.jpg again
+#[cfg(not(target_arch = "wasm32"))]
+pub fn path_for_format(path: &Path, format: ImageFormat) -> PathBuf {
+    match ImageFormat::from_path(path) {
+        Ok(named) if named != format => path.with_extension(format.extensions_str()[0]),
+        _ => path.to_path_buf(),
+    }
+}
+
+// how many pixels make up a chunk of a parallel encryption
+pub const CHUNK_LEN: u32 = 1 << 16;
+
+pub fn encrypt_image(img: &mut Image, key: u64) -> OperationReport {
+    encrypt_image_with(img, key, &EncryptOptions::default())
+}
+
+// scramble the pixels with the Arnold cat map alone, which has no key but the number of iterations;
+// an image that isn't square is padded to one, and the output has to be written losslessly
+pub fn encrypt_arnold(img: &mut Image, iterations: u32) -> OperationReport {
+    let options = EncryptOptions {
+        scramble_algorithm: ScrambleAlgorithm::ArnoldCat,
+        ..Default::default()
+    };
+    encrypt_image_with(img, iterations as u64, &options)
+}
+
+// encrypt only the rectangle of this (x, y, width, height), clipped to the image, and leave the rest as it is;
+// `decrypt_image` reads the rectangle from the header and decrypts just that
+pub fn encrypt_region(
+    img: &mut Image,
+    key: u64,
+    (x, y, width, height): (u32, u32, u32, u32),
+) -> Result<OperationReport, GeometryError> {
+    let geometry = Geometry::from(Rect {
+        x,
+        y,
+        width,
+        height,
+    });
+    // a rectangle outside the image would leave no region, and the whole image would be encrypted
+    if geometry.resolve(img.width, img.height).is_none() {
+        return Err(GeometryError::Empty(geometry.to_string()));
+    }
+    let options = EncryptOptions {
+        regions: vec![Region::from(Shape::Rect(geometry))],
+        ..Default::default()
+    };
+    Ok(encrypt_image_with(img, key, &options))
+}
+
+pub fn encrypt_image_with(img: &mut Image, key: u64, options: &EncryptOptions) -> OperationReport {
+    encrypt_image_with_progress(img, key, options, |_| {})
+}
+
+// the same, telling the callback as every phase of the encryption starts and ends
+pub fn encrypt_image_with_progress(
+    img: &mut Image,
+    key: u64,
+    options: &EncryptOptions,
+    mut progress: impl FnMut(Progress),
+) -> OperationReport {
+    let progress = &mut progress;
+    let total = img.pixels.len() as u64;
+    let mut report = OperationReport {
+        bytes_in: total,
+        ..Default::default()
+    };
+    let started = report::start_phase(progress, Phase::Prepare, total);
+    for step in options.pipeline.apply(img) {
+        report
+            .warnings
+            .push(OperationWarning::StepSkipped(step.to_string()));
+    }
+    // when nothing changes the pixels before a single XOR chain runs over the whole image, that pass
+    // hashes them on its way, rather than multi-hundred-megabyte images being read again just for hashes
+    let single_pass = options.watermark.is_none()
+        && options.fingerprint.is_none()
+        && !options.convergent
+        && options.normalize.is_none_or(|color| color == img.color)
+        && options.regions.is_empty()
+        && options.skip_channels.is_empty()
+        && options.rounds <= 1
+        && !options.permute_only
+        && !options.parallel
+        && options.scramble_algorithm == ScrambleAlgorithm::XorChain;
+    // the ciphertext is only hashed on the way if nothing changes it afterwards
+    let mut hashes = single_pass.then(|| {
+        let reshaped = options.noise.is_some() || options.banner.is_some();
+        PassHashes::new(img, options.hash_ciphertext && !reshaped)
+    });
+    // the digest is of the image as it came in, so copies watermarked for different recipients still match
+    let plaintext_digest =
+        (!single_pass).then(|| SealedDigest::seal(digest::plaintext_digest(img), key));
+    if let Some(watermark) = &options.watermark {
+        watermark::apply_watermark(img, watermark);
+    }
+    if let Some(recipient) = &options.fingerprint {
+        embed_fingerprint(img, recipient);
+    }
+
+    let cipher = match options.cipher {
+        Cipher::ChaCha20 => Cipher::ChaCha20,
+        Cipher::Legacy | Cipher::Dct => Cipher::Legacy,
+    };
+    let iterations = key_check_iterations(img.width, img.height);
+    let mut header = EncryptionHeader {
+        cipher: Some(cipher),
+        original_format: Some(img.format),
+        dimensions: Some((img.width, img.height)),
+        permutation_unit: Some(options.permutation_unit),
+        key_fingerprint: Some(KeyFingerprint::hardened(key, iterations)),
+        key_check_iterations: Some(iterations),
+        key_id: Some(KeyFingerprint::id(key)),
+        jpeg_container: options.jpeg_container
+            && img.format == ImageFormat::Jpeg
+            && !options.disguise,
+        kdf: options.kdf,
+        plaintext_digest,
+        search_tags: (!options.tags.is_empty()).then(|| SearchTags::seal(&options.tags, key)),
+        chunk_len: (options.parallel
+            && !options.permute_only
+            && options.scramble_algorithm == ScrambleAlgorithm::XorChain)
+            .then_some(CHUNK_LEN),
+        scramble_algorithm: (!options.permute_only
+            && options.scramble_algorithm != ScrambleAlgorithm::XorChain)
+            .then_some(options.scramble_algorithm),
+        permute_only: options.permute_only,
+        disguised: options.disguise,
+        rounds: (options.rounds > 1).then_some(options.rounds.min(MAX_ROUNDS)),
+        ..Default::default()
+    };
+    report.cipher = header.cipher;
+    if let Some(color) = options.normalize {
+        if img.color != color {
+            header.original_color = Some(img.color);
+            img.convert_color(color);
+            report.warnings.push(OperationWarning::ColorConverted {
+                from: header.original_color.unwrap_or(color),
+                to: img.color,
+            });
+        }
+    }
+    if img.color.bytes_per_pixel() > img.color.channel_count() {
+        header.sample_order = Some(SampleOrder::LittleEndian);
+    }
+
+    let cipher_key = if options.convergent {
+        let derived = convergent_key(img, key);
+        header.convergent_key = Some(derived ^ convergent_key_mask(key));
+        derived
+    } else {
+        key
+    };
+    // a convergent key is already unique to the plaintext, and a random nonce would make
+    // equal images encrypt differently
+    if cipher == Cipher::ChaCha20 {
+        header.nonce = Some(if options.convergent {
+            [0; NONCE_LEN]
+        } else {
+            rand::random()
+        });
+    }
+    let keystream = Keystream::of(&header);
+
+    // regions that fall outside the image are skipped, and if none is left the whole image is encrypted;
+    // the Arnold cat map skips them all, as it only runs on the whole image padded to a square
+    let squared = header.scramble_algorithm == Some(ScrambleAlgorithm::ArnoldCat);
+    for region in &options.regions {
+        match region.shape.resolve(img.width, img.height) {
+            Some(shape) if !squared => header.regions.push(shape),
+            _ => report
+                .warnings
+                .push(OperationWarning::RegionSkipped(region.shape.to_string())),
+        }
+    }
+    // the channels are those of the color type the image is encrypted in
+    let channel_count = img.color.channel_count() as usize;
+    let mut skipped = Vec::new();
+    for &channel in &options.skip_channels {
+        match channel.index(img.color) {
+            Some(index) => skipped.push(index),
+            None => report
+                .warnings
+                .push(OperationWarning::NoSuchChannel(channel)),
+        }
+    }
+    skipped.sort_unstable();
+    skipped.dedup();
+    if skipped.len() < channel_count {
+        header.skipped_channels = skipped.iter().map(|&c| c as u8).collect();
+        if !skipped.is_empty() {
+            let channels = skipped
+                .iter()
+                .filter_map(|&index| Channel::at(index, img.color))
+                .collect();
+            report
+                .warnings
+                .push(OperationWarning::ChannelsPassedThrough(channels));
+        }
+    } else {
+        report.warnings.push(OperationWarning::ChannelsNotSkipped);
+    }
+    let kept = kept_channels(channel_count, &header.skipped_channels);
+    // padded before the channels are taken apart, so they all get the same square
+    if squared {
+        scramble::pad_to_square(img);
+    }
+
+    let unit = options.permutation_unit;
+    let scrambler = options
+        .scramble_algorithm
+        .with(keystream, unit, header.chunk_len);
+    let rounds = header.rounds.unwrap_or(1);
+    let crypt = |img: &mut Image, key| {
+        for round in 0..rounds {
+            let key = round_key(key, round);
+            if options.permute_only {
+                permute_pixels(img, key, keystream, unit)
+            } else {
+                scrambler.scramble(img, key)
+            }
+        }
+    };
+    let encrypt = |img: &mut Image, key| match &kept {
+        Some(kept) => img.with_channels(kept, |part| crypt(part, key)),
+        None => crypt(img, key),
+    };
+    report.end_phase(progress, Phase::Prepare, total, started);
+    let started = report::start_phase(progress, Phase::Encrypt, total);
+    if let Some(hashes) = &mut hashes {
+        let steps = encrypt_whole(img, cipher_key, keystream, unit, Some(hashes));
+        header.plaintext_digest = Some(SealedDigest::seal(hashes.digest(), key));
+        report.steps = steps.phases();
+    } else {
+        if header.regions.is_empty() {
+            encrypt(img, cipher_key);
+        }
+        for (i, shape) in header.regions.iter().enumerate() {
+            img.with_shape(shape, |region| encrypt(region, region_key(cipher_key, i)));
+        }
+        if !options.permute_only {
+            report.steps = scrambler.steps();
+        }
+    }
+    report.end_phase(progress, Phase::Encrypt, total, started);
+
+    let started = report::start_phase(progress, Phase::Finish, total);
+    let whole = header.regions.is_empty() && header.skipped_channels.is_empty();
+    match options.noise {
+        Some(shape) if whole => header.noise = Some(noise::shape_noise(img, shape)),
+        Some(_) => report.warnings.push(OperationWarning::NoiseNotShaped),
+        None => {}
+    }
+
+    if let Some(banner) = &options.banner {
+        header.reserved = banner::add_banner(img, banner);
+        if header.reserved.is_none() {
+            report.warnings.push(OperationWarning::BannerNotAdded);
+        }
+    }
+    // the original format is in the header, for decryption to write the image back in
+    if (!options.keep_lossy_format || options.disguise) && !header.jpeg_container {
+        let format = lossless_format(img.format);
+        if format != img.format {
+            report.warnings.push(OperationWarning::FormatChanged {
+                from: img.format,
+                to: format,
+            });
+        }
+        img.format = format;
+    }
+    img.header = Some(header);
+    auth::authenticate(img, key);
+    if options.hash_ciphertext {
+        let hashed = hashes.and_then(|hashes| hashes.content_hash());
+        report.content_hash = Some(hashed.unwrap_or_else(|| content_hash(img)));
+    }
+    report.end_phase(progress, Phase::Finish, total, started);
+    report.bytes_out = img.pixels.len() as u64;
+    report
+}
+
+// the positions of the channels that are encrypted, or None if they all are
+fn kept_channels(channel_count: usize, skipped: &[u8]) -> Option<Vec<usize>> {
+    (!skipped.is_empty()).then(|| {
+        (0..channel_count)
+            .filter(|&c| !skipped.contains(&(c as u8)))
+            .collect()
+    })
+}
+
+// the generator the cipher state is drawn from
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Keystream {
+    Legacy,
+    ChaCha20([u8; NONCE_LEN]),
+}
+
+impl Keystream {
+    // images without a nonce can't have been encrypted with ChaCha20 by this version, so a missing one
+    // is taken as all zeros rather than failing
+    pub(crate) fn of(header: &EncryptionHeader) -> Self {
+        match header.cipher {
+            Some(Cipher::ChaCha20) => Keystream::ChaCha20(header.nonce.unwrap_or_default()),
+            _ => Keystream::Legacy,
+        }
+    }
+}
+
+// derive everything the cipher needs from the key: the initial value, one random number per pixel and the pixel permutation
+fn cipher_state(
+    key: u64,
+    keystream: Keystream,
+    width: u32,
+    height: u32,
+    unit: PermutationUnit,
+    steps: &mut CipherSteps,
+) -> (u32, Vec<u32>, Permutation) {
+    match keystream {
+        Keystream::Legacy => cipher_state_from(
+            &mut Xoshiro256PlusPlus::seed_from_u64(key),
+            width,
+            height,
+            unit,
+            steps,
+        ),
+        Keystream::ChaCha20(nonce) => cipher_state_from(
+            &mut ChaCha20Rng::from_key(key, nonce),
+            width,
+            height,
+            unit,
+            steps,
+        ),
+    }
+}
+
+fn cipher_state_from(
+    rng: &mut impl RngCore,
+    width: u32,
+    height: u32,
+    unit: PermutationUnit,
+    steps: &mut CipherSteps,
+) -> (u32, Vec<u32>, Permutation) {
+    let started = report::start();
+    let (start, rand_nums) = keystream_from(rng, (width * height) as usize);
+    steps.keystream += report::elapsed(started);
+    let started = report::start();
+    let permutation = permutation::permutation(unit, width, height, rng);
+    steps.permutation += report::elapsed(started);
+    (start, rand_nums, permutation)
+}
+
+// the initial value and the permutation of `cipher_state`, skipping over the random numbers
+// in between, for a decryption that draws them a part at a time with `keystream_at`
+fn seek_state(
+    key: u64,
+    keystream: Keystream,
+    width: u32,
+    height: u32,
+    unit: PermutationUnit,
+    steps: &mut CipherSteps,
+) -> (u32, Permutation) {
+    match keystream {
+        Keystream::Legacy => seek_state_from(
+            &mut Xoshiro256PlusPlus::seed_from_u64(key),
+            width,
+            height,
+            unit,
+            steps,
+        ),
+        Keystream::ChaCha20(nonce) => seek_state_from(
+            &mut ChaCha20Rng::from_key(key, nonce),
+            width,
+            height,
+            unit,
+            steps,
+        ),
+    }
+}
+
+fn seek_state_from(
+    rng: &mut impl SeekableRng,
+    width: u32,
+    height: u32,
+    unit: PermutationUnit,
+    steps: &mut CipherSteps,
+) -> (u32, Permutation) {
+    let started = report::start();
+    let start = rng.next_u32();
+    rng.skip_u32(width as u64 * height as u64);
+    steps.keystream += report::elapsed(started);
+    let started = report::start();
+    let permutation = permutation::permutation(unit, width, height, rng);
+    steps.permutation += report::elapsed(started);
+    (start, permutation)
+}
+
+// `len` of the random numbers of `cipher_state`, from the one for the pixel at `offset` on
+fn keystream_at(key: u64, keystream: Keystream, offset: u64, len: usize) -> Vec<u32> {
+    let numbers_at = |rng: &mut dyn SeekableRng| {
+        // after the initial value
+        rng.skip_u32(1 + offset);
+        (0..len).map(|_| rng.next_u32()).collect()
+    };
+    match keystream {
+        Keystream::Legacy => numbers_at(&mut Xoshiro256PlusPlus::seed_from_u64(key)),
+        Keystream::ChaCha20(nonce) => numbers_at(&mut ChaCha20Rng::from_key(key, nonce)),
+    }
+}
 
-    let dim = (img.width * img.height) as usize;
-    let channels = img.color.channel_count() as usize;
+fn keystream_from(rng: &mut impl RngCore, len: usize) -> (u32, Vec<u32>) {
+    // this value is used in the first step of encrypting the pixels, so it must be obtained before other RNG calls
+    let start = rng.next_u32();
 
-    let mut rand_nums = Vec::<u32>::with_capacity(dim);
+    let mut rand_nums = Vec::<u32>::with_capacity(len);
     for _ in 0..rand_nums.capacity() {
-        rand_nums.push(rng.gen());
+        rand_nums.push(rng.next_u32());
     }
 
-    let mut permutation = (0..dim as u32).collect::<Vec<u32>>();
-    permutation.shuffle(&mut rng);
+    (start, rand_nums)
+}
 
-    // permute the pixels of the buffer based on the above permutation
-    let mut pixels_perm = Vec::with_capacity(channels * dim);
-    for perm in permutation {
-        for c in 0..channels {
-            pixels_perm.push(img.pixels[channels * perm as usize + c]);
+// the initial value and random numbers of one chunk of a parallel encryption
+fn chunk_keystream(key: u64, keystream: Keystream, len: usize) -> (u32, Vec<u32>) {
+    match keystream {
+        Keystream::Legacy => keystream_from(&mut Xoshiro256PlusPlus::seed_from_u64(key), len),
+        Keystream::ChaCha20(nonce) => keystream_from(&mut ChaCha20Rng::from_key(key, nonce), len),
+    }
+}
+
+// the permutation of a parallel encryption, which still spans the whole image so pixels move across chunks
+fn chunked_permutation(
+    key: u64,
+    keystream: Keystream,
+    width: u32,
+    height: u32,
+    unit: PermutationUnit,
+) -> Permutation {
+    match keystream {
+        Keystream::Legacy => permutation::permutation(
+            unit,
+            width,
+            height,
+            &mut Xoshiro256PlusPlus::seed_from_u64(key),
+        ),
+        Keystream::ChaCha20(nonce) => {
+            permutation::permutation(unit, width, height, &mut ChaCha20Rng::from_key(key, nonce))
         }
     }
+}
 
-    // encrypt the first set of bytes by doing some XORs
-    let mut enc_pixels = Vec::<u8>::with_capacity(channels * dim);
-    for c in 0..channels {
-        enc_pixels.push(byte(start, c) ^ pixels_perm[c] ^ byte(rand_nums[0], c));
+pub(crate) fn encrypt_pixels(
+    img: &mut Image,
+    key: u64,
+    keystream: Keystream,
+    unit: PermutationUnit,
+    chunk_len: Option<u32>,
+) -> CipherSteps {
+    // the chain works on bytes, so every byte of a 16-bit or float sample is chained as a channel of its own
+    let channels = img.color.bytes_per_pixel() as usize;
+    let mut steps = CipherSteps::default();
+    if let Some(chunk_len) = chunk_len {
+        let started = report::start();
+        let permutation = chunked_permutation(key, keystream, img.width, img.height, unit);
+        steps.permutation = report::elapsed(started);
+        // every chunk gathers its pixels through its part of the permutation and chains them on its own
+        let (len, chunk_len) = (permutation.len(), chunk_len.max(1) as usize);
+        let chunk_steps = Mutex::new(CipherSteps::default());
+        let started = report::start();
+        img.pixels = (0..len.div_ceil(chunk_len))
+            .into_par_iter()
+            .flat_map_iter(|i| {
+                let permutation = permutation.range(i * chunk_len..len.min((i + 1) * chunk_len));
+                let mut steps = CipherSteps::default();
+                let started = report::start();
+                let (start, rand_nums) =
+                    chunk_keystream(chunk_key(key, i), keystream, permutation.len());
+                steps.keystream = report::elapsed(started);
+                let started = report::start();
+                let pixels = encrypt_chain(&img.pixels, channels, start, &rand_nums, permutation);
+                steps.diffusion = report::elapsed(started);
+                add_chunk_steps(&chunk_steps, steps);
+                pixels
+            })
+            .collect();
+        return steps.add(split_parallel(&chunk_steps, report::elapsed(started)));
     }
 
-    // encrypt each pixel based on the previous one
-    for i in 1..dim {
+    encrypt_whole(img, key, keystream, unit, None)
+}
+
+// how many pixels the hashed pass chains before feeding them to the hashes, so what they read is still in cache
+const HASH_BLOCK: usize = 1 << 14;
+
+// the XOR chain over the whole image in one go; with hashes, they are fed the plaintext and
+// the ciphertext a block at a time as the chain runs, instead of reading the image again afterwards
+fn encrypt_whole(
+    img: &mut Image,
+    key: u64,
+    keystream: Keystream,
+    unit: PermutationUnit,
+    mut hashes: Option<&mut PassHashes>,
+) -> CipherSteps {
+    let channels = img.color.bytes_per_pixel() as usize;
+    let mut steps = CipherSteps::default();
+    let (start, rand_nums, permutation) =
+        cipher_state(key, keystream, img.width, img.height, unit, &mut steps);
+    let started = report::start();
+    let block = match hashes {
+        Some(_) => HASH_BLOCK,
+        None => rand_nums.len().max(1),
+    };
+    let mut enc_pixels = Vec::with_capacity(img.pixels.len());
+    let mut prev = (0..channels).map(|c| byte(start, c)).collect::<Vec<_>>();
+    for (i, rand_nums) in rand_nums.chunks(block).enumerate() {
+        let from = enc_pixels.len();
+        let run = permutation.range(i * block..i * block + rand_nums.len());
+        chain(&img.pixels, &prev, rand_nums, run, &mut enc_pixels);
+        prev.copy_from_slice(&enc_pixels[enc_pixels.len() - channels..]);
+        if let Some(hashes) = &mut hashes {
+            hashes.update(&img.pixels[from..enc_pixels.len()], &enc_pixels[from..]);
+        }
+    }
+    img.pixels = enc_pixels;
+    steps.diffusion = report::elapsed(started);
+    steps
+}
+
+fn add_chunk_steps(total: &Mutex<CipherSteps>, steps: CipherSteps) {
+    let mut total = total.lock().unwrap_or_else(|err| err.into_inner());
+    *total = total.add(steps);
+}
+
+// the chunks of a parallel encryption overlap, so the time they take is split between their steps
+// in proportion to the time they spent in each
+fn split_parallel(chunk_steps: &Mutex<CipherSteps>, elapsed: Duration) -> CipherSteps {
+    let chunk_steps = *chunk_steps.lock().unwrap_or_else(|err| err.into_inner());
+    let busy = (chunk_steps.keystream + chunk_steps.diffusion).as_secs_f64();
+    let keystream = match busy {
+        busy if busy > 0.0 => elapsed.mul_f64(chunk_steps.keystream.as_secs_f64() / busy),
+        _ => Duration::ZERO,
+    };
+    CipherSteps {
+        keystream,
+        diffusion: elapsed.saturating_sub(keystream),
+        ..Default::default()
+    }
+}
+
+fn encrypt_chain(
+    pixels: &[u8],
+    channels: usize,
+    start: u32,
+    rand_nums: &[u32],
+    permutation: Indices,
+) -> Vec<u8> {
+    let mut enc_pixels = Vec::with_capacity(channels * permutation.len());
+    let prev = (0..channels).map(|c| byte(start, c)).collect::<Vec<_>>();
+    chain(pixels, &prev, rand_nums, permutation, &mut enc_pixels);
+    enc_pixels
+}
+
+// chain a run of pixels onto the end of `enc_pixels`; `prev` is the pixel before the run,
+// or the bytes of the initial value at the start of a chain
+fn chain(
+    pixels: &[u8],
+    prev: &[u8],
+    rand_nums: &[u32],
+    permutation: impl Iterator<Item = u32>,
+    enc_pixels: &mut Vec<u8>,
+) {
+    // monomorphize the hot loop over the usual channel counts, so the inner channel loop is unrolled
+    match *prev {
+        [prev] => encrypt_single_channel(pixels, prev, rand_nums, permutation, enc_pixels),
+        [a, b] => encrypt_channels(pixels, [a, b], rand_nums, permutation, enc_pixels),
+        [a, b, c] => encrypt_channels(pixels, [a, b, c], rand_nums, permutation, enc_pixels),
+        [a, b, c, d] => encrypt_channels(pixels, [a, b, c, d], rand_nums, permutation, enc_pixels),
+        _ => encrypt_dynamic(pixels, prev, rand_nums, permutation, enc_pixels),
+    }
+}
+
+fn encrypt_dynamic(
+    pixels: &[u8],
+    prev: &[u8],
+    rand_nums: &[u32],
+    permutation: impl Iterator<Item = u32>,
+    enc_pixels: &mut Vec<u8>,
+) {
+    let channels = prev.len();
+    let mut prev = prev.to_vec();
+    // encrypt each pixel of the permutation based on the previous one
+    for (perm, &rand_num) in permutation.zip(rand_nums) {
+        let pixel = &pixels[channels * perm as usize..][..channels];
         for c in 0..channels {
-            enc_pixels.push(
-                enc_pixels[channels * (i - 1) + c]
-                    ^ pixels_perm[channels * i + c]
-                    ^ byte(rand_nums[i], c),
-            );
+            prev[c] ^= pixel[c] ^ byte(rand_num, c);
         }
+        enc_pixels.extend_from_slice(&prev);
     }
+}
 
-    img.pixels = enc_pixels;
+// the same permutation and XOR chain as `encrypt_dynamic`, with the channel count known at compile time
+fn encrypt_channels<const C: usize>(
+    pixels: &[u8],
+    mut prev: [u8; C],
+    rand_nums: &[u32],
+    permutation: impl Iterator<Item = u32>,
+    enc_pixels: &mut Vec<u8>,
+) {
+    for (perm, &rand_num) in permutation.zip(rand_nums) {
+        let pixel = &pixels[C * perm as usize..][..C];
+        let rand_bytes = rand_num.to_le_bytes();
+        for ((prev, &pixel), &rand_byte) in prev.iter_mut().zip(pixel).zip(&rand_bytes) {
+            *prev ^= pixel ^ rand_byte;
+        }
+        enc_pixels.extend_from_slice(&prev);
+    }
 }
 
-pub fn decrypt_image(img: &mut Image, key: u64) {
-    let mut rng = SmallRng::seed_from_u64(key);
-    // get the same initial value used for encrypting
-    let start = rng.gen::<u32>();
+// grayscale images have a single byte per pixel, so there is no channel loop:
+// the permutation is a plain gather and the XOR chain runs over one contiguous keystream
+fn encrypt_single_channel(
+    pixels: &[u8],
+    mut prev: u8,
+    rand_nums: &[u32],
+    permutation: impl Iterator<Item = u32>,
+    enc_pixels: &mut Vec<u8>,
+) {
+    enc_pixels.extend(permutation.zip(rand_nums).map(|(perm, &rand_num)| {
+        prev ^= pixels[perm as usize] ^ byte(rand_num, 0);
+        prev
+    }));
+}
 
-    let dim = (img.width * img.height) as usize;
-    let channels = img.color.channel_count() as usize;
+// check what the header records against the image and the key before decrypting anything,
+// so a wrong key or a damaged file is an error instead of noise
+fn check_header(img: &Image, key: u64) -> Result<(), DecryptError> {
+    let Some(header) = &img.header else {
+        return Ok(());
+    };
+    if header.cipher == Some(Cipher::Dct) {
+        return Err(DecryptError::DctDomain);
+    }
+    if header.share.is_some() {
+        return Err(DecryptError::Share);
+    }
+    // the tag checks the key along with the pixels and the header; without one the fingerprint is all there is
+    auth::verify(img, key)?;
+    if header.auth_tag.is_none() && header.check_key(key) == Some(false) {
+        return Err(DecryptError::WrongKey);
+    }
 
-    let mut rand_nums = Vec::<u32>::with_capacity(dim);
-    for _ in 0..rand_nums.capacity() {
-        rand_nums.push(rng.gen());
+    // the dimensions are those of the ciphertext, without the plaintext strip
+    let found = header
+        .reserved
+        .and_then(|strip| banner::rest(strip, img.width, img.height))
+        .map_or((img.width, img.height), |rest| (rest.width, rest.height));
+    // shaped noise takes more rows than the ciphertext it holds
+    let expected = header
+        .scrambled_dimensions()
+        .map(|(width, height)| (width, header.noise.map_or(height, |noise| noise.height)));
+    if let Some(expected) = expected.filter(|&expected| expected != found) {
+        return Err(DecryptError::DimensionMismatch { expected, found });
     }
 
-    let mut permutation = (0..dim as u32).collect::<Vec<u32>>();
-    permutation.shuffle(&mut rng);
+    // the rest of the header is only trusted as far as it fits the image, a damaged one mustn't
+    // index outside of it; the regions are in the plaintext, which noise takes rows off
+    let (width, height) = header.dimensions.unwrap_or(found);
+    let outside = |rect: &Rect| {
+        rect.width == 0
+            || rect.height == 0
+            || rect.x as u64 + rect.width as u64 > width as u64
+            || rect.y as u64 + rect.height as u64 > height as u64
+    };
+    if header.regions.iter().any(|shape| match shape {
+        PixelShape::Rect(rect) => outside(rect),
+        _ => false,
+    }) {
+        return Err(DecryptError::Malformed(
+            "a region is empty or outside the image",
+        ));
+    }
+    if header.rounds.is_some_and(|rounds| rounds > MAX_ROUNDS) {
+        return Err(DecryptError::Malformed("too many rounds"));
+    }
+    Ok(())
+}
 
-    // compute the inverse of the above permutation
-    let mut inv_permutation = vec![0u32; dim];
-    for i in 0..permutation.len() {
-        inv_permutation[permutation[i] as usize] = i as u32;
+// whether the key is the one the image was encrypted with, so decrypting won't turn it into noise;
+// checked against the key fingerprint in the header and the authentication tag, if there is one.
+// An image without a header has nothing to check the key against, and no key is right for it
+pub fn verify_key(img: &Image, key: u64) -> bool {
+    let Some(header) = &img.header else {
+        return false;
+    };
+    // the tag of a JPEG encrypted in the DCT domain is of its coefficients, which decoding it doesn't give
+    if header.cipher == Some(Cipher::Dct) {
+        return header.check_key(key) == Some(true);
     }
+    match header.check_key(key) {
+        Some(false) => false,
+        _ if header.auth_tag.is_some() || auth::tag_expected(img) => auth::verify(img, key).is_ok(),
+        matched => matched == Some(true),
+    }
+}
+
+pub fn decrypt_image(img: &mut Image, key: u64) -> Result<OperationReport, DecryptError> {
+    decrypt_image_with_progress(img, key, |_| {})
+}
+
+// undo `encrypt_arnold`; any other number of iterations fails the key check like a wrong key
+pub fn decrypt_arnold(img: &mut Image, iterations: u32) -> Result<OperationReport, DecryptError> {
+    decrypt_image(img, iterations as u64)
+}
+
+// the same, telling the callback as every phase of the decryption starts and ends
+pub fn decrypt_image_with_progress(
+    img: &mut Image,
+    key: u64,
+    mut progress: impl FnMut(Progress),
+) -> Result<OperationReport, DecryptError> {
+    let progress = &mut progress;
+    let total = img.pixels.len() as u64;
+    let mut report = OperationReport {
+        bytes_in: total,
+        ..Default::default()
+    };
+    let started = report::start_phase(progress, Phase::Verify, total);
+    // the image is left as it is if its authentication tag doesn't match
+    check_header(img, key)?;
+    let header = img.header.take().unwrap_or_default();
+    report.cipher = Some(header.cipher.unwrap_or_default());
+    if header.auth_tag.is_none() && header.check_key(key).is_none() {
+        report.warnings.push(OperationWarning::KeyUnchecked);
+    }
+    report.end_phase(progress, Phase::Verify, total, started);
+
+    let started = report::start_phase(progress, Phase::Decrypt, total);
+    let big_endian = header.sample_order == Some(SampleOrder::BigEndian);
+    if big_endian {
+        swap_samples(img.color, &mut img.pixels);
+    }
+    if let Some(strip) = header.reserved {
+        banner::remove_strip(img, strip);
+    }
+    if let (Some(noise), Some((_, height))) = (header.noise, header.scrambled_dimensions()) {
+        noise::unshape_noise(img, noise.shape, height);
+    }
+
+    let key = match header.convergent_key {
+        Some(masked) => masked ^ convergent_key_mask(key),
+        None => key,
+    };
+    let unit = header.permutation_unit.unwrap_or_default();
+    let keystream = Keystream::of(&header);
+    let kept = kept_channels(img.color.channel_count() as usize, &header.skipped_channels);
+    let scrambler =
+        header
+            .scramble_algorithm
+            .unwrap_or_default()
+            .with(keystream, unit, header.chunk_len);
+    // the rounds are undone last first
+    let rounds = header.rounds.unwrap_or(1);
+    let crypt = |img: &mut Image, key| {
+        for round in (0..rounds).rev() {
+            let key = round_key(key, round);
+            if header.permute_only {
+                unpermute_pixels(img, key, keystream, unit)
+            } else {
+                scrambler.unscramble(img, key)
+            }
+        }
+    };
+    let decrypt = |img: &mut Image, key| match &kept {
+        Some(kept) => img.with_channels(kept, |part| crypt(part, key)),
+        None => crypt(img, key),
+    };
+    if let Some(rows) = header.band_rows {
+        let band_len = (img.width * rows) as usize * img.color.bytes_per_pixel() as usize;
+        img.pixels = img
+            .pixels
+            .chunks(band_len.max(1))
+            .enumerate()
+            .flat_map(|(i, band)| decrypt_band(band, img.width, img.color, key, keystream, i))
+            .collect();
+    } else if header.regions.is_empty() {
+        decrypt(img, key);
+    }
+    // backwards, so overlapping regions are undone in the right order
+    for (i, shape) in header.regions.iter().enumerate().rev() {
+        img.with_shape(shape, |region| decrypt(region, region_key(key, i)));
+    }
+    if !header.permute_only && header.band_rows.is_none() {
+        report.steps = scrambler.steps();
+    }
+    if big_endian {
+        swap_samples(img.color, &mut img.pixels);
+    }
+    if let (Some(ScrambleAlgorithm::ArnoldCat), Some((width, height))) =
+        (header.scramble_algorithm, header.dimensions)
+    {
+        scramble::crop_square(img, width, height);
+    }
+    report.end_phase(progress, Phase::Decrypt, total, started);
+
+    // undo whatever was done to the image before encrypting it
+    let started = report::start_phase(progress, Phase::Finish, total);
+    if let Some(color) = header.original_color {
+        img.convert_color(color);
+    }
+    if let Some(format) = header.original_format {
+        img.format = format;
+    }
+    report.end_phase(progress, Phase::Finish, total, started);
+    report.bytes_out = img.pixels.len() as u64;
+    Ok(report)
+}
+
+// gather whole pixels through the permutation the way the chain does, without changing any of them
+fn permute_pixels(img: &mut Image, key: u64, keystream: Keystream, unit: PermutationUnit) {
+    let pixel_size = img.color.bytes_per_pixel() as usize;
+    let permutation = chunked_permutation(key, keystream, img.width, img.height, unit);
+    img.pixels = permutation
+        .iter()
+        .flat_map(|perm| &img.pixels[pixel_size * perm as usize..][..pixel_size])
+        .copied()
+        .collect();
+}
+
+// the inverse of `permute_pixels`, scatter every pixel back to where it came from
+fn unpermute_pixels(img: &mut Image, key: u64, keystream: Keystream, unit: PermutationUnit) {
+    let pixel_size = img.color.bytes_per_pixel() as usize;
+    let permutation = chunked_permutation(key, keystream, img.width, img.height, unit);
+    let mut pixels = vec![0u8; img.pixels.len()];
+    for (perm, pixel) in permutation.iter().zip(img.pixels.chunks_exact(pixel_size)) {
+        pixels[pixel_size * perm as usize..][..pixel_size].copy_from_slice(pixel);
+    }
+    img.pixels = pixels;
+}
+
+pub(crate) fn decrypt_pixels(
+    img: &mut Image,
+    key: u64,
+    keystream: Keystream,
+    unit: PermutationUnit,
+    chunk_len: Option<u32>,
+) -> CipherSteps {
+    let channels = img.color.bytes_per_pixel() as usize;
+    let mut steps = CipherSteps::default();
+    if let Some(chunk_len) = chunk_len {
+        let started = report::start();
+        let permutation = chunked_permutation(key, keystream, img.width, img.height, unit);
+        steps.permutation = report::elapsed(started);
+        let chunk_len = chunk_len.max(1) as usize;
+        let chunk_steps = Mutex::new(CipherSteps::default());
+        let started = report::start();
+        let permuted = img
+            .pixels
+            .par_chunks(channels * chunk_len)
+            .enumerate()
+            .flat_map_iter(|(i, pixels)| {
+                let mut steps = CipherSteps::default();
+                let started = report::start();
+                let (start, rand_nums) =
+                    chunk_keystream(chunk_key(key, i), keystream, pixels.len() / channels);
+                steps.keystream = report::elapsed(started);
+                let started = report::start();
+                let prev = (0..channels).map(|c| byte(start, c)).collect();
+                let pixels = unchain(pixels, prev, &rand_nums);
+                steps.diffusion = report::elapsed(started);
+                add_chunk_steps(&chunk_steps, steps);
+                pixels
+            })
+            .collect::<Vec<_>>();
+
+        img.pixels = scatter(&permuted, channels, &permutation);
+        return steps.add(split_parallel(&chunk_steps, report::elapsed(started)));
+    }
+
+    // undoing the chain only takes the ciphertext before every pixel, so on more than one thread
+    // the image is split up and every part skips to its own random numbers in the keystream
+    if rayon::current_num_threads() > 1 {
+        let (start, permutation) =
+            seek_state(key, keystream, img.width, img.height, unit, &mut steps);
+        let chunk_steps = Mutex::new(CipherSteps::default());
+        let started = report::start();
+        let permuted = img
+            .pixels
+            .par_chunks(channels * CHUNK_LEN as usize)
+            .enumerate()
+            .flat_map_iter(|(i, pixels)| {
+                let offset = i * CHUNK_LEN as usize;
+                let mut steps = CipherSteps::default();
+                let started = report::start();
+                let rand_nums =
+                    keystream_at(key, keystream, offset as u64, pixels.len() / channels);
+                steps.keystream = report::elapsed(started);
+                let started = report::start();
+                let prev = match offset {
+                    0 => (0..channels).map(|c| byte(start, c)).collect(),
+                    _ => img.pixels[channels * (offset - 1)..][..channels].to_vec(),
+                };
+                let pixels = unchain(pixels, prev, &rand_nums);
+                steps.diffusion = report::elapsed(started);
+                add_chunk_steps(&chunk_steps, steps);
+                pixels
+            })
+            .collect::<Vec<_>>();
+        img.pixels = scatter(&permuted, channels, &permutation);
+        return steps.add(split_parallel(&chunk_steps, report::elapsed(started)));
+    }
+
+    // get the same values used for encrypting
+    let (start, rand_nums, permutation) =
+        cipher_state(key, keystream, img.width, img.height, unit, &mut steps);
+    let started = report::start();
+    img.pixels = decrypt_chain(&img.pixels, channels, start, &rand_nums, permutation.iter());
+    steps.diffusion = report::elapsed(started);
+    steps
+}
+
+fn decrypt_chain(
+    pixels: &[u8],
+    channels: usize,
+    start: u32,
+    rand_nums: &[u32],
+    permutation: Indices,
+) -> Vec<u8> {
+    match channels {
+        1 => decrypt_single_channel(pixels, start, rand_nums, permutation),
+        2 => decrypt_channels::<2>(pixels, start, rand_nums, permutation),
+        3 => decrypt_channels::<3>(pixels, start, rand_nums, permutation),
+        4 => decrypt_channels::<4>(pixels, start, rand_nums, permutation),
+        _ => decrypt_dynamic(pixels, channels, start, rand_nums, permutation),
+    }
+}
+
+// a band of whole rows of a streamed image, encrypted on its own with a key of its own,
+// so only one band has to be in memory at a time; see `encrypt_stream`
+pub(crate) fn encrypt_band(
+    pixels: &[u8],
+    width: u32,
+    color: ColorType,
+    key: u64,
+    keystream: Keystream,
+    index: usize,
+) -> Vec<u8> {
+    let rows = pixels.len() / (width as usize * color.bytes_per_pixel() as usize).max(1);
+    let (start, rand_nums, permutation) = cipher_state(
+        chunk_key(key, index),
+        keystream,
+        width,
+        rows as u32,
+        PermutationUnit::Pixel,
+        &mut CipherSteps::default(),
+    );
+    let channels = color.bytes_per_pixel() as usize;
+    encrypt_chain(pixels, channels, start, &rand_nums, permutation.iter())
+}
+
+pub(crate) fn decrypt_band(
+    pixels: &[u8],
+    width: u32,
+    color: ColorType,
+    key: u64,
+    keystream: Keystream,
+    index: usize,
+) -> Vec<u8> {
+    let rows = pixels.len() / (width as usize * color.bytes_per_pixel() as usize).max(1);
+    let (start, rand_nums, permutation) = cipher_state(
+        chunk_key(key, index),
+        keystream,
+        width,
+        rows as u32,
+        PermutationUnit::Pixel,
+        &mut CipherSteps::default(),
+    );
+    let channels = color.bytes_per_pixel() as usize;
+    decrypt_chain(pixels, channels, start, &rand_nums, permutation.iter())
+}
+
+fn decrypt_dynamic(
+    pixels: &[u8],
+    channels: usize,
+    start: u32,
+    rand_nums: &[u32],
+    permutation: Indices,
+) -> Vec<u8> {
+    let dim = permutation.len();
 
     // compute the first set of unencrypted, but permuted pixels from the encrypted ones
     let mut pixels_perm = Vec::<u8>::with_capacity(channels * dim);
-    for c in 0..channels {
-        pixels_perm.push(byte(start, c) ^ img.pixels[c] ^ byte(rand_nums[0], c));
+    for (c, &pixel) in pixels.iter().enumerate().take(channels) {
+        pixels_perm.push(byte(start, c) ^ pixel ^ byte(rand_nums[0], c));
     }
 
     // decrypt each pixel based on the previous one
     for i in 1..dim {
         for c in 0..channels {
             pixels_perm.push(
-                img.pixels[channels * (i - 1) + c]
-                    ^ img.pixels[channels * i + c]
-                    ^ byte(rand_nums[i], c),
+                pixels[channels * (i - 1) + c] ^ pixels[channels * i + c] ^ byte(rand_nums[i], c),
             )
         }
     }
 
-    let mut dec_pixels = Vec::with_capacity(channels * dim);
-    // put the permuted pixels into the right order by using the inverse of the permutation
-    for perm in inv_permutation {
+    // put the permuted pixels back into the right order by scattering them through the permutation,
+    // which needs no inverse of it
+    let mut dec_pixels = vec![0u8; channels * dim];
+    for (perm, pixel) in permutation.zip(pixels_perm.chunks_exact(channels)) {
+        dec_pixels[channels * perm as usize..][..channels].copy_from_slice(pixel);
+    }
+
+    dec_pixels
+}
+
+// undo the XOR chain over a run of pixels, leaving them in permuted order; `prev` is the pixel
+// before the run, or the bytes of the initial value at the start of a chain
+fn unchain(pixels: &[u8], mut prev: Vec<u8>, rand_nums: &[u32]) -> Vec<u8> {
+    let channels = prev.len();
+    let mut pixels_perm = Vec::with_capacity(pixels.len());
+    for (pixel, &rand_num) in pixels.chunks_exact(channels).zip(rand_nums) {
         for c in 0..channels {
-            dec_pixels.push(pixels_perm[channels * perm as usize + c]);
+            pixels_perm.push(prev[c] ^ pixel[c] ^ byte(rand_num, c));
         }
+        prev.copy_from_slice(pixel);
+    }
+    pixels_perm
+}
+
+// put pixels in permuted order back into place; a plain copy, not worth splitting up
+fn scatter(permuted: &[u8], channels: usize, permutation: &Permutation) -> Vec<u8> {
+    let mut pixels = vec![0u8; permuted.len()];
+    for (perm, pixel) in permutation.iter().zip(permuted.chunks_exact(channels)) {
+        pixels[channels * perm as usize..][..channels].copy_from_slice(pixel);
     }
+    pixels
+}
 
-    img.pixels = dec_pixels;
+// the inverse of `encrypt_channels`, scattering every decrypted pixel straight to its original position
+fn decrypt_channels<const C: usize>(
+    pixels: &[u8],
+    start: u32,
+    rand_nums: &[u32],
+    permutation: Indices,
+) -> Vec<u8> {
+    let mut dec_pixels = vec![0u8; C * permutation.len()];
+    let mut prev: [u8; C] = std::array::from_fn(|c| byte(start, c));
+    for ((perm, &rand_num), pixel) in permutation.zip(rand_nums).zip(pixels.chunks_exact(C)) {
+        let rand_bytes = rand_num.to_le_bytes();
+        let dec_pixel = &mut dec_pixels[C * perm as usize..][..C];
+        for (((dec, &prev), &pixel), &rand_byte) in
+            dec_pixel.iter_mut().zip(&prev).zip(pixel).zip(&rand_bytes)
+        {
+            *dec = prev ^ pixel ^ rand_byte;
+        }
+        prev.copy_from_slice(pixel);
+    }
+    dec_pixels
+}
+
+// the inverse of `encrypt_single_channel`; scattering through the permutation
+// puts every pixel back in place without computing the inverse permutation
+fn decrypt_single_channel(
+    pixels: &[u8],
+    start: u32,
+    rand_nums: &[u32],
+    permutation: Indices,
+) -> Vec<u8> {
+    let mut dec_pixels = vec![0u8; pixels.len()];
+    let mut prev = byte(start, 0);
+    for ((perm, &rand_num), &pixel) in permutation.zip(rand_nums).zip(pixels) {
+        dec_pixels[perm as usize] = prev ^ pixel ^ byte(rand_num, 0);
+        prev = pixel;
+    }
+    dec_pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use super::*;
+    use crate::test_util::{random_image, rng, round_trips, COLORS};
+
+    #[test]
+    fn round_trip() {
+        let mut rng = rng();
+        // the last size spans more than one chunk of a parallel encryption
+        let sizes = [(1, 1), (1, 13), (13, 1), (31, 17), (128, 96), (300, 256)];
+        for cipher in [Cipher::Legacy, Cipher::ChaCha20] {
+            for parallel in [false, true] {
+                for color in COLORS {
+                    for (width, height) in sizes {
+                        let original = random_image(&mut rng, width, height, color);
+                        let options = EncryptOptions {
+                            cipher,
+                            parallel,
+                            ..Default::default()
+                        };
+                        assert!(
+                            round_trips(&original, rng.next_u64(), &options),
+                            "{:?} parallel {} {:?} {}x{}",
+                            cipher,
+                            parallel,
+                            color,
+                            width,
+                            height
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // a permutation-only encryption has to keep every pixel value, whatever it moves around
+    #[test]
+    fn permute_only_keeps_histogram() {
+        let mut rng = rng();
+        let units = [
+            PermutationUnit::Pixel,
+            PermutationUnit::Row,
+            PermutationUnit::Block(4),
+            PermutationUnit::Tile(8),
+            PermutationUnit::Bijection,
+        ];
+        // every pixel value of the image, in order, as many times as it occurs
+        let histogram = |img: &Image| {
+            let mut pixels = img
+                .pixels
+                .chunks_exact(img.color.bytes_per_pixel() as usize)
+                .map(<[u8]>::to_vec)
+                .collect::<Vec<_>>();
+            pixels.sort_unstable();
+            pixels
+        };
+        for unit in units {
+            for color in COLORS {
+                let original = random_image(&mut rng, 37, 23, color);
+                let options = EncryptOptions {
+                    permutation_unit: unit,
+                    permute_only: true,
+                    ..Default::default()
+                };
+                let mut img = original.clone();
+                encrypt_image_with(&mut img, 1, &options);
+                // the pixels have to move for the check to mean anything
+                assert_ne!(img.pixels, original.pixels, "{:?} {:?}", unit, color);
+                assert_eq!(
+                    histogram(&img),
+                    histogram(&original),
+                    "{:?} {:?}",
+                    unit,
+                    color
+                );
+                assert!(round_trips(&original, rng.next_u64(), &options));
+            }
+        }
+    }
+
+    #[test]
+    fn bijection_round_trip() {
+        let mut rng = rng();
+        for parallel in [false, true] {
+            for color in COLORS {
+                let original = random_image(&mut rng, 300, 256, color);
+                let options = EncryptOptions {
+                    permutation_unit: PermutationUnit::Bijection,
+                    parallel,
+                    ..Default::default()
+                };
+                assert!(
+                    round_trips(&original, rng.next_u64(), &options),
+                    "parallel {} {:?}",
+                    parallel,
+                    color
+                );
+            }
+        }
+    }
+
+    // with more than one thread, decryption splits up a chain and seeks into its keystream
+    #[test]
+    fn decrypt_on_threads() {
+        let mut rng = rng();
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for (cipher, unit) in [
+            (Cipher::Legacy, PermutationUnit::Pixel),
+            (Cipher::ChaCha20, PermutationUnit::Bijection),
+        ] {
+            let original = random_image(&mut rng, 300, 256, ColorType::Rgb8);
+            let options = EncryptOptions {
+                cipher,
+                permutation_unit: unit,
+                ..Default::default()
+            };
+            assert!(
+                pool.install(|| round_trips(&original, rng.next_u64(), &options)),
+                "{:?} {:?}",
+                cipher,
+                unit
+            );
+        }
+    }
+
+    // a skipped alpha channel has to come out of the encryption exactly as it went in
+    #[test]
+    fn skip_alpha() {
+        let mut rng = rng();
+        for color in [
+            ColorType::La8,
+            ColorType::Rgba8,
+            ColorType::Rgba16,
+            ColorType::Rgba32F,
+        ] {
+            let original = random_image(&mut rng, 37, 23, color);
+            let options = EncryptOptions {
+                skip_channels: vec![Channel::Alpha],
+                ..Default::default()
+            };
+            let mut img = original.clone();
+            encrypt_image_with(&mut img, 1, &options);
+            let sample_size = (color.bytes_per_pixel() / color.channel_count()) as usize;
+            let alpha = |img: &Image| {
+                img.pixels
+                    .chunks_exact(color.bytes_per_pixel() as usize)
+                    .flat_map(|pixel| &pixel[pixel.len() - sample_size..])
+                    .copied()
+                    .collect::<Vec<_>>()
+            };
+            assert_ne!(img.pixels, original.pixels, "{:?}", color);
+            assert_eq!(alpha(&img), alpha(&original), "{:?}", color);
+            assert!(round_trips(&original, rng.next_u64(), &options));
+        }
+    }
+
+    // the key check has to survive writing the image out, and turn away any other key
+    #[test]
+    fn verify_key_after_writing() {
+        let mut rng = rng();
+        let key = rng.next_u64();
+        let original = random_image(&mut rng, 37, 23, ColorType::Rgb8);
+        let mut img = original.clone();
+        encrypt_image(&mut img, key);
+        let img = load_image_from_bytes(&write_image_to_vec(&img).unwrap()).unwrap();
+        assert!(verify_key(&img, key));
+        assert!(!verify_key(&img, key ^ 1));
+        assert!(!verify_key(&original, key));
+    }
+
+    // the key id is the same whatever the size of the image, while the key check is stretched
+    // for every size; guesses are as costly as the cheaper of the two
+    #[test]
+    fn key_id_across_sizes() {
+        let mut rng = rng();
+        let key = rng.next_u64();
+        let headers = [(3, 2), (900, 700)].map(|(width, height)| {
+            let mut img = random_image(&mut rng, width, height, ColorType::L8);
+            encrypt_image(&mut img, key);
+            img.header.unwrap()
+        });
+        for header in &headers {
+            assert_eq!(header.key_id, Some(KeyFingerprint::id(key)));
+            assert_ne!(header.key_id, Some(KeyFingerprint::id(key ^ 1)));
+        }
+        assert_ne!(headers[0].key_fingerprint, headers[1].key_fingerprint);
+
+        let (width, height) = (8000, 6000);
+        let huge = EncryptionHeader {
+            dimensions: Some((width, height)),
+            key_check_iterations: Some(key_check_iterations(width, height)),
+            ..headers[1].clone()
+        };
+        let cost = huge.guess_cost().unwrap();
+        assert_eq!(cost.check_hashes, Some(KEY_ID_ITERATIONS as u64 + 1));
+        assert!(!cost.check_is_hardened());
+    }
+
+    // the tag covers the header as well as the pixels, and can't be dropped from a header
+    // of a version that writes it; one of the version before may still go without
+    #[test]
+    fn authenticated_header() {
+        let mut rng = rng();
+        let key = rng.next_u64();
+        let original = random_image(&mut rng, 37, 23, ColorType::Rgb8);
+        let mut img = original.clone();
+        encrypt_image(&mut img, key);
+        let img = load_image_from_bytes(&write_image_to_vec(&img).unwrap()).unwrap();
+        let tampered = |change: fn(&mut EncryptionHeader)| {
+            let mut img = img.clone();
+            change(img.header.as_mut().unwrap());
+            img
+        };
+
+        let mut permuted = tampered(|header| header.permute_only = true);
+        assert_eq!(
+            decrypt_image(&mut permuted, key).err(),
+            Some(DecryptError::AuthenticationFailed)
+        );
+        let mut stripped = tampered(|header| header.auth_tag = None);
+        assert!(!verify_key(&stripped, key));
+        assert_eq!(
+            decrypt_image(&mut stripped, key).err(),
+            Some(DecryptError::AuthenticationFailed)
+        );
+        let legacy = tampered(|header| {
+            header.auth_tag = None;
+            header.pixel_tag_only = true;
+        });
+        let mut legacy = load_image_from_bytes(&write_image_to_vec(&legacy).unwrap()).unwrap();
+        assert!(legacy.header.as_ref().unwrap().pixel_tag_only);
+        assert!(decrypt_image(&mut legacy, key).is_ok());
+        assert_eq!(legacy.pixels, original.pixels);
+    }
+
+    // a big-endian machine ran the cipher over its own samples and wrote them out as numbers,
+    // which read back here with the bytes of every sample the other way around
+    #[test]
+    fn big_endian_samples() {
+        let mut rng = rng();
+        let key = rng.next_u64();
+        let original = random_image(&mut rng, 37, 23, ColorType::Rgb16);
+        let mut img = original.clone();
+        swap_samples(img.color, &mut img.pixels);
+        encrypt_image(&mut img, key);
+        // in a header of the version before the tag covered it
+        if let Some(header) = &mut img.header {
+            header.sample_order = Some(SampleOrder::BigEndian);
+            header.pixel_tag_only = true;
+        }
+        auth::authenticate(&mut img, key);
+        swap_samples(img.color, &mut img.pixels);
+        assert!(decrypt_image(&mut img, key).is_ok());
+        assert_eq!(img.pixels, original.pixels);
+    }
+
+    // more rounds give a different ciphertext than one, and are undone in reverse
+    #[test]
+    fn rounds() {
+        let mut rng = rng();
+        for parallel in [false, true] {
+            let key = rng.next_u64();
+            let original = random_image(&mut rng, 37, 12, ColorType::Rgb8);
+            let options = EncryptOptions {
+                parallel,
+                ..Default::default()
+            };
+            let mut single = original.clone();
+            encrypt_image_with(&mut single, key, &options);
+            let options = EncryptOptions {
+                rounds: 3,
+                ..options
+            };
+            let mut img = original.clone();
+            encrypt_image_with(&mut img, key, &options);
+            assert_eq!(img.header().and_then(|header| header.rounds), Some(3));
+            assert_ne!(img.pixels, single.pixels);
+            assert!(round_trips(&original, key, &options));
+        }
+    }
+
+    // the digest and the content hash worked out during the encryption pass, over several blocks of it,
+    // are those of a pass of their own
+    #[test]
+    fn hashes_in_the_pass() {
+        let mut rng = rng();
+        let options = EncryptOptions {
+            hash_ciphertext: true,
+            ..Default::default()
+        };
+        for color in [ColorType::L8, ColorType::Rgb8, ColorType::Rgba16] {
+            let key = rng.next_u64();
+            let original = random_image(&mut rng, 211, 97, color);
+            let mut img = original.clone();
+            let report = encrypt_image_with_progress(&mut img, key, &options, |_| {});
+            let digest = img
+                .header()
+                .and_then(|header| header.plaintext_digest)
+                .map(|digest| digest.open(key));
+            assert_eq!(
+                digest,
+                Some(digest::plaintext_digest(&original)),
+                "{:?}",
+                color
+            );
+            assert_eq!(report.content_hash, Some(content_hash(&img)), "{:?}", color);
+            assert!(round_trips(&original, key, &options));
+        }
+    }
+
+    // an image over the limits is rejected from its header, before a decoder allocates or a thread starts
+    #[test]
+    fn limits_before_decoding() {
+        let encoded = |format| {
+            let mut bytes = Vec::new();
+            DynamicImage::new_rgb8(100, 100)
+                .write_to(&mut Cursor::new(&mut bytes), format)
+                .unwrap();
+            bytes
+        };
+        let png = encoded(image::ImageOutputFormat::Png);
+        let jpeg = encoded(image::ImageOutputFormat::Jpeg(90));
+        let load = |bytes: &[u8], options| match load_image_from_bytes_with(bytes, &options) {
+            Ok(img) => Ok((img.width, img.height)),
+            Err(ImageEncryptionError::Limit(err)) => Err(err),
+            Err(err) => panic!("{}", err),
+        };
+
+        let options = LoadOptions {
+            max_pixels: Some(9999),
+            ..LoadOptions::default()
+        };
+        assert_eq!(load(&png, options), Err(LimitError::TooManyPixels(10000)));
+        assert_eq!(load(&jpeg, options), Err(LimitError::TooManyPixels(10000)));
+
+        // the PNG could have 16-bit samples, a JPEG has 8-bit ones
+        let options = LoadOptions {
+            max_bytes: Some(50_000),
+            timeout: Some(Duration::from_secs(10)),
+            ..LoadOptions::default()
+        };
+        assert_eq!(load(&png, options), Err(LimitError::TooManyBytes(80_000)));
+        assert_eq!(load(&jpeg, options), Ok((100, 100)));
+
+        // a header claiming far more pixels than the file holds
+        let mut forged = png.clone();
+        forged[16..24].copy_from_slice(&[0, 0, 0xEA, 0x60, 0, 0, 0xEA, 0x60]);
+        let crc = png_store::crc32(&forged[12..29]);
+        forged[29..33].copy_from_slice(&crc.to_be_bytes());
+        assert_eq!(
+            load(
+                &forged,
+                LoadOptions {
+                    max_bytes: Some(512 * 1024 * 1024),
+                    ..LoadOptions::default()
+                }
+            ),
+            Err(LimitError::TooManyBytes(60_000 * 60_000 * 8))
+        );
+    }
 }
@@ -0,0 +1,135 @@
+use std::{error::Error, fmt, time::Duration};
+
+use image::{io::Limits, ImageFormat};
+
+// resource limits for decoding images that come from untrusted sources,
+// checked against the dimensions in the image header before any pixel is decoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadOptions {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_pixels: Option<u64>,
+    // the most memory the decoder may allocate, which is mostly the decoded pixel buffer;
+    // checked against the widest pixels the format can decode to before decoding, enforced again by the
+    // decoder before it allocates, and 512 MiB when not set
+    pub max_bytes: Option<u64>,
+    // best-effort: decoding runs on a separate thread and the caller stops waiting for it after this long,
+    // but the thread can't be stopped and keeps running in the background until the decoder returns;
+    // what bounds its time and memory are the size limits above, checked before it starts
+    pub timeout: Option<Duration>,
+}
+
+impl LoadOptions {
+    // limits generous enough for real photos and scans, but small enough that a hostile file can't exhaust a server
+    pub fn untrusted() -> Self {
+        LoadOptions {
+            max_width: Some(16384),
+            max_height: Some(16384),
+            max_pixels: Some(64 * 1024 * 1024),
+            max_bytes: Some(512 * 1024 * 1024),
+            timeout: Some(Duration::from_secs(10)),
+        }
+    }
+
+    // the limits for image's decoders, which check them before decoding
+    pub(crate) fn decoder_limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = self.max_width;
+        limits.max_image_height = self.max_height;
+        if self.max_bytes.is_some() {
+            limits.max_alloc = self.max_bytes;
+        }
+        limits
+    }
+
+    pub(crate) fn check_dimensions(&self, width: u32, height: u32) -> Result<(), LimitError> {
+        if self.max_width.is_some_and(|max| width > max) {
+            return Err(LimitError::TooWide(width));
+        }
+        if self.max_height.is_some_and(|max| height > max) {
+            return Err(LimitError::TooTall(height));
+        }
+        let pixels = width as u64 * height as u64;
+        if self.max_pixels.is_some_and(|max| pixels > max) {
+            return Err(LimitError::TooManyPixels(pixels));
+        }
+        Ok(())
+    }
+
+    // checks the dimensions, and the most memory the decoded pixels of an image of that format could take,
+    // so an image over the limits is rejected before anything is decoded
+    pub(crate) fn check_decoded_size(
+        &self,
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+    ) -> Result<(), LimitError> {
+        self.check_dimensions(width, height)?;
+        let bytes = width as u64 * height as u64 * max_bytes_per_pixel(format);
+        if self.max_bytes.is_some_and(|max| bytes > max) {
+            return Err(LimitError::TooManyBytes(bytes));
+        }
+        Ok(())
+    }
+}
+
+// the most bytes a pixel decoded from the format takes, for the widest color type its decoder produces
+fn max_bytes_per_pixel(format: ImageFormat) -> u64 {
+    match format {
+        ImageFormat::Jpeg => 3,
+        ImageFormat::Gif
+        | ImageFormat::Bmp
+        | ImageFormat::Tga
+        | ImageFormat::Dds
+        | ImageFormat::WebP
+        | ImageFormat::Avif => 4,
+        // 16 bits a channel
+        ImageFormat::Png
+        | ImageFormat::Tiff
+        | ImageFormat::Pnm
+        | ImageFormat::Farbfeld
+        | ImageFormat::Ico => 8,
+        // 32-bit floats, and whatever formats get added
+        _ => 16,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    TooWide(u32),
+    TooTall(u32),
+    TooManyPixels(u64),
+    // the decoded pixels could take this many bytes
+    TooManyBytes(u64),
+    Timeout(Duration),
+    // encrypting the image in memory would take more than the memory budget, in bytes
+    MemoryBudget { needed: u64, budget: u64 },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::TooWide(width) => write!(f, "image is too wide ({} pixels)", width),
+            LimitError::TooTall(height) => write!(f, "image is too tall ({} pixels)", height),
+            LimitError::TooManyPixels(pixels) => {
+                write!(f, "image has too many pixels ({})", pixels)
+            }
+            LimitError::TooManyBytes(bytes) => write!(
+                f,
+                "the decoded image could take up to {} MB of memory",
+                bytes.div_ceil(1_000_000)
+            ),
+            LimitError::Timeout(timeout) => {
+                write!(f, "decoding took longer than {:?}", timeout)
+            }
+            LimitError::MemoryBudget { needed, budget } => write!(
+                f,
+                "the image would take about {} MB of memory, more than the budget of {} MB",
+                needed.div_ceil(1_000_000),
+                budget / 1_000_000
+            ),
+        }
+    }
+}
+
+impl Error for LimitError {}
@@ -0,0 +1,260 @@
+use std::{fmt, path::Path};
+
+use image::{ColorType, ImageFormat};
+
+use crate::{
+    compare_images, decode_image, decrypt_image, encode_image_with, encrypt_image_with,
+    EncryptOptions, Image, LoadOptions, MetadataKind, WriteOptions,
+};
+
+// something about the image that an operation would silently not carry over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InformationLoss {
+    // the output is written in a lossy format, so the pixels don't survive exactly
+    LossyFormat(ImageFormat),
+    // metadata of the source file that isn't written back out
+    DroppedMetadata(MetadataKind),
+    ColorConversion { from: ColorType, to: ColorType },
+}
+
+impl fmt::Display for InformationLoss {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InformationLoss::LossyFormat(format) => {
+                write!(f, "{:?} is a lossy format, the pixels would change", format)
+            }
+            InformationLoss::DroppedMetadata(MetadataKind::Orientation) => write!(
+                f,
+                "the EXIF orientation would be dropped, the image would display rotated"
+            ),
+            InformationLoss::DroppedMetadata(kind) => {
+                write!(f, "{} metadata would be dropped", kind)
+            }
+            InformationLoss::ColorConversion { from, to } => {
+                write!(
+                    f,
+                    "the pixels would be converted from {:?} to {:?}",
+                    from, to
+                )
+            }
+        }
+    }
+}
+
+// everything encrypting the image with these options and writing it out would lose;
+// decryption is checked with the default options
+pub fn information_loss(img: &Image, options: &EncryptOptions) -> Vec<InformationLoss> {
+    let mut losses = Vec::new();
+    // a JPEG container keeps both the exact ciphertext and the metadata
+    if img.format == ImageFormat::Jpeg && options.jpeg_container {
+        return losses;
+    }
+    // otherwise the ciphertext is written as a PNG
+    if img.format == ImageFormat::Jpeg && options.keep_lossy_format {
+        losses.push(InformationLoss::LossyFormat(img.format));
+    }
+    losses.extend(
+        img.metadata
+            .iter()
+            .copied()
+            .map(InformationLoss::DroppedMetadata),
+    );
+    if let Some(color) = options.normalize.filter(|&color| color != img.color) {
+        losses.push(InformationLoss::ColorConversion {
+            from: img.color,
+            to: color,
+        });
+    }
+    losses
+}
+
+// a step of encrypting an image, writing it out, loading it back and decrypting it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CycleStep {
+    Encrypt,
+    Write,
+    Load,
+    Decrypt,
+}
+
+impl fmt::Display for CycleStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CycleStep::Encrypt => "encrypt",
+            CycleStep::Write => "write",
+            CycleStep::Load => "load",
+            CycleStep::Decrypt => "decrypt",
+        })
+    }
+}
+
+// why the image wouldn't come back bit for bit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Downgrade {
+    // the plaintext is marked before it is encrypted, and the mark is decrypted with it
+    Marked(&'static str),
+    // the color type the image is encrypted in can't hold all of it
+    ColorConversion {
+        from: ColorType,
+        to: ColorType,
+    },
+    // the ciphertext can't be encoded at all
+    Unwritable(String),
+    // the format stores the ciphertext in another color type, like one without 16-bit samples
+    StoredAs {
+        format: ImageFormat,
+        from: ColorType,
+        to: ColorType,
+    },
+    // the format doesn't keep the samples exactly
+    LossyFormat(ImageFormat),
+    // the contents don't tell the format, and the extension of the output path makes the file
+    // decode as another one than it is written in
+    WrongExtension {
+        written: ImageFormat,
+        read: ImageFormat,
+    },
+    Unreadable(String),
+    // the file is read back without its encryption header
+    HeaderLost(ImageFormat),
+    Undecryptable(String),
+}
+
+impl Downgrade {
+    // the step that loses the data
+    pub fn step(&self) -> CycleStep {
+        match self {
+            Downgrade::Marked(_) | Downgrade::ColorConversion { .. } => CycleStep::Encrypt,
+            Downgrade::Unwritable(_) | Downgrade::StoredAs { .. } | Downgrade::LossyFormat(_) => {
+                CycleStep::Write
+            }
+            Downgrade::WrongExtension { .. }
+            | Downgrade::Unreadable(_)
+            | Downgrade::HeaderLost(_) => CycleStep::Load,
+            Downgrade::Undecryptable(_) => CycleStep::Decrypt,
+        }
+    }
+}
+
+impl fmt::Display for Downgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.step())?;
+        match self {
+            Downgrade::Marked(mark) => {
+                write!(f, "{} is added to the image and decrypted with it", mark)
+            }
+            Downgrade::ColorConversion { from, to } => write!(
+                f,
+                "the pixels are converted from {:?} to {:?}, which doesn't convert back exactly",
+                from, to
+            ),
+            Downgrade::Unwritable(err) => write!(f, "the ciphertext can't be encoded: {}", err),
+            Downgrade::StoredAs { format, from, to } => write!(
+                f,
+                "{:?} stores the {:?} ciphertext as {:?}",
+                format, from, to
+            ),
+            Downgrade::LossyFormat(format) => write!(
+                f,
+                "{:?} doesn't keep the ciphertext exactly, so it would never decrypt",
+                format
+            ),
+            Downgrade::WrongExtension { written, read } => write!(
+                f,
+                "the file is written as {:?} but its extension makes it load as {:?}",
+                written, read
+            ),
+            Downgrade::Unreadable(err) => write!(f, "the file can't be read back: {}", err),
+            Downgrade::HeaderLost(format) => {
+                write!(f, "{:?} is read back without the encryption header", format)
+            }
+            Downgrade::Undecryptable(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+// run the whole cycle in memory, encrypting the image with these options, writing it as it would be
+// to the output path, loading it back from there and decrypting it, and tell the first step
+// that would keep the image from coming back bit for bit, if any
+pub fn check_exact(
+    img: &Image,
+    output: impl AsRef<Path>,
+    key: u64,
+    options: &EncryptOptions,
+    write_options: &WriteOptions,
+) -> Result<(), Downgrade> {
+    if options.watermark.is_some() {
+        return Err(Downgrade::Marked("the watermark"));
+    }
+    if options.fingerprint.is_some() {
+        return Err(Downgrade::Marked("the fingerprint"));
+    }
+    let mut encrypted = img.clone();
+    encrypt_image_with(&mut encrypted, key, options);
+    let mut decrypted = encrypted.clone();
+    if decrypt_image(&mut decrypted, key).is_ok() && !compare_images(img, &decrypted).identical {
+        return Err(Downgrade::ColorConversion {
+            from: img.color,
+            to: options.normalize.unwrap_or(img.color),
+        });
+    }
+
+    let bytes = encode_image_with(&encrypted, write_options)
+        .map_err(|err| Downgrade::Unwritable(err.to_string()))?;
+    // a disguise is always a PNG, whatever the format of the image in it
+    let written = match &encrypted.header {
+        Some(header) if header.disguised => ImageFormat::Png,
+        _ => encrypted.format,
+    };
+    let read = ImageFormat::from_path(output).ok();
+    let mut loaded =
+        decode_image(&bytes, read, &LoadOptions::default()).map_err(|err| match read {
+            Some(read) if read != written => Downgrade::WrongExtension { written, read },
+            _ => Downgrade::Unreadable(err.to_string()),
+        })?;
+    if loaded.header.is_none() {
+        return Err(Downgrade::HeaderLost(written));
+    }
+    if loaded.color != encrypted.color {
+        return Err(Downgrade::StoredAs {
+            format: written,
+            from: encrypted.color,
+            to: loaded.color,
+        });
+    }
+    if !compare_images(&encrypted, &loaded).identical {
+        return Err(Downgrade::LossyFormat(written));
+    }
+
+    decrypt_image(&mut loaded, key).map_err(|err| Downgrade::Undecryptable(err.to_string()))?;
+    if !compare_images(img, &loaded).identical {
+        return Err(Downgrade::Undecryptable(
+            "the decrypted pixels differ from the original".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{random_image, rng};
+
+    // the cycle check has to pass a PNG and find where a JPEG kept as a JPEG loses the ciphertext
+    #[test]
+    fn check_exact_cycle() {
+        let png = random_image(&mut rng(), 37, 23, ColorType::Rgb8);
+        let jpeg = Image {
+            format: ImageFormat::Jpeg,
+            ..png.clone()
+        };
+        let keep_jpeg = EncryptOptions {
+            keep_lossy_format: true,
+            ..Default::default()
+        };
+        let write = WriteOptions::default();
+        assert!(check_exact(&png, "out.png", 1, &EncryptOptions::default(), &write).is_ok());
+        let downgrade = check_exact(&jpeg, "out.jpg", 1, &keep_jpeg, &write).unwrap_err();
+        assert_eq!(downgrade.step(), CycleStep::Write);
+    }
+}
@@ -1,45 +1,2818 @@
-use clap::Parser;
+use std::{
+    env,
+    error::Error,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
 
-use image_encryption::{decrypt_image, encrypt_image, load_image, write_image};
+use clap::{Parser, Subcommand};
+
+use image::{ColorType, ImageFormat};
+use image_encryption::{
+    add_manifest_entry, audit, check_exact, combine_images, contact_sheet, content_addressed_name,
+    decrypt_animation, decrypt_image, decrypt_image_with_progress, decrypt_jpeg_dct,
+    decrypt_layers, decrypt_stream, embed, encode_image, encrypt_animation, encrypt_image_with,
+    encrypt_image_with_progress, encrypt_jpeg_dct, encrypt_layers, encrypt_stream,
+    estimate_working_set, extract, fingerprint_detected, fingerprint_score, information_loss,
+    is_animated, key_weakness, load_animation, load_image, load_image_with,
+    load_layers_with_progress, migrate_legacy, parse_key, parse_regions_json, passphrase_weakness,
+    path_for_format, process_directory_with_progress, read_header, redact_image, regions_json,
+    register_context_menu, rekey_image, rekey_jpeg_dct, run_cross_vectors, split_image,
+    terminal_graphics, thumbnail, unregister_context_menu, update_thumbnail_cache, upload,
+    verify_key, verify_manifest, write_animation, write_file_atomic_with, write_image,
+    write_image_atomic_with, write_image_with_progress, write_layers, Banner, BannerEdge,
+    CacheStatus, Channel, Cipher, DctError, DirectoryOptions, DirectoryProgress, EncryptOptions,
+    EncryptionHeader, GraphicsProtocol, Image, ImageEncryptionError, InformationLoss, KdfParams,
+    KeyFingerprint, KeySource, KeyWeakness, LimitError, LoadOptions, ManifestStatus, Mode,
+    NoiseShape, OperationReport, OperationWarning, PermutationUnit, Phase, Pipeline,
+    PngCompression, PngFilter, Progress, QrCode, Redaction, Region, RekeyError, SampleOrder,
+    ScrambleAlgorithm, Shape, StegoError, TempLocation, TiffCompression, UploadOptions, Watermark,
+    WatermarkContent, WatermarkPosition, WriteOptions, MAX_ROUNDS, MAX_SHARES,
+};
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Color {
+    L8,
+    La8,
+    Rgb8,
+    Rgba8,
+    L16,
+    La16,
+    Rgb16,
+    Rgba16,
+    Rgb32f,
+    Rgba32f,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Protocol {
+    Kitty,
+    Sixel,
+}
+
+impl From<Protocol> for GraphicsProtocol {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Kitty => GraphicsProtocol::Kitty,
+            Protocol::Sixel => GraphicsProtocol::Sixel,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Unit {
+    Pixel,
+    Row,
+    Column,
+    Block,
+    Bijection,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Scramble {
+    Full,
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Algorithm {
+    Legacy,
+    Chacha20,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum ScrambleScheme {
+    XorChain,
+    ArnoldCat,
+    ChaoticMap,
+    BitPlane,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Algo {
+    Cipher,
+    Arnold,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Noise {
+    FilmGrain,
+    Gaussian,
+}
+
+impl From<Noise> for NoiseShape {
+    fn from(noise: Noise) -> Self {
+        match noise {
+            Noise::FilmGrain => NoiseShape::FilmGrain,
+            Noise::Gaussian => NoiseShape::Gaussian,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Edge {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum RedactMethod {
+    Pixelate,
+    Blur,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ReportFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum PngLevel {
+    Store,
+    Fast,
+    Default,
+    Best,
+}
+
+impl From<PngLevel> for PngCompression {
+    fn from(level: PngLevel) -> Self {
+        match level {
+            PngLevel::Store => PngCompression::Store,
+            PngLevel::Fast => PngCompression::Fast,
+            PngLevel::Default => PngCompression::Default,
+            PngLevel::Best => PngCompression::Best,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
-pub enum Mode {
-    Enc,
-    Dec,
+enum Filter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    Adaptive,
+}
+
+impl From<Filter> for PngFilter {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::None => PngFilter::None,
+            Filter::Sub => PngFilter::Sub,
+            Filter::Up => PngFilter::Up,
+            Filter::Avg => PngFilter::Avg,
+            Filter::Paeth => PngFilter::Paeth,
+            Filter::Adaptive => PngFilter::Adaptive,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum TiffCodec {
+    None,
+    Lzw,
+    Deflate,
+    Packbits,
+}
+
+impl From<TiffCodec> for TiffCompression {
+    fn from(codec: TiffCodec) -> Self {
+        match codec {
+            TiffCodec::None => TiffCompression::None,
+            TiffCodec::Lzw => TiffCompression::Lzw,
+            TiffCodec::Deflate => TiffCompression::Deflate,
+            TiffCodec::Packbits => TiffCompression::PackBits,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl From<Position> for WatermarkPosition {
+    fn from(position: Position) -> Self {
+        match position {
+            Position::TopLeft => WatermarkPosition::TopLeft,
+            Position::TopRight => WatermarkPosition::TopRight,
+            Position::BottomLeft => WatermarkPosition::BottomLeft,
+            Position::BottomRight => WatermarkPosition::BottomRight,
+            Position::Center => WatermarkPosition::Center,
+        }
+    }
+}
+
+impl From<Color> for ColorType {
+    fn from(color: Color) -> Self {
+        match color {
+            Color::L8 => ColorType::L8,
+            Color::La8 => ColorType::La8,
+            Color::Rgb8 => ColorType::Rgb8,
+            Color::Rgba8 => ColorType::Rgba8,
+            Color::L16 => ColorType::L16,
+            Color::La16 => ColorType::La16,
+            Color::Rgb16 => ColorType::Rgb16,
+            Color::Rgba16 => ColorType::Rgba16,
+            Color::Rgb32f => ColorType::Rgb32F,
+            Color::Rgba32f => ColorType::Rgba32F,
+        }
+    }
 }
 
 /// simple image encryption program
 #[derive(Debug, Parser)]
 struct Args {
-    /// encrypt an image or decrypt an encrypted one
-    #[clap(value_enum)]
-    mode: Mode,
-    /// the encryption/decryption key
-    key: u64,
-    /// image input path
+    #[clap(subcommand)]
+    command: Command,
+    /// refuse to encrypt with keys that are easy to guess, instead of only warning about them
+    #[clap(long, global = true)]
+    enforce_strong_keys: bool,
+    /// how many threads images encrypted with --parallel are processed on; all cores by default
+    #[clap(long, global = true)]
+    threads: Option<usize>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// encrypt an image
+    Enc(Box<EncArgs>),
+    /// decrypt an encrypted image
+    Dec(DecArgs),
+    /// decrypt an image in memory and show it in the terminal, without writing the plaintext anywhere
+    View {
+        /// the decryption key
+        #[clap(value_parser = parse_key)]
+        key: u64,
+        /// the encrypted image
+        input: String,
+        /// terminal graphics protocol to draw with; detected from the terminal if omitted
+        #[clap(long, value_enum)]
+        protocol: Option<Protocol>,
+        /// images larger than this many pixels on a side are scaled down to fit
+        #[clap(long, default_value_t = 1024)]
+        max_size: u32,
+    },
+    /// decrypt a directory of images in memory and render them as a single grid overview,
+    /// without writing any of the decrypted images
+    ContactSheet {
+        /// the decryption key
+        #[clap(value_parser = parse_key)]
+        key: u64,
+        /// directory of encrypted images
+        dir: String,
+        /// contact sheet output path
+        output: String,
+        /// number of images per row
+        #[clap(long, default_value_t = 6)]
+        columns: u32,
+        /// size in pixels of each grid cell
+        #[clap(long, default_value_t = 256)]
+        tile_size: u32,
+    },
+    /// keep encrypted thumbnails of a directory of encrypted images in its `.thumbnails` subdirectory,
+    /// so viewers can page through the album quickly; they are encrypted with the same key,
+    /// only missing or outdated ones are made and those of deleted images are removed
+    Thumbnails {
+        /// the key the images are encrypted with
+        #[clap(value_parser = parse_key)]
+        key: u64,
+        /// directory of encrypted images
+        dir: String,
+        /// the most pixels a thumbnail is wide or high
+        #[clap(long, default_value_t = 256)]
+        size: u32,
+    },
+    /// encrypt an image with the options `enc` takes and upload the result
+    /// to an http or https endpoint, without writing it to disk
+    Upload {
+        /// the encryption key
+        #[clap(value_parser = parse_key)]
+        key: u64,
+        /// image input path
+        input: String,
+        /// http or https url the encrypted image is PUT to, in chunks with a Content-Range header;
+        /// after a failed chunk, the upload goes on from the bytes the server says it has
+        url: String,
+        #[clap(flatten)]
+        options: Box<EncryptOptionArgs>,
+        /// size in bytes of each uploaded chunk
+        #[clap(long, default_value_t = UploadOptions::default().chunk_size)]
+        chunk_size: usize,
+        /// how many times a failed chunk is resent
+        #[clap(long, default_value_t = UploadOptions::default().retries)]
+        retries: u32,
+    },
+    /// run statistical and known-plaintext attacks on an encrypted image
+    /// and report how well it holds up
+    Audit {
+        /// the encrypted image to attack
+        ciphertext: String,
+        /// the image it was encrypted from, to also run the known-plaintext attack
+        #[clap(long)]
+        plaintext: Option<String>,
+    },
+    /// re-encrypt encrypted images with a new key, checking each one was encrypted with the old key;
+    /// every file is replaced atomically
+    Rekey {
+        /// the key the images are encrypted with
+        #[clap(value_parser = parse_key)]
+        old_key: u64,
+        /// the key to encrypt them with instead
+        #[clap(value_parser = parse_key)]
+        new_key: u64,
+        /// an encrypted image, or a directory of them
+        path: String,
+        /// also rekey the images in all subdirectories
+        #[clap(long)]
+        recursive: bool,
+        /// directory for the temporary files the rekeyed images are written to before replacing the originals;
+        /// by default they go next to the originals
+        #[clap(long, conflicts_with = "tmpfs")]
+        temp_dir: Option<String>,
+        /// don't use temporary files at all, only memory: the originals are overwritten directly,
+        /// so an interrupted run can leave a file half written
+        #[clap(long)]
+        tmpfs: bool,
+    },
+    /// add a header to an image encrypted by a version from before there was one, so its key is checked
+    /// and `dec` writes it back in its original format; the pixels are left as they are.
+    /// Nothing in such an image tells whether the key is right, so nothing is written if it decrypts to noise
+    Migrate {
+        /// the key the image was encrypted with
+        #[clap(value_parser = parse_key)]
+        key: u64,
+        /// the encrypted image
+        input: String,
+        /// where to write it; by default it replaces the input
+        output: Option<String>,
+        /// the format of the image before it was encrypted, like `jpg`; by default that of the input
+        #[clap(long, value_parser = parse_format)]
+        format: Option<ImageFormat>,
+        /// write the header even if the key decrypts the image to noise, for images that are noise to begin with
+        #[clap(long)]
+        force: bool,
+    },
+    /// find encrypted images made from the same original, without decrypting them,
+    /// by comparing the digests of the originals sealed in their headers
+    Dedupe {
+        /// the key the images are encrypted with
+        #[clap(value_parser = parse_key)]
+        key: u64,
+        /// encrypted images, or directories of them
+        #[clap(required = true)]
+        paths: Vec<String>,
+        /// also look in all subdirectories
+        #[clap(long)]
+        recursive: bool,
+    },
+    /// list the encrypted images that were tagged with all of the given tags, reading only their headers
+    Find {
+        /// the key the images are encrypted with
+        #[clap(value_parser = parse_key)]
+        key: u64,
+        /// a tag the images must have; can be repeated
+        #[clap(long = "tag", required = true, multiple_occurrences = true)]
+        tags: Vec<String>,
+        /// encrypted images, or directories of them
+        #[clap(required = true)]
+        paths: Vec<String>,
+        /// also look in all subdirectories
+        #[clap(long)]
+        recursive: bool,
+    },
+    /// irreversibly pixelate or blur parts of an image, or all of it if no region is given;
+    /// there is no key and the original can't be recovered
+    Redact {
+        /// image input path
+        input: String,
+        /// image output path
+        /// if omitted, input file is overwritten
+        output: Option<String>,
+        /// a region to redact, in the same syntax as `enc --region`; can be repeated
+        #[clap(long, multiple_occurrences = true)]
+        region: Vec<Shape>,
+        /// a JSON file listing regions to redact, in the same format as `enc --regions-json`
+        #[clap(long)]
+        regions_json: Option<String>,
+        /// how to redact the regions
+        #[clap(long, value_enum, default_value = "pixelate")]
+        method: RedactMethod,
+        /// side length in pixels of the blocks for `--method pixelate`
+        #[clap(long, default_value_t = 16)]
+        block_size: u32,
+        /// strength of `--method blur`, as the standard deviation in pixels
+        #[clap(long, default_value_t = 8.0)]
+        sigma: f32,
+    },
+    /// split an image into shares that each look like noise, to hand out to several parties;
+    /// there is no key, and only all of the shares together give the image back with `combine`
+    Split {
+        /// image input path
+        input: String,
+        /// how many shares to split it into
+        #[clap(value_parser = clap::value_parser!(u32).range(2..=MAX_SHARES as i64))]
+        shares: u32,
+        /// directory to write the shares into, named after the input like `photo-share-1-of-3.png`;
+        /// if omitted, they are written next to the input
+        output: Option<String>,
+    },
+    /// put an image split with `split` back together from all of its shares
+    Combine {
+        /// the shares, in any order
+        #[clap(required = true)]
+        shares: Vec<String>,
+        /// image output path
+        #[clap(long)]
+        output: String,
+    },
+    /// hide a file, encrypted with the key, in the lowest bits of the colors of a cover image,
+    /// which still looks the same; `reveal` gets it back. The cover has to stay losslessly encoded
+    Hide {
+        /// the key to hide it with
+        #[clap(value_parser = parse_key)]
+        key: u64,
+        /// cover image input path
+        cover: String,
+        /// the file to hide
+        payload: String,
+        /// image output path
+        /// if omitted, the cover is overwritten, or written next to it as a PNG if it is a JPEG
+        output: Option<String>,
+    },
+    /// get back a file hidden in an image with `hide`
+    Reveal {
+        /// the key it was hidden with
+        #[clap(value_parser = parse_key)]
+        key: u64,
+        /// the image it is hidden in
+        cover: String,
+        /// path to write the file to
+        output: String,
+    },
+    /// generate a random key and show its fingerprint
+    Keygen,
+    /// check whether a key decrypts an encrypted image, without decrypting or writing anything;
+    /// exits with 77 if it doesn't
+    #[clap(allow_missing_positional = true)]
+    Check {
+        /// the key, in decimal, as hex like `0x1f2e3d4c5b6a7988`, or as 8 bytes of base64
+        /// like `base64:Hy49TFtqeYg=`, or `-` or left out to type it in; with --passphrase, a file holding
+        /// the passphrase on its first line, or `-` or left out to type it in
+        key: Option<String>,
+        /// the encrypted image
+        input: String,
+        /// read the key, or with --passphrase the passphrase, from this environment variable
+        #[clap(long, value_name = "VAR")]
+        key_env: Option<String>,
+        /// check the key derived from the passphrase the image was encrypted with
+        #[clap(long)]
+        passphrase: bool,
+    },
+    /// show the encryption parameters stored in an encrypted image, without decrypting it
+    Info {
+        /// the encrypted image
+        input: String,
+    },
+    /// check which recipient's fingerprint a decrypted image carries
+    DetectFingerprint {
+        /// the decrypted image to check
+        image: String,
+        /// the recipient ids to check against
+        #[clap(required = true)]
+        recipients: Vec<String>,
+    },
+    /// check the files listed in a checksum manifest
+    VerifyManifest {
+        /// the manifest to check
+        manifest: String,
+    },
+    /// add "Encrypt with image_encryption" and "Decrypt with image_encryption" to the file manager's menu
+    /// for images: registry entries on Windows, desktop entries under "Open With" on Linux;
+    /// they work on the file in place and prompt for the key in a terminal
+    RegisterContextMenu {
+        /// remove the entries instead
+        #[clap(long)]
+        remove: bool,
+    },
+    /// check the cipher against the known-answer vectors every platform must reproduce bit for bit
+    SelfTest {
+        /// also print what this machine computes for every vector, as hex, so the output of two machines
+        /// can be compared line for line, or sent along with a report of a failing check
+        #[clap(long)]
+        cross_vectors: bool,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct CryptArgs {
+    /// image input path, or a directory of images to process all of
+    // not required, so KEY can be left out before it, see `shift_paths`
+    #[clap(default_value = "", hide_default_value = true)]
     input: String,
-    /// image output path
-    /// if omitted, input file is overwritten
+    /// image output path, or for a directory the directory to mirror it into;
+    /// if omitted, input file is overwritten, or replaced by one with the extension of the format
+    /// it is written in (an encrypted photo.jpg becomes photo.png)
     output: Option<String>,
+    /// when the input is a directory, also process the images in all its subdirectories
+    #[clap(long)]
+    recursive: bool,
+    /// name the output after the BLAKE3 hash of its pixels
+    /// (the ciphertext when encrypting, the plaintext when decrypting);
+    /// the output path, if given, is used as the directory to write into
+    #[clap(long)]
+    name_by_hash: bool,
+    /// record the SHA-256 digest of the output in a SHA256SUMS-style manifest
+    #[clap(long)]
+    manifest: Option<String>,
+    /// fail instead of silently losing information: lossy output formats,
+    /// metadata that isn't carried over, or color type conversions
+    #[clap(long)]
+    strict: bool,
+    /// decode the input with resource limits (at most 16384 pixels a side, 64 megapixels, 512 MiB of pixels),
+    /// checked before decoding, for images from untrusted sources; waiting for the decoder also gives up after
+    /// 10 seconds
+    #[clap(long)]
+    untrusted: bool,
+    /// a JSON file describing the encrypted regions, in the format of `--regions-json`:
+    /// written with the labels from `--regions-json` when encrypting,
+    /// and used instead of the regions recorded in the image when decrypting
+    #[clap(long)]
+    sidecar: Option<String>,
+    /// how hard to compress PNG output; by default encrypted images are stored uncompressed,
+    /// since ciphertext doesn't compress, and everything else is compressed fast
+    #[clap(long, value_enum)]
+    png_compression: Option<PngLevel>,
+    /// the filter PNG output is run through before compressing
+    #[clap(long, value_enum, default_value = "adaptive")]
+    png_filter: Filter,
+    /// the compression of TIFF output
+    #[clap(long, value_enum, default_value = "none")]
+    tiff_compression: TiffCodec,
+    /// the quality of JPEG output, from 1 to 100; anything under 100 loses more of the image
+    #[clap(long, default_value_t = 100, value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: u8,
+    /// print a report of the image once it is written: the bytes in and out, how long each phase took,
+    /// the cipher and anything that didn't go as asked; as JSON, it is all that is printed
+    #[clap(long, value_enum)]
+    report: Option<ReportFormat>,
+    /// print how long every phase took to stderr once the image is written, from decoding the file
+    /// through the steps of the cipher to encoding it again, to see whether the codec or the cipher
+    /// takes the time
+    #[clap(long)]
+    time: bool,
+    /// when the input is a directory, read and write at most this many megabytes per second,
+    /// so a job in the background doesn't keep the disk busy and the machine hot
+    #[clap(long, value_name = "MB/s", value_parser = parse_throughput)]
+    max_throughput: Option<f64>,
+    /// the most megabytes of memory an image may take; a TIFF that would take more is encrypted
+    /// a band at a time as with --stream, and anything else is refused instead of running out of memory
+    #[clap(long, value_name = "MB", value_parser = clap::value_parser!(u64).range(1..))]
+    max_memory: Option<u64>,
+    // how long deriving the key from a passphrase took, for --time
+    #[clap(skip)]
+    kdf_time: Option<Duration>,
 }
 
-fn main() {
-    let args = Args::parse();
+impl CryptArgs {
+    fn write_options(&self) -> WriteOptions {
+        WriteOptions {
+            png_compression: self.png_compression.map(PngCompression::from),
+            png_filter: self.png_filter.into(),
+            tiff_compression: self.tiff_compression.into(),
+            jpeg_quality: self.jpeg_quality,
+        }
+    }
+}
 
-    let mut img = match load_image(&args.input) {
-        Ok(val) => val,
+// how to encrypt, shared by every command that encrypts an image
+#[derive(Debug, clap::Args)]
+struct EncryptOptionArgs {
+    /// what generates the keystream and the permutation: the legacy generator is fast but predictable,
+    /// so its ciphertext can be broken; ChaCha20 is a secure stream cipher with a random nonce per image
+    #[clap(long, value_enum, default_value = "legacy")]
+    cipher: Algorithm,
+    /// preprocessing steps to run on the image before encrypting it, separated by commas, like
+    /// `resize=50%,grayscale,strip-exif`: `resize=` a geometry like `50%` or `800x600`, `crop=` a geometry
+    /// like `800x600+10+10`, `grayscale`, `rotate=90`, `180` or `270`, `flip=horizontal` or `vertical`,
+    /// and `strip-exif` to drop the metadata of the source; the decrypted image is the preprocessed one
+    #[clap(long, default_value = "", hide_default_value = true)]
+    pipeline: Pipeline,
+    /// convert the image to this color type before encrypting;
+    /// the original color type is restored on decryption
+    #[clap(long, value_enum)]
+    normalize: Option<Color>,
+    /// text to stamp on the image before encrypting it, so decrypted copies are traceable
+    #[clap(long, conflicts_with = "watermark-image")]
+    watermark_text: Option<String>,
+    /// image to overlay on the image before encrypting it
+    #[clap(long)]
+    watermark_image: Option<String>,
+    /// where to place the watermark
+    #[clap(long, value_enum, default_value = "bottom-right")]
+    watermark_position: Position,
+    /// watermark opacity, from 0 (invisible) to 1 (opaque)
+    #[clap(long, default_value_t = 0.5)]
+    watermark_opacity: f32,
+    /// embed an invisible fingerprint keyed by this recipient id before encrypting,
+    /// so leaked decrypted copies can be traced with `detect-fingerprint`
+    #[clap(long)]
+    fingerprint: Option<String>,
+    /// derive the cipher key from the image contents as well as the key,
+    /// so the same image always encrypts to the same file and can be deduplicated;
+    /// anyone holding two such files can tell whether they are the same image
+    #[clap(long)]
+    convergent: bool,
+    /// what the permutation moves around: single pixels diffuse best,
+    /// whole rows, columns or blocks are faster to shuffle but leave more of the image intact;
+    /// `bijection` moves single pixels with a keyed function instead of a table, taking no memory for it
+    #[clap(long, value_enum, default_value = "pixel")]
+    permutation_unit: Unit,
+    /// how the pixels are scrambled: `full` moves them anywhere in the image as `--permutation-unit` says;
+    /// `block` shuffles blocks and then the pixels within them, which is much faster on large images
+    #[clap(
+        long,
+        value_enum,
+        default_value = "full",
+        conflicts_with = "permutation-unit"
+    )]
+    scramble: Scramble,
+    /// side length in pixels of the blocks for `--permutation-unit block` and `--scramble block`
+    #[clap(long, default_value_t = 16)]
+    block_size: u32,
+    /// only encrypt this part of the image, as an ImageMagick-style geometry:
+    /// `WxH+X+Y` in pixels, or with percentages of the image size like `50%x25%+10+10`,
+    /// or as `X,Y,W,H`;
+    /// `ellipse:WxH+X+Y` for the ellipse inside a geometry, `polygon:X,Y;X,Y;X,Y` for a polygon;
+    /// can be repeated to encrypt several regions
+    #[clap(long, multiple_occurrences = true)]
+    region: Vec<Shape>,
+    /// a JSON file listing regions to encrypt, either as geometry strings
+    /// or as objects with x, y, width and height (in pixels, or percentage strings)
+    #[clap(long)]
+    regions_json: Option<String>,
+    /// a line of text to show in a strip along the encrypted image, like "ENCRYPTED, key 1a2b:3c4d";
+    /// the strip isn't encrypted and is removed on decryption
+    #[clap(long)]
+    label: Option<String>,
+    /// an image like a logo to show in the strip, before the label
+    #[clap(long)]
+    banner_image: Option<String>,
+    /// which edge of the encrypted image the strip goes along
+    #[clap(long, value_enum, default_value = "bottom")]
+    banner_edge: Edge,
+    /// thickness of the strip in pixels; by default it fits the label
+    #[clap(long)]
+    banner_size: Option<u32>,
+    /// text to put in a QR code at the end of the strip, like the URL of the decryption tool
+    /// or the key fingerprint; the strip is plaintext, so this must never be the key
+    #[clap(long)]
+    banner_qr: Option<String>,
+    /// for JPEG inputs, write a gray placeholder JPEG that keeps the EXIF, XMP and other metadata of the source,
+    /// with the encrypted image carried losslessly inside it, so the file can still be indexed
+    #[clap(long)]
+    jpeg_container: bool,
+    /// chain the pixels in independent chunks so encrypting and decrypting use every core,
+    /// which is much faster on large photos; the output can't be decrypted by older versions
+    #[clap(long)]
+    parallel: bool,
+    /// a tag to find the image by with `find`, like "vacation"; stored as a token that only
+    /// the key can match, and lost when the image is rekeyed; can be repeated
+    #[clap(long = "tag", multiple_occurrences = true)]
+    tags: Vec<String>,
+    /// write the encrypted pixels of a JPEG as a JPEG, which loses some of them so it never decrypts exactly;
+    /// by default it is written as a PNG, under a .png extension unless an output path is given,
+    /// and decrypting it writes a JPEG again
+    #[clap(long, conflicts_with = "jpeg-container")]
+    keep_format: bool,
+    /// only shuffle the pixels without changing their values, so the output has exactly the same
+    /// color histogram; the picture is hidden but its colors are not, so this isn't encryption proper
+    #[clap(long, conflicts_with = "parallel")]
+    permute_only: bool,
+    /// the scheme that scrambles the pixels: the default XOR chain, or one of the classic schemes
+    /// from the literature, for comparing them with `audit`; those are weaker and not for real use.
+    /// The scheme is recorded in the output, so `dec` needs no flag to undo it
+    #[clap(
+        long,
+        value_enum,
+        default_value = "xor-chain",
+        conflicts_with_all = &["permute-only", "parallel"]
+    )]
+    scramble_algorithm: ScrambleScheme,
+    /// run the permutation and diffusion this many times, each round with a key derived from the key,
+    /// for images where a single pass still shows some of their structure; every round takes as long as
+    /// the first, and `dec` undoes as many as the output records
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=MAX_ROUNDS as i64))]
+    rounds: u32,
+    /// make the ciphertext look like film grain or Gaussian noise around mid gray instead of uniform static,
+    /// for putting it where static would draw attention; the output gets a few rows taller
+    #[clap(long, value_enum, conflicts_with_all = &["region", "regions-json", "permute-only"])]
+    noise: Option<Noise>,
+    /// experimental: hide the encrypted image in the low bits of a generated picture of clouds,
+    /// written as a PNG that looks like an ordinary photo; `dec` finds the image in it again.
+    /// Only a casual look is fooled, and any lossy re-encoding of the picture destroys the image
+    #[clap(long, conflicts_with_all = &["jpeg-container", "keep-format"])]
+    disguise: bool,
+    /// channels to leave as they are, as letters: `r`, `g`, `b`, `l` for gray and `a` for alpha,
+    /// like `a` to keep the transparency of the image for compositing; only the others are encrypted
+    #[clap(
+        long,
+        conflicts_with = "noise",
+        value_parser = |letters: &str| parse_channels(letters).map(|_| letters.to_string())
+    )]
+    skip_channels: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct EncArgs {
+    /// the encryption key, in decimal, as hex like `0x1f2e3d4c5b6a7988`,
+    /// or as 8 bytes of base64 like `base64:Hy49TFtqeYg=`, or `-` or left out to type it in;
+    /// with --passphrase, a file holding the passphrase on its first line, or `-` or left out to type it in
+    key: Option<String>,
+    #[clap(flatten)]
+    common: CryptArgs,
+    /// read the key, or with --passphrase the passphrase, from this environment variable
+    /// instead of a KEY argument, so it doesn't show up in the process list or the shell history
+    #[clap(long, value_name = "VAR")]
+    key_env: Option<String>,
+    /// derive the key from a passphrase instead, with PBKDF2 and a random salt stored in the output
+    #[clap(long)]
+    passphrase: bool,
+    #[clap(flatten)]
+    options: EncryptOptionArgs,
+    /// don't write anything, only check whether encrypting to the output path with these options,
+    /// loading the file back and decrypting it gives exactly the same pixels, and which step wouldn't
+    #[clap(long)]
+    check_exact: bool,
+    /// for baseline JPEG inputs, encrypt the quantized DCT coefficients instead of the pixels,
+    /// so the output is a valid JPEG of the same quality that decrypts to exactly the original;
+    /// none of the options that change the pixels apply
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "normalize", "watermark-text", "watermark-image", "fingerprint", "convergent",
+            "region", "regions-json", "label", "banner-image", "banner-qr", "jpeg-container",
+            "name-by-hash", "sidecar", "strict", "untrusted", "jpeg-quality", "passphrase", "cipher",
+            "tags", "parallel", "keep-format", "permute-only", "noise", "disguise", "skip-channels",
+            "check-exact", "scramble-algorithm", "rounds",
+        ]
+    )]
+    dct: bool,
+    /// for 8-bit TIFF inputs too big to fit in memory, encrypt a band of rows at a time
+    /// with the legacy cipher, shuffling pixels only within their band;
+    /// the output is an uncompressed TIFF, and none of the options that change the pixels apply
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "normalize", "watermark-text", "watermark-image", "fingerprint", "convergent",
+            "region", "regions-json", "label", "banner-image", "banner-qr", "jpeg-container",
+            "name-by-hash", "sidecar", "strict", "untrusted", "jpeg-quality", "passphrase", "cipher",
+            "tags", "parallel", "keep-format", "permute-only", "noise", "disguise", "skip-channels", "dct",
+            "check-exact",
+            "permutation-unit",
+            "block-size",
+            "scramble",
+            "scramble-algorithm",
+            "rounds",
+        ]
+    )]
+    stream: bool,
+    /// `arnold` scrambles the pixels with the classic Arnold cat map instead of encrypting them,
+    /// padding the image to a square first; it takes no KEY, --iterations is all that undoes it,
+    /// so it only hides the picture from a casual look
+    #[clap(long, value_enum, default_value = "cipher")]
+    algo: Algo,
+    /// how many times to apply the Arnold cat map; `dec` needs the same number to undo it
+    #[clap(
+        long,
+        required_if_eq("algo", "arnold"),
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = &[
+            "normalize", "watermark-text", "watermark-image", "fingerprint", "convergent",
+            "region", "regions-json", "label", "banner-image", "banner-qr", "jpeg-container",
+            "name-by-hash", "sidecar", "strict", "untrusted", "jpeg-quality", "passphrase", "cipher",
+            "tags", "parallel", "keep-format", "permute-only", "noise", "disguise", "skip-channels",
+            "check-exact", "scramble-algorithm", "permutation-unit", "block-size", "scramble",
+            "dct", "stream", "rounds", "key-env",
+        ]
+    )]
+    iterations: Option<u32>,
+}
+
+// the regions given on the command line followed by the ones in the JSON file
+fn regions(shapes: &[Shape], json: Option<&str>) -> Result<Vec<Region>, Box<dyn Error>> {
+    let mut regions = shapes.iter().cloned().map(Region::from).collect::<Vec<_>>();
+    if let Some(path) = json {
+        regions.extend(parse_regions_json(&fs::read_to_string(path)?)?);
+    }
+    Ok(regions)
+}
+
+// the plaintext strip, if anything is asked to go in it
+fn banner(args: &EncryptOptionArgs, key: u64) -> Result<Option<Banner>, Box<dyn Error>> {
+    if args.label.is_none()
+        && args.banner_image.is_none()
+        && args.banner_size.is_none()
+        && args.banner_qr.is_none()
+    {
+        return Ok(None);
+    }
+    if let Some(text) = &args.banner_qr {
+        if text.contains(&key.to_string()) {
+            return Err("the QR code would give away the key".into());
+        }
+    }
+    Ok(Some(Banner {
+        edge: match args.banner_edge {
+            Edge::Top => BannerEdge::Top,
+            Edge::Bottom => BannerEdge::Bottom,
+            Edge::Left => BannerEdge::Left,
+            Edge::Right => BannerEdge::Right,
+        },
+        size: args.banner_size,
+        text: args.label.clone(),
+        logo: args.banner_image.as_ref().map(load_image).transpose()?,
+        qr: args
+            .banner_qr
+            .as_ref()
+            .map(|text| QrCode::encode(text.as_bytes()))
+            .transpose()?,
+    }))
+}
+
+fn parse_format(value: &str) -> Result<ImageFormat, String> {
+    ImageFormat::from_extension(value).ok_or_else(|| {
+        format!(
+            "unknown image format {}, expected an extension like png",
+            value
+        )
+    })
+}
+
+fn parse_throughput(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(mb) if mb.is_finite() && mb * 1e6 >= 1.0 => Ok(mb),
+        _ => Err("expected a number of megabytes per second, like 20 or 0.5".to_string()),
+    }
+}
+
+// channels given as letters, like "a" or "rb"
+fn parse_channels(letters: &str) -> Result<Vec<Channel>, String> {
+    letters
+        .chars()
+        .filter(|c| !matches!(c, ',' | ' '))
+        .map(|c| match c.to_ascii_lowercase() {
+            'r' => Ok(Channel::Red),
+            'g' => Ok(Channel::Green),
+            'b' => Ok(Channel::Blue),
+            'l' => Ok(Channel::Luma),
+            'a' => Ok(Channel::Alpha),
+            _ => Err(format!("unknown channel {}, expected r, g, b, l or a", c)),
+        })
+        .collect()
+}
+
+fn encrypt_options(args: &EncryptOptionArgs, key: u64) -> Result<EncryptOptions, Box<dyn Error>> {
+    let content = match (&args.watermark_text, &args.watermark_image) {
+        (Some(text), _) => Some(WatermarkContent::Text(text.clone())),
+        (None, Some(path)) => Some(WatermarkContent::Overlay(Box::new(load_image(path)?))),
+        (None, None) => None,
+    };
+
+    let regions = regions(&args.region, args.regions_json.as_deref())?;
+
+    Ok(EncryptOptions {
+        pipeline: args.pipeline.clone(),
+        normalize: args.normalize.map(ColorType::from),
+        watermark: content.map(|content| Watermark {
+            content,
+            position: args.watermark_position.into(),
+            opacity: args.watermark_opacity,
+        }),
+        fingerprint: args.fingerprint.clone(),
+        convergent: args.convergent,
+        permutation_unit: match (args.scramble, args.permutation_unit) {
+            (Scramble::Block, _) => PermutationUnit::Tile(args.block_size.max(1)),
+            (Scramble::Full, Unit::Pixel) => PermutationUnit::Pixel,
+            (Scramble::Full, Unit::Row) => PermutationUnit::Row,
+            (Scramble::Full, Unit::Column) => PermutationUnit::Column,
+            (Scramble::Full, Unit::Block) => PermutationUnit::Block(args.block_size.max(1)),
+            (Scramble::Full, Unit::Bijection) => PermutationUnit::Bijection,
+        },
+        regions,
+        banner: banner(args, key)?,
+        jpeg_container: args.jpeg_container,
+        kdf: None,
+        cipher: match args.cipher {
+            Algorithm::Legacy => Cipher::Legacy,
+            Algorithm::Chacha20 => Cipher::ChaCha20,
+        },
+        tags: args.tags.clone(),
+        parallel: args.parallel,
+        keep_lossy_format: args.keep_format,
+        permute_only: args.permute_only,
+        scramble_algorithm: match args.scramble_algorithm {
+            ScrambleScheme::XorChain => ScrambleAlgorithm::XorChain,
+            ScrambleScheme::ArnoldCat => ScrambleAlgorithm::ArnoldCat,
+            ScrambleScheme::ChaoticMap => ScrambleAlgorithm::ChaoticMap,
+            ScrambleScheme::BitPlane => ScrambleAlgorithm::BitPlane,
+        },
+        noise: args.noise.map(NoiseShape::from),
+        disguise: args.disguise,
+        rounds: args.rounds,
+        hash_ciphertext: false,
+        skip_channels: args
+            .skip_channels
+            .as_deref()
+            .map(parse_channels)
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+#[derive(Debug, clap::Args)]
+struct DecArgs {
+    /// the decryption key, in decimal, as hex like `0x1f2e3d4c5b6a7988`,
+    /// or as 8 bytes of base64 like `base64:Hy49TFtqeYg=`, or `-` or left out to type it in;
+    /// with --try-keys, a file of candidate keys instead
+    key: Option<String>,
+    #[clap(flatten)]
+    common: CryptArgs,
+    /// read the key, or with --passphrase the passphrase, from this environment variable
+    /// instead of a KEY argument, so it doesn't show up in the process list or the shell history
+    #[clap(long, value_name = "VAR", conflicts_with = "try-keys")]
+    key_env: Option<String>,
+    /// check every key in the KEY file, one per line, against the key check in the image header
+    /// and report which one the image was encrypted with; nothing is decrypted unless --write is passed
+    #[clap(long)]
+    try_keys: bool,
+    /// decrypt the image with the key --try-keys found
+    #[clap(long, requires = "try-keys")]
+    write: bool,
+    /// derive the key from the passphrase the image was encrypted with,
+    /// read from the KEY file or the --key-env variable, or typed in
+    #[clap(long, conflicts_with = "try-keys")]
+    passphrase: bool,
+    /// for an image scrambled with `enc --algo arnold`, how many times the Arnold cat map was applied,
+    /// which is what undoes it instead of a KEY
+    #[clap(long, conflicts_with_all = &["try-keys", "passphrase", "key-env"])]
+    iterations: Option<u32>,
+}
+
+// type a line without it showing on the terminal
+fn prompt_hidden(prompt: &str) -> io::Result<String> {
+    eprint!("{}", prompt);
+    // stty works on the terminal it gets as stdin; when stdin isn't one there is nothing to hide
+    let hidden = io::stdin().is_terminal()
+        && process::Command::new("stty")
+            .arg("-echo")
+            .status()
+            .is_ok_and(|status| status.success());
+    let mut line = String::new();
+    let result = io::stdin().read_line(&mut line);
+    if hidden {
+        let _ = process::Command::new("stty").arg("echo").status();
+        eprintln!();
+    }
+    result?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// the passphrase in the variable --key-env names, on the first line of a file, or typed in
+// if the path is `-` or left out; a typed passphrase for a new encryption is asked for twice,
+// so a typo doesn't lock the image
+fn read_passphrase(
+    source: Option<&str>,
+    key_env: Option<&str>,
+    confirm: bool,
+) -> Result<String, ImageEncryptionError> {
+    let passphrase = if let Some(name) = key_env {
+        env::var(name).map_err(|_| {
+            ImageEncryptionError::Invalid(format!("the environment variable {} isn't set", name))
+        })?
+    } else if let Some(path) = source.filter(|&source| source != "-") {
+        let contents = fs::read_to_string(path)?;
+        contents.lines().next().unwrap_or_default().to_string()
+    } else {
+        let passphrase = prompt_hidden("passphrase: ")?;
+        if confirm
+            && io::stdin().is_terminal()
+            && prompt_hidden("repeat passphrase: ")? != passphrase
+        {
+            return Err(ImageEncryptionError::Invalid(
+                "the passphrases don't match".to_string(),
+            ));
+        }
+        passphrase
+    };
+    if passphrase.is_empty() {
+        return Err(ImageEncryptionError::Invalid(
+            "the passphrase is empty".to_string(),
+        ));
+    }
+    Ok(passphrase)
+}
+
+// the key in the variable --key-env names, as given, or typed in if it is `-` or left out;
+// like a passphrase, a typed key for a new encryption is asked for twice
+fn read_key(
+    source: Option<&str>,
+    key_env: Option<&str>,
+    confirm: bool,
+) -> Result<u64, ImageEncryptionError> {
+    let source = match (key_env, source) {
+        (Some(name), _) => KeySource::Env(name.to_string()),
+        (None, Some(key)) if key != "-" => KeySource::Text(key.to_string()),
+        (None, _) => {
+            let key = prompt_hidden("key: ")?;
+            if confirm && io::stdin().is_terminal() && prompt_hidden("repeat key: ")? != key {
+                return Err(ImageEncryptionError::Invalid(
+                    "the keys don't match".to_string(),
+                ));
+            }
+            KeySource::Text(key)
+        }
+    };
+    source.resolve()
+}
+
+// clap takes the first path for KEY when it is left out: a single argument is the input, with the key
+// typed in, and when the key comes from elsewhere, like --key-env or --iterations, the first argument
+// is the input and the second the output
+fn shift_paths(key: &mut Option<String>, key_elsewhere: bool, common: &mut CryptArgs) {
+    if common.input.is_empty() {
+        let Some(input) = key.take() else {
+            eprintln!("no INPUT path given");
+            std::process::exit(2);
+        };
+        common.input = input;
+    } else if key_elsewhere {
+        if common.output.is_some() {
+            eprintln!("a KEY argument can't be given along with --key-env or --iterations");
+            std::process::exit(2);
+        }
+        let input = key.take().unwrap_or_default();
+        common.output = Some(std::mem::replace(&mut common.input, input));
+    }
+}
+
+// the key for decrypting an image encrypted with a passphrase, derived with the salt in its header
+fn passphrase_key(
+    source: Option<&str>,
+    key_env: Option<&str>,
+    input: &str,
+) -> Result<(u64, Duration), ImageEncryptionError> {
+    let kdf = read_header(input)?
+        .and_then(|header| header.kdf)
+        .ok_or_else(|| {
+            ImageEncryptionError::Unsupported(format!(
+                "{} wasn't encrypted with a passphrase",
+                input
+            ))
+        })?;
+    let passphrase = read_passphrase(source, key_env, false)?;
+    let started = Instant::now();
+    Ok((kdf.derive_key(&passphrase), started.elapsed()))
+}
+
+// the first key in the file that matches the key check in the header of the image, if any;
+// blank lines and lines starting with `#` are skipped
+fn try_keys(keys: &str, input: &str) -> Result<Option<u64>, ImageEncryptionError> {
+    let header = read_header(input)?.ok_or(ImageEncryptionError::NotEncrypted)?;
+    let keys = fs::read_to_string(keys)?;
+
+    let mut tried = 0;
+    for (i, line) in keys.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let key = match parse_key(line) {
+            Ok(key) => key,
+            Err(err) => {
+                eprintln!("line {}: {}", i + 1, err);
+                continue;
+            }
+        };
+        tried += 1;
+        match header.check_key(key) {
+            Some(true) => {
+                println!("the key on line {} matches", i + 1);
+                return Ok(Some(key));
+            }
+            Some(false) => {}
+            None => {
+                return Err(ImageEncryptionError::Unsupported(
+                    "the image header has no key check to try keys against".to_string(),
+                ))
+            }
+        }
+    }
+    println!("none of the {} keys match", tried);
+    Ok(None)
+}
+
+// exit codes from sysexits.h, so a script can tell a missing file from a wrong key from a broken file
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_NOINPUT: i32 = 66;
+const EX_UNAVAILABLE: i32 = 69;
+const EX_IOERR: i32 = 74;
+const EX_NOPERM: i32 = 77;
+
+fn exit_code(err: &ImageEncryptionError) -> i32 {
+    match err {
+        ImageEncryptionError::Io(err) if err.kind() == io::ErrorKind::NotFound => EX_NOINPUT,
+        ImageEncryptionError::Io(_) => EX_IOERR,
+        ImageEncryptionError::BadKey(_) | ImageEncryptionError::Invalid(_) => EX_USAGE,
+        ImageEncryptionError::AuthFailure
+        | ImageEncryptionError::WrongKey
+        | ImageEncryptionError::Rekey(RekeyError::Tampered | RekeyError::Implausible)
+        | ImageEncryptionError::Stego(StegoError::NotFound) => EX_NOPERM,
+        ImageEncryptionError::UnsupportedColor(_)
+        | ImageEncryptionError::Unsupported(_)
+        | ImageEncryptionError::Dct(DctError::Unsupported(_))
+        | ImageEncryptionError::Upload(_) => EX_UNAVAILABLE,
+        _ => EX_DATAERR,
+    }
+}
+
+// report the error and exit with its code
+fn fail(err: impl Into<ImageEncryptionError>) -> ! {
+    clear_progress();
+    let err = err.into();
+    eprintln!("{}", err);
+    process::exit(exit_code(&err))
+}
+
+// exit with the code of an error that was already reported, like the first of a batch of them
+fn exit_with(err: &ImageEncryptionError) -> ! {
+    clear_progress();
+    process::exit(exit_code(err))
+}
+
+// the same for the errors of reading the options, which are mistakes in the arguments
+// unless a file they name couldn't be read
+fn fail_options(err: Box<dyn Error>) -> ! {
+    let err = match err.downcast::<ImageEncryptionError>() {
+        Ok(err) => fail(*err),
+        Err(err) => err,
+    };
+    match err.downcast::<io::Error>() {
+        Ok(err) => fail(*err),
         Err(err) => {
             eprintln!("{}", err);
+            process::exit(EX_USAGE)
+        }
+    }
+}
+
+// with --strict, nothing is written if encrypting would lose any of the image
+fn refuse_losses(losses: &[InformationLoss]) {
+    for loss in losses {
+        eprintln!("strict: {}", loss);
+    }
+    if !losses.is_empty() {
+        fail(ImageEncryptionError::Unsupported(
+            "--strict refuses to encrypt with any of the image lost".to_string(),
+        ));
+    }
+}
+
+// whether a progress bar is on the last line of stderr, for errors to clear it before they are printed
+static PROGRESS_DRAWN: AtomicBool = AtomicBool::new(false);
+
+fn clear_progress() {
+    if PROGRESS_DRAWN.swap(false, Ordering::Relaxed) {
+        eprint!("\r\x1b[K");
+    }
+}
+
+// how much of encrypting or decrypting a file is done, its phases making up equal parts
+fn image_fraction(mode: Mode, progress: Progress) -> f64 {
+    let phases: &[Phase] = match mode {
+        Mode::Enc => &[
+            Phase::Read,
+            Phase::Decode,
+            Phase::Prepare,
+            Phase::Encrypt,
+            Phase::Finish,
+            Phase::Encode,
+            Phase::Write,
+        ],
+        Mode::Dec => &[
+            Phase::Read,
+            Phase::Decode,
+            Phase::Verify,
+            Phase::Decrypt,
+            Phase::Finish,
+            Phase::Encode,
+            Phase::Write,
+        ],
+    };
+    let index = phases.iter().position(|&phase| phase == progress.phase);
+    (index.unwrap_or(0) as f64 + progress.fraction()) / phases.len() as f64
+}
+
+// a progress bar with an estimate of the time left, redrawn in place on stderr when it is a terminal;
+// nothing is drawn for operations that are over in a moment
+struct ProgressBar {
+    enabled: bool,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+impl ProgressBar {
+    const WIDTH: usize = 30;
+
+    fn new() -> Self {
+        ProgressBar {
+            enabled: io::stderr().is_terminal(),
+            started: Instant::now(),
+            drawn: None,
+        }
+    }
+
+    fn image(&mut self, mode: Mode, progress: Progress) {
+        self.draw(image_fraction(mode, progress), &progress.phase.to_string());
+    }
+
+    fn directory(&mut self, mode: Mode, progress: DirectoryProgress) {
+        let fraction = match progress.files {
+            0 => 1.0,
+            files => {
+                (progress.files_done as f64 + image_fraction(mode, progress.current)) / files as f64
+            }
+        };
+        let label = format!(
+            "{}/{} {}",
+            (progress.files_done + 1).min(progress.files),
+            progress.files,
+            progress.current.phase
+        );
+        self.draw(fraction, &label);
+    }
+
+    fn draw(&mut self, fraction: f64, label: &str) {
+        let now = Instant::now();
+        let elapsed = now - self.started;
+        if !self.enabled
+            || elapsed < Duration::from_millis(300)
+            || self
+                .drawn
+                .is_some_and(|drawn| now - drawn < Duration::from_millis(100))
+        {
             return;
         }
+        self.drawn = Some(now);
+        PROGRESS_DRAWN.store(true, Ordering::Relaxed);
+
+        let fraction = fraction.clamp(0.0, 1.0);
+        let filled = (fraction * Self::WIDTH as f64) as usize;
+        let eta = if fraction > 0.0 {
+            let left = elapsed.mul_f64((1.0 - fraction) / fraction).as_secs();
+            format!(", {}:{:02} left", left / 60, left % 60)
+        } else {
+            String::new()
+        };
+        eprint!(
+            "\r[{}{}] {:3.0}% {}{}\x1b[K",
+            "#".repeat(filled),
+            "-".repeat(Self::WIDTH - filled),
+            fraction * 100.0,
+            label,
+            eta
+        );
+    }
+
+    fn finish(&mut self) {
+        self.drawn = None;
+        clear_progress();
+    }
+}
+
+// refuse options that can't apply to the image, which would otherwise be quietly ignored
+fn check_options(img: &Image, options: &EncryptOptions) {
+    if let Some(region) = options
+        .regions
+        .iter()
+        .find(|region| region.shape.resolve(img.width(), img.height()).is_none())
+    {
+        fail(ImageEncryptionError::Invalid(format!(
+            "region {} is outside the image",
+            region.shape
+        )));
+    }
+
+    // the channels are those of the color type the image is encrypted in
+    let color = options.normalize.unwrap_or(img.color());
+    let mut skipped = options
+        .skip_channels
+        .iter()
+        .filter_map(|channel| channel.index(color))
+        .collect::<Vec<_>>();
+    skipped.sort_unstable();
+    skipped.dedup();
+    if !skipped.is_empty() && skipped.len() == color.channel_count() as usize {
+        fail(ImageEncryptionError::Invalid(
+            "--skip-channels leaves none of the channels of the image to encrypt".to_string(),
+        ));
+    }
+}
+
+fn crypt(mode: Mode, key: u64, args: CryptArgs, options: &EncryptOptions) {
+    if Path::new(&args.input).is_dir() {
+        return crypt_directory(mode, key, args, options);
+    }
+    if is_animated(&args.input) {
+        return crypt_animation(mode, key, args, options);
+    }
+    if let Some(budget) = args.max_memory.map(|mb| mb * 1_000_000) {
+        let needed = match estimate_working_set(&args.input) {
+            Ok(needed) => needed,
+            Err(err) => fail(err),
+        };
+        if needed > budget {
+            let tiff = ImageFormat::from_path(&args.input).ok() == Some(ImageFormat::Tiff);
+            if mode == Mode::Dec || !tiff {
+                fail(LimitError::MemoryBudget { needed, budget });
+            }
+            eprintln!(
+                "note: {} would take about {} MB of memory, so it is streamed a band at a time \
+                 with the legacy cipher and none of the other encryption options",
+                args.input,
+                needed.div_ceil(1_000_000)
+            );
+            return crypt_stream(mode, key, args);
+        }
+    }
+    let load_options = if args.untrusted {
+        LoadOptions::untrusted()
+    } else {
+        LoadOptions::default()
     };
+    let mut bar = ProgressBar::new();
+    // reading the file is all that happens before decoding it starts
+    let load_started = Instant::now();
+    let mut decode_started = None;
+    let mut layers = match load_layers_with_progress(&args.input, &load_options, |progress| {
+        if progress.phase == Phase::Decode {
+            decode_started.get_or_insert_with(Instant::now);
+        }
+        bar.image(mode, progress)
+    }) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+    let decode_started = decode_started.unwrap_or(load_started);
+    let load_phases = [
+        (Phase::Read, decode_started - load_started),
+        (Phase::Decode, decode_started.elapsed()),
+    ];
+    bar.finish();
+    if layers.len() > 1 {
+        return crypt_layers(mode, key, args, options, layers);
+    }
+    let mut img = layers.remove(0);
 
-    match args.mode {
-        Mode::Enc => encrypt_image(&mut img, args.key),
-        Mode::Dec => decrypt_image(&mut img, args.key),
+    // preprocess here rather than when encrypting, so the checks below are of the image it gives
+    let preprocessed;
+    let mut skipped_steps = Vec::new();
+    let options = match mode {
+        Mode::Enc if !options.pipeline.is_empty() => {
+            skipped_steps = options.pipeline.apply(&mut img);
+            preprocessed = EncryptOptions {
+                pipeline: Pipeline::default(),
+                ..options.clone()
+            };
+            &preprocessed
+        }
+        _ => options,
+    };
+
+    if args.strict {
+        refuse_losses(&information_loss(&img, options));
+    }
+
+    check_options(&img, options);
+
+    let mut report = match mode {
+        Mode::Enc => {
+            let report = encrypt_image_with_progress(&mut img, key, options, |progress| {
+                bar.image(mode, progress)
+            });
+            bar.finish();
+            if args.report != Some(ReportFormat::Json) {
+                print_fingerprint(key);
+            }
+            if let Some(sidecar) = &args.sidecar {
+                if let Err(err) = write_sidecar(sidecar, &img, options) {
+                    fail(err);
+                }
+            }
+            report
+        }
+        Mode::Dec => {
+            if let Some(sidecar) = &args.sidecar {
+                if let Err(err) = read_sidecar(sidecar, &mut img) {
+                    fail_options(err);
+                }
+            }
+            let result =
+                decrypt_image_with_progress(&mut img, key, |progress| bar.image(mode, progress));
+            match result {
+                Ok(report) => report,
+                Err(err) => fail(err),
+            }
+        }
+    };
+    let skipped_steps = skipped_steps
+        .iter()
+        .map(|step| OperationWarning::StepSkipped(step.to_string()));
+    report.warnings.splice(0..0, skipped_steps);
+
+    let write_options = args.write_options();
+    let mut renamed_input = None;
+    let output = if args.name_by_hash {
+        let dir = match args.output {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(&args.input)
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+        };
+        // encrypting may have hashed the ciphertext already
+        let name = match &report.content_hash {
+            Some(hash) => format!("{}.{}", hash, img.format().extensions_str()[0]),
+            None => content_addressed_name(&img),
+        };
+        dir.join(name)
+    } else if let Some(output) = args.output {
+        let output = PathBuf::from(output);
+        if let Ok(format) = ImageFormat::from_path(&output) {
+            if format != img.format() {
+                eprintln!(
+                    "note: {} is written as {:?}, the format of the image",
+                    output.display(),
+                    img.format()
+                );
+            }
+        }
+        output
+    } else {
+        // the image replaces the input, under the extension of the format it is written in
+        let output = path_for_format(Path::new(&args.input), img.format());
+        if output != Path::new(&args.input) {
+            if output.exists() {
+                fail(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", output.display()),
+                ));
+            }
+            renamed_input = Some(args.input.clone());
+        }
+        output
+    };
+
+    let result = write_image_with_progress(&output, img, &write_options, |progress| {
+        bar.image(mode, progress)
+    });
+    bar.finish();
+    let report = match result {
+        Ok(written) => report.then(written),
+        Err(err) => fail(err),
+    };
+    if let Some(input) = renamed_input {
+        if let Err(err) = fs::remove_file(&input) {
+            fail(err);
+        }
+        eprintln!("note: {} is now {}", input, output.display());
     }
+    match args.report {
+        Some(ReportFormat::Text) => println!("{}", report),
+        Some(ReportFormat::Json) => println!("{}", report.to_json()),
+        None => {
+            for warning in &report.warnings {
+                eprintln!("warning: {}", warning);
+            }
+        }
+    }
+    if args.time {
+        let kdf = args.kdf_time.map(|elapsed| (Phase::Kdf, elapsed));
+        let phases = kdf.into_iter().chain(load_phases).collect();
+        print_times(
+            &OperationReport {
+                phases,
+                ..Default::default()
+            }
+            .then(report),
+        );
+    }
+
+    if let Some(manifest) = args.manifest {
+        if let Err(err) = add_manifest_entry(manifest, &output) {
+            eprintln!("{}", err)
+        }
+    }
+}
+
+// every phase with its share of the total, and the steps of the cipher under the phase they are part of
+fn print_times(report: &OperationReport) {
+    let total = report.total_time();
+    let share = |elapsed: Duration| match total.is_zero() {
+        true => 0.0,
+        false => elapsed.as_secs_f64() / total.as_secs_f64() * 100.0,
+    };
+    for &(phase, elapsed) in &report.phases {
+        eprintln!(
+            "{:<14}{:>10.1?} {:>5.1}%",
+            phase.to_string(),
+            elapsed,
+            share(elapsed)
+        );
+        if let Phase::Encrypt | Phase::Decrypt = phase {
+            for &(step, elapsed) in &report.steps {
+                eprintln!(
+                    "  {:<12}{:>10.1?} {:>5.1}%",
+                    step.to_string(),
+                    elapsed,
+                    share(elapsed)
+                );
+            }
+        }
+    }
+    eprintln!("{:<14}{:>10.1?}", "total", total);
+}
+
+// only directories are paced, a single image is done as fast as it goes
+fn check_max_throughput(args: &CryptArgs) {
+    if args.max_throughput.is_some() && !Path::new(&args.input).is_dir() {
+        fail(ImageEncryptionError::Invalid(
+            "--max-throughput only applies to directories".to_string(),
+        ));
+    }
+}
+
+fn crypt_directory(mode: Mode, key: u64, args: CryptArgs, options: &EncryptOptions) {
+    if args.name_by_hash
+        || args.sidecar.is_some()
+        || args.strict
+        || (args.report.is_some() || args.time)
+    {
+        fail(ImageEncryptionError::Invalid(
+            "--name-by-hash, --sidecar, --strict, --report and --time don't apply to directories"
+                .to_string(),
+        ));
+    }
+    let options = DirectoryOptions {
+        output: args.output.as_ref().map(PathBuf::from),
+        recursive: args.recursive,
+        load: if args.untrusted {
+            LoadOptions::untrusted()
+        } else {
+            LoadOptions::default()
+        },
+        encrypt: options.clone(),
+        write: args.write_options(),
+        max_throughput: args.max_throughput.map(|mb| (mb * 1e6) as u64),
+        max_memory: args.max_memory.map(|mb| mb * 1_000_000),
+    };
+    let mut bar = ProgressBar::new();
+    let outcomes =
+        match process_directory_with_progress(&args.input, mode, key, &options, |progress| {
+            bar.directory(mode, progress)
+        }) {
+            Ok(outcomes) => outcomes,
+            Err(err) => fail(err),
+        };
+    bar.finish();
+    if let Mode::Enc = mode {
+        print_fingerprint(key);
+    }
+
+    let mut failures = 0;
+    for outcome in &outcomes {
+        match &outcome.result {
+            Ok(report) => {
+                println!("{}: {}", outcome.input.display(), outcome.output.display());
+                for warning in &report.warnings {
+                    eprintln!("warning: {}: {}", outcome.input.display(), warning);
+                }
+                if let Some(manifest) = &args.manifest {
+                    if let Err(err) = add_manifest_entry(manifest, &outcome.output) {
+                        eprintln!("{}", err)
+                    }
+                }
+            }
+            Err(err) => {
+                failures += 1;
+                println!("{}: {}", outcome.input.display(), err);
+            }
+        }
+    }
+
+    println!(
+        "{} of {} images {}",
+        outcomes.len() - failures,
+        outcomes.len(),
+        match mode {
+            Mode::Enc => "encrypted",
+            Mode::Dec => "decrypted",
+        }
+    );
+    if let Some(err) = outcomes
+        .iter()
+        .find_map(|outcome| outcome.result.as_ref().err())
+    {
+        exit_with(err);
+    }
+}
+
+// tell whether the image would come back bit for bit from being encrypted to the output, without writing it
+fn check_exact_cycle(key: u64, args: CryptArgs, options: &EncryptOptions) {
+    let load_options = if args.untrusted {
+        LoadOptions::untrusted()
+    } else {
+        LoadOptions::default()
+    };
+    let img = match load_image_with(&args.input, &load_options) {
+        Ok(img) => img,
+        Err(err) => fail(err),
+    };
+    let output = args.output.as_deref().unwrap_or(&args.input);
+    match check_exact(&img, output, key, options, &args.write_options()) {
+        Ok(()) => println!(
+            "bit-exact: {} would decrypt to exactly the pixels of the image",
+            output
+        ),
+        Err(downgrade) => {
+            println!("not bit-exact, {}", downgrade);
+            exit_with(&ImageEncryptionError::Unsupported(downgrade.to_string()));
+        }
+    }
+}
+
+// encrypt or decrypt every page of a multi-page TIFF or view of an MPO, each with a key of its own
+fn crypt_layers(
+    mode: Mode,
+    key: u64,
+    args: CryptArgs,
+    options: &EncryptOptions,
+    mut layers: Vec<Image>,
+) {
+    if args.name_by_hash || args.sidecar.is_some() || (args.report.is_some() || args.time) {
+        fail(ImageEncryptionError::Invalid(
+            "--name-by-hash, --sidecar, --report and --time don't apply to images with several layers".to_string(),
+        ));
+    }
+    if args.strict {
+        let losses = layers
+            .iter()
+            .flat_map(|layer| information_loss(layer, options))
+            .collect::<Vec<_>>();
+        refuse_losses(&losses);
+    }
+
+    match mode {
+        Mode::Enc => {
+            encrypt_layers(&mut layers, key, options);
+            print_fingerprint(key);
+        }
+        Mode::Dec => {
+            if let Err(err) = decrypt_layers(&mut layers, key) {
+                fail(err);
+            }
+        }
+    }
+
+    let output = args.output.unwrap_or(args.input);
+    if let Err(err) = write_layers(&output, &layers) {
+        fail(err);
+    }
+    if let Some(manifest) = args.manifest {
+        if let Err(err) = add_manifest_entry(manifest, &output) {
+            eprintln!("{}", err)
+        }
+    }
+}
 
-    if let Err(err) = write_image(args.output.unwrap_or(args.input), img) {
+// encrypt or decrypt every frame of an animated GIF, PNG or WebP, each with a key of its own;
+// encrypted frames are written as an animated PNG, since a GIF can't hold ciphertext
+fn crypt_animation(mode: Mode, key: u64, args: CryptArgs, options: &EncryptOptions) {
+    if args.name_by_hash || args.sidecar.is_some() || (args.report.is_some() || args.time) {
+        fail(ImageEncryptionError::Invalid(
+            "--name-by-hash, --sidecar, --report and --time don't apply to animations".to_string(),
+        ));
+    }
+    let mut animation = match load_animation(&args.input) {
+        Ok(animation) => animation,
+        Err(err) => fail(err),
+    };
+    if args.strict {
+        let losses = animation
+            .images
+            .iter()
+            .flat_map(|frame| information_loss(frame, options))
+            .collect::<Vec<_>>();
+        refuse_losses(&losses);
+    }
+
+    match mode {
+        Mode::Enc => {
+            encrypt_animation(&mut animation, key, options, true);
+            print_fingerprint(key);
+        }
+        Mode::Dec => {
+            if let Err(err) = decrypt_animation(&mut animation, key) {
+                fail(err);
+            }
+        }
+    }
+
+    let output = args.output.unwrap_or(args.input);
+    if let Err(err) = write_animation(&output, &animation) {
+        fail(err);
+    }
+    if let Some(manifest) = args.manifest {
+        if let Err(err) = add_manifest_entry(manifest, &output) {
+            eprintln!("{}", err)
+        }
+    }
+}
+
+// encrypt or decrypt a JPEG in the DCT domain, working on the file as it is instead of decoded pixels
+fn crypt_dct(mode: Mode, key: u64, args: CryptArgs) {
+    if args.name_by_hash || args.sidecar.is_some() || (args.report.is_some() || args.time) {
+        fail(ImageEncryptionError::Invalid("--name-by-hash, --sidecar, --report and --time don't apply to JPEGs encrypted in the DCT domain".to_string()));
+    }
+    let result = fs::read(&args.input)
+        .map_err(ImageEncryptionError::from)
+        .and_then(|bytes| {
+            Ok(match mode {
+                Mode::Enc => encrypt_jpeg_dct(&bytes, key)?,
+                Mode::Dec => decrypt_jpeg_dct(&bytes, key)?,
+            })
+        });
+    let bytes = match result {
+        Ok(bytes) => bytes,
+        Err(err) => fail(err),
+    };
+    if let Mode::Enc = mode {
+        print_fingerprint(key);
+    }
+
+    let output = args.output.unwrap_or(args.input);
+    if let Err(err) = fs::write(&output, bytes) {
+        fail(err);
+    }
+    if let Some(manifest) = args.manifest {
+        if let Err(err) = add_manifest_entry(manifest, &output) {
+            eprintln!("{}", err)
+        }
+    }
+}
+
+// encrypt or decrypt a TIFF a band at a time, never holding the whole image in memory;
+// the output goes to a temporary file next to it first, so the input can be the output
+fn crypt_stream(mode: Mode, key: u64, args: CryptArgs) {
+    if args.name_by_hash
+        || args.sidecar.is_some()
+        || (args.report.is_some() || args.time)
+        || Path::new(&args.input).is_dir()
+    {
+        fail(ImageEncryptionError::Invalid(
+            "--name-by-hash, --sidecar, --report, --time and directories don't apply to streamed TIFFs".to_string(),
+        ));
+    }
+    let output = PathBuf::from(args.output.as_ref().unwrap_or(&args.input));
+    let mut temp_name = OsString::from(".");
+    temp_name.push(output.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.tmp", process::id()));
+    let temp = output.with_file_name(temp_name);
+
+    let result = File::open(&args.input)
+        .and_then(|input| Ok((BufReader::new(input), BufWriter::new(File::create(&temp)?))))
+        .map_err(ImageEncryptionError::from)
+        .and_then(|(reader, mut writer)| {
+            match mode {
+                Mode::Enc => encrypt_stream(reader, &mut writer, key)?,
+                Mode::Dec => decrypt_stream(reader, &mut writer, key)?,
+            }
+            writer.flush()?;
+            Ok(fs::rename(&temp, &output)?)
+        });
+    if let Err(err) = result {
+        let _ = fs::remove_file(&temp);
+        fail(err);
+    }
+    if let Mode::Enc = mode {
+        print_fingerprint(key);
+    }
+    if let Some(manifest) = args.manifest {
+        if let Err(err) = add_manifest_entry(manifest, &output) {
+            eprintln!("{}", err)
+        }
+    }
+}
+
+// the regions as they were encrypted, in pixels, with the labels they were given
+fn write_sidecar(path: &str, img: &Image, options: &EncryptOptions) -> io::Result<()> {
+    let shapes = img.header().map_or(&[][..], |header| &header.regions);
+    // every region was checked to be inside the image, so they line up with the options
+    let regions = shapes
+        .iter()
+        .zip(&options.regions)
+        .map(|(shape, region)| Region {
+            shape: Shape::from(shape.clone()),
+            label: region.label.clone(),
+        })
+        .collect::<Vec<_>>();
+    fs::write(path, regions_json(&regions))
+}
+
+// decrypt the regions listed in a sidecar file, whatever the image header says
+fn read_sidecar(path: &str, img: &mut Image) -> Result<(), Box<dyn Error>> {
+    let shapes = parse_regions_json(&fs::read_to_string(path)?)?
+        .iter()
+        .map(|region| {
+            region
+                .shape
+                .resolve(img.width(), img.height())
+                .ok_or_else(|| format!("region {} is outside the image", region.shape))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // an image that lost its header has no tag to check the regions against
+    let mut header = img.header().cloned().unwrap_or(EncryptionHeader {
+        pixel_tag_only: true,
+        ..Default::default()
+    });
+    header.regions = shapes;
+    img.set_header(Some(header));
+    Ok(())
+}
+
+fn redact(input: String, output: Option<String>, regions: &[Region], redaction: Redaction) {
+    let mut img = match load_image(&input) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+
+    if let Some(region) = regions
+        .iter()
+        .find(|region| region.shape.resolve(img.width(), img.height()).is_none())
+    {
+        fail(ImageEncryptionError::Invalid(format!(
+            "region {} is outside the image",
+            region.shape
+        )));
+    }
+
+    redact_image(&mut img, regions, redaction);
+    if let Err(err) = write_image(output.unwrap_or(input), img) {
+        fail(err);
+    }
+}
+
+fn split(input: String, count: u32, output: Option<String>) {
+    let img = match load_image(&input) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+    let shares = match split_image(&img, count) {
+        Ok(shares) => shares,
+        Err(err) => fail(err),
+    };
+
+    let input = Path::new(&input);
+    let dir = match output {
+        Some(dir) => PathBuf::from(dir),
+        None => input.parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    for (i, share) in shares.into_iter().enumerate() {
+        let extension = share.format().extensions_str()[0];
+        let path = dir.join(format!(
+            "{}-share-{}-of-{}.{}",
+            stem,
+            i + 1,
+            count,
+            extension
+        ));
+        if let Err(err) = write_image(&path, share) {
+            fail(err);
+        }
+        println!("{}", path.display());
+    }
+}
+
+fn combine(paths: Vec<String>, output: String) {
+    let shares = match paths.iter().map(load_image).collect::<Result<Vec<_>, _>>() {
+        Ok(shares) => shares,
+        Err(err) => fail(err),
+    };
+    let img = match combine_images(&shares) {
+        Ok(img) => img,
+        Err(err) => fail(err),
+    };
+    if let Err(err) = write_image(output, img) {
+        fail(err);
+    }
+}
+
+fn hide(key: u64, cover: String, payload: String, output: Option<String>) {
+    let mut img = match load_image(&cover) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+    let payload = match fs::read(&payload) {
+        Ok(payload) => payload,
+        Err(err) => fail(err),
+    };
+    let format = img.format();
+    if let Err(err) = embed(&mut img, &payload, key) {
+        fail(err);
+    }
+
+    // a JPEG cover is written as a PNG, which a file named .jpg would hide
+    let output = match output {
+        Some(output) => output,
+        None if img.format() == format => cover,
+        None => {
+            let path = Path::new(&cover).with_extension(img.format().extensions_str()[0]);
+            path.to_string_lossy().into_owned()
+        }
+    };
+    if img.format() != format {
+        eprintln!(
+            "note: the cover is written as {:?}, a lossy format would lose what is hidden in it",
+            img.format()
+        );
+    }
+    if let Err(err) = write_image(&output, img) {
+        fail(err);
+    }
+    println!("{}", output);
+}
+
+fn reveal(key: u64, cover: String, output: String) {
+    let img = match load_image(&cover) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+    let payload = match extract(&img, key) {
+        Ok(payload) => payload,
+        Err(err) => fail(err),
+    };
+    if let Err(err) = fs::write(&output, payload) {
+        fail(err);
+    }
+}
+
+fn view(key: u64, input: String, protocol: Option<Protocol>, max_size: u32) {
+    let mut img = match load_image(&input) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+    if let Err(err) = decrypt_image(&mut img, key) {
+        fail(err);
+    }
+
+    let protocol = protocol.map_or_else(GraphicsProtocol::detect, GraphicsProtocol::from);
+    let graphics = terminal_graphics(&img, protocol, max_size);
+    if let Err(err) = io::stdout().lock().write_all(&graphics) {
+        eprintln!("{}", err);
+    }
+}
+
+fn render_contact_sheet(key: u64, dir: String, output: String, columns: u32, tile_size: u32) {
+    let mut paths = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>(),
+        Err(err) => fail(err),
+    };
+    paths.sort();
+
+    let mut tiles = Vec::with_capacity(paths.len());
+    for path in paths {
+        let mut img = match load_image(&path) {
+            Ok(val) => val,
+            Err(err) => {
+                eprintln!("skipping {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        if let Err(err) = decrypt_image(&mut img, key) {
+            eprintln!("skipping {}: {}", path.display(), err);
+            continue;
+        }
+        // only the thumbnail is kept, so memory use doesn't grow with the size of the originals
+        tiles.push(thumbnail(&img, tile_size));
+    }
+
+    if let Err(err) = write_image(output, contact_sheet(&tiles, columns, tile_size)) {
         eprintln!("{}", err)
+    }
+}
+
+fn update_thumbnails(key: u64, dir: String, size: u32) {
+    let entries = match update_thumbnail_cache(&dir, key, size) {
+        Ok(entries) => entries,
+        Err(err) => fail(err),
     };
+
+    for entry in &entries {
+        match &entry.result {
+            Ok(CacheStatus::Updated) => println!("{}: updated", entry.thumbnail.display()),
+            Ok(CacheStatus::UpToDate) => {}
+            Ok(CacheStatus::Removed) => println!("{}: removed", entry.thumbnail.display()),
+            Err(err) => println!("{}: {}", entry.image.display(), err),
+        }
+    }
+    if let Some(err) = entries.iter().find_map(|entry| entry.result.as_ref().err()) {
+        exit_with(err);
+    }
+}
+
+fn encrypt_and_upload(
+    key: u64,
+    input: String,
+    url: String,
+    options: &EncryptOptions,
+    upload_options: UploadOptions,
+) {
+    let mut img = match load_image(&input) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+    // preprocess first, so the options are checked against the image it gives
+    let skipped_steps = options.pipeline.apply(&mut img);
+    let options = EncryptOptions {
+        pipeline: Pipeline::default(),
+        ..options.clone()
+    };
+    check_options(&img, &options);
+    let report = encrypt_image_with(&mut img, key, &options);
+    for step in skipped_steps {
+        eprintln!(
+            "warning: {}",
+            OperationWarning::StepSkipped(step.to_string())
+        );
+    }
+    for warning in &report.warnings {
+        eprintln!("warning: {}", warning);
+    }
+
+    let bytes = match encode_image(&img) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+    if let Err(err) = upload(&url, &bytes, &upload_options) {
+        fail(err);
+    }
+    print_fingerprint(key);
+}
+
+fn audit_ciphertext(ciphertext: String, plaintext: Option<String>) {
+    let ciphertext = match load_image(&ciphertext) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+    let plaintext = match plaintext.map(load_image).transpose() {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+
+    let results = audit(&ciphertext, plaintext.as_ref());
+    let passed = results.iter().filter(|result| result.passed).count();
+    for result in &results {
+        println!(
+            "{}: {:.4} (threshold {}): {}",
+            result.name,
+            result.value,
+            result.threshold,
+            if result.passed { "ok" } else { "WEAK" }
+        );
+    }
+    println!("score: {}/{}", passed, results.len());
+}
+
+// the files in a directory, sorted, and those of its subdirectories if recursive
+fn list_files(dir: &Path, recursive: bool, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            if recursive {
+                list_files(&path, recursive, files)?;
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn rekey(old_key: u64, new_key: u64, path: String, recursive: bool, temp: &TempLocation) {
+    let path = PathBuf::from(path);
+    let mut files = Vec::new();
+    if path.is_dir() {
+        if let Err(err) = list_files(&path, recursive, &mut files) {
+            fail(err);
+        }
+    } else {
+        files.push(path);
+    }
+
+    let mut failures = 0;
+    let mut first_failure = None;
+    for file in &files {
+        let dct = read_header(file)
+            .ok()
+            .flatten()
+            .is_some_and(|header| header.cipher == Some(Cipher::Dct));
+        let result = if dct {
+            fs::read(file)
+                .map_err(ImageEncryptionError::from)
+                .and_then(|bytes| rekey_jpeg_dct(&bytes, old_key, new_key))
+                .and_then(|bytes| Ok(write_file_atomic_with(file, &bytes, temp)?))
+        } else {
+            load_image(file).and_then(|mut img| {
+                rekey_image(&mut img, old_key, new_key)?;
+                write_image_atomic_with(file, img, temp).map(drop)
+            })
+        };
+        match result {
+            Ok(()) => println!("{}: rekeyed", file.display()),
+            Err(err) => {
+                failures += 1;
+                println!("{}: {}", file.display(), err);
+                first_failure.get_or_insert(err);
+            }
+        }
+    }
+
+    println!(
+        "{} of {} files rekeyed",
+        files.len() - failures,
+        files.len()
+    );
+    if let Some(err) = first_failure {
+        exit_with(&err);
+    }
+}
+
+fn migrate(
+    key: u64,
+    input: String,
+    output: Option<String>,
+    format: Option<ImageFormat>,
+    force: bool,
+) {
+    let mut img = match load_image(&input) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+    let format = format.unwrap_or(img.format());
+    match migrate_legacy(&mut img, key, format, force) {
+        Ok(()) => {}
+        Err(RekeyError::Implausible) => {
+            eprintln!("note: pass --force if the image is noise to begin with");
+            fail(RekeyError::Implausible);
+        }
+        Err(err) => fail(err),
+    }
+    print_fingerprint(key);
+    if let Err(err) = write_image(output.unwrap_or(input), img) {
+        fail(err);
+    }
+}
+
+// the files given, with the directories among them replaced by the files inside them
+fn expand_paths(paths: Vec<String>, recursive: bool) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in paths.into_iter().map(PathBuf::from) {
+        if path.is_dir() {
+            if let Err(err) = list_files(&path, recursive, &mut files) {
+                fail(err);
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+fn dedupe(key: u64, paths: Vec<String>, recursive: bool) {
+    let files = expand_paths(paths, recursive);
+
+    // group the files by the digest of their original; a wrong key opens every digest to a different random value,
+    // so it finds no duplicates rather than false ones
+    let mut groups: Vec<(_, Vec<&PathBuf>)> = Vec::new();
+    for file in &files {
+        let digest = match read_header(file) {
+            Ok(Some(header)) => header.plaintext_digest.map(|digest| digest.open(key)),
+            Ok(None) => {
+                println!("{}: not encrypted", file.display());
+                continue;
+            }
+            Err(err) => {
+                println!("{}: {}", file.display(), err);
+                continue;
+            }
+        };
+        let Some(digest) = digest else {
+            println!(
+                "{}: no digest of the original in the header",
+                file.display()
+            );
+            continue;
+        };
+        match groups.iter_mut().find(|(other, _)| *other == digest) {
+            Some((_, group)) => group.push(file),
+            None => groups.push((digest, vec![file])),
+        }
+    }
+
+    let duplicates = groups
+        .iter()
+        .filter(|(_, group)| group.len() > 1)
+        .collect::<Vec<_>>();
+    for (_, group) in &duplicates {
+        println!("same original:");
+        for file in group {
+            println!("  {}", file.display());
+        }
+    }
+    println!(
+        "{} groups of duplicates among {} files",
+        duplicates.len(),
+        files.len()
+    );
+}
+
+// the matches are printed alone on stdout, so they can be piped to other commands
+fn find(key: u64, tags: &[String], paths: Vec<String>, recursive: bool) {
+    for file in expand_paths(paths, recursive) {
+        let header = match read_header(&file) {
+            Ok(Some(header)) => header,
+            // plain images are expected in the directories searched
+            Ok(None) => continue,
+            Err(err) => {
+                eprintln!("{}: {}", file.display(), err);
+                continue;
+            }
+        };
+        if header
+            .search_tags
+            .as_ref()
+            .is_some_and(|search_tags| tags.iter().all(|tag| search_tags.contains(key, tag)))
+        {
+            println!("{}", file.display());
+        }
+    }
+}
+
+// the fingerprint of the key that `info` shows for every file it encrypts, whatever their size
+fn print_fingerprint(key: u64) {
+    println!("key fingerprint: {}", KeyFingerprint::id(key));
+}
+
+// warn about a guessable key before encrypting with it, or stop if strong keys are enforced
+fn check_key(key: u64, enforce: bool) {
+    check_weaknesses(key_weakness(key), enforce, "generate one with `keygen`")
+}
+
+fn check_weaknesses(weaknesses: Vec<KeyWeakness>, enforce: bool, advice: &str) {
+    for weakness in &weaknesses {
+        eprintln!("warning: {}", weakness);
+    }
+    if enforce && !weaknesses.is_empty() {
+        fail(ImageEncryptionError::Invalid(format!(
+            "refusing to encrypt with a weak key, {}",
+            advice
+        )));
+    }
+}
+
+fn keygen() {
+    let key = rand::random::<u64>();
+    println!("key: {} ({:#018x})", key, key);
+    print_fingerprint(key);
+}
+
+fn check(key: u64, input: String) {
+    let header = match read_header(&input) {
+        Ok(Some(header)) => header,
+        Ok(None) => {
+            eprintln!("{} has no encryption header", input);
+            exit_with(&ImageEncryptionError::NotEncrypted);
+        }
+        Err(err) => fail(err),
+    };
+    // a JPEG encrypted in the DCT domain and a streamed TIFF are checked by the fingerprint alone,
+    // without decoding them
+    let verified = if header.cipher == Some(Cipher::Dct) || header.band_rows.is_some() {
+        header.check_key(key) == Some(true)
+    } else {
+        match load_image(&input) {
+            Ok(img) => verify_key(&img, key),
+            Err(err) => fail(err),
+        }
+    };
+    if verified {
+        println!("{}: the key decrypts it", input);
+    } else {
+        println!("{}: the key doesn't decrypt it", input);
+        exit_with(&ImageEncryptionError::WrongKey);
+    }
+}
+
+fn show_info(input: String) {
+    let header = match read_header(&input) {
+        Ok(Some(val)) => val,
+        Ok(None) => {
+            eprintln!("{} has no encryption header", input);
+            exit_with(&ImageEncryptionError::NotEncrypted);
+        }
+        Err(err) => fail(err),
+    };
+
+    if let Some(id) = header.key_id {
+        println!("key fingerprint: {}", id);
+    }
+    match (header.key_fingerprint, header.key_check_iterations) {
+        (Some(_), Some(iterations)) => println!("key check: hardened, {} iterations", iterations),
+        (Some(fingerprint), None) => println!("key check: {} (not hardened)", fingerprint),
+        _ => {}
+    }
+    if let Some(cost) = header.guess_cost() {
+        if !cost.check_is_hardened() {
+            println!(
+                "warning: checking guessed keys against the header is cheaper than decrypting"
+            );
+        }
+    }
+    if let Some(cipher) = header.cipher {
+        println!("cipher: {:?}", cipher);
+    }
+    if let Some((width, height)) = header.dimensions {
+        println!("dimensions: {}x{}", width, height);
+    }
+    if let Some(format) = header.original_format {
+        println!("original format: {:?}", format);
+    }
+    if let Some(color) = header.original_color {
+        println!("original color type: {:?}", color);
+    }
+    match header.permutation_unit {
+        Some(PermutationUnit::Block(size)) => println!("permutation unit: {}px blocks", size),
+        Some(PermutationUnit::Tile(size)) => {
+            println!("permutation unit: {}px blocks, scrambled within", size)
+        }
+        Some(unit) => println!("permutation unit: {:?}", unit),
+        None => {}
+    }
+    match header.sample_order {
+        Some(SampleOrder::LittleEndian) => println!("sample byte order: little-endian"),
+        Some(SampleOrder::BigEndian) => println!("sample byte order: big-endian"),
+        None => {}
+    }
+    for region in &header.regions {
+        println!("encrypted region: {}", region);
+    }
+    if let Some(strip) = header.reserved {
+        println!("plaintext strip: {}", strip);
+    }
+    if header.convergent_key.is_some() {
+        println!("convergent: yes");
+    }
+    if header.jpeg_container {
+        println!("container: JPEG placeholder");
+    }
+    if let Some(kdf) = header.kdf {
+        println!(
+            "key derived from a passphrase: PBKDF2-HMAC-SHA256, {} iterations",
+            kdf.iterations
+        );
+    }
+    if header.auth_tag.is_some() {
+        println!("authenticated: HMAC-SHA256");
+    }
+    if let Some(tags) = &header.search_tags {
+        println!("search tags: {}", tags.tokens.len());
+    }
+    if let Some(len) = header.chunk_len {
+        println!("parallel: chunks of {} pixels", len);
+    }
+    if let Some(rows) = header.band_rows {
+        println!("streamed: bands of {} rows", rows);
+    }
+    if header.permute_only {
+        println!("permuted only: the color histogram is that of the plaintext");
+    }
+    if let Some(algorithm) = header.scramble_algorithm {
+        println!("scramble algorithm: {:?}", algorithm);
+    }
+    if let Some(rounds) = header.rounds {
+        println!("rounds: {}", rounds);
+    }
+    if let Some(share) = header.share {
+        println!(
+            "share: {} of {}, no key, combined with all the others",
+            share.index + 1,
+            share.count
+        );
+    }
+    if header.disguised {
+        println!("disguised: hidden in the low bits of a generated picture");
+    }
+    if !header.skipped_channels.is_empty() {
+        let positions = header
+            .skipped_channels
+            .iter()
+            .map(u8::to_string)
+            .collect::<Vec<_>>();
+        println!("channels left as they were: {}", positions.join(", "));
+    }
+    if let Some(noise) = header.noise {
+        println!("noise-shaped: {:?}, {} rows", noise.shape, noise.height);
+    }
+    if let Some(index) = header.frame_index {
+        println!("frame: {}", index);
+    }
+}
+
+fn detect_fingerprint(image: String, recipients: Vec<String>) {
+    let img = match load_image(&image) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+
+    for recipient in recipients {
+        println!(
+            "{}: {:.4}{}",
+            recipient,
+            fingerprint_score(&img, &recipient),
+            if fingerprint_detected(&img, &recipient) {
+                " (match)"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+fn check_manifest(manifest: String) {
+    let entries = match verify_manifest(manifest) {
+        Ok(val) => val,
+        Err(err) => fail(err),
+    };
+
+    let mut failures = 0;
+    for (path, status) in &entries {
+        if *status != ManifestStatus::Ok {
+            failures += 1;
+        }
+        let status = match status {
+            ManifestStatus::Ok => "OK",
+            ManifestStatus::Mismatch => "FAILED",
+            ManifestStatus::Missing => "MISSING",
+        };
+        println!("{}: {}", path.display(), status);
+    }
+
+    if failures > 0 {
+        eprintln!("{} of {} files did not verify", failures, entries.len());
+        // a file that is gone is missing input, one that changed is bad data
+        let missing = entries
+            .iter()
+            .any(|(_, status)| *status == ManifestStatus::Missing);
+        exit_with(&if missing {
+            io::Error::from(io::ErrorKind::NotFound).into()
+        } else {
+            ImageEncryptionError::Malformed("modified since it was added".to_string())
+        });
+    }
+}
+
+fn register(remove: bool) {
+    let result = if remove {
+        unregister_context_menu()
+    } else {
+        env::current_exe()
+            .map_err(Into::into)
+            .and_then(register_context_menu)
+    };
+    match result {
+        Ok(entries) => {
+            for entry in entries {
+                println!(
+                    "{}: {}",
+                    entry,
+                    if remove { "removed" } else { "installed" }
+                );
+            }
+        }
+        Err(err) => fail(err),
+    }
+}
+
+fn self_test(cross_vectors: bool) {
+    let results = run_cross_vectors();
+
+    let failures = results.iter().filter(|result| !result.passed).count();
+    for result in &results {
+        let status = if result.passed { "ok" } else { "FAILED" };
+        if cross_vectors {
+            println!("{}: {} {}", result.name, status, result.output);
+        } else {
+            println!("{}: {}", result.name, status);
+        }
+    }
+
+    if failures > 0 {
+        // the cipher doesn't give the same ciphertexts on this platform as everywhere else
+        fail(ImageEncryptionError::Unsupported(format!(
+            "{} of {} checks failed",
+            failures,
+            results.len()
+        )));
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Some(threads) = args.threads {
+        if let Err(err) = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+        {
+            fail(ImageEncryptionError::Invalid(err.to_string()));
+        }
+    }
+
+    // only the keys that new ciphertexts are made with; enc keys are checked once they are read
+    match &args.command {
+        Command::Upload { key, .. } => check_key(*key, args.enforce_strong_keys),
+        Command::Rekey { new_key, .. } => check_key(*new_key, args.enforce_strong_keys),
+        _ => {}
+    }
+
+    let enforce_strong_keys = args.enforce_strong_keys;
+    match args.command {
+        Command::Enc(mut args) => {
+            let key_elsewhere = args.key_env.is_some() || args.iterations.is_some();
+            shift_paths(&mut args.key, key_elsewhere, &mut args.common);
+            check_max_throughput(&args.common);
+            // the Arnold cat map has no key, the number of iterations stands in for it
+            let (key, kdf) = if let Some(iterations) = args.iterations {
+                (iterations as u64, None)
+            } else if args.passphrase {
+                let passphrase =
+                    match read_passphrase(args.key.as_deref(), args.key_env.as_deref(), true) {
+                        Ok(passphrase) => passphrase,
+                        Err(err) => fail(err),
+                    };
+                check_weaknesses(
+                    passphrase_weakness(&passphrase),
+                    enforce_strong_keys,
+                    "use a longer passphrase",
+                );
+                let kdf = KdfParams::random();
+                let started = Instant::now();
+                let key = kdf.derive_key(&passphrase);
+                args.common.kdf_time = Some(started.elapsed());
+                (key, Some(kdf))
+            } else {
+                match read_key(args.key.as_deref(), args.key_env.as_deref(), true) {
+                    Ok(key) => {
+                        check_key(key, enforce_strong_keys);
+                        (key, None)
+                    }
+                    Err(err) => fail(err),
+                }
+            };
+            if args.iterations.is_some() {
+                if args.algo != Algo::Arnold {
+                    fail(ImageEncryptionError::Invalid(
+                        "--iterations only applies to --algo arnold".to_string(),
+                    ));
+                }
+                let options = EncryptOptions {
+                    scramble_algorithm: ScrambleAlgorithm::ArnoldCat,
+                    ..Default::default()
+                };
+                crypt(Mode::Enc, key, args.common, &options)
+            } else if args.dct {
+                crypt_dct(Mode::Enc, key, args.common)
+            } else if args.stream {
+                crypt_stream(Mode::Enc, key, args.common)
+            } else {
+                match encrypt_options(&args.options, key) {
+                    Ok(options) => {
+                        let options = EncryptOptions {
+                            kdf,
+                            hash_ciphertext: args.common.name_by_hash,
+                            ..options
+                        };
+                        if args.check_exact {
+                            check_exact_cycle(key, args.common, &options)
+                        } else {
+                            crypt(Mode::Enc, key, args.common, &options)
+                        }
+                    }
+                    Err(err) => fail_options(err),
+                }
+            }
+        }
+        Command::Dec(mut args) => {
+            let key_elsewhere = args.key_env.is_some() || args.iterations.is_some();
+            shift_paths(&mut args.key, key_elsewhere, &mut args.common);
+            check_max_throughput(&args.common);
+            let key = if args.try_keys {
+                let Some(keys) = &args.key else {
+                    eprintln!("--try-keys needs a KEY file of candidate keys");
+                    std::process::exit(2);
+                };
+                match try_keys(keys, &args.common.input) {
+                    Ok(Some(key)) if args.write => key,
+                    Ok(Some(_)) => return,
+                    Ok(None) => exit_with(&ImageEncryptionError::WrongKey),
+                    Err(err) => fail(err),
+                }
+            } else if args.passphrase {
+                match passphrase_key(
+                    args.key.as_deref(),
+                    args.key_env.as_deref(),
+                    &args.common.input,
+                ) {
+                    Ok((key, elapsed)) => {
+                        args.common.kdf_time = Some(elapsed);
+                        key
+                    }
+                    Err(err) => fail(err),
+                }
+            } else if let Some(iterations) = args.iterations {
+                iterations as u64
+            } else {
+                match read_key(args.key.as_deref(), args.key_env.as_deref(), false) {
+                    Ok(key) => key,
+                    Err(err) => fail(err),
+                }
+            };
+            let header = read_header(&args.common.input).ok().flatten();
+            if header
+                .as_ref()
+                .is_some_and(|header| header.cipher == Some(Cipher::Dct))
+            {
+                crypt_dct(Mode::Dec, key, args.common)
+            } else if header.is_some_and(|header| header.band_rows.is_some()) {
+                crypt_stream(Mode::Dec, key, args.common)
+            } else {
+                crypt(Mode::Dec, key, args.common, &EncryptOptions::default())
+            }
+        }
+        Command::View {
+            key,
+            input,
+            protocol,
+            max_size,
+        } => view(key, input, protocol, max_size),
+        Command::ContactSheet {
+            key,
+            dir,
+            output,
+            columns,
+            tile_size,
+        } => render_contact_sheet(key, dir, output, columns, tile_size),
+        Command::Thumbnails { key, dir, size } => update_thumbnails(key, dir, size),
+        Command::Upload {
+            key,
+            input,
+            url,
+            options,
+            chunk_size,
+            retries,
+        } => match encrypt_options(&options, key) {
+            Ok(options) => encrypt_and_upload(
+                key,
+                input,
+                url,
+                &options,
+                UploadOptions {
+                    chunk_size,
+                    retries,
+                },
+            ),
+            Err(err) => fail_options(err),
+        },
+        Command::Audit {
+            ciphertext,
+            plaintext,
+        } => audit_ciphertext(ciphertext, plaintext),
+        Command::Rekey {
+            old_key,
+            new_key,
+            path,
+            recursive,
+            temp_dir,
+            tmpfs,
+        } => {
+            let temp = match (temp_dir, tmpfs) {
+                (_, true) => TempLocation::Memory,
+                (Some(dir), false) => TempLocation::Dir(dir.into()),
+                (None, false) => TempLocation::NextToOutput,
+            };
+            rekey(old_key, new_key, path, recursive, &temp)
+        }
+        Command::Migrate {
+            key,
+            input,
+            output,
+            format,
+            force,
+        } => migrate(key, input, output, format, force),
+        Command::Dedupe {
+            key,
+            paths,
+            recursive,
+        } => dedupe(key, paths, recursive),
+        Command::Find {
+            key,
+            tags,
+            paths,
+            recursive,
+        } => find(key, &tags, paths, recursive),
+        Command::Redact {
+            input,
+            output,
+            region,
+            regions_json,
+            method,
+            block_size,
+            sigma,
+        } => {
+            let redaction = match method {
+                RedactMethod::Pixelate => Redaction::Pixelate(block_size),
+                RedactMethod::Blur => Redaction::Blur(sigma),
+            };
+            match regions(&region, regions_json.as_deref()) {
+                Ok(regions) => redact(input, output, &regions, redaction),
+                Err(err) => fail_options(err),
+            }
+        }
+        Command::Split {
+            input,
+            shares,
+            output,
+        } => split(input, shares, output),
+        Command::Combine { shares, output } => combine(shares, output),
+        Command::Hide {
+            key,
+            cover,
+            payload,
+            output,
+        } => hide(key, cover, payload, output),
+        Command::Reveal { key, cover, output } => reveal(key, cover, output),
+        Command::Keygen => keygen(),
+        Command::Check {
+            key,
+            input,
+            key_env,
+            passphrase,
+        } => {
+            let key = if passphrase {
+                passphrase_key(key.as_deref(), key_env.as_deref(), &input).map(|(key, _)| key)
+            } else {
+                read_key(key.as_deref(), key_env.as_deref(), false)
+            };
+            match key {
+                Ok(key) => check(key, input),
+                Err(err) => fail(err),
+            }
+        }
+        Command::Info { input } => show_info(input),
+        Command::DetectFingerprint { image, recipients } => detect_fingerprint(image, recipients),
+        Command::VerifyManifest { manifest } => check_manifest(manifest),
+        Command::RegisterContextMenu { remove } => register(remove),
+        Command::SelfTest { cross_vectors } => self_test(cross_vectors),
+    }
 }
@@ -0,0 +1,115 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::{sha256::Sha256, to_hex};
+
+// the state of a file listed in a checksum manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestStatus {
+    Ok,
+    Mismatch,
+    Missing,
+}
+
+// the hex SHA-256 digest of a file's contents
+pub fn file_sha256(path: impl AsRef<Path>) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+// parse the "<digest>  <path>" lines of a SHA256SUMS-style manifest
+fn read_entries(manifest: &Path) -> io::Result<Vec<(String, String)>> {
+    let contents = match fs::read_to_string(manifest) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            // the second separator character is ' ' for text mode and '*' for binary mode
+            match line.split_once(' ') {
+                Some((digest, name)) if name.starts_with([' ', '*']) => {
+                    Ok((digest.to_lowercase(), name[1..].to_string()))
+                }
+                _ => Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("malformed manifest line: {}", line),
+                )),
+            }
+        })
+        .collect()
+}
+
+// add (or update) the entry of a file in a manifest; paths are recorded relative to
+// the manifest's directory when possible, so the manifest can travel with the files
+pub fn add_manifest_entry(manifest: impl AsRef<Path>, file: impl AsRef<Path>) -> io::Result<()> {
+    let manifest = manifest.as_ref();
+    let file = file.as_ref();
+    let base = manifest.parent().unwrap_or_else(|| Path::new(""));
+
+    let file_abs = fs::canonicalize(file)?;
+    let base_abs = fs::canonicalize(if base.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        base
+    })?;
+    let name = file_abs
+        .strip_prefix(&base_abs)
+        .unwrap_or(&file_abs)
+        .to_string_lossy()
+        .into_owned();
+
+    let digest = file_sha256(file)?;
+    let mut entries = read_entries(manifest)?;
+    match entries.iter_mut().find(|(_, entry)| *entry == name) {
+        Some(entry) => entry.0 = digest,
+        None => entries.push((digest, name)),
+    }
+
+    let contents: String = entries
+        .iter()
+        .map(|(digest, name)| format!("{}  {}\n", digest, name))
+        .collect();
+    fs::write(manifest, contents)
+}
+
+// check every file listed in a manifest, resolving relative paths against the manifest's directory
+pub fn verify_manifest(manifest: impl AsRef<Path>) -> io::Result<Vec<(PathBuf, ManifestStatus)>> {
+    let manifest = manifest.as_ref();
+    if !manifest.exists() {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("manifest not found: {}", manifest.display()),
+        ));
+    }
+    let base = manifest.parent().unwrap_or_else(|| Path::new(""));
+
+    read_entries(manifest)?
+        .into_iter()
+        .map(|(digest, name)| {
+            let path = base.join(&name);
+            let status = match file_sha256(&path) {
+                Ok(actual) if actual == digest => ManifestStatus::Ok,
+                Ok(_) => ManifestStatus::Mismatch,
+                Err(err) if err.kind() == ErrorKind::NotFound => ManifestStatus::Missing,
+                Err(err) => return Err(err),
+            };
+            Ok((PathBuf::from(name), status))
+        })
+        .collect()
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+
+use image::ImageFormat;
+
+use crate::jpeg_container::jpeg_segments;
+
+// kinds of metadata a source file can carry; only the pixels are ever written back out,
+// so all of them are lost on the way through, unless a JPEG is encrypted into a JPEG container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum MetadataKind {
+    Exif,
+    // an EXIF orientation other than "top left", which viewers apply when displaying the image
+    Orientation,
+    IccProfile,
+    Xmp,
+    Text,
+    Timestamp,
+    PhysicalSize,
+}
+
+impl fmt::Display for MetadataKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MetadataKind::Exif => "EXIF",
+            MetadataKind::Orientation => "EXIF orientation",
+            MetadataKind::IccProfile => "ICC profile",
+            MetadataKind::Xmp => "XMP",
+            MetadataKind::Text => "text",
+            MetadataKind::Timestamp => "timestamp",
+            MetadataKind::PhysicalSize => "physical size",
+        })
+    }
+}
+
+fn push(kinds: &mut Vec<MetadataKind>, kind: MetadataKind) {
+    if !kinds.contains(&kind) {
+        kinds.push(kind);
+    }
+}
+
+fn push_exif(kinds: &mut Vec<MetadataKind>, tiff: &[u8]) {
+    push(kinds, MetadataKind::Exif);
+    if exif_orientation(tiff).is_some_and(|orientation| orientation != 1) {
+        push(kinds, MetadataKind::Orientation);
+    }
+}
+
+// the orientation tag (0x0112) of the first IFD of a TIFF-structured EXIF block
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let little_endian = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let u16_at = |i: usize| {
+        let bytes = [*tiff.get(i)?, *tiff.get(i + 1)?];
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let u32_at = |i: usize| {
+        let bytes = tiff.get(i..i + 4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    (0..u16_at(ifd)? as usize)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(0x0112))
+        .and_then(|entry| u16_at(entry + 8))
+}
+
+fn png_metadata(bytes: &[u8], kinds: &mut Vec<MetadataKind>) {
+    let mut rest = bytes.get(8..).unwrap_or_default();
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let Some(data) = rest.get(8..8 + len) else {
+            break;
+        };
+        match &rest[4..8] {
+            b"tEXt" | b"zTXt" => push(kinds, MetadataKind::Text),
+            // XMP is stored as an iTXt chunk with a well known keyword
+            b"iTXt" if data.starts_with(b"XML:com.adobe.xmp\0") => push(kinds, MetadataKind::Xmp),
+            b"iTXt" => push(kinds, MetadataKind::Text),
+            b"eXIf" => push_exif(kinds, data),
+            b"iCCP" => push(kinds, MetadataKind::IccProfile),
+            b"tIME" => push(kinds, MetadataKind::Timestamp),
+            b"pHYs" => push(kinds, MetadataKind::PhysicalSize),
+            b"IEND" => break,
+            _ => {}
+        }
+        rest = rest.get(12 + len..).unwrap_or_default();
+    }
+}
+
+fn jpeg_metadata(bytes: &[u8], kinds: &mut Vec<MetadataKind>) {
+    for (marker, segment) in jpeg_segments(bytes).0 {
+        let data = &segment[4..];
+        match marker {
+            0xE1 if data.starts_with(b"Exif\0\0") => push_exif(kinds, &data[6..]),
+            0xE1 if data.starts_with(b"http://ns.adobe.com/xap/") => push(kinds, MetadataKind::Xmp),
+            0xE2 if data.starts_with(b"ICC_PROFILE\0") => push(kinds, MetadataKind::IccProfile),
+            0xFE => push(kinds, MetadataKind::Text),
+            _ => {}
+        }
+    }
+}
+
+fn webp_metadata(bytes: &[u8], kinds: &mut Vec<MetadataKind>) {
+    let mut rest = bytes.get(12..).unwrap_or_default();
+    while rest.len() >= 8 {
+        let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+        let Some(data) = rest.get(8..8 + len) else {
+            break;
+        };
+        match &rest[..4] {
+            b"EXIF" => push_exif(kinds, data.strip_prefix(b"Exif\0\0").unwrap_or(data)),
+            b"ICCP" => push(kinds, MetadataKind::IccProfile),
+            b"XMP " => push(kinds, MetadataKind::Xmp),
+            _ => {}
+        }
+        // chunks are padded to an even size
+        rest = rest.get(8 + len + len % 2..).unwrap_or_default();
+    }
+}
+
+// the kinds of metadata in an encoded image, for the formats whose metadata is worth looking for
+pub(crate) fn find_metadata(bytes: &[u8], format: ImageFormat) -> Vec<MetadataKind> {
+    let mut kinds = Vec::new();
+    match format {
+        ImageFormat::Png => png_metadata(bytes, &mut kinds),
+        ImageFormat::Jpeg => jpeg_metadata(bytes, &mut kinds),
+        ImageFormat::WebP => webp_metadata(bytes, &mut kinds),
+        _ => {}
+    }
+    kinds
+}
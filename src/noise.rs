@@ -0,0 +1,273 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use crate::Image;
+
+// ciphertext is uniform static, which stands out wherever it is put; shaping turns it into samples
+// spread around mid gray like film grain or sensor noise. Uniform bytes keep their distribution under
+// any one to one mapping, so the ciphertext is read as a stream of bits and decoded with a prefix code
+// built for the target distribution: every value comes out about as often as it should, the image
+// grows a few rows to hold the extra samples, and encoding them with the same code gives the bits back
+
+// the weights of the code are the density scaled to this, so even the rarest values keep a code
+const WEIGHT_SCALE: f64 = (1u64 << 24) as f64;
+// how many standard deviations of the sample count the shaped image leaves room for,
+// so the height depends only on the size of the image and not on its ciphertext
+const SPARE_DEVIATIONS: f64 = 8.0;
+
+// the distribution the ciphertext is made to follow
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum NoiseShape {
+    // a fine grain close to mid gray
+    #[default]
+    FilmGrain,
+    // a broad bell of Gaussian noise
+    Gaussian,
+}
+
+impl NoiseShape {
+    // the standard deviation of the samples, in levels of a byte
+    fn deviation(self) -> f64 {
+        match self {
+            NoiseShape::FilmGrain => 20.0,
+            NoiseShape::Gaussian => 40.0,
+        }
+    }
+}
+
+// how the ciphertext was shaped, and the height of the shaped image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ShapedNoise {
+    pub shape: NoiseShape,
+    pub height: u32,
+}
+
+impl ShapedNoise {
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![self.shape as u8];
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&shape, height) = bytes.split_first()?;
+        let shape = match shape {
+            0 => NoiseShape::FilmGrain,
+            1 => NoiseShape::Gaussian,
+            _ => return None,
+        };
+        Some(ShapedNoise {
+            shape,
+            height: u32::from_le_bytes(height.try_into().ok()?),
+        })
+    }
+}
+
+// a canonical Huffman code over the byte values, weighted by the target density
+struct Code {
+    lengths: [u32; 256],
+    codes: [u64; 256],
+    // the values in the order of their codes, and how many codes there are of every length
+    symbols: Vec<u8>,
+    counts: Vec<u64>,
+}
+
+impl Code {
+    fn new(shape: NoiseShape) -> Self {
+        let deviation = shape.deviation();
+        let mut heap = (0..256)
+            .map(|value| {
+                let distance = (value as f64 - 127.5) / deviation;
+                let weight = (WEIGHT_SCALE * (-distance * distance / 2.0).exp()) as u64;
+                Reverse((weight.max(1), value))
+            })
+            .collect::<BinaryHeap<_>>();
+
+        // the two lightest nodes are merged until one is left; ties go to the lower node,
+        // so the code is the same everywhere
+        let mut parents = vec![0; 511];
+        let mut next = 256;
+        while let (Some(Reverse((a, i))), Some(Reverse((b, j)))) = (heap.pop(), heap.pop()) {
+            parents[i] = next;
+            parents[j] = next;
+            heap.push(Reverse((a + b, next)));
+            next += 1;
+        }
+        let root = next - 1;
+        let mut lengths = [0; 256];
+        for (value, length) in lengths.iter_mut().enumerate() {
+            let mut node = value;
+            while node != root {
+                node = parents[node];
+                *length += 1;
+            }
+        }
+
+        // canonical codes are handed out in order of length, then of value
+        let mut symbols = (0..=255).collect::<Vec<u8>>();
+        symbols.sort_by_key(|&value| (lengths[value as usize], value));
+        let mut counts = vec![0; *lengths.iter().max().unwrap() as usize + 1];
+        let mut codes = [0; 256];
+        let (mut code, mut length) = (0u64, 0);
+        for &value in &symbols {
+            let value = value as usize;
+            counts[lengths[value] as usize] += 1;
+            code <<= lengths[value] - length;
+            length = lengths[value];
+            codes[value] = code;
+            code += 1;
+        }
+        Code {
+            lengths,
+            codes,
+            symbols,
+            counts,
+        }
+    }
+
+    // the next value the bits decode to
+    fn decode(&self, bits: &mut Bits) -> u8 {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for &count in &self.counts[1..] {
+            code |= bits.next();
+            if code - first < count {
+                return self.symbols[index + (code - first) as usize];
+            }
+            index += count as usize;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        unreachable!("a Huffman code is complete, so every string of bits decodes")
+    }
+
+    // how many samples uniform bits decode to, with room to spare: a value comes up
+    // with probability 2^-length, which gives the mean and variance of the length of a code
+    fn capacity(&self, bits: u64) -> u64 {
+        let (mut mean, mut square) = (0.0, 0.0);
+        for &length in &self.lengths {
+            let (length, p) = (length as f64, 0.5f64.powi(length as i32));
+            mean += p * length;
+            square += p * length * length;
+        }
+        let variance = square - mean * mean;
+        let bits = bits as f64;
+        let spread = (bits * variance / mean.powi(3)).sqrt();
+        (bits / mean + SPARE_DEVIATIONS * spread).ceil() as u64
+    }
+}
+
+// the bits of a buffer from the most significant of its first byte, starting over at the end,
+// so the samples past the ciphertext are filler that looks just the same
+struct Bits<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Bits<'_> {
+    fn next(&mut self) -> u64 {
+        let byte = self.bytes[self.position / 8 % self.bytes.len()];
+        let bit = byte >> (7 - self.position % 8) & 1;
+        self.position += 1;
+        bit as u64
+    }
+}
+
+// turn the pixels into shaped noise of the same width, as many rows as they take
+pub(crate) fn shape_noise(img: &mut Image, shape: NoiseShape) -> ShapedNoise {
+    let row_len = img.width as usize * img.color.bytes_per_pixel() as usize;
+    if img.pixels.is_empty() || row_len == 0 {
+        return ShapedNoise {
+            shape,
+            height: img.height,
+        };
+    }
+
+    let code = Code::new(shape);
+    let bits = img.pixels.len() * 8;
+    let len = code.capacity(bits as u64).div_ceil(row_len as u64) as usize * row_len;
+    let mut reader = Bits {
+        bytes: &img.pixels,
+        position: 0,
+    };
+    let mut samples = Vec::with_capacity(len);
+    // in the rare case the ciphertext takes more samples than there is room for, the image grows further
+    while reader.position < bits || samples.len() < len || samples.len() % row_len != 0 {
+        samples.push(code.decode(&mut reader));
+    }
+
+    img.height = (samples.len() / row_len) as u32;
+    img.pixels = samples;
+    ShapedNoise {
+        shape,
+        height: img.height,
+    }
+}
+
+// the inverse of `shape_noise`, back to the pixels of an image of the given height
+pub(crate) fn unshape_noise(img: &mut Image, shape: NoiseShape, height: u32) {
+    let len = img.width as usize * height as usize * img.color.bytes_per_pixel() as usize;
+    let code = Code::new(shape);
+    let mut pixels = Vec::with_capacity(len);
+    let (mut pending, mut pending_bits) = (0u64, 0);
+    for &sample in &img.pixels {
+        if pixels.len() >= len {
+            break;
+        }
+        let length = code.lengths[sample as usize];
+        pending = pending << length | code.codes[sample as usize];
+        pending_bits += length;
+        while pending_bits >= 8 {
+            pending_bits -= 8;
+            pixels.push((pending >> pending_bits) as u8);
+        }
+        pending &= (1 << pending_bits) - 1;
+    }
+    pixels.resize(len, 0);
+    img.pixels = pixels;
+    img.height = height;
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        encrypt_image_with,
+        test_util::{random_image, rng, round_trips, COLORS},
+        EncryptOptions,
+    };
+
+    // the standard deviation of the byte values
+    fn deviation(bytes: &[u8]) -> f64 {
+        let len = bytes.len().max(1) as f64;
+        let mean = bytes.iter().map(|&byte| byte as f64).sum::<f64>() / len;
+        let square = bytes
+            .iter()
+            .map(|&byte| (byte as f64 - mean).powi(2))
+            .sum::<f64>();
+        (square / len).sqrt()
+    }
+
+    // shaped noise spreads far less than the uniform bytes of plain ciphertext, whose deviation is about 74
+    #[test]
+    fn shaped() {
+        let mut rng = rng();
+        for shape in [NoiseShape::FilmGrain, NoiseShape::Gaussian] {
+            for color in COLORS {
+                let key = rng.next_u64();
+                let original = random_image(&mut rng, 37, 23, color);
+                let options = EncryptOptions {
+                    noise: Some(shape),
+                    ..Default::default()
+                };
+                let mut img = original.clone();
+                encrypt_image_with(&mut img, key, &options);
+                assert!(img.height > original.height, "{:?} {:?}", shape, color);
+                assert!(deviation(&img.pixels) < 55.0, "{:?} {:?}", shape, color);
+                assert!(round_trips(&original, key, &options));
+            }
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use std::ops::Range;
+
+use rand::RngCore;
+
+use crate::rng::shuffle;
+
+// what the cipher moves around as a whole when it permutes the image:
+// coarser units need fewer random numbers to shuffle but leave more structure in place
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PermutationUnit {
+    #[default]
+    Pixel,
+    Row,
+    Column,
+    // square blocks of this many pixels a side; blocks are only swapped with blocks of the same shape,
+    // so the partial blocks on the right and bottom edges are shuffled among themselves
+    Block(u32),
+    // square tiles of this many pixels a side, shuffled like blocks and then shuffled within:
+    // every pixel moves, but only ever into the tile its own came from, so the cipher reads the image
+    // one small area at a time instead of from all over it, which keeps large images in cache
+    Tile(u32),
+    // single pixels like `Pixel`, but moved by a keyed bijection worked out index by index
+    // instead of a shuffled table, so the permutation takes no memory however large the image
+    Bijection,
+}
+
+impl PermutationUnit {
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        match self {
+            PermutationUnit::Pixel => vec![0],
+            PermutationUnit::Row => vec![1],
+            PermutationUnit::Column => vec![2],
+            PermutationUnit::Block(size) => {
+                let mut bytes = vec![3];
+                bytes.extend_from_slice(&size.to_le_bytes());
+                bytes
+            }
+            PermutationUnit::Tile(size) => {
+                let mut bytes = vec![4];
+                bytes.extend_from_slice(&size.to_le_bytes());
+                bytes
+            }
+            PermutationUnit::Bijection => vec![5],
+        }
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0] => Some(PermutationUnit::Pixel),
+            [1] => Some(PermutationUnit::Row),
+            [2] => Some(PermutationUnit::Column),
+            [3, size @ ..] => Some(PermutationUnit::Block(u32::from_le_bytes(
+                size.try_into().ok()?,
+            )))
+            .filter(|&unit| unit != PermutationUnit::Block(0)),
+            [4, size @ ..] => Some(PermutationUnit::Tile(u32::from_le_bytes(
+                size.try_into().ok()?,
+            )))
+            .filter(|&unit| unit != PermutationUnit::Tile(0)),
+            [5] => Some(PermutationUnit::Bijection),
+            _ => None,
+        }
+    }
+}
+
+// shuffle a range of indices
+fn shuffled(len: u32, rng: &mut impl RngCore) -> Vec<u32> {
+    let mut indices = (0..len).collect::<Vec<_>>();
+    shuffle(&mut indices, rng);
+    indices
+}
+
+// the pixel every position of the permuted image takes, either looked up in a table
+// or computed when it's needed
+pub(crate) enum Permutation {
+    Table(Vec<u32>),
+    Bijection(IndexBijection),
+}
+
+impl Permutation {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Permutation::Table(table) => table.len(),
+            Permutation::Bijection(bijection) => bijection.len as usize,
+        }
+    }
+
+    pub(crate) fn iter(&self) -> Indices<'_> {
+        self.range(0..self.len())
+    }
+
+    // the sources of a run of positions, like one chunk of a parallel encryption
+    pub(crate) fn range(&self, range: Range<usize>) -> Indices<'_> {
+        match self {
+            Permutation::Table(table) => Indices::Table(table[range].iter()),
+            Permutation::Bijection(bijection) => {
+                Indices::Bijection(bijection, range.start as u32..range.end as u32)
+            }
+        }
+    }
+}
+
+pub(crate) enum Indices<'a> {
+    Table(std::slice::Iter<'a, u32>),
+    Bijection(&'a IndexBijection, Range<u32>),
+}
+
+impl Iterator for Indices<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        match self {
+            Indices::Table(table) => table.next().copied(),
+            Indices::Bijection(bijection, range) => range.next().map(|index| bijection.at(index)),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Indices::Table(table) => table.size_hint(),
+            Indices::Bijection(_, range) => range.size_hint(),
+        }
+    }
+}
+
+impl ExactSizeIterator for Indices<'_> {}
+
+const FEISTEL_ROUNDS: usize = 6;
+
+// a balanced Feistel network over the smallest even number of bits that holds every index,
+// walked along its cycles until it lands back inside `0..len`; the domain is less than four times
+// `len`, so that takes under four steps on average
+pub(crate) struct IndexBijection {
+    len: u32,
+    half_bits: u32,
+    keys: [u64; FEISTEL_ROUNDS],
+}
+
+impl IndexBijection {
+    pub(crate) fn new(len: u32, rng: &mut impl RngCore) -> Self {
+        let bits = u32::BITS - len.saturating_sub(1).leading_zeros();
+        IndexBijection {
+            len,
+            half_bits: bits.div_ceil(2).max(1),
+            keys: std::array::from_fn(|_| rng.next_u64()),
+        }
+    }
+
+    fn feistel(&self, index: u64) -> u64 {
+        let mask = (1 << self.half_bits) - 1;
+        let (mut left, mut right) = (index >> self.half_bits, index & mask);
+        for &key in &self.keys {
+            (left, right) = (right, left ^ (round(right ^ key) & mask));
+        }
+        left << self.half_bits | right
+    }
+
+    pub(crate) fn at(&self, index: u32) -> u32 {
+        let mut index = index as u64;
+        loop {
+            index = self.feistel(index);
+            if index < self.len as u64 {
+                return index as u32;
+            }
+        }
+    }
+}
+
+// the round function, the finalizer of splitmix64
+fn round(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+// the permutation of the pixels in row-major order, made by shuffling the given units;
+// with `Pixel` this is exactly the shuffle the cipher has always done, so existing ciphertexts still decrypt
+pub(crate) fn permutation(
+    unit: PermutationUnit,
+    width: u32,
+    height: u32,
+    rng: &mut impl RngCore,
+) -> Permutation {
+    let table = match unit {
+        PermutationUnit::Pixel => shuffled(width * height, rng),
+        PermutationUnit::Row => shuffled(height, rng)
+            .into_iter()
+            .flat_map(|row| (0..width).map(move |x| row * width + x))
+            .collect(),
+        PermutationUnit::Column => {
+            let columns = shuffled(width, rng);
+            (0..height)
+                .flat_map(|y| columns.iter().map(move |&column| y * width + column))
+                .collect()
+        }
+        PermutationUnit::Block(size) => block_permutation(size.max(1), width, height, rng),
+        PermutationUnit::Tile(size) => tile_permutation(size.max(1), width, height, rng),
+        PermutationUnit::Bijection => {
+            return Permutation::Bijection(IndexBijection::new(width * height, rng))
+        }
+    };
+    Permutation::Table(table)
+}
+
+// the block every block of a grid of `size` pixel blocks takes its pixels from, by block coordinates
+fn block_sources(size: u32, width: u32, height: u32, rng: &mut impl RngCore) -> Vec<(u32, u32)> {
+    let (columns, rows) = (width.div_ceil(size), height.div_ceil(size));
+    // blocks in the last column or row may be smaller, so there are up to four groups of same-shaped blocks
+    let group = |bx: u32, by: u32| (bx + 1 == columns) as usize + 2 * (by + 1 == rows) as usize;
+
+    let mut groups = vec![Vec::new(); 4];
+    for by in 0..rows {
+        for bx in 0..columns {
+            groups[group(bx, by)].push((bx, by));
+        }
+    }
+    let targets = groups
+        .iter()
+        .map(|blocks| shuffled(blocks.len() as u32, rng))
+        .collect::<Vec<_>>();
+
+    // where every block takes its pixels from
+    let mut sources = vec![(0, 0); (columns * rows) as usize];
+    for (blocks, targets) in groups.iter().zip(&targets) {
+        for (&(bx, by), &target) in blocks.iter().zip(targets) {
+            sources[(by * columns + bx) as usize] = blocks[target as usize];
+        }
+    }
+    sources
+}
+
+fn block_permutation(size: u32, width: u32, height: u32, rng: &mut impl RngCore) -> Vec<u32> {
+    let columns = width.div_ceil(size);
+    let sources = block_sources(size, width, height, rng);
+    let mut permutation = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let (sx, sy) = sources[(y / size * columns + x / size) as usize];
+            permutation.push((sy * size + y % size) * width + sx * size + x % size);
+        }
+    }
+    permutation
+}
+
+fn tile_permutation(size: u32, width: u32, height: u32, rng: &mut impl RngCore) -> Vec<u32> {
+    let (columns, rows) = (width.div_ceil(size), height.div_ceil(size));
+    let sources = block_sources(size, width, height, rng);
+
+    // every tile gets a shuffle of its own, drawn in the order of the tiles
+    let mut permutation = vec![0; (width * height) as usize];
+    for by in 0..rows {
+        for bx in 0..columns {
+            let (sx, sy) = sources[(by * columns + bx) as usize];
+            let tile_width = size.min(width - bx * size);
+            let tile_height = size.min(height - by * size);
+            for (i, source) in shuffled(tile_width * tile_height, rng)
+                .into_iter()
+                .enumerate()
+            {
+                let (x, y) = (i as u32 % tile_width, i as u32 / tile_width);
+                let (source_x, source_y) = (source % tile_width, source / tile_width);
+                permutation[((by * size + y) * width + bx * size + x) as usize] =
+                    (sy * size + source_y) * width + sx * size + source_x;
+            }
+        }
+    }
+    permutation
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::replace;
+
+    use super::*;
+    use crate::test_util::rng;
+
+    // the bijection has to reach every index exactly once, whatever the length
+    #[test]
+    fn bijection_reaches_every_index() {
+        let mut rng = rng();
+        for len in [0, 1, 2, 3, 17, 1000, 1 << 16, 70001] {
+            let permutation = permutation(PermutationUnit::Bijection, len, 1, &mut rng);
+            let mut seen = vec![false; len as usize];
+            let visited = permutation
+                .iter()
+                .filter(|&i| !replace(&mut seen[i as usize], true));
+            assert_eq!(permutation.len(), len as usize);
+            assert_eq!(visited.count(), len as usize, "{}", len);
+        }
+    }
+}
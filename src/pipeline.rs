@@ -0,0 +1,208 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use image::imageops::FilterType;
+
+use crate::{Geometry, Image};
+
+// preprocessing run on the plaintext before it is encrypted, written as steps separated by commas
+// like "resize=50%,grayscale,strip-exif", so preparing an image doesn't take other tools that write
+// plaintext files in between
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PipelineStep {
+    // resample to the size of a geometry, like `50%` or `800x600`; its offsets don't matter
+    Resize(Geometry),
+    // keep the part of the image inside a geometry, like `800x600+10+10`
+    Crop(Geometry),
+    // luma instead of color, keeping the alpha channel and the sample depth
+    Grayscale,
+    // clockwise, by 90, 180 or 270 degrees
+    Rotate(u32),
+    FlipHorizontal,
+    FlipVertical,
+    // drop the metadata of the source file, which a JPEG container would otherwise carry over
+    StripMetadata,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pipeline {
+    pub steps: Vec<PipelineStep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineError {
+    UnknownStep(String),
+    // a step with a value it can't take, or without the value it needs
+    InvalidValue(String),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::UnknownStep(step) => write!(
+                f,
+                "unknown preprocessing step {}, expected resize, crop, grayscale, rotate, flip or strip-exif",
+                step
+            ),
+            PipelineError::InvalidValue(step) => write!(f, "invalid preprocessing step {}", step),
+        }
+    }
+}
+
+impl Error for PipelineError {}
+
+impl FromStr for PipelineStep {
+    type Err = PipelineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || PipelineError::InvalidValue(s.to_string());
+        let (name, value) = match s.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (s.trim(), None),
+        };
+        let geometry = || value.and_then(|value| value.parse::<Geometry>().ok());
+        let step = match (name.to_ascii_lowercase().as_str(), value) {
+            ("resize", _) => PipelineStep::Resize(geometry().ok_or_else(invalid)?),
+            ("crop", _) => PipelineStep::Crop(geometry().ok_or_else(invalid)?),
+            ("grayscale" | "greyscale", None) => PipelineStep::Grayscale,
+            ("rotate", Some(degrees)) => match degrees.parse() {
+                Ok(degrees @ (90 | 180 | 270)) => PipelineStep::Rotate(degrees),
+                _ => return Err(invalid()),
+            },
+            ("flip", Some("horizontal" | "h")) => PipelineStep::FlipHorizontal,
+            ("flip", Some("vertical" | "v")) => PipelineStep::FlipVertical,
+            ("strip-exif" | "strip-metadata", None) => PipelineStep::StripMetadata,
+            (
+                "grayscale" | "greyscale" | "rotate" | "flip" | "strip-exif" | "strip-metadata",
+                _,
+            ) => return Err(invalid()),
+            _ => return Err(PipelineError::UnknownStep(name.to_string())),
+        };
+        Ok(step)
+    }
+}
+
+impl FromStr for Pipeline {
+    type Err = PipelineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let steps = s
+            .split(',')
+            .filter(|step| !step.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Pipeline { steps })
+    }
+}
+
+impl fmt::Display for PipelineStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineStep::Resize(geometry) => {
+                write!(f, "resize={}x{}", geometry.width, geometry.height)
+            }
+            PipelineStep::Crop(geometry) => write!(f, "crop={}", geometry),
+            PipelineStep::Grayscale => write!(f, "grayscale"),
+            PipelineStep::Rotate(degrees) => write!(f, "rotate={}", degrees),
+            PipelineStep::FlipHorizontal => write!(f, "flip=horizontal"),
+            PipelineStep::FlipVertical => write!(f, "flip=vertical"),
+            PipelineStep::StripMetadata => write!(f, "strip-exif"),
+        }
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let steps = self.steps.iter().map(PipelineStep::to_string);
+        write!(f, "{}", steps.collect::<Vec<_>>().join(","))
+    }
+}
+
+impl Pipeline {
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    // run the steps on the image in order, returning the ones that were skipped because they would
+    // leave no pixels, image can't work on its color type, or they rotate by another angle
+    pub fn apply(&self, img: &mut Image) -> Vec<PipelineStep> {
+        self.steps
+            .iter()
+            .filter(|step| !apply_step(img, step))
+            .copied()
+            .collect()
+    }
+}
+
+// whether the step could be run
+fn apply_step(img: &mut Image, step: &PipelineStep) -> bool {
+    let processed = match *step {
+        PipelineStep::Crop(geometry) => {
+            let Some(rect) = geometry.resolve(img.width, img.height) else {
+                return false;
+            };
+            img.pixels = img.crop_pixels(rect);
+            (img.width, img.height) = (rect.width, rect.height);
+            return true;
+        }
+        PipelineStep::StripMetadata => {
+            img.metadata.clear();
+            img.jpeg_segments.clear();
+            return true;
+        }
+        PipelineStep::Resize(geometry) => {
+            let width = u32::try_from(geometry.width.resolve(img.width));
+            let height = u32::try_from(geometry.height.resolve(img.height));
+            let (Ok(width @ 1..), Ok(height @ 1..)) = (width, height) else {
+                return false;
+            };
+            img.to_dynamic()
+                .map(|image| image.resize_exact(width, height, FilterType::Lanczos3))
+        }
+        PipelineStep::Grayscale => img.to_dynamic().map(|image| image.grayscale()),
+        PipelineStep::Rotate(90) => img.to_dynamic().map(|image| image.rotate90()),
+        PipelineStep::Rotate(180) => img.to_dynamic().map(|image| image.rotate180()),
+        PipelineStep::Rotate(270) => img.to_dynamic().map(|image| image.rotate270()),
+        PipelineStep::Rotate(_) => return false,
+        PipelineStep::FlipHorizontal => img.to_dynamic().map(|image| image.fliph()),
+        PipelineStep::FlipVertical => img.to_dynamic().map(|image| image.flipv()),
+    };
+    // color types image can't represent are left as they are
+    match processed {
+        Some(processed) => {
+            img.set_dynamic(processed);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ColorType;
+
+    use super::*;
+    use crate::{
+        decrypt_image, encrypt_image_with,
+        test_util::{random_image, rng},
+        EncryptOptions,
+    };
+
+    // preprocessing runs before encrypting, so decrypting gives the image it made; a pipeline
+    // written back out parses to the same steps
+    #[test]
+    fn runs_before_encrypting() {
+        let mut img = random_image(&mut rng(), 37, 12, ColorType::Rgba8);
+        let pipeline = "resize=50%,grayscale,rotate=90,crop=4x5+1+2,flip=h,strip-exif"
+            .parse::<Pipeline>()
+            .unwrap();
+        assert_eq!(pipeline.to_string().parse(), Ok(pipeline.clone()));
+        let options = EncryptOptions {
+            pipeline,
+            ..Default::default()
+        };
+        encrypt_image_with(&mut img, 1, &options);
+        assert!(decrypt_image(&mut img, 1).is_ok());
+        assert_eq!((img.width, img.height, img.color), (4, 5, ColorType::La8));
+    }
+}
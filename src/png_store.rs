@@ -0,0 +1,280 @@
+use std::time::Duration;
+
+use image::{
+    error::{
+        ImageFormatHint, ParameterError, ParameterErrorKind, UnsupportedError, UnsupportedErrorKind,
+    },
+    ColorType, ImageError, ImageFormat, ImageResult,
+};
+
+// a PNG whose image data is left uncompressed, in stored deflate blocks with no row filters:
+// ciphertext looks like noise to the compressor, so compressing it only costs time
+// and the file comes out barely smaller, if at all
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+// the most a stored deflate block can hold
+const BLOCK_LEN: usize = u16::MAX as usize;
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // the sums can't overflow within this many bytes before they are reduced
+    for chunk in bytes.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// the color type and bit depth of a PNG with these pixels
+fn png_color(color: ColorType) -> ImageResult<(u8, u8)> {
+    match color {
+        ColorType::L8 => Ok((0, 8)),
+        ColorType::La8 => Ok((4, 8)),
+        ColorType::Rgb8 => Ok((2, 8)),
+        ColorType::Rgba8 => Ok((6, 8)),
+        ColorType::L16 => Ok((0, 16)),
+        ColorType::La16 => Ok((4, 16)),
+        ColorType::Rgb16 => Ok((2, 16)),
+        ColorType::Rgba16 => Ok((6, 16)),
+        _ => Err(ImageError::Unsupported(
+            UnsupportedError::from_format_and_kind(
+                ImageFormatHint::Exact(ImageFormat::Png),
+                UnsupportedErrorKind::Color(color.into()),
+            ),
+        )),
+    }
+}
+
+// the image data of the pixels as an uncompressed zlib stream
+fn stored_zlib(pixels: &[u8], width: u32, height: u32, color: ColorType) -> ImageResult<Vec<u8>> {
+    let (_, depth) = png_color(color)?;
+    // every row starts with its filter type, 0 for none; 16-bit samples are big endian in the file
+    let row_len = width as usize * color.bytes_per_pixel() as usize;
+    if pixels.len() != row_len * height as usize {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::DimensionMismatch,
+        )));
+    }
+    let mut raw = Vec::with_capacity((row_len + 1) * height as usize);
+    for row in pixels.chunks_exact(row_len.max(1)) {
+        raw.push(0);
+        if depth == 16 {
+            raw.extend(
+                row.chunks_exact(2)
+                    .flat_map(|sample| [sample[1], sample[0]]),
+            );
+        } else {
+            raw.extend_from_slice(row);
+        }
+    }
+
+    // a zlib stream without compression: the header, stored blocks and the checksum
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(BLOCK_LEN).collect::<Vec<_>>();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i + 1 == blocks.len()) as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    if blocks.is_empty() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+    Ok(zlib)
+}
+
+// the signature and IHDR of a PNG of this size and color type
+fn png_start(width: u32, height: u32, color: ColorType) -> ImageResult<Vec<u8>> {
+    let (color_type, depth) = png_color(color)?;
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // depth, color type, then deflate compression, adaptive filtering and no interlacing
+    ihdr.extend_from_slice(&[depth, color_type, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &ihdr);
+    Ok(png)
+}
+
+pub(crate) fn encode_stored(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+) -> ImageResult<Vec<u8>> {
+    let zlib = stored_zlib(pixels, width, height, color)?;
+    let mut png = png_start(width, height, color)?;
+    write_chunk(&mut png, b"IDAT", &zlib);
+    write_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+// a frame of an animated PNG, with a chunk of its own to go before its image data
+pub(crate) struct StoredFrame<'a> {
+    pub pixels: &'a [u8],
+    pub delay: Duration,
+    pub chunk: Option<([u8; 4], Vec<u8>)>,
+}
+
+// an animated PNG that loops forever, every frame left uncompressed like in `encode_stored`;
+// each frame covers the whole image and replaces the one before it, so it decodes to exactly its pixels.
+// The first frame is also the default image, for viewers that don't animate
+pub(crate) fn encode_stored_animation(
+    frames: &[StoredFrame],
+    width: u32,
+    height: u32,
+    color: ColorType,
+) -> ImageResult<Vec<u8>> {
+    let mut png = png_start(width, height, color)?;
+    let mut actl = (frames.len() as u32).to_be_bytes().to_vec();
+    actl.extend_from_slice(&0u32.to_be_bytes());
+    write_chunk(&mut png, b"acTL", &actl);
+
+    // fcTL and fdAT chunks share one sequence of numbers
+    let mut sequence = 0u32;
+    for (i, frame) in frames.iter().enumerate() {
+        // the delay in milliseconds, as long as it fits
+        let delay = frame.delay.as_millis().min(u16::MAX as u128) as u16;
+        let mut fctl = sequence.to_be_bytes().to_vec();
+        fctl.extend_from_slice(&width.to_be_bytes());
+        fctl.extend_from_slice(&height.to_be_bytes());
+        fctl.extend_from_slice(&[0; 8]);
+        fctl.extend_from_slice(&delay.to_be_bytes());
+        fctl.extend_from_slice(&1000u16.to_be_bytes());
+        // no disposal, and the frame replaces what is under it instead of blending over it
+        fctl.extend_from_slice(&[0, 0]);
+        write_chunk(&mut png, b"fcTL", &fctl);
+        sequence += 1;
+
+        if let Some((kind, data)) = &frame.chunk {
+            write_chunk(&mut png, kind, data);
+        }
+        let zlib = stored_zlib(frame.pixels, width, height, color)?;
+        if i == 0 {
+            write_chunk(&mut png, b"IDAT", &zlib);
+        } else {
+            let mut fdat = sequence.to_be_bytes().to_vec();
+            fdat.extend_from_slice(&zlib);
+            write_chunk(&mut png, b"fdAT", &fdat);
+            sequence += 1;
+        }
+    }
+    write_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+// the data of every chunk of a PNG, in order, as far as it is well formed
+pub(crate) fn chunks(png: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    let mut rest = png.strip_prefix(SIGNATURE).unwrap_or_default();
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        if rest.len() < 12 + len {
+            break;
+        }
+        chunks.push((rest[4..8].try_into().unwrap(), &rest[8..8 + len]));
+        rest = &rest[12 + len..];
+    }
+    chunks
+}
+
+// the exact size `encode_stored` writes for an image of this size and color type
+pub(crate) fn stored_len(width: u32, height: u32, color: ColorType) -> u64 {
+    let raw = height as u64 * (1 + width as u64 * color.bytes_per_pixel() as u64);
+    let blocks = raw.div_ceil(BLOCK_LEN as u64).max(1);
+    let zlib = 2 + 5 * blocks + raw + 4;
+    // the signature, then IHDR, IDAT and IEND with 12 bytes of length, type and CRC each
+    SIGNATURE.len() as u64 + (12 + 13) + (12 + zlib) + 12
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dynamic_bytes,
+        test_util::{random_image, rng},
+    };
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        // long enough for the sums to be reduced on the way, against reducing them after every byte
+        let bytes = vec![0xFF; 100_000];
+        let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), &byte| {
+            let a = (a + byte as u32) % 65521;
+            (a, (b + a) % 65521)
+        });
+        assert_eq!(adler32(&bytes), (b << 16) | a);
+    }
+
+    // the image crate reads back exactly the pixels written, whether the image data fits
+    // in a single stored block or is split over several
+    #[test]
+    fn decodes_to_pixels() {
+        let mut rng = rng();
+        let images = [
+            (1, 1, ColorType::L8),
+            (37, 23, ColorType::La8),
+            (17, 9, ColorType::Rgb16),
+            // one row over a block, and rows spread over several blocks
+            (21845, 3, ColorType::Rgb8),
+            (300, 120, ColorType::Rgba16),
+        ];
+        for (width, height, color) in images {
+            let img = random_image(&mut rng, width, height, color);
+            let png = encode_stored(&img.pixels, width, height, color).unwrap();
+            assert_eq!(png.len() as u64, stored_len(width, height, color));
+            let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap();
+            assert_eq!(decoded.color(), color);
+            assert_eq!(dynamic_bytes(decoded), img.pixels, "{}x{}", width, height);
+        }
+        assert!(encode_stored(&[0; 5], 2, 1, ColorType::Rgb8).is_err());
+        assert!(encode_stored(&[0; 12], 1, 1, ColorType::Rgb32F).is_err());
+    }
+}
@@ -0,0 +1,640 @@
+use std::{error::Error, fmt};
+
+// a QR code in byte mode at error correction level M, versions 1 to 10 (up to 213 bytes),
+// which is plenty for a URL or a key fingerprint
+
+// per version: the error correction codewords per block, and the blocks as (count, data codewords)
+const BLOCKS: [(usize, [(usize, usize); 2]); 10] = [
+    (10, [(1, 16), (0, 0)]),
+    (16, [(1, 28), (0, 0)]),
+    (26, [(1, 44), (0, 0)]),
+    (18, [(2, 32), (0, 0)]),
+    (24, [(2, 43), (0, 0)]),
+    (16, [(4, 27), (0, 0)]),
+    (18, [(4, 31), (0, 0)]),
+    (22, [(2, 38), (2, 39)]),
+    (22, [(3, 36), (2, 37)]),
+    (26, [(4, 43), (1, 44)]),
+];
+
+// the centers of the alignment patterns along each axis, per version
+const ALIGNMENT: [&[usize]; 10] = [
+    &[],
+    &[6, 18],
+    &[6, 22],
+    &[6, 26],
+    &[6, 30],
+    &[6, 34],
+    &[6, 22, 38],
+    &[6, 24, 42],
+    &[6, 26, 46],
+    &[6, 28, 50],
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrError {
+    // how many bytes were too many
+    pub len: usize,
+}
+
+impl fmt::Display for QrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes don't fit in a QR code", self.len)
+    }
+}
+
+impl Error for QrError {}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct QrCode {
+    size: usize,
+    // row by row, true for dark modules
+    modules: Vec<bool>,
+}
+
+impl fmt::Debug for QrCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QrCode({}x{})", self.size, self.size)
+    }
+}
+
+// multiplication in GF(256) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_mul(x: u8, y: u8) -> u8 {
+    let mut z = 0u8;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x1D);
+        z ^= ((y >> i) & 1) * x;
+    }
+    z
+}
+
+// the Reed-Solomon error correction codewords for a block of data
+fn reed_solomon(data: &[u8], degree: usize) -> Vec<u8> {
+    // the coefficients of the generator polynomial, highest first without the leading 1
+    let mut divisor = vec![0u8; degree];
+    divisor[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_mul(divisor[j], root);
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+        root = gf_mul(root, 2);
+    }
+
+    let mut remainder = vec![0u8; degree];
+    for &byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+        for (r, &coefficient) in remainder.iter_mut().zip(&divisor) {
+            *r ^= gf_mul(coefficient, factor);
+        }
+    }
+    remainder
+}
+
+// the data codewords: mode, length, the bytes, a terminator and padding up to the capacity
+fn data_codewords(data: &[u8], version: usize, capacity: usize) -> Vec<u8> {
+    let mut bits = Vec::new();
+    let mut push = |value: usize, len: usize| {
+        for i in (0..len).rev() {
+            bits.push((value >> i) & 1 == 1);
+        }
+    };
+    push(0b0100, 4);
+    push(data.len(), if version < 10 { 8 } else { 16 });
+    for &byte in data {
+        push(byte as usize, 8);
+    }
+    let terminator = (capacity * 8 - bits.len()).min(4);
+    bits.extend(std::iter::repeat_n(false, terminator));
+    bits.resize(bits.len().div_ceil(8) * 8, false);
+
+    let mut codewords = bits
+        .chunks_exact(8)
+        .map(|byte| byte.iter().fold(0u8, |acc, &bit| acc << 1 | bit as u8))
+        .collect::<Vec<_>>();
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if codewords.len() >= capacity {
+            break;
+        }
+        codewords.push(pad);
+    }
+    codewords
+}
+
+// the version with its BCH error correction bits, drawn in two blocks from version 7 on
+fn version_bits(version: usize) -> usize {
+    let mut remainder = version;
+    for _ in 0..12 {
+        remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1F25);
+    }
+    version << 12 | remainder
+}
+
+// the error correction level (M is 0) and mask with their BCH bits, masked so they are never all light
+fn format_bits(mask: u8) -> u32 {
+    let data = mask as u32;
+    let mut remainder = data;
+    for _ in 0..10 {
+        remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+    }
+    (data << 10 | remainder) ^ 0x5412
+}
+
+fn mask_applies(mask: u8, x: usize, y: usize) -> bool {
+    match mask {
+        0 => (x + y).is_multiple_of(2),
+        1 => y.is_multiple_of(2),
+        2 => x.is_multiple_of(3),
+        3 => (x + y).is_multiple_of(3),
+        4 => (x / 3 + y / 2).is_multiple_of(2),
+        5 => x * y % 2 + x * y % 3 == 0,
+        6 => (x * y % 2 + x * y % 3).is_multiple_of(2),
+        _ => ((x + y) % 2 + x * y % 3).is_multiple_of(2),
+    }
+}
+
+struct Builder {
+    size: usize,
+    modules: Vec<bool>,
+    // modules of the fixed patterns, which the data and the mask leave alone
+    function: Vec<bool>,
+}
+
+impl Builder {
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i.is_multiple_of(2));
+            self.set_function(i, 6, i.is_multiple_of(2));
+        }
+
+        // the finders in three corners, with their light separators
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4i64..=4 {
+                for dx in -4i64..=4 {
+                    let (x, y) = (cx as i64 + dx, cy as i64 + dy);
+                    if x >= 0 && y >= 0 && x < size as i64 && y < size as i64 {
+                        let distance = dx.abs().max(dy.abs());
+                        self.set_function(x as usize, y as usize, distance != 2 && distance != 4);
+                    }
+                }
+            }
+        }
+
+        let centers = ALIGNMENT[version - 1];
+        let last = centers.len().saturating_sub(1);
+        for (i, &cy) in centers.iter().enumerate() {
+            for (j, &cx) in centers.iter().enumerate() {
+                // the corners with finders in them
+                if [(0, 0), (0, last), (last, 0)].contains(&(i, j)) {
+                    continue;
+                }
+                for dy in -2i64..=2 {
+                    for dx in -2i64..=2 {
+                        let x = (cx as i64 + dx) as usize;
+                        let y = (cy as i64 + dy) as usize;
+                        self.set_function(x, y, dx.abs().max(dy.abs()) != 1);
+                    }
+                }
+            }
+        }
+
+        // reserve the format areas until the mask is known
+        self.draw_format(0);
+
+        if version >= 7 {
+            let bits = version_bits(version);
+            for i in 0..18 {
+                let dark = (bits >> i) & 1 == 1;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set_function(a, b, dark);
+                self.set_function(b, a, dark);
+            }
+        }
+    }
+
+    // the error correction level (M is 0) and mask, in both copies of the format information
+    fn draw_format(&mut self, mask: u8) {
+        let size = self.size;
+        let bits = format_bits(mask);
+        let bit = |i: usize| (bits >> i) & 1 == 1;
+
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        // always dark
+        self.set_function(8, size - 8, true);
+    }
+
+    // place the codewords in the zigzag of two-module columns, from the bottom right corner
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        loop {
+            // the vertical timing pattern is skipped over
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward {
+                        size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+                    if !self.function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = (codewords[i >> 3] >> (7 - (i & 7))) & 1 == 1;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u8) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if !self.function[y * self.size + x] && mask_applies(mask, x, y) {
+                    self.modules[y * self.size + x] ^= true;
+                }
+            }
+        }
+    }
+
+    // how hard the symbol is to read: long runs, 2x2 blocks and an unbalanced number of dark modules;
+    // the lowest scoring mask gets used
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let module = |x: usize, y: usize| self.modules[y * size + x];
+        let mut penalty = 0;
+
+        for transposed in [false, true] {
+            for a in 0..size {
+                let mut run = 1;
+                for b in 1..size {
+                    let (current, previous) = if transposed {
+                        (module(a, b), module(a, b - 1))
+                    } else {
+                        (module(b, a), module(b - 1, a))
+                    };
+                    if current == previous {
+                        run += 1;
+                        if run == 5 {
+                            penalty += 3;
+                        } else if run > 5 {
+                            penalty += 1;
+                        }
+                    } else {
+                        run = 1;
+                    }
+                }
+            }
+        }
+
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = module(x, y);
+                if color == module(x + 1, y)
+                    && color == module(x, y + 1)
+                    && color == module(x + 1, y + 1)
+                {
+                    penalty += 3;
+                }
+            }
+        }
+
+        let dark = self.modules.iter().filter(|&&dark| dark).count();
+        let total = size * size;
+        let deviation = (dark * 20).abs_diff(total * 10);
+        penalty += deviation.div_ceil(total).saturating_sub(1) * 10;
+        penalty
+    }
+}
+
+impl QrCode {
+    // the smallest code that holds the data
+    pub fn encode(data: &[u8]) -> Result<QrCode, QrError> {
+        let fits = |version: usize| {
+            let (_, groups) = BLOCKS[version - 1];
+            let capacity = groups.iter().map(|(count, len)| count * len).sum::<usize>();
+            let header_bits = 4 + if version < 10 { 8 } else { 16 };
+            header_bits + data.len() * 8 <= capacity * 8
+        };
+        let Some(version) = (1..=BLOCKS.len()).find(|&version| fits(version)) else {
+            let (_, groups) = BLOCKS[BLOCKS.len() - 1];
+            let capacity = groups.iter().map(|(count, len)| count * len).sum::<usize>();
+            return Err(QrError {
+                len: data.len() - (capacity - 3),
+            });
+        };
+
+        let (ec_len, groups) = BLOCKS[version - 1];
+        let capacity = groups.iter().map(|(count, len)| count * len).sum();
+        let codewords = data_codewords(data, version, capacity);
+
+        // split into blocks, then interleave their data and their error correction
+        let mut blocks = Vec::new();
+        let mut rest = &codewords[..];
+        for &(count, len) in &groups {
+            for _ in 0..count {
+                let (block, next) = rest.split_at(len);
+                blocks.push((block, reed_solomon(block, ec_len)));
+                rest = next;
+            }
+        }
+        let mut interleaved = Vec::new();
+        let longest = groups.iter().map(|&(_, len)| len).max().unwrap_or(0);
+        for i in 0..longest {
+            interleaved.extend(blocks.iter().filter_map(|(block, _)| block.get(i)));
+        }
+        for i in 0..ec_len {
+            interleaved.extend(blocks.iter().map(|(_, ec)| ec[i]));
+        }
+
+        let size = version * 4 + 17;
+        let mut builder = Builder {
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        };
+        builder.draw_patterns(version);
+        builder.draw_codewords(&interleaved);
+
+        let best = (0..8)
+            .min_by_key(|&mask| {
+                builder.apply_mask(mask);
+                builder.draw_format(mask);
+                let penalty = builder.penalty();
+                // masking is its own inverse
+                builder.apply_mask(mask);
+                penalty
+            })
+            .unwrap();
+        builder.apply_mask(best);
+        builder.draw_format(best);
+
+        Ok(QrCode {
+            size,
+            modules: builder.modules,
+        })
+    }
+
+    // the number of modules on a side, without the quiet zone
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the format information of level M for every mask, and the version information of versions 7 to 10,
+    // from the tables of the QR code spec (ISO/IEC 18004, annexes C and D)
+    const FORMAT: [u32; 8] = [
+        0x5412, 0x5125, 0x5E7C, 0x5B4B, 0x45F9, 0x40CE, 0x4F97, 0x4AA0,
+    ];
+    const VERSION: [usize; 4] = [0x07C94, 0x085BC, 0x09A99, 0x0A4D3];
+    // the most bytes every version holds at level M
+    const CAPACITY: [usize; 10] = [14, 26, 42, 62, 84, 106, 122, 152, 180, 213];
+
+    #[test]
+    fn format_and_version_bits() {
+        for (mask, &bits) in (0..).zip(&FORMAT) {
+            assert_eq!(format_bits(mask), bits);
+        }
+        for (version, &bits) in (7..).zip(&VERSION) {
+            assert_eq!(version_bits(version), bits);
+        }
+    }
+
+    #[test]
+    fn codewords() {
+        // byte mode, the length, "hello", the terminator and the pad bytes
+        assert_eq!(
+            data_codewords(b"hello", 1, 16),
+            [
+                0x40, 0x56, 0x86, 0x56, 0xC6, 0xC6, 0xF0, 0xEC, 0x11, 0xEC, 0x11, 0xEC, 0x11, 0xEC,
+                0x11, 0xEC
+            ]
+        );
+        // the error correction of the data codewords of "HELLO WORLD" at 1-M
+        let data = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17,
+        ];
+        assert_eq!(
+            reed_solomon(&data, 10),
+            [196, 35, 39, 119, 235, 215, 231, 226, 93, 23]
+        );
+    }
+
+    // whether a module belongs to a fixed pattern, worked out from the spec rather than from what was drawn
+    fn is_function(version: usize, x: usize, y: usize) -> bool {
+        let size = version * 4 + 17;
+        // the finders with their separators and the format information beside them
+        let finder = (y < 9 && (x < 9 || x >= size - 8)) || (x < 9 && y >= size - 8);
+        let centers = ALIGNMENT[version - 1];
+        let alignment = centers.iter().any(|&cy| {
+            centers.iter().any(|&cx| {
+                let in_finder = (cx < 9 || cx >= size - 8) && cy < 9 || cx < 9 && cy >= size - 8;
+                !in_finder && x.abs_diff(cx) <= 2 && y.abs_diff(cy) <= 2
+            })
+        });
+        let version_area = version >= 7
+            && ((x >= size - 11 && x < size - 8 && y < 6)
+                || (y >= size - 11 && y < size - 8 && x < 6));
+        finder || x == 6 || y == 6 || alignment || version_area
+    }
+
+    // read the code back the way a scanner does: the format information, the codewords with the mask
+    // taken off, which must be Reed-Solomon codes without errors, and the bytes they hold
+    fn read(code: &QrCode) -> Vec<u8> {
+        let (size, version) = (code.size(), (code.size() - 17) / 4);
+        let dark = |x: usize, y: usize| code.is_dark(x, y) as u32;
+        let first = (0..6).map(|i| dark(8, i) << i).sum::<u32>()
+            | dark(8, 7) << 6
+            | dark(8, 8) << 7
+            | dark(7, 8) << 8
+            | (9..15).map(|i| dark(14 - i, 8) << i).sum::<u32>();
+        let second = (0..8).map(|i| dark(size - 1 - i, 8) << i).sum::<u32>()
+            | (8..15).map(|i| dark(8, size - 15 + i) << i).sum::<u32>();
+        assert_eq!(first, second);
+        assert!(code.is_dark(8, size - 8));
+        let mask = FORMAT.iter().position(|&bits| bits == first).unwrap();
+        if version >= 7 {
+            let block = |transposed: bool| {
+                (0..18)
+                    .map(|i| {
+                        let (a, b) = (size - 11 + i % 3, i / 3);
+                        (if transposed { dark(b, a) } else { dark(a, b) } as usize) << i
+                    })
+                    .sum::<usize>()
+            };
+            assert_eq!(block(false), VERSION[version - 7]);
+            assert_eq!(block(true), VERSION[version - 7]);
+        }
+
+        // the columns in pairs from the right, up and down in turn, skipping the vertical timing pattern
+        let mut bits = Vec::new();
+        let columns = (1..size)
+            .rev()
+            .step_by(2)
+            .map(|x| if x <= 6 { x - 1 } else { x });
+        for (pair, right) in columns.enumerate() {
+            for step in 0..size {
+                let y = if pair % 2 == 0 { size - 1 - step } else { step };
+                for x in [right, right - 1] {
+                    if !is_function(version, x, y) {
+                        let flip = match mask {
+                            0 => (y + x) % 2 == 0,
+                            1 => y % 2 == 0,
+                            2 => x % 3 == 0,
+                            3 => (y + x) % 3 == 0,
+                            4 => (y / 2 + x / 3) % 2 == 0,
+                            5 => (y * x) % 2 + (y * x) % 3 == 0,
+                            6 => ((y * x) % 2 + (y * x) % 3) % 2 == 0,
+                            _ => ((y + x) % 2 + (y * x) % 3) % 2 == 0,
+                        };
+                        bits.push(code.is_dark(x, y) != flip);
+                    }
+                }
+            }
+        }
+        let codewords = bits
+            .chunks_exact(8)
+            .map(|byte| byte.iter().fold(0u8, |acc, &bit| acc << 1 | bit as u8))
+            .collect::<Vec<_>>();
+
+        // take the blocks apart again and check every one has no errors: its polynomial is zero
+        // at every root of the generator
+        let (ec_len, groups) = BLOCKS[version - 1];
+        let lens = groups
+            .iter()
+            .flat_map(|&(count, len)| std::iter::repeat_n(len, count))
+            .collect::<Vec<_>>();
+        let longest = *lens.iter().max().unwrap();
+        let mut blocks = vec![Vec::new(); lens.len()];
+        let mut rest = codewords.iter();
+        for i in 0..longest + ec_len {
+            for (block, &len) in blocks.iter_mut().zip(&lens) {
+                if i < len || i >= longest {
+                    block.push(*rest.next().unwrap());
+                }
+            }
+        }
+        let mut data = Vec::new();
+        for (block, &len) in blocks.iter().zip(&lens) {
+            let mut root = 1;
+            for _ in 0..ec_len {
+                let value = block.iter().fold(0, |acc, &c| gf_mul(acc, root) ^ c);
+                assert_eq!(value, 0);
+                root = gf_mul(root, 2);
+            }
+            data.extend_from_slice(&block[..len]);
+        }
+
+        let bit = |i: usize| (data[i / 8] >> (7 - i % 8)) & 1 == 1;
+        let number = |from: usize, len: usize| {
+            (from..from + len).fold(0, |acc, i| acc << 1 | bit(i) as usize)
+        };
+        assert_eq!(number(0, 4), 0b0100);
+        let len_bits = if version < 10 { 8 } else { 16 };
+        (0..number(4, len_bits))
+            .map(|i| number(4 + len_bits + i * 8, 8) as u8)
+            .collect()
+    }
+
+    // the modules of a fixed payload, so a change to how codes are drawn or masked shows up
+    #[test]
+    fn fixed_payload() {
+        let rows = [
+            "#######..##...#######",
+            "#.....#..##...#.....#",
+            "#.###.#..#..#.#.###.#",
+            "#.###.#...##..#.###.#",
+            "#.###.#..##.#.#.###.#",
+            "#.....#.#..##.#.....#",
+            "#######.#.#.#.#######",
+            "...........##........",
+            "#..#.##.##...#.#.....",
+            "..#.##....#...#....##",
+            "...##.####..##...##.#",
+            "###.##..#..#.....#.##",
+            ".##.#.##..#.#.#.#....",
+            "........##.#...##.#.#",
+            "#######...#..#.#.###.",
+            "#.....#.#.####.##....",
+            "#.###.#....#..###...#",
+            "#.###.#.##.#...#.####",
+            "#.###.#..##.#...#.#.#",
+            "#.....#..##..##......",
+            "#######.#####..#.#.#.",
+        ];
+        let code = QrCode::encode(b"hello").unwrap();
+        assert_eq!(code.size(), rows.len());
+        for (y, row) in rows.iter().enumerate() {
+            for (x, module) in row.chars().enumerate() {
+                assert_eq!(code.is_dark(x, y), module == '#', "({}, {})", x, y);
+            }
+        }
+        assert_eq!(read(&code), b"hello");
+    }
+
+    // every version holds as many bytes as the spec says, and reads back to them
+    #[test]
+    fn read_back() {
+        for (version, &capacity) in (1..).zip(&CAPACITY) {
+            for len in [capacity, capacity + 1] {
+                let data = (0..len)
+                    .map(|i| (i * 37 + version) as u8)
+                    .collect::<Vec<_>>();
+                let Ok(code) = QrCode::encode(&data) else {
+                    assert_eq!((version, len), (10, 214));
+                    continue;
+                };
+                let expected = if len == capacity {
+                    version
+                } else {
+                    version + 1
+                };
+                assert_eq!(code.size(), expected * 4 + 17);
+                assert_eq!(read(&code), data);
+            }
+        }
+        assert_eq!(QrCode::encode(&[0; 214]), Err(QrError { len: 1 }));
+        assert_eq!(QrCode::encode(&[0; 300]), Err(QrError { len: 87 }));
+    }
+}
@@ -0,0 +1,284 @@
+use std::{error::Error, fmt, ops::Range};
+
+use image::{ColorType, ImageFormat};
+
+use crate::{
+    chacha20::NONCE_LEN, decrypt_pixels, encrypt_pixels, swap_samples, Image, Keystream,
+    PermutationUnit, CHUNK_LEN,
+};
+
+// the order the rows of a frame are stored in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RowOrder {
+    #[default]
+    TopDown,
+    // the bottom row first, like Windows DIBs with a positive height and OpenGL's glReadPixels
+    BottomUp,
+}
+
+// the layout of a frame in a borrowed buffer, like a screenshot, a GPU download or a compositor's
+// framebuffer: `height` rows of `width` pixels, each row starting `stride` bytes after the one before it.
+// Only the size of a pixel matters, not the order of its channels, so BGRA is described as Rgba8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameDesc {
+    pub width: u32,
+    pub height: u32,
+    // bytes from the start of a row to the start of the next, the row itself and its padding
+    pub stride: usize,
+    pub color: ColorType,
+    pub row_order: RowOrder,
+}
+
+impl FrameDesc {
+    // a frame without row padding
+    pub fn packed(width: u32, height: u32, color: ColorType) -> Self {
+        FrameDesc {
+            width,
+            height,
+            stride: width as usize * color.bytes_per_pixel() as usize,
+            color,
+            row_order: RowOrder::TopDown,
+        }
+    }
+
+    // the layout of a Windows DIB: rows padded to four bytes, the bottom one first
+    pub fn dib(width: u32, height: u32, color: ColorType) -> Self {
+        let row_len = width as usize * color.bytes_per_pixel() as usize;
+        FrameDesc {
+            stride: row_len.next_multiple_of(4),
+            row_order: RowOrder::BottomUp,
+            ..FrameDesc::packed(width, height, color)
+        }
+    }
+
+    // the bytes of pixels in a row, without the padding
+    fn row_len(&self) -> usize {
+        self.width as usize * self.color.bytes_per_pixel() as usize
+    }
+
+    // where the rows of the image are in the buffer, from the top one down
+    fn rows(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        (0..self.height as usize).map(move |y| {
+            let row = match self.row_order {
+                RowOrder::TopDown => y,
+                RowOrder::BottomUp => self.height as usize - 1 - y,
+            };
+            row * self.stride..row * self.stride + self.row_len()
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawFrameError {
+    // the stride is shorter than a row of pixels
+    StrideTooShort {
+        stride: usize,
+        row_len: usize,
+    },
+    // the buffer ends before the last row does
+    BufferTooShort {
+        len: usize,
+        needed: usize,
+    },
+    // an image written to a frame of another size or color type
+    ImageMismatch {
+        image: (u32, u32, ColorType),
+        frame: (u32, u32, ColorType),
+    },
+}
+
+impl fmt::Display for RawFrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RawFrameError::StrideTooShort { stride, row_len } => write!(
+                f,
+                "a stride of {} bytes is shorter than a row of {} bytes",
+                stride, row_len
+            ),
+            RawFrameError::BufferTooShort { len, needed } => write!(
+                f,
+                "the frame needs {} bytes, but the buffer has {}",
+                needed, len
+            ),
+            RawFrameError::ImageMismatch { image, frame } => write!(
+                f,
+                "a {}x{} {:?} image doesn't fit a {}x{} {:?} frame",
+                image.0, image.1, image.2, frame.0, frame.1, frame.2
+            ),
+        }
+    }
+}
+
+impl Error for RawFrameError {}
+
+// the bytes from the first pixel to the last one; the last row doesn't need its padding
+fn frame_len(desc: &FrameDesc) -> Result<usize, RawFrameError> {
+    let row_len = desc.row_len();
+    if desc.stride < row_len {
+        return Err(RawFrameError::StrideTooShort {
+            stride: desc.stride,
+            row_len,
+        });
+    }
+    Ok(match desc.height {
+        0 => 0,
+        height => desc.stride * (height as usize - 1) + row_len,
+    })
+}
+
+// check that the buffer holds the whole frame
+fn check_buffer(len: usize, desc: &FrameDesc) -> Result<(), RawFrameError> {
+    let needed = frame_len(desc)?;
+    if len < needed {
+        return Err(RawFrameError::BufferTooShort { len, needed });
+    }
+    Ok(())
+}
+
+// copy a frame out of its buffer into an image, which any of the cipher's functions can then work on;
+// the image is a PNG, the lossless format the buffer is closest to. 16-bit samples are taken to be in
+// the machine's own byte order, like those of any buffer it made
+pub fn read_raw_frame(buffer: &[u8], desc: FrameDesc) -> Result<Image, RawFrameError> {
+    check_buffer(buffer.len(), &desc)?;
+    let mut img = Image {
+        format: ImageFormat::Png,
+        pixels: desc.rows().flat_map(|row| &buffer[row]).copied().collect(),
+        color: desc.color,
+        width: desc.width,
+        height: desc.height,
+        header: None,
+        metadata: Vec::new(),
+        jpeg_segments: Vec::new(),
+    };
+    if cfg!(target_endian = "big") {
+        swap_samples(img.color, &mut img.pixels);
+    }
+    Ok(img)
+}
+
+// copy an image back into a frame of the same size and color type, leaving the padding between rows as it is
+pub fn write_raw_frame(
+    img: &Image,
+    buffer: &mut [u8],
+    desc: FrameDesc,
+) -> Result<(), RawFrameError> {
+    if (img.width, img.height, img.color) != (desc.width, desc.height, desc.color) {
+        return Err(RawFrameError::ImageMismatch {
+            image: (img.width, img.height, img.color),
+            frame: (desc.width, desc.height, desc.color),
+        });
+    }
+    check_buffer(buffer.len(), &desc)?;
+    let row_len = desc.row_len();
+    if row_len == 0 {
+        return Ok(());
+    }
+    for (row, pixels) in desc.rows().zip(img.pixels.chunks_exact(row_len)) {
+        let row = &mut buffer[row];
+        row.copy_from_slice(pixels);
+        if cfg!(target_endian = "big") {
+            swap_samples(img.color, row);
+        }
+    }
+    Ok(())
+}
+
+// run the cipher over the rows of the frame; it permutes pixels across the whole frame,
+// so the rows go through it as one image
+fn crypt_frame(
+    buffer: &mut [u8],
+    desc: FrameDesc,
+    crypt: impl FnOnce(&mut Image),
+) -> Result<(), RawFrameError> {
+    let mut img = read_raw_frame(buffer, desc)?;
+    if img.pixels.is_empty() {
+        return Ok(());
+    }
+    crypt(&mut img);
+    write_raw_frame(&img, buffer, desc)
+}
+
+// encrypt a frame where it is, for capture tools and compositors that can't reshape their buffers;
+// with ChaCha20, split into chunks that are encrypted in parallel. A frame has no header to keep
+// a nonce in, so every frame must be encrypted with a key of its own, like `frame_key(key, index)`
+pub fn encrypt_raw_frame(
+    buffer: &mut [u8],
+    desc: FrameDesc,
+    key: u64,
+) -> Result<(), RawFrameError> {
+    crypt_frame(buffer, desc, |img| {
+        let keystream = Keystream::ChaCha20([0; NONCE_LEN]);
+        encrypt_pixels(img, key, keystream, PermutationUnit::Pixel, Some(CHUNK_LEN));
+    })
+}
+
+pub fn decrypt_raw_frame(
+    buffer: &mut [u8],
+    desc: FrameDesc,
+    key: u64,
+) -> Result<(), RawFrameError> {
+    crypt_frame(buffer, desc, |img| {
+        let keystream = Keystream::ChaCha20([0; NONCE_LEN]);
+        decrypt_pixels(img, key, keystream, PermutationUnit::Pixel, Some(CHUNK_LEN));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{decrypt_image, encrypt_image, test_util::rng};
+
+    // a framebuffer with padded rows has to come back as it was, and the padding must never change
+    #[test]
+    fn padded_rows() {
+        let mut rng = rng();
+        let (width, height) = (37, 23);
+        let desc = FrameDesc {
+            stride: width as usize * 4 + 12,
+            ..FrameDesc::packed(width, height, ColorType::Rgba8)
+        };
+        let mut frame = vec![0; desc.stride * height as usize];
+        rng.fill_bytes(&mut frame);
+        let original = frame.clone();
+        let padding = |frame: &[u8]| {
+            frame
+                .chunks(desc.stride)
+                .flat_map(|row| row[width as usize * 4..].to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert!(encrypt_raw_frame(&mut frame, desc, 1).is_ok());
+        assert_ne!(frame, original);
+        assert_eq!(padding(&frame), padding(&original));
+        assert!(decrypt_raw_frame(&mut frame, desc, 1).is_ok());
+        assert_eq!(frame, original);
+    }
+
+    // a bottom-up DIB goes through the whole cipher, header and all, as an image the right way up
+    #[test]
+    fn bottom_up_dib() {
+        let mut rng = rng();
+        let (width, height) = (37, 23);
+        let desc = FrameDesc::dib(width, height, ColorType::Rgb8);
+        let mut frame = vec![0; desc.stride * height as usize];
+        rng.fill_bytes(&mut frame);
+        let original = frame.clone();
+        let plain = read_raw_frame(&frame, desc).unwrap();
+        assert_eq!(
+            plain.pixels[..width as usize * 3],
+            frame[frame.len() - desc.stride..][..width as usize * 3]
+        );
+        let mut img = plain.clone();
+        encrypt_image(&mut img, 1);
+        write_raw_frame(&img, &mut frame, desc).unwrap();
+        let mut img = Image {
+            header: img.header,
+            ..read_raw_frame(&frame, desc).unwrap()
+        };
+        assert!(decrypt_image(&mut img, 1).is_ok());
+        write_raw_frame(&img, &mut frame, desc).unwrap();
+        assert_eq!(img.pixels, plain.pixels);
+        assert_eq!(frame, original);
+    }
+}
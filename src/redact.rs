@@ -0,0 +1,89 @@
+use image::imageops::FilterType;
+
+use crate::{Image, PixelShape, Rect, Region};
+
+// how to destroy the contents of a region; unlike encryption there is no key and nothing to undo
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Redaction {
+    // replace the region with blocks of this many pixels a side, each filled with its average color
+    Pixelate(u32),
+    // a gaussian blur with this standard deviation in pixels; small ones can leave text readable
+    Blur(f32),
+}
+
+// redact a rectangle of the image, only keeping the redacted pixels the mask covers
+fn redact_rect(img: &mut Image, rect: Rect, mask: Option<&[bool]>, redaction: Redaction) {
+    let mut region = Image {
+        format: img.format,
+        pixels: img.crop_pixels(rect),
+        color: img.color,
+        width: rect.width,
+        height: rect.height,
+        header: None,
+        metadata: Vec::new(),
+        jpeg_segments: Vec::new(),
+    };
+    // color types image can't represent are left as they are
+    let Some(dynamic) = region.to_dynamic() else {
+        return;
+    };
+    let redacted = match redaction {
+        Redaction::Pixelate(size) => {
+            let size = size.max(1);
+            dynamic
+                .resize_exact(
+                    rect.width.div_ceil(size),
+                    rect.height.div_ceil(size),
+                    FilterType::Triangle,
+                )
+                .resize_exact(rect.width, rect.height, FilterType::Nearest)
+        }
+        Redaction::Blur(sigma) => dynamic.blur(sigma),
+    };
+    let original = std::mem::take(&mut region.pixels);
+    region.set_dynamic(redacted);
+
+    if let Some(mask) = mask {
+        let pixel_size = img.color.bytes_per_pixel() as usize;
+        for ((pixel, original), &inside) in region
+            .pixels
+            .chunks_exact_mut(pixel_size)
+            .zip(original.chunks_exact(pixel_size))
+            .zip(mask)
+        {
+            if !inside {
+                pixel.copy_from_slice(original);
+            }
+        }
+    }
+    img.paste_pixels(rect, &region.pixels);
+}
+
+// irreversibly pixelate or blur the regions of the image, or the whole image if there are none;
+// regions that fall outside the image are skipped
+pub fn redact_image(img: &mut Image, regions: &[Region], redaction: Redaction) {
+    if img.pixels.is_empty() {
+        return;
+    }
+    if regions.is_empty() {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: img.width,
+            height: img.height,
+        };
+        return redact_rect(img, rect, None, redaction);
+    }
+
+    for region in regions {
+        match region.shape.resolve(img.width, img.height) {
+            Some(PixelShape::Rect(rect)) => redact_rect(img, rect, None, redaction),
+            Some(shape) => {
+                if let Some((rect, mask)) = shape.mask(img.width, img.height) {
+                    redact_rect(img, rect, Some(&mask), redaction);
+                }
+            }
+            None => {}
+        }
+    }
+}
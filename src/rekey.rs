@@ -0,0 +1,234 @@
+use std::{error::Error, fmt};
+
+use image::ImageFormat;
+
+use crate::{
+    audit, auth, banner, decrypt_image, decrypt_jpeg_dct, encrypt_image_with, encrypt_jpeg_dct,
+    key_check_iterations, parse_header, Channel, Cipher, EncryptOptions, EncryptionHeader, Image,
+    ImageEncryptionError, KeyFingerprint, PermutationUnit, Region, SampleOrder, SealedDigest,
+    Shape,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RekeyError {
+    // the image has no encryption header, so it is either not encrypted or was never meant to be rekeyed
+    NotEncrypted,
+    // the image was encrypted with another key
+    WrongKey,
+    // the header doesn't record which key the image was encrypted with, so the old key can't be checked
+    Unverified,
+    // the old key is right, but the ciphertext doesn't match its authentication tag
+    Tampered,
+    // the image already has an encryption header, so there is nothing to migrate
+    HasHeader,
+    // the key decrypts the image to noise, so it is most likely not the one it was encrypted with
+    Implausible,
+}
+
+impl fmt::Display for RekeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RekeyError::NotEncrypted => write!(f, "not encrypted"),
+            RekeyError::WrongKey => write!(f, "encrypted with a different key"),
+            RekeyError::Unverified => write!(f, "no key fingerprint to check the old key against"),
+            RekeyError::Tampered => write!(f, "the image was modified after it was encrypted"),
+            RekeyError::HasHeader => write!(f, "already has an encryption header"),
+            RekeyError::Implausible => write!(
+                f,
+                "the key decrypts the image to noise, so it is most likely not the one it was encrypted with"
+            ),
+        }
+    }
+}
+
+impl Error for RekeyError {}
+
+fn check_old_key(
+    header: Option<&EncryptionHeader>,
+    old_key: u64,
+) -> Result<&EncryptionHeader, RekeyError> {
+    let header = header.ok_or(RekeyError::NotEncrypted)?;
+    match header.check_key(old_key) {
+        Some(true) => Ok(header),
+        Some(false) => Err(RekeyError::WrongKey),
+        None => Err(RekeyError::Unverified),
+    }
+}
+
+// the same for a JPEG encrypted in the DCT domain, from and to the bytes of the file
+pub fn rekey_jpeg_dct(
+    bytes: &[u8],
+    old_key: u64,
+    new_key: u64,
+) -> Result<Vec<u8>, ImageEncryptionError> {
+    check_old_key(parse_header(bytes)?.as_ref(), old_key)?;
+    Ok(encrypt_jpeg_dct(
+        &decrypt_jpeg_dct(bytes, old_key)?,
+        new_key,
+    )?)
+}
+
+// decrypt the image with the old key and encrypt it again with the new one, with the same options it was encrypted with;
+// nothing is done unless the header confirms the old key is the right one
+pub fn rekey_image(img: &mut Image, old_key: u64, new_key: u64) -> Result<(), RekeyError> {
+    let header = check_old_key(img.header.as_ref(), old_key)?;
+
+    // the encrypted color type is the one it was normalized to, if it was
+    let options = EncryptOptions {
+        normalize: header.original_color.map(|_| img.color),
+        convergent: header.convergent_key.is_some(),
+        jpeg_container: header.jpeg_container,
+        cipher: header.cipher.unwrap_or_default(),
+        parallel: header.chunk_len.is_some(),
+        permute_only: header.permute_only,
+        noise: header.noise.map(|noise| noise.shape),
+        disguise: header.disguised,
+        skip_channels: header
+            .skipped_channels
+            .iter()
+            .filter_map(|&c| Channel::at(c as usize, img.color))
+            .collect(),
+        permutation_unit: header.permutation_unit.unwrap_or_default(),
+        scramble_algorithm: header.scramble_algorithm.unwrap_or_default(),
+        rounds: header.rounds.unwrap_or(1),
+        regions: header
+            .regions
+            .iter()
+            .map(|shape| Region::from(Shape::from(shape.clone())))
+            .collect(),
+        // the tags can't be recovered from their tokens, so they don't survive a new key
+        ..Default::default()
+    };
+
+    // the reserved strip isn't encrypted, so it is put back as it is
+    let (width, height) = (img.width, img.height);
+    let reserved = header.reserved;
+    let strip = reserved.map(|strip| img.crop_pixels(strip));
+
+    // the decrypted image may carry a watermark the original didn't, so the digest is carried over as it is
+    let digest = header.plaintext_digest.map(|digest| digest.open(old_key));
+
+    decrypt_image(img, old_key).map_err(|_| RekeyError::Tampered)?;
+    encrypt_image_with(img, new_key, &options);
+    if let (Some(strip), Some(pixels)) = (reserved, strip) {
+        banner::attach_strip(img, strip, &pixels, width, height);
+        if let Some(header) = &mut img.header {
+            header.reserved = Some(strip);
+        }
+    }
+    if let Some(header) = &mut img.header {
+        header.plaintext_digest = digest.map(|digest| SealedDigest::seal(digest, new_key));
+    }
+    // the strip went back in after the ciphertext was tagged
+    auth::authenticate(img, new_key);
+    Ok(())
+}
+
+// give a ciphertext from before there was a header the one encrypting it would write today, so its key
+// is checked, its pixels authenticated and `dec` writes it back in its original format; those versions
+// only had the legacy cipher moving single pixels, so the pixels are kept exactly as they are.
+// Nothing in a headerless ciphertext tells whether the key is the right one, so it is decrypted to see
+// whether that gives an image or noise, unless `force` is set, e.g. for an image of noise
+pub fn migrate_legacy(
+    img: &mut Image,
+    key: u64,
+    original_format: ImageFormat,
+    force: bool,
+) -> Result<(), RekeyError> {
+    if img.header.is_some() {
+        return Err(RekeyError::HasHeader);
+    }
+    if !force {
+        let mut plaintext = img.clone();
+        // without a header it decrypts with the legacy cipher, as it was encrypted
+        decrypt_image(&mut plaintext, key).map_err(|_| RekeyError::Implausible)?;
+        if !audit::looks_like_image(&plaintext) {
+            return Err(RekeyError::Implausible);
+        }
+    }
+    let iterations = key_check_iterations(img.width, img.height);
+    img.header = Some(EncryptionHeader {
+        cipher: Some(Cipher::Legacy),
+        original_format: Some(original_format),
+        dimensions: Some((img.width, img.height)),
+        permutation_unit: Some(PermutationUnit::Pixel),
+        key_fingerprint: Some(KeyFingerprint::hardened(key, iterations)),
+        key_check_iterations: Some(iterations),
+        key_id: Some(KeyFingerprint::id(key)),
+        // they were little-endian, as every machine they were encrypted on was
+        sample_order: (img.color.bytes_per_pixel() > img.color.channel_count())
+            .then_some(SampleOrder::LittleEndian),
+        ..Default::default()
+    });
+    auth::authenticate(img, key);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ColorType;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        encrypt_image,
+        test_util::{random_image, rng},
+        verify_key,
+    };
+
+    // a headerless ciphertext gets a header that checks the key and decrypts it to the original format,
+    // without its pixels changing
+    #[test]
+    fn migrate_headerless() {
+        let mut rng = rng();
+        for color in [ColorType::Rgb8, ColorType::Rgba16] {
+            let key = rng.next_u64();
+            // a gradient with some noise on it, which a wrong key doesn't decrypt to
+            let mut original = random_image(&mut rng, 29, 11, color);
+            let bpp = color.bytes_per_pixel() as usize;
+            for (i, sample) in original.pixels.iter_mut().enumerate() {
+                *sample = (i / bpp % 29 * 8) as u8 + *sample % 8;
+            }
+            let mut img = original.clone();
+            encrypt_image(&mut img, key);
+            img.header = None;
+            let ciphertext = img.pixels.clone();
+
+            assert_eq!(
+                migrate_legacy(&mut img, key ^ 1, ImageFormat::Jpeg, false),
+                Err(RekeyError::Implausible)
+            );
+            assert_eq!(img.header, None);
+            migrate_legacy(&mut img, key, ImageFormat::Jpeg, false).unwrap();
+            assert_eq!(img.pixels, ciphertext);
+            assert_eq!(
+                migrate_legacy(&mut img, key, ImageFormat::Jpeg, true),
+                Err(RekeyError::HasHeader)
+            );
+            assert!(verify_key(&img, key));
+            assert!(!verify_key(&img, key ^ 1));
+            assert!(decrypt_image(&mut img, key).is_ok());
+            assert_eq!(img.format, ImageFormat::Jpeg);
+            assert_eq!(img.pixels, original.pixels);
+        }
+    }
+
+    // an image of noise can't be told from a wrong key, so it takes forcing
+    #[test]
+    fn migrate_noise() {
+        let mut rng = rng();
+        let key = rng.next_u64();
+        let original = random_image(&mut rng, 64, 48, ColorType::Rgb8);
+        let mut img = original.clone();
+        encrypt_image(&mut img, key);
+        img.header = None;
+
+        assert_eq!(
+            migrate_legacy(&mut img, key, ImageFormat::Png, false),
+            Err(RekeyError::Implausible)
+        );
+        migrate_legacy(&mut img, key, ImageFormat::Png, true).unwrap();
+        assert!(decrypt_image(&mut img, key).is_ok());
+        assert_eq!(img.pixels, original.pixels);
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use image::{ColorType, ImageFormat};
+
+use crate::{json::Json, Channel, Cipher, MetadataKind};
+
+// a step of an operation, timed on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    // reading the file, before it is decoded
+    Read,
+    Decode,
+    // deriving the key from a passphrase, which the CLI does before anything else
+    Kdf,
+    // everything done to the plaintext before it is encrypted: digest, watermark, color conversion
+    Prepare,
+    Encrypt,
+    // checking the header, the key and the authentication tag before decrypting
+    Verify,
+    Decrypt,
+    // what is done after the pixel cipher: noise, the banner strip, the authentication tag,
+    // or undoing the color conversion after decrypting
+    Finish,
+    Encode,
+    Write,
+    // the steps of the XOR chain within the encrypt or decrypt phase: generating the random numbers,
+    // generating the permutation, and moving the pixels through it while chaining them
+    Keystream,
+    Permutation,
+    Diffusion,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Read => "read",
+            Phase::Decode => "decode",
+            Phase::Kdf => "kdf",
+            Phase::Prepare => "prepare",
+            Phase::Encrypt => "encrypt",
+            Phase::Verify => "verify",
+            Phase::Decrypt => "decrypt",
+            Phase::Finish => "finish",
+            Phase::Encode => "encode",
+            Phase::Write => "write",
+            Phase::Keystream => "keystream",
+            Phase::Permutation => "permutation",
+            Phase::Diffusion => "diffusion",
+        })
+    }
+}
+
+// how far an operation has got, passed to progress callbacks as it goes: every phase is reported as
+// it starts and as it ends, and reading and writing files also as every block is done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    // bytes of the phase done, out of `total`: file bytes for reading and writing, pixel bytes otherwise
+    pub done: u64,
+    pub total: u64,
+}
+
+impl Progress {
+    pub(crate) fn started(phase: Phase, total: u64) -> Self {
+        Progress {
+            phase,
+            done: 0,
+            total,
+        }
+    }
+
+    pub(crate) fn finished(phase: Phase, total: u64) -> Self {
+        Progress {
+            phase,
+            done: total,
+            total,
+        }
+    }
+
+    // how much of the phase is done, from 0 to 1
+    pub fn fraction(&self) -> f64 {
+        match self.total {
+            0 => 1.0,
+            total => self.done as f64 / total as f64,
+        }
+    }
+}
+
+// something an operation went through with, but not quite the way it was asked to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OperationWarning {
+    // metadata of the source file that wasn't written out
+    DroppedMetadata(MetadataKind),
+    ColorConverted { from: ColorType, to: ColorType },
+    // a lossy format replaced by a lossless one, which keeps the ciphertext exactly
+    FormatChanged { from: ImageFormat, to: ImageFormat },
+    // a region outside the image, which was left out
+    RegionSkipped(String),
+    // channels skipped as asked, which are in the ciphertext as they were in the plaintext
+    ChannelsPassedThrough(Vec<Channel>),
+    // a channel to skip that the color type of the image doesn't have
+    NoSuchChannel(Channel),
+    // skipping every channel would leave nothing encrypted, so none were skipped
+    ChannelsNotSkipped,
+    // ciphertext written in a lossy format, which changes it, so it won't decrypt to the image
+    LossyCiphertext(ImageFormat),
+    // noise is only shaped over a whole encrypted image, not regions or some of the channels
+    NoiseNotShaped,
+    // the banner couldn't be drawn in the color type of the image
+    BannerNotAdded,
+    // there was no fingerprint or tag to check the key against, so a wrong one gives garbage
+    KeyUnchecked,
+    // the image was too big for the memory budget, so it was encrypted a band at a time instead
+    Streamed,
+    // a preprocessing step that would leave no pixels or can't work on the image, which was left out
+    StepSkipped(String),
+}
+
+impl fmt::Display for OperationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OperationWarning::DroppedMetadata(kind) => write!(f, "{} metadata was dropped", kind),
+            OperationWarning::ColorConverted { from, to } => {
+                write!(f, "the pixels were converted from {:?} to {:?}", from, to)
+            }
+            OperationWarning::FormatChanged { from, to } => {
+                write!(f, "{:?} is a lossy format, the image is {:?} now", from, to)
+            }
+            OperationWarning::RegionSkipped(region) => {
+                write!(f, "region {} is outside the image and was skipped", region)
+            }
+            OperationWarning::ChannelsPassedThrough(channels) => {
+                let names = channels
+                    .iter()
+                    .map(|channel| format!("{:?}", channel).to_lowercase())
+                    .collect::<Vec<_>>();
+                match names.split_last() {
+                    Some((last, [])) => write!(f, "the {} channel", last)?,
+                    Some((last, rest)) => {
+                        write!(f, "the {} and {} channels", rest.join(", "), last)?
+                    }
+                    None => f.write_str("no channel")?,
+                }
+                f.write_str(" passed through unencrypted")
+            }
+            OperationWarning::NoSuchChannel(channel) => write!(
+                f,
+                "the image has no {} channel to skip",
+                format!("{:?}", channel).to_lowercase()
+            ),
+            OperationWarning::LossyCiphertext(format) => write!(
+                f,
+                "{:?} is a lossy format, the ciphertext won't decrypt to the image",
+                format
+            ),
+            OperationWarning::ChannelsNotSkipped => write!(
+                f,
+                "skipping every channel would leave nothing encrypted, all of them were encrypted"
+            ),
+            OperationWarning::NoiseNotShaped => write!(
+                f,
+                "noise is only shaped over the whole image, it was left out"
+            ),
+            OperationWarning::BannerNotAdded => write!(
+                f,
+                "the banner can't be drawn in the color type of the image, it was left out"
+            ),
+            OperationWarning::KeyUnchecked => write!(
+                f,
+                "the image has nothing to check the key against, a wrong one decrypts to noise"
+            ),
+            OperationWarning::Streamed => write!(
+                f,
+                "too big for the memory budget, streamed a band at a time with the legacy cipher \
+                 and none of the other encryption options"
+            ),
+            OperationWarning::StepSkipped(step) => write!(
+                f,
+                "preprocessing step {} can't be run on the image, it was left out",
+                step
+            ),
+        }
+    }
+}
+
+// what an encryption, decryption or write did, for the CLI to summarize or print as JSON
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OperationReport {
+    // pixel bytes for encrypting and decrypting, and for writing the encoded file size out
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // in the order they ran
+    pub phases: Vec<(Phase, Duration)>,
+    // the steps of the encrypt or decrypt phase, for schemes that time them; part of its time,
+    // not in addition to it
+    pub steps: Vec<(Phase, Duration)>,
+    // None for writing, which doesn't encrypt anything
+    pub cipher: Option<Cipher>,
+    pub warnings: Vec<OperationWarning>,
+    // the `content_hash` of the ciphertext, when `EncryptOptions::hash_ciphertext` asks for it
+    pub content_hash: Option<String>,
+}
+
+// std has no clock on wasm32-unknown-unknown, where every phase takes no time
+pub(crate) fn start() -> Option<Instant> {
+    (!cfg!(target_arch = "wasm32")).then(Instant::now)
+}
+
+pub(crate) fn elapsed(started: Option<Instant>) -> Duration {
+    started.map_or(Duration::ZERO, |started| started.elapsed())
+}
+
+// the time the XOR chain spent in each of its steps, added up over every region it ran on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct CipherSteps {
+    pub(crate) keystream: Duration,
+    pub(crate) permutation: Duration,
+    pub(crate) diffusion: Duration,
+}
+
+impl CipherSteps {
+    pub(crate) fn add(self, other: CipherSteps) -> Self {
+        CipherSteps {
+            keystream: self.keystream + other.keystream,
+            permutation: self.permutation + other.permutation,
+            diffusion: self.diffusion + other.diffusion,
+        }
+    }
+
+    pub(crate) fn phases(self) -> Vec<(Phase, Duration)> {
+        vec![
+            (Phase::Keystream, self.keystream),
+            (Phase::Permutation, self.permutation),
+            (Phase::Diffusion, self.diffusion),
+        ]
+    }
+}
+
+// the same, telling the progress callback the phase started
+pub(crate) fn start_phase(
+    progress: &mut dyn FnMut(Progress),
+    phase: Phase,
+    total: u64,
+) -> Option<Instant> {
+    progress(Progress::started(phase, total));
+    start()
+}
+
+impl OperationReport {
+    pub(crate) fn time(&mut self, phase: Phase, started: Option<Instant>) {
+        self.phases.push((phase, elapsed(started)));
+    }
+
+    // time a phase begun with `start_phase`, telling the progress callback it ended
+    pub(crate) fn end_phase(
+        &mut self,
+        progress: &mut dyn FnMut(Progress),
+        phase: Phase,
+        total: u64,
+        started: Option<Instant>,
+    ) {
+        self.time(phase, started);
+        progress(Progress::finished(phase, total));
+    }
+
+    // the report of this operation followed by another on its output, like encrypting and writing
+    pub fn then(mut self, next: OperationReport) -> Self {
+        self.bytes_out = next.bytes_out;
+        self.phases.extend(next.phases);
+        self.steps.extend(next.steps);
+        self.cipher = self.cipher.or(next.cipher);
+        self.warnings.extend(next.warnings);
+        self.content_hash = self.content_hash.or(next.content_hash);
+        self
+    }
+
+    pub fn total_time(&self) -> Duration {
+        self.phases.iter().map(|&(_, elapsed)| elapsed).sum()
+    }
+
+    // compact JSON, durations in seconds
+    pub fn to_json(&self) -> String {
+        let number = |n: u64| Json::Number(n as f64);
+        let phases = |phases: &[(Phase, Duration)]| {
+            let phases = phases
+                .iter()
+                .map(|(phase, elapsed)| {
+                    Json::Object(vec![
+                        ("phase".to_string(), Json::String(phase.to_string())),
+                        ("seconds".to_string(), Json::Number(elapsed.as_secs_f64())),
+                    ])
+                })
+                .collect();
+            Json::Array(phases)
+        };
+        let cipher = self
+            .cipher
+            .map_or(Json::Null, |cipher| Json::String(format!("{:?}", cipher)));
+        let content_hash = self
+            .content_hash
+            .as_ref()
+            .map_or(Json::Null, |hash| Json::String(hash.clone()));
+        let warnings = self
+            .warnings
+            .iter()
+            .map(|warning| Json::String(warning.to_string()))
+            .collect();
+        Json::Object(vec![
+            ("bytes_in".to_string(), number(self.bytes_in)),
+            ("bytes_out".to_string(), number(self.bytes_out)),
+            ("cipher".to_string(), cipher),
+            ("content_hash".to_string(), content_hash),
+            ("phases".to_string(), phases(&self.phases)),
+            (
+                "seconds".to_string(),
+                Json::Number(self.total_time().as_secs_f64()),
+            ),
+            ("steps".to_string(), phases(&self.steps)),
+            ("warnings".to_string(), Json::Array(warnings)),
+        ])
+        .to_string()
+    }
+}
+
+// the summary the CLI prints, a line for each thing
+impl fmt::Display for OperationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(cipher) = self.cipher {
+            writeln!(f, "cipher: {:?}", cipher)?;
+        }
+        writeln!(f, "bytes: {} in, {} out", self.bytes_in, self.bytes_out)?;
+        for (phase, elapsed) in &self.phases {
+            writeln!(f, "{}: {:.1?}", phase, elapsed)?;
+            if let Phase::Encrypt | Phase::Decrypt = phase {
+                for (step, elapsed) in &self.steps {
+                    writeln!(f, "  {}: {:.1?}", step, elapsed)?;
+                }
+            }
+        }
+        write!(f, "total: {:.1?}", self.total_time())?;
+        for warning in &self.warnings {
+            write!(f, "\nwarning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
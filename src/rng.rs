@@ -0,0 +1,219 @@
+use std::sync::OnceLock;
+
+use rand::{Error, RngCore};
+
+// a generator that can jump ahead to any of its outputs without producing the ones before them,
+// so workers can each start at their own part of a keystream
+pub(crate) trait SeekableRng: RngCore {
+    // carry on as if `next_u32` had been called this many more times
+    fn skip_u32(&mut self, count: u64);
+}
+
+// the xoshiro256++ generator, seeded the way `rand_core::SeedableRng::seed_from_u64` does it
+//
+// `rand::rngs::SmallRng` is this exact generator on 64-bit targets, but it is documented as
+// platform-dependent (32-bit targets get xoshiro128++), so the cipher pins the algorithm here
+// to produce the same keystream and permutation on every architecture and endianness
+pub struct Xoshiro256PlusPlus {
+    s: [u64; 4],
+}
+
+impl Xoshiro256PlusPlus {
+    pub fn seed_from_u64(mut state: u64) -> Self {
+        // expand the u64 into a 256-bit seed with PCG32, 4 little-endian bytes at a time
+        let mut seed = [0u8; 32];
+        for chunk in seed.chunks_exact_mut(4) {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(11634580027462260723);
+            let xorshifted = (((state >> 18) ^ state) >> 27) as u32;
+            let rot = (state >> 59) as u32;
+            chunk.copy_from_slice(&xorshifted.rotate_right(rot).to_le_bytes());
+        }
+        Self::from_seed(seed)
+    }
+
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        // an all-zero state would only ever produce zeros
+        if seed == [0; 32] {
+            return Self::seed_from_u64(0);
+        }
+        let mut s = [0u64; 4];
+        for (word, chunk) in s.iter_mut().zip(seed.chunks_exact(8)) {
+            *word = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        Self { s }
+    }
+}
+
+impl RngCore for Xoshiro256PlusPlus {
+    fn next_u32(&mut self) -> u32 {
+        // the lowest bits have some linear dependencies, so use the upper ones
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let result = self.s[0]
+            .wrapping_add(self.s[3])
+            .rotate_left(23)
+            .wrapping_add(self.s[0]);
+        step(&mut self.s);
+        result
+    }
+
+    // bytes are always taken from the little-endian representation of each output
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+fn step(s: &mut [u64; 4]) {
+    let t = s[1] << 17;
+
+    s[2] ^= s[0];
+    s[3] ^= s[1];
+    s[1] ^= s[2];
+    s[0] ^= s[3];
+
+    s[2] ^= t;
+
+    s[3] = s[3].rotate_left(45);
+}
+
+// the state update is linear over GF(2), so `n` steps are a 256 by 256 bit matrix: the matrices
+// of every power of two steps are worked out once, and any jump is a product of some of them
+type Matrix = [[u64; 4]; 256];
+
+// where every bit of the state ends up, as one column per bit
+fn apply(matrix: &Matrix, state: &[u64; 4]) -> [u64; 4] {
+    let mut result = [0; 4];
+    for (bit, column) in matrix.iter().enumerate() {
+        if state[bit / 64] >> (bit % 64) & 1 == 1 {
+            for (result, column) in result.iter_mut().zip(column) {
+                *result ^= column;
+            }
+        }
+    }
+    result
+}
+
+fn powers_of_two_steps() -> &'static [Matrix] {
+    static POWERS: OnceLock<Vec<Matrix>> = OnceLock::new();
+    POWERS.get_or_init(|| {
+        let mut one_step = [[0; 4]; 256];
+        for (bit, column) in one_step.iter_mut().enumerate() {
+            column[bit / 64] = 1 << (bit % 64);
+            step(column);
+        }
+        let mut powers = vec![one_step];
+        for _ in 1..u64::BITS {
+            let last = powers.last().unwrap();
+            let squared = std::array::from_fn(|bit| apply(last, &last[bit]));
+            powers.push(squared);
+        }
+        powers
+    })
+}
+
+impl SeekableRng for Xoshiro256PlusPlus {
+    // every output is one step, however many of its bits are used
+    fn skip_u32(&mut self, count: u64) {
+        for (power, matrix) in powers_of_two_steps().iter().enumerate() {
+            if count >> power & 1 == 1 {
+                self.s = apply(matrix, &self.s);
+            }
+        }
+    }
+}
+
+// the ways of drawing from a generator the cipher uses. They are the algorithms of rand 0.8, which
+// the cipher called on before, so existing ciphertexts still decrypt; having them here means its output
+// doesn't change with whichever version of rand the build resolves
+
+// a number in `0..bound`, by widening multiplication with rejection of the biased low products
+pub(crate) fn below(rng: &mut (impl RngCore + ?Sized), bound: u32) -> u32 {
+    debug_assert!(bound > 0);
+    // not the exact bias zone, which would take a division, but a conservative one
+    let zone = (bound << bound.leading_zeros()).wrapping_sub(1);
+    loop {
+        let product = rng.next_u32() as u64 * bound as u64;
+        if product as u32 <= zone {
+            return (product >> 32) as u32;
+        }
+    }
+}
+
+// Fisher-Yates from the back, which slices of more than `u32::MAX` items can't take
+pub(crate) fn shuffle<T>(items: &mut [T], rng: &mut (impl RngCore + ?Sized)) {
+    for i in (1..items.len()).rev() {
+        items.swap(i, below(rng, i as u32 + 1) as usize);
+    }
+}
+
+// a float in `0..1` with all 53 bits of its mantissa random
+pub(crate) fn unit_f64(rng: &mut (impl RngCore + ?Sized)) -> f64 {
+    (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// a float in `low..high`, drawn from `1..2` with 52 random bits and scaled; should rounding land it on
+// `high`, the scale is made a step smaller and another one is drawn
+pub(crate) fn between_f64(rng: &mut (impl RngCore + ?Sized), low: f64, high: f64) -> f64 {
+    let mut scale = high - low;
+    loop {
+        let one_to_two = f64::from_bits(rng.next_u64() >> 12 | 1023 << 52);
+        let value = (one_to_two - 1.0) * scale + low;
+        if value < high {
+            return value;
+        }
+        scale = f64::from_bits(scale.to_bits() - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // skipping ahead in the keystream has to land on the same numbers as drawing up to there
+    #[test]
+    fn skip_ahead() {
+        for offset in [0, 1, 15, 16, 17, 1000, 123_457] {
+            let mut drawn = Xoshiro256PlusPlus::seed_from_u64(offset);
+            let mut skipped = Xoshiro256PlusPlus::seed_from_u64(offset);
+            for _ in 0..offset {
+                drawn.next_u32();
+            }
+            skipped.skip_u32(offset);
+            assert!(
+                (0..20).all(|_| drawn.next_u32() == skipped.next_u32()),
+                "{}",
+                offset
+            );
+        }
+    }
+
+    // what rand 0.8 drew from the same generator
+    #[test]
+    fn draws_of_rand() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(5);
+        let mut indices = (0..16).collect::<Vec<u32>>();
+        shuffle(&mut indices, &mut rng);
+        assert_eq!(
+            indices,
+            [9, 7, 6, 0, 15, 14, 2, 1, 3, 5, 12, 13, 8, 4, 10, 11]
+        );
+        assert_eq!(below(&mut rng, 1000), 168);
+        assert_eq!(unit_f64(&mut rng).to_bits(), 4606441202518198480);
+        assert_eq!(
+            between_f64(&mut rng, 0.01, 0.99).to_bits(),
+            4596633379782123684
+        );
+    }
+}
@@ -0,0 +1,475 @@
+use std::{cell::Cell, time::Duration};
+
+use rand::RngCore;
+
+use crate::{
+    blake3,
+    chacha20::ChaCha20Rng,
+    decrypt_pixels, encrypt_pixels,
+    report::CipherSteps,
+    rng::{between_f64, shuffle, unit_f64, Xoshiro256PlusPlus},
+    Image, Keystream, PermutationUnit, Phase,
+};
+
+// the scheme that turns the pixels into ciphertext, recorded in the header so decryption runs its inverse.
+// Only the XOR chain is meant for protecting images; the others are classic schemes from the literature,
+// kept for comparing how they hold up with `audit`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum ScrambleAlgorithm {
+    // the key-derived permutation and XOR chain every image was encrypted with before there was a choice
+    #[default]
+    XorChain,
+    // the pixels moved by the classic Arnold cat map, on the image padded to a square, as many times
+    // as the key says; the values stay as they are
+    ArnoldCat,
+    // the pixels sorted by a logistic map sequence, then the bytes chained with more of it
+    ChaoticMap,
+    // every channel sliced into its eight bit planes, each shuffled with a permutation of its own
+    // and XORed with keystream bits, then put back together, which changes the values as well as moving them
+    BitPlane,
+}
+
+// a scheme that scrambles an image in place with a key and undoes it with the same key
+pub trait Scrambler {
+    fn scramble(&self, img: &mut Image, key: u64);
+    fn unscramble(&self, img: &mut Image, key: u64);
+
+    // how long its steps have taken so far, for schemes that time them apart
+    fn steps(&self) -> Vec<(Phase, Duration)> {
+        Vec::new()
+    }
+}
+
+impl ScrambleAlgorithm {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            ScrambleAlgorithm::XorChain => 0,
+            ScrambleAlgorithm::ArnoldCat => 1,
+            ScrambleAlgorithm::ChaoticMap => 2,
+            ScrambleAlgorithm::BitPlane => 3,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ScrambleAlgorithm::XorChain),
+            1 => Some(ScrambleAlgorithm::ArnoldCat),
+            2 => Some(ScrambleAlgorithm::ChaoticMap),
+            3 => Some(ScrambleAlgorithm::BitPlane),
+            _ => None,
+        }
+    }
+
+    // the scheme on its own, keyed like the legacy cipher, for running it outside of `encrypt_image`
+    pub fn scrambler(self) -> Box<dyn Scrambler> {
+        self.with(Keystream::Legacy, PermutationUnit::Pixel, None)
+    }
+
+    // the scheme with the generator, permutation unit and chunks of an encryption
+    pub(crate) fn with(
+        self,
+        keystream: Keystream,
+        unit: PermutationUnit,
+        chunk_len: Option<u32>,
+    ) -> Box<dyn Scrambler> {
+        match self {
+            ScrambleAlgorithm::XorChain => Box::new(XorChain {
+                keystream,
+                unit,
+                chunk_len,
+                steps: Cell::default(),
+            }),
+            ScrambleAlgorithm::ArnoldCat => Box::new(ArnoldCat),
+            ScrambleAlgorithm::ChaoticMap => Box::new(ChaoticMap { keystream }),
+            ScrambleAlgorithm::BitPlane => Box::new(BitPlane { keystream }),
+        }
+    }
+}
+
+// the parameters of every scheme but the XOR chain are drawn from a generator keyed apart from it,
+// ChaCha20 with the nonce of the image if it was encrypted with it
+fn scheme_rng(key: u64, keystream: Keystream, scheme: &[u8]) -> Box<dyn RngCore> {
+    let hash = blake3::Hasher::new()
+        .update(b"image_encryption scramble\0")
+        .update(scheme)
+        .update(&key.to_le_bytes())
+        .finalize();
+    let key = u64::from_le_bytes(hash[..8].try_into().unwrap());
+    match keystream {
+        Keystream::Legacy => Box::new(Xoshiro256PlusPlus::seed_from_u64(key)),
+        Keystream::ChaCha20(nonce) => Box::new(ChaCha20Rng::from_key(key, nonce)),
+    }
+}
+
+// move pixel `i` to `destinations[i]`
+fn scatter(img: &mut Image, destinations: &[u32]) {
+    let pixel_size = img.color.bytes_per_pixel() as usize;
+    let mut pixels = vec![0; img.pixels.len()];
+    for (&to, pixel) in destinations.iter().zip(img.pixels.chunks_exact(pixel_size)) {
+        pixels[pixel_size * to as usize..][..pixel_size].copy_from_slice(pixel);
+    }
+    img.pixels = pixels;
+}
+
+// take pixel `i` from `sources[i]`, undoing `scatter`
+fn gather(img: &mut Image, sources: &[u32]) {
+    let pixel_size = img.color.bytes_per_pixel() as usize;
+    img.pixels = sources
+        .iter()
+        .flat_map(|&from| &img.pixels[pixel_size * from as usize..][..pixel_size])
+        .copied()
+        .collect();
+}
+
+struct XorChain {
+    keystream: Keystream,
+    unit: PermutationUnit,
+    chunk_len: Option<u32>,
+    // added up over every region it runs on
+    steps: Cell<CipherSteps>,
+}
+
+impl Scrambler for XorChain {
+    fn scramble(&self, img: &mut Image, key: u64) {
+        let steps = encrypt_pixels(img, key, self.keystream, self.unit, self.chunk_len);
+        self.steps.set(self.steps.get().add(steps));
+    }
+
+    fn unscramble(&self, img: &mut Image, key: u64) {
+        let steps = decrypt_pixels(img, key, self.keystream, self.unit, self.chunk_len);
+        self.steps.set(self.steps.get().add(steps));
+    }
+
+    fn steps(&self) -> Vec<(Phase, Duration)> {
+        self.steps.get().phases()
+    }
+}
+
+// the classic Arnold cat map (x, y) -> (x + y, x + 2y) mod n on an n by n image, iterated as many times
+// as the key: it comes back to the start after at most 3n iterations, so that is all the key is worth,
+// and the map only hides a picture from a casual look. `encrypt_arnold` uses it with the number of
+// iterations for the key
+struct ArnoldCat;
+
+// the map iterated `iterations` times, as the power of its matrix mod `n`
+fn matrix_power(n: u64, iterations: u64) -> [[u64; 2]; 2] {
+    let multiply = |a: [[u64; 2]; 2], b: [[u64; 2]; 2]| {
+        let cell = |i: usize, j: usize| (a[i][0] * b[0][j] % n + a[i][1] * b[1][j] % n) % n;
+        [[cell(0, 0), cell(0, 1)], [cell(1, 0), cell(1, 1)]]
+    };
+    let mut power = [[1 % n, 0], [0, 1 % n]];
+    let mut base = [[1, 1], [1, 2 % n]];
+    let mut iterations = iterations;
+    while iterations > 0 {
+        if iterations & 1 == 1 {
+            power = multiply(power, base);
+        }
+        base = multiply(base, base);
+        iterations >>= 1;
+    }
+    power
+}
+
+// pad the image with zeros on the right or the bottom to the square the Arnold cat map runs on;
+// decrypting crops it back to the dimensions in the header
+pub(crate) fn pad_to_square(img: &mut Image) {
+    let (width, height) = (img.width, img.height);
+    let n = width.max(height);
+    if width == height {
+        return;
+    }
+    let pixel_size = img.color.bytes_per_pixel() as usize;
+    let row_len = width as usize * pixel_size;
+    let mut square = vec![0; n as usize * n as usize * pixel_size];
+    if row_len > 0 {
+        for (row, pixels) in img.pixels.chunks_exact(row_len).enumerate() {
+            square[row * n as usize * pixel_size..][..row_len].copy_from_slice(pixels);
+        }
+    }
+    img.pixels = square;
+    (img.width, img.height) = (n, n);
+}
+
+// crop the padding of `pad_to_square` off again
+pub(crate) fn crop_square(img: &mut Image, width: u32, height: u32) {
+    let pixel_size = img.color.bytes_per_pixel() as usize;
+    let row_len = width as usize * pixel_size;
+    img.pixels = img
+        .pixels
+        .chunks_exact((img.width as usize * pixel_size).max(1))
+        .take(height as usize)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect();
+    (img.width, img.height) = (width, height);
+}
+
+impl ArnoldCat {
+    // where every pixel of the square ends up after the iterations
+    fn destinations(&self, img: &Image, key: u64) -> Vec<u32> {
+        let n = img.width as u64;
+        if n == 0 {
+            return Vec::new();
+        }
+        let [[a, b], [c, d]] = matrix_power(n, key);
+        (0..n * n)
+            .map(|position| {
+                let (x, y) = (position % n, position / n);
+                let to_x = (a * x % n + b * y % n) % n;
+                let to_y = (c * x % n + d * y % n) % n;
+                (to_y * n + to_x) as u32
+            })
+            .collect()
+    }
+}
+
+impl Scrambler for ArnoldCat {
+    fn scramble(&self, img: &mut Image, key: u64) {
+        pad_to_square(img);
+        let destinations = self.destinations(img, key);
+        scatter(img, &destinations)
+    }
+
+    // the padding is left on, for the caller that knows the size to crop it off
+    fn unscramble(&self, img: &mut Image, key: u64) {
+        let destinations = self.destinations(img, key);
+        gather(img, &destinations)
+    }
+}
+
+struct ChaoticMap {
+    keystream: Keystream,
+}
+
+// the logistic map x -> r x (1 - x) in its chaotic range, started from a key-derived point;
+// IEEE arithmetic gives the same sequence on every machine
+struct Logistic {
+    r: f64,
+    x: f64,
+}
+
+impl Logistic {
+    fn new(rng: &mut dyn RngCore) -> Self {
+        let mut logistic = Logistic {
+            r: 3.99 + unit_f64(rng) * 0.01,
+            x: between_f64(rng, 0.01, 0.99),
+        };
+        // the first values still show where it started
+        for _ in 0..1000 {
+            logistic.next();
+        }
+        logistic
+    }
+
+    fn next(&mut self) -> f64 {
+        self.x = self.r * self.x * (1.0 - self.x);
+        // rounding can land the orbit on 0 or 1, where it would stay, so it is nudged off them
+        if self.x <= f64::EPSILON || self.x >= 1.0 - f64::EPSILON {
+            self.x = 0.5 + self.x / 4.0;
+        }
+        self.x
+    }
+
+    fn byte(&mut self) -> u8 {
+        (self.next() * 1e14) as u64 as u8
+    }
+}
+
+impl ChaoticMap {
+    // the order that sorts one value of the map for every pixel, and the map to draw bytes from after it
+    fn sources(&self, img: &Image, key: u64) -> (Vec<u32>, Logistic) {
+        let mut rng = scheme_rng(key, self.keystream, b"chaotic map");
+        let mut logistic = Logistic::new(&mut *rng);
+        let values = (0..img.width * img.height)
+            .map(|_| logistic.next())
+            .collect::<Vec<_>>();
+        let mut sources = (0..values.len() as u32).collect::<Vec<_>>();
+        sources.sort_by(|&a, &b| values[a as usize].total_cmp(&values[b as usize]));
+        (sources, logistic)
+    }
+}
+
+impl Scrambler for ChaoticMap {
+    fn scramble(&self, img: &mut Image, key: u64) {
+        let (sources, mut logistic) = self.sources(img, key);
+        gather(img, &sources);
+        let mut previous = logistic.byte();
+        for byte in &mut img.pixels {
+            *byte ^= logistic.byte() ^ previous;
+            previous = *byte;
+        }
+    }
+
+    fn unscramble(&self, img: &mut Image, key: u64) {
+        let (sources, mut logistic) = self.sources(img, key);
+        let mut previous = logistic.byte();
+        for byte in &mut img.pixels {
+            let cipher = *byte;
+            *byte ^= logistic.byte() ^ previous;
+            previous = cipher;
+        }
+        scatter(img, &sources)
+    }
+}
+
+struct BitPlane {
+    keystream: Keystream,
+}
+
+// the bits of one plane of one channel, a bit per pixel, packed 64 to a word
+type Plane = Vec<u64>;
+
+fn bit(plane: &[u64], i: usize) -> u64 {
+    plane[i / 64] >> (i % 64) & 1
+}
+
+// bit `plane` of byte `channel` of every pixel; the bytes of wider samples are channels of their own
+fn slice_plane(pixels: &[u8], pixel_size: usize, channel: usize, plane: u32) -> Plane {
+    let mut bits = vec![0; (pixels.len() / pixel_size).div_ceil(64)];
+    for (i, pixel) in pixels.chunks_exact(pixel_size).enumerate() {
+        bits[i / 64] |= ((pixel[channel] >> plane & 1) as u64) << (i % 64);
+    }
+    bits
+}
+
+// write the plane back into bit `plane` of byte `channel` of every pixel
+fn merge_plane(pixels: &mut [u8], pixel_size: usize, channel: usize, plane: u32, bits: &[u64]) {
+    let mask = !(1 << plane);
+    for (i, pixel) in pixels.chunks_exact_mut(pixel_size).enumerate() {
+        pixel[channel] = pixel[channel] & mask | (bit(bits, i) as u8) << plane;
+    }
+}
+
+// bit `i` taken from bit `sources[i]`
+fn gather_bits(bits: &[u64], sources: &[u32]) -> Plane {
+    let mut gathered = vec![0; bits.len()];
+    for (i, &from) in sources.iter().enumerate() {
+        gathered[i / 64] |= bit(bits, from as usize) << (i % 64);
+    }
+    gathered
+}
+
+// bit `i` put back at bit `sources[i]`, undoing `gather_bits`
+fn scatter_bits(bits: &[u64], sources: &[u32]) -> Plane {
+    let mut scattered = vec![0; bits.len()];
+    for (i, &to) in sources.iter().enumerate() {
+        scattered[to as usize / 64] |= bit(bits, i) << (to % 64);
+    }
+    scattered
+}
+
+impl BitPlane {
+    // every plane of every channel in turn, lowest first, drawing its permutation and then its
+    // keystream bits, so undoing it draws them in the same order
+    fn crypt(&self, img: &mut Image, key: u64, scramble: bool) {
+        let mut rng = scheme_rng(key, self.keystream, b"bit plane");
+        let pixel_size = img.color.bytes_per_pixel() as usize;
+        let len = (img.pixels.len() / pixel_size) as u32;
+        for channel in 0..pixel_size {
+            for plane in 0..8 {
+                let mut sources = (0..len).collect::<Vec<_>>();
+                shuffle(&mut sources, &mut rng);
+                let bits = slice_plane(&img.pixels, pixel_size, channel, plane);
+                let keystream = (0..bits.len()).map(|_| rng.next_u64());
+                let bits = if scramble {
+                    let mut bits = gather_bits(&bits, &sources);
+                    bits.iter_mut()
+                        .zip(keystream)
+                        .for_each(|(bits, key)| *bits ^= key);
+                    bits
+                } else {
+                    let bits = bits
+                        .iter()
+                        .zip(keystream)
+                        .map(|(bits, key)| bits ^ key)
+                        .collect::<Vec<_>>();
+                    scatter_bits(&bits, &sources)
+                };
+                merge_plane(&mut img.pixels, pixel_size, channel, plane, &bits);
+            }
+        }
+    }
+}
+
+impl Scrambler for BitPlane {
+    fn scramble(&self, img: &mut Image, key: u64) {
+        self.crypt(img, key, true)
+    }
+
+    fn unscramble(&self, img: &mut Image, key: u64) {
+        self.crypt(img, key, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageFormat};
+
+    use super::*;
+    use crate::{
+        decrypt_arnold, decrypt_image, encrypt_arnold, encrypt_image_with,
+        test_util::{random_image, rng, round_trips, COLORS},
+        Cipher, EncryptOptions,
+    };
+
+    // the other schemes have to be recorded in the header, so decrypting finds its way back without being told
+    #[test]
+    fn recorded_round_trip() {
+        let mut rng = rng();
+        let algorithms = [
+            ScrambleAlgorithm::ArnoldCat,
+            ScrambleAlgorithm::ChaoticMap,
+            ScrambleAlgorithm::BitPlane,
+        ];
+        for algorithm in algorithms {
+            for cipher in [Cipher::Legacy, Cipher::ChaCha20] {
+                for color in COLORS {
+                    let key = rng.next_u64();
+                    let original = random_image(&mut rng, 31, 17, color);
+                    let options = EncryptOptions {
+                        cipher,
+                        scramble_algorithm: algorithm,
+                        ..Default::default()
+                    };
+                    let mut img = original.clone();
+                    encrypt_image_with(&mut img, key, &options);
+                    let recorded = img.header().and_then(|header| header.scramble_algorithm);
+                    assert_eq!(recorded, Some(algorithm));
+                    assert_ne!(img.pixels, original.pixels);
+                    assert!(decrypt_image(&mut img, key).is_ok());
+                    assert!(
+                        round_trips(&original, key, &options),
+                        "{:?} {:?} {:?}",
+                        algorithm,
+                        cipher,
+                        color
+                    );
+                }
+            }
+        }
+    }
+
+    // the keyless Arnold cat map pads the image to a square and crops it back,
+    // and only the number of iterations it was scrambled with undoes it
+    #[test]
+    fn padded_round_trip() {
+        let original = Image {
+            format: ImageFormat::Jpeg,
+            ..random_image(&mut rng(), 37, 12, ColorType::Rgba8)
+        };
+        let mut img = original.clone();
+        encrypt_arnold(&mut img, 7);
+        assert_eq!(
+            (img.width, img.height, img.format),
+            (37, 37, ImageFormat::Png)
+        );
+        assert!(decrypt_arnold(&mut img.clone(), 8).is_err());
+        assert!(decrypt_arnold(&mut img, 7).is_ok());
+        assert_eq!(
+            (img.width, img.height, img.format),
+            (37, 12, ImageFormat::Jpeg)
+        );
+        assert_eq!(img.pixels, original.pixels);
+    }
+}
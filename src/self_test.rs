@@ -0,0 +1,153 @@
+use image::{ColorType, ImageFormat};
+use rand::RngCore;
+
+use crate::{blake3, chacha20, encrypt_image, kdf, rng::Xoshiro256PlusPlus, to_hex, Image};
+
+// the outcome of a single self-test check
+#[derive(Debug, Clone)]
+pub struct SelfTestResult {
+    pub name: String,
+    pub passed: bool,
+    // what this platform computed, in hex, so the output of two machines can be compared
+    pub output: String,
+}
+
+// a known-answer vector: the BLAKE3 hash of the ciphertext of a generated plaintext
+struct CrossVector {
+    name: &'static str,
+    key: u64,
+    width: u32,
+    height: u32,
+    color: ColorType,
+    ciphertext_hash: &'static str,
+}
+
+// the first outputs of the keystream generator for a fixed seed, checked on their own
+// so a platform-dependent RNG path can be told apart from a platform-dependent cipher path
+const KEYSTREAM_SEED: u64 = 0x0123_4567_89ab_cdef;
+const KEYSTREAM_VECTOR: [u64; 4] = [
+    0x23e3fb6f2ea63945,
+    0x34c954fbb4dda192,
+    0x42d84ad8202195e5,
+    0x6a806ba43decd0b1,
+];
+
+// the first 8 bytes of PBKDF2-HMAC-SHA256 of "password" and "salt" with 4096 iterations,
+// from the published test vectors, read as a little-endian key
+const PBKDF2_VECTOR: u64 = u64::from_le_bytes([0xc5, 0xe4, 0x78, 0xd5, 0x92, 0x88, 0xc8, 0x41]);
+
+// the keystream block of the ChaCha20 test vector in RFC 8439, section 2.3.2: key 00..1f,
+// nonce 00:00:00:09:00:00:00:4a:00:00:00:00 and block counter 1, of which the first 16 bytes are checked
+const CHACHA20_VECTOR: [u8; 16] = [
+    0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20, 0x71, 0xc4,
+];
+
+// these were produced on x86_64 and must match bit for bit on every other architecture
+const CROSS_VECTORS: &[CrossVector] = &[
+    CrossVector {
+        name: "luma8 7x5",
+        key: 0,
+        width: 7,
+        height: 5,
+        color: ColorType::L8,
+        ciphertext_hash: "783180388fda6840607d744c060795820b2f087e1cc2f45d257b238af0df9a27",
+    },
+    CrossVector {
+        name: "la8 16x9",
+        key: 1,
+        width: 16,
+        height: 9,
+        color: ColorType::La8,
+        ciphertext_hash: "5a694b518ae6e1bf0ca9a62e3134a43f202466e1846e1f57607a585a2606e5f4",
+    },
+    CrossVector {
+        name: "rgb8 33x17",
+        key: 0xdead_beef,
+        width: 33,
+        height: 17,
+        color: ColorType::Rgb8,
+        ciphertext_hash: "59953bdf529b50553127dbcddce173c157ccb0ab3d28615f045031f8788b27a3",
+    },
+    CrossVector {
+        name: "rgba8 64x64",
+        key: u64::MAX,
+        width: 64,
+        height: 64,
+        color: ColorType::Rgba8,
+        ciphertext_hash: "260045050d65f20437380e41a0674db6099357d9e65e3310fa45cb867c005049",
+    },
+    CrossVector {
+        name: "rgb8 1x1",
+        key: 123_456_789,
+        width: 1,
+        height: 1,
+        color: ColorType::Rgb8,
+        ciphertext_hash: "e68d7053f8d7fb2bf3c3fd78eece3abf559e93c56039574f74be487c9e5bdb36",
+    },
+];
+
+// a deterministic plaintext pattern, so the vectors don't need to ship image files
+fn vector_image(width: u32, height: u32, color: ColorType) -> Image {
+    let len = (width * height) as usize * color.bytes_per_pixel() as usize;
+    Image {
+        format: ImageFormat::Png,
+        pixels: (0..len).map(|i| (i * 31 + 7) as u8).collect(),
+        color,
+        width,
+        height,
+        header: None,
+        metadata: Vec::new(),
+        jpeg_segments: Vec::new(),
+    }
+}
+
+// check the cipher against the known-answer vectors shipped in the crate
+pub fn run_cross_vectors() -> Vec<SelfTestResult> {
+    let mut results = Vec::with_capacity(CROSS_VECTORS.len() + 3);
+
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(KEYSTREAM_SEED);
+    let keystream = KEYSTREAM_VECTOR.map(|_| rng.next_u64());
+    results.push(SelfTestResult {
+        name: "xoshiro256++ keystream".to_string(),
+        passed: keystream == KEYSTREAM_VECTOR,
+        output: keystream.map(|v| format!("{:016x}", v)).join(" "),
+    });
+    let pbkdf2 = kdf::pbkdf2_key(b"password", b"salt", 4096);
+    results.push(SelfTestResult {
+        name: "pbkdf2-hmac-sha256".to_string(),
+        passed: pbkdf2 == PBKDF2_VECTOR,
+        output: to_hex(&pbkdf2.to_le_bytes()),
+    });
+    let key = std::array::from_fn(|i| i as u32 * 0x0404_0404 + 0x0302_0100);
+    let block = chacha20::block(&key, 1, &[0x0900_0000, 0x4a00_0000, 0]);
+    results.push(SelfTestResult {
+        name: "chacha20 block".to_string(),
+        passed: block[..16] == CHACHA20_VECTOR,
+        output: to_hex(&block[..16]),
+    });
+
+    for vector in CROSS_VECTORS {
+        let mut img = vector_image(vector.width, vector.height, vector.color);
+        encrypt_image(&mut img, vector.key);
+        let hash = to_hex(&blake3::Hasher::new().update(&img.pixels).finalize());
+        results.push(SelfTestResult {
+            name: vector.name.to_string(),
+            passed: hash == vector.ciphertext_hash,
+            output: hash,
+        });
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cross_vectors() {
+        for result in run_cross_vectors() {
+            assert!(result.passed, "{}", result.name);
+        }
+    }
+}
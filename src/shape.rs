@@ -0,0 +1,309 @@
+use std::{fmt, str::FromStr};
+
+use crate::geometry::{parse_length, Geometry, GeometryError, Length, Rect};
+
+// the outline of a region to encrypt, with sizes that can be percentages of the image
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Shape {
+    Rect(Geometry),
+    // the ellipse inscribed in the geometry's rectangle
+    Ellipse(Geometry),
+    // the vertices of a polygon, as (x, y) offsets from the top left corner of the image
+    Polygon(Vec<(Length, Length)>),
+}
+
+// a shape resolved against an image, in pixels; unlike rectangles, ellipses and polygons
+// aren't clipped to the image, so they keep their outline
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum PixelShape {
+    Rect(Rect),
+    Ellipse {
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+    },
+    Polygon(Vec<(i32, i32)>),
+}
+
+const KIND_RECT: u8 = 0;
+const KIND_ELLIPSE: u8 = 1;
+const KIND_POLYGON: u8 = 2;
+
+impl PixelShape {
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            PixelShape::Rect(rect) => {
+                bytes.push(KIND_RECT);
+                bytes.extend_from_slice(&rect.to_bytes());
+            }
+            PixelShape::Ellipse {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                bytes.push(KIND_ELLIPSE);
+                bytes.extend_from_slice(&x.to_le_bytes());
+                bytes.extend_from_slice(&y.to_le_bytes());
+                bytes.extend_from_slice(&width.to_le_bytes());
+                bytes.extend_from_slice(&height.to_le_bytes());
+            }
+            PixelShape::Polygon(points) => {
+                bytes.push(KIND_POLYGON);
+                bytes.extend_from_slice(&(points.len() as u32).to_le_bytes());
+                for (x, y) in points {
+                    bytes.extend_from_slice(&x.to_le_bytes());
+                    bytes.extend_from_slice(&y.to_le_bytes());
+                }
+            }
+        }
+        bytes
+    }
+
+    // read one shape from the start of the bytes, returning it and the rest of the bytes
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let (&kind, rest) = bytes.split_first()?;
+        let word = |i: usize| -> Option<[u8; 4]> { rest.get(i * 4..i * 4 + 4)?.try_into().ok() };
+        match kind {
+            KIND_RECT => Some((PixelShape::Rect(Rect::from_bytes(rest)?), rest.get(16..)?)),
+            KIND_ELLIPSE => Some((
+                PixelShape::Ellipse {
+                    x: i32::from_le_bytes(word(0)?),
+                    y: i32::from_le_bytes(word(1)?),
+                    width: u32::from_le_bytes(word(2)?),
+                    height: u32::from_le_bytes(word(3)?),
+                },
+                rest.get(16..)?,
+            )),
+            KIND_POLYGON => {
+                let count = u32::from_le_bytes(word(0)?) as usize;
+                let points = (0..count)
+                    .map(|i| {
+                        Some((
+                            i32::from_le_bytes(word(1 + i * 2)?),
+                            i32::from_le_bytes(word(2 + i * 2)?),
+                        ))
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some((PixelShape::Polygon(points), rest.get(4 + count * 8..)?))
+            }
+            _ => None,
+        }
+    }
+
+    // the bounding box of the shape, as (left, top, right, bottom) with right and bottom exclusive
+    fn bounds(&self) -> (i64, i64, i64, i64) {
+        match self {
+            PixelShape::Rect(rect) => (
+                rect.x as i64,
+                rect.y as i64,
+                rect.x as i64 + rect.width as i64,
+                rect.y as i64 + rect.height as i64,
+            ),
+            &PixelShape::Ellipse {
+                x,
+                y,
+                width,
+                height,
+            } => (
+                x as i64,
+                y as i64,
+                x as i64 + width as i64,
+                y as i64 + height as i64,
+            ),
+            PixelShape::Polygon(points) => points.iter().fold(
+                (i64::MAX, i64::MAX, i64::MIN, i64::MIN),
+                |(left, top, right, bottom), &(x, y)| {
+                    let (x, y) = (x as i64, y as i64);
+                    (left.min(x), top.min(y), right.max(x), bottom.max(y))
+                },
+            ),
+        }
+    }
+
+    // whether the center of the pixel at (x, y) lies inside the shape
+    fn contains(&self, x: i64, y: i64) -> bool {
+        let (px, py) = (x as f64 + 0.5, y as f64 + 0.5);
+        match self {
+            PixelShape::Rect(_) => true,
+            &PixelShape::Ellipse {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let (rx, ry) = (width as f64 / 2.0, height as f64 / 2.0);
+                let (dx, dy) = ((px - x as f64 - rx) / rx, (py - y as f64 - ry) / ry);
+                dx * dx + dy * dy <= 1.0
+            }
+            // even-odd rule: count the edges a ray going right from the point crosses
+            PixelShape::Polygon(points) => {
+                let mut inside = false;
+                for (i, &(x1, y1)) in points.iter().enumerate() {
+                    let (x2, y2) = points[(i + 1) % points.len()];
+                    let (x1, y1, x2, y2) = (x1 as f64, y1 as f64, x2 as f64, y2 as f64);
+                    if (y1 > py) != (y2 > py) && px < x1 + (py - y1) / (y2 - y1) * (x2 - x1) {
+                        inside = !inside;
+                    }
+                }
+                inside
+            }
+        }
+    }
+
+    // rasterize the shape: its bounding box clipped to the image, and for every pixel of the box,
+    // row by row, whether it is inside the shape; None if the shape doesn't cover any pixel of the image
+    pub(crate) fn mask(&self, image_width: u32, image_height: u32) -> Option<(Rect, Vec<bool>)> {
+        let (left, top, right, bottom) = self.bounds();
+        let (left, top) = (left.max(0), top.max(0));
+        let (right, bottom) = (
+            right.min(image_width as i64),
+            bottom.min(image_height as i64),
+        );
+        if right <= left || bottom <= top {
+            return None;
+        }
+
+        let mask = (top..bottom)
+            .flat_map(|y| (left..right).map(move |x| (x, y)))
+            .map(|(x, y)| self.contains(x, y))
+            .collect::<Vec<_>>();
+        mask.contains(&true).then(|| {
+            let rect = Rect {
+                x: left as u32,
+                y: top as u32,
+                width: (right - left) as u32,
+                height: (bottom - top) as u32,
+            };
+            (rect, mask)
+        })
+    }
+}
+
+impl Shape {
+    // the shape in pixels for an image of the given size, or None if it doesn't cover any pixel of it
+    pub fn resolve(&self, image_width: u32, image_height: u32) -> Option<PixelShape> {
+        let shape = match self {
+            Shape::Rect(geometry) => {
+                return geometry
+                    .resolve(image_width, image_height)
+                    .map(PixelShape::Rect)
+            }
+            Shape::Ellipse(geometry) => {
+                let (x, y, width, height) = geometry.resolve_unclipped(image_width, image_height);
+                PixelShape::Ellipse {
+                    x: x.try_into().ok()?,
+                    y: y.try_into().ok()?,
+                    width: width.try_into().ok()?,
+                    height: height.try_into().ok()?,
+                }
+            }
+            Shape::Polygon(points) => PixelShape::Polygon(
+                points
+                    .iter()
+                    .map(|&(x, y)| {
+                        Some((
+                            x.resolve(image_width).try_into().ok()?,
+                            y.resolve(image_height).try_into().ok()?,
+                        ))
+                    })
+                    .collect::<Option<_>>()?,
+            ),
+        };
+        shape.mask(image_width, image_height).map(|_| shape)
+    }
+}
+
+impl From<PixelShape> for Shape {
+    fn from(shape: PixelShape) -> Self {
+        let pixels = |value: i64| Length::Pixels(value);
+        match shape {
+            PixelShape::Rect(rect) => Shape::Rect(Geometry::from(rect)),
+            PixelShape::Ellipse {
+                x,
+                y,
+                width,
+                height,
+            } => Shape::Ellipse(Geometry {
+                width: pixels(width as i64),
+                height: pixels(height as i64),
+                x: pixels(x as i64),
+                y: pixels(y as i64),
+            }),
+            PixelShape::Polygon(points) => Shape::Polygon(
+                points
+                    .into_iter()
+                    .map(|(x, y)| (pixels(x as i64), pixels(y as i64)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<Geometry> for Shape {
+    fn from(geometry: Geometry) -> Self {
+        Shape::Rect(geometry)
+    }
+}
+
+// a geometry like `20x10+5+5`, an ellipse inside a geometry like `ellipse:20x10+5+5`,
+// or a polygon like `polygon:10,10;50,10;30,40` with at least three vertices
+impl FromStr for Shape {
+    type Err = GeometryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(geometry) = s.strip_prefix("ellipse:") {
+            return geometry.parse().map(Shape::Ellipse);
+        }
+        let Some(points) = s.strip_prefix("polygon:") else {
+            return s.parse().map(Shape::Rect);
+        };
+
+        let invalid = || GeometryError::Invalid(s.to_string());
+        let points = points
+            .split([';', ' '])
+            .filter(|point| !point.is_empty())
+            .map(|point| {
+                let (x, y) = point.split_once(',')?;
+                Some((parse_length(x.trim())?, parse_length(y.trim())?))
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        if points.len() < 3 {
+            return Err(invalid());
+        }
+        Ok(Shape::Polygon(points))
+    }
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Shape::Rect(geometry) => write!(f, "{}", geometry),
+            Shape::Ellipse(geometry) => write!(f, "ellipse:{}", geometry),
+            Shape::Polygon(points) => {
+                f.write_str("polygon:")?;
+                for (i, (x, y)) in points.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(";")?;
+                    }
+                    write!(f, "{},{}", x, y)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for PixelShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PixelShape::Rect(rect) => write!(f, "{}", rect),
+            shape => write!(f, "{}", Shape::from(shape.clone())),
+        }
+    }
+}
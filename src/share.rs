@@ -0,0 +1,237 @@
+use std::{error::Error, fmt};
+
+use rand::RngCore;
+
+use crate::{lossless_format, EncryptionHeader, Image};
+
+// visual secret sharing, n of n: every share but the last is random, and the last is the image
+// XORed with all of them, so each share on its own is uniform noise, all of them together give
+// the image back and any fewer say nothing about it. There is no key, the shares are what the
+// parties are given
+
+// the most shares an image can be split into, every one as large as the image
+pub const MAX_SHARES: u32 = 255;
+
+pub const SHARE_SET_LEN: usize = 8;
+
+// which share of which split an image is, recorded in its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ShareInfo {
+    // random, the same for all the shares of one split, so shares of different splits aren't mixed up
+    pub set: [u8; SHARE_SET_LEN],
+    // from 0
+    pub index: u32,
+    pub count: u32,
+}
+
+impl ShareInfo {
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = self.set.to_vec();
+        bytes.extend_from_slice(&self.index.to_le_bytes());
+        bytes.extend_from_slice(&self.count.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: [u8; SHARE_SET_LEN + 8] = bytes.try_into().ok()?;
+        let info = ShareInfo {
+            set: bytes[..SHARE_SET_LEN].try_into().unwrap(),
+            index: u32::from_le_bytes(bytes[SHARE_SET_LEN..][..4].try_into().unwrap()),
+            count: u32::from_le_bytes(bytes[SHARE_SET_LEN + 4..].try_into().unwrap()),
+        };
+        info.is_valid().then_some(info)
+    }
+
+    fn is_valid(&self) -> bool {
+        (2..=MAX_SHARES).contains(&self.count) && self.index < self.count
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShareError {
+    // an image is split into 2 to `MAX_SHARES` shares
+    ShareCount(u32),
+    // one of the images has no share in its header
+    NotAShare,
+    // the shares come from different splits
+    DifferentSplits,
+    // the shares aren't all of the same size and color type, so one of them was converted or resized
+    Mismatch,
+    // the same share was given twice
+    Duplicate(u32),
+    // some of the shares are missing, and without all of them nothing of the image can be recovered
+    Missing { found: u32, count: u32 },
+}
+
+impl fmt::Display for ShareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShareError::ShareCount(count) => write!(
+                f,
+                "an image can't be split into {} shares, only 2 to {}",
+                count, MAX_SHARES
+            ),
+            ShareError::NotAShare => write!(f, "not a share of a split image"),
+            ShareError::DifferentSplits => write!(f, "the shares come from different splits"),
+            ShareError::Mismatch => write!(
+                f,
+                "the shares aren't all of the same size and color type, one was converted or resized"
+            ),
+            ShareError::Duplicate(index) => write!(f, "share {} is given twice", index + 1),
+            ShareError::Missing { found, count } => write!(
+                f,
+                "only {} of the {} shares are given, all of them are needed",
+                found, count
+            ),
+        }
+    }
+}
+
+impl Error for ShareError {}
+
+// split the image into `count` shares of its size and color type, each of which looks like noise;
+// they have to be written losslessly, so a JPEG gives PNG shares, and `combine_images` writes it back
+// as a JPEG. The metadata of the image isn't kept, so it can't give anything away
+pub fn split_image(img: &Image, count: u32) -> Result<Vec<Image>, ShareError> {
+    if !(2..=MAX_SHARES).contains(&count) {
+        return Err(ShareError::ShareCount(count));
+    }
+    let mut rng = rand::thread_rng();
+    let mut set = [0; SHARE_SET_LEN];
+    rng.fill_bytes(&mut set);
+    let mut last = img.pixels.clone();
+    let mut shares = Vec::with_capacity(count as usize);
+    for index in 0..count {
+        let pixels = if index + 1 < count {
+            let mut pixels = vec![0; img.pixels.len()];
+            rng.fill_bytes(&mut pixels);
+            xor_into(&mut last, &pixels);
+            pixels
+        } else {
+            std::mem::take(&mut last)
+        };
+        shares.push(Image {
+            format: lossless_format(img.format),
+            pixels,
+            color: img.color,
+            width: img.width,
+            height: img.height,
+            header: Some(EncryptionHeader {
+                original_format: Some(img.format),
+                share: Some(ShareInfo { set, index, count }),
+                ..Default::default()
+            }),
+            metadata: Vec::new(),
+            jpeg_segments: Vec::new(),
+        });
+    }
+    Ok(shares)
+}
+
+// put an image split with `split_image` back together from all of its shares, in any order
+pub fn combine_images(shares: &[Image]) -> Result<Image, ShareError> {
+    let infos = shares
+        .iter()
+        .map(|share| {
+            let header = share.header.as_ref();
+            header
+                .and_then(|header| header.share)
+                .filter(ShareInfo::is_valid)
+                .ok_or(ShareError::NotAShare)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some((first, info)) = shares.first().zip(infos.first()) else {
+        return Err(ShareError::Missing { found: 0, count: 2 });
+    };
+    if infos
+        .iter()
+        .any(|other| (other.set, other.count) != (info.set, info.count))
+    {
+        return Err(ShareError::DifferentSplits);
+    }
+    let shape = |img: &Image| (img.width, img.height, img.color);
+    if shares.iter().any(|share| shape(share) != shape(first)) {
+        return Err(ShareError::Mismatch);
+    }
+    let mut seen = vec![false; info.count as usize];
+    for other in &infos {
+        if std::mem::replace(&mut seen[other.index as usize], true) {
+            return Err(ShareError::Duplicate(other.index));
+        }
+    }
+    if infos.len() < info.count as usize {
+        return Err(ShareError::Missing {
+            found: infos.len() as u32,
+            count: info.count,
+        });
+    }
+
+    let mut pixels = first.pixels.clone();
+    for share in &shares[1..] {
+        xor_into(&mut pixels, &share.pixels);
+    }
+    let header = first.header.as_ref();
+    Ok(Image {
+        format: header
+            .and_then(|header| header.original_format)
+            .unwrap_or(first.format),
+        pixels,
+        color: first.color,
+        width: first.width,
+        height: first.height,
+        header: None,
+        metadata: Vec::new(),
+        jpeg_segments: Vec::new(),
+    })
+}
+
+fn xor_into(pixels: &mut [u8], share: &[u8]) {
+    for (pixel, &share) in pixels.iter_mut().zip(share) {
+        *pixel ^= share;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageFormat};
+
+    use super::*;
+    use crate::{
+        decrypt_image, load_image_from_bytes,
+        test_util::{random_image, rng},
+        write_image_to_vec, DecryptError,
+    };
+
+    // the shares of a split put back together in any order, read back from files, give the image;
+    // without one of them they don't, and none of them can be decrypted
+    #[test]
+    fn split_and_combine() {
+        let mut rng = rng();
+        for color in [ColorType::Rgb8, ColorType::Rgba16] {
+            let original = Image {
+                format: ImageFormat::Jpeg,
+                ..random_image(&mut rng, 37, 12, color)
+            };
+            let mut shares = split_image(&original, 3).unwrap();
+            let written = shares
+                .iter()
+                .map(|share| load_image_from_bytes(&write_image_to_vec(share).unwrap()).unwrap())
+                .collect::<Vec<_>>();
+            shares.reverse();
+            for shares in [&shares, &written] {
+                let img = combine_images(shares).unwrap();
+                assert_eq!(img.pixels, original.pixels, "{:?}", color);
+                assert_eq!(img.format, ImageFormat::Jpeg);
+            }
+            assert_eq!(
+                combine_images(&shares[1..]).err(),
+                Some(ShareError::Missing { found: 2, count: 3 })
+            );
+            assert_eq!(
+                decrypt_image(&mut shares[0], 1).err(),
+                Some(DecryptError::Share)
+            );
+        }
+    }
+}
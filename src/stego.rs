@@ -0,0 +1,235 @@
+use std::{error::Error, fmt};
+
+use rand::RngCore;
+
+use crate::{
+    blake3,
+    chacha20::{ChaCha20Rng, NONCE_LEN},
+    kdf::Hmac,
+    lossless_format,
+    permutation::IndexBijection,
+    Image,
+};
+
+// LSB steganography: a payload encrypted with the key is hidden in the lowest bit of the color samples
+// of any cover picture, a bit per sample, in an order drawn from the key, so the changes are spread
+// over the whole picture and look like sensor noise. Unlike `EncryptOptions::disguise` the cover is
+// a real picture, but anything that re-encodes it lossily destroys the payload
+//
+//     [nonce][payload length: u32 le][payload...][HMAC-SHA256 tag]
+//
+// everything after the nonce is XORed with a ChaCha20 keystream under it, and the tag covers all before it
+const TAG_LEN: usize = 32;
+// what a payload of no bytes still takes
+const OVERHEAD: usize = NONCE_LEN + 4 + TAG_LEN;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StegoError {
+    // the payload needs more low bits than the cover has
+    TooLarge { len: usize, capacity: usize },
+    // nothing is hidden in the cover with this key, or the cover was modified since
+    NotFound,
+}
+
+impl fmt::Display for StegoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StegoError::TooLarge { len, capacity } => write!(
+                f,
+                "a payload of {} bytes doesn't fit in the cover, which can hide {} bytes",
+                len, capacity
+            ),
+            StegoError::NotFound => write!(
+                f,
+                "nothing is hidden in the image with this key, or it was modified since"
+            ),
+        }
+    }
+}
+
+impl Error for StegoError {}
+
+// the keys of the payload cipher, its tag and the order of the bits are kept apart
+fn subkey(label: &[u8], key: u64) -> [u8; 32] {
+    blake3::Hasher::new()
+        .update(b"image_encryption stego ")
+        .update(label)
+        .update(&key.to_le_bytes())
+        .finalize()
+}
+
+// the low bits of the cover a payload can go in, and the order the key puts them in
+struct Slots {
+    // where the low byte of every sample of a color channel is, alpha being left alone
+    pixel_size: usize,
+    sample_size: usize,
+    channels: usize,
+    samples: u32,
+    order: IndexBijection,
+}
+
+impl Slots {
+    fn of(cover: &Image, key: u64) -> Self {
+        let channels = cover.color.channel_count() as usize - cover.color.has_alpha() as usize;
+        let samples = cover.pixels.len() / cover.color.bytes_per_pixel() as usize * channels;
+        let samples = samples.min(u32::MAX as usize) as u32;
+        let mut rng = ChaCha20Rng::new(subkey(b"order\0", key), [0; NONCE_LEN]);
+        Slots {
+            pixel_size: cover.color.bytes_per_pixel() as usize,
+            sample_size: cover.color.bytes_per_pixel() as usize
+                / cover.color.channel_count() as usize,
+            channels,
+            samples,
+            order: IndexBijection::new(samples, &mut rng),
+        }
+    }
+
+    // how many bytes fit, not counting what every payload takes besides its own
+    fn capacity(&self) -> usize {
+        (self.samples as usize / 8).saturating_sub(OVERHEAD)
+    }
+
+    // the byte of the cover the `bit`th bit of what is hidden goes in the lowest bit of;
+    // samples are little-endian, so their low byte comes first
+    fn offset(&self, bit: usize) -> usize {
+        let sample = self.order.at(bit as u32) as usize;
+        sample / self.channels * self.pixel_size + sample % self.channels * self.sample_size
+    }
+
+    fn write(&self, pixels: &mut [u8], from: usize, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            for bit in 0..8 {
+                let offset = self.offset((from + i) * 8 + bit);
+                pixels[offset] = pixels[offset] & !1 | byte >> (7 - bit) & 1;
+            }
+        }
+    }
+
+    fn read(&self, pixels: &[u8], from: usize, len: usize) -> Vec<u8> {
+        (from..from + len)
+            .map(|i| {
+                (0..8).fold(0, |byte, bit| {
+                    byte << 1 | pixels[self.offset(i * 8 + bit)] & 1
+                })
+            })
+            .collect()
+    }
+}
+
+// the bytes of the payload that fit in the cover
+pub fn stego_capacity(cover: &Image) -> usize {
+    Slots::of(cover, 0).capacity()
+}
+
+// hide the payload, encrypted with the key, in the low bits of the cover; it has to be written
+// losslessly to keep them, so a JPEG cover becomes a PNG
+pub fn embed(cover: &mut Image, payload: &[u8], key: u64) -> Result<(), StegoError> {
+    let slots = Slots::of(cover, key);
+    // a cover too small for even an empty payload has no room for anything
+    if OVERHEAD + payload.len() > slots.samples as usize / 8 {
+        return Err(StegoError::TooLarge {
+            len: payload.len(),
+            capacity: slots.capacity(),
+        });
+    }
+
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+    let mut sealed = (payload.len() as u32).to_le_bytes().to_vec();
+    sealed.extend_from_slice(payload);
+    let mut keystream = vec![0; sealed.len()];
+    ChaCha20Rng::new(subkey(b"cipher\0", key), nonce).fill_bytes(&mut keystream);
+    for (byte, key_byte) in sealed.iter_mut().zip(keystream) {
+        *byte ^= key_byte;
+    }
+    let tag = Hmac::new(&subkey(b"tag\0", key)).mac(&[&nonce, &sealed]);
+
+    slots.write(&mut cover.pixels, 0, &nonce);
+    slots.write(&mut cover.pixels, NONCE_LEN, &sealed);
+    slots.write(&mut cover.pixels, NONCE_LEN + sealed.len(), &tag);
+    cover.format = lossless_format(cover.format);
+    Ok(())
+}
+
+// the payload hidden in the cover with `embed`, once its tag shows it was hidden with this key
+pub fn extract(cover: &Image, key: u64) -> Result<Vec<u8>, StegoError> {
+    let slots = Slots::of(cover, key);
+    if (slots.samples as usize / 8) < OVERHEAD {
+        return Err(StegoError::NotFound);
+    }
+    let nonce: [u8; NONCE_LEN] = slots.read(&cover.pixels, 0, NONCE_LEN).try_into().unwrap();
+    let mut cipher = ChaCha20Rng::new(subkey(b"cipher\0", key), nonce);
+    let sealed_len = slots.read(&cover.pixels, NONCE_LEN, 4);
+    let len = u32::from_le_bytes(sealed_len[..].try_into().unwrap()) ^ cipher.next_u32();
+    // a length that can't fit is a wrong key, there is no point reading that far
+    if len as usize > slots.capacity() {
+        return Err(StegoError::NotFound);
+    }
+    let mut sealed = sealed_len;
+    sealed.extend(slots.read(&cover.pixels, NONCE_LEN + 4, len as usize));
+    let tag = slots.read(&cover.pixels, NONCE_LEN + sealed.len(), TAG_LEN);
+    if Hmac::new(&subkey(b"tag\0", key)).mac(&[&nonce, &sealed])[..] != tag[..] {
+        return Err(StegoError::NotFound);
+    }
+
+    let mut payload = sealed.split_off(4);
+    let mut keystream = vec![0; payload.len()];
+    cipher.fill_bytes(&mut keystream);
+    for (byte, key_byte) in payload.iter_mut().zip(keystream) {
+        *byte ^= key_byte;
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageFormat};
+
+    use super::*;
+    use crate::{
+        load_image_from_bytes,
+        test_util::{random_image, rng},
+        write_image_to_vec,
+    };
+
+    // a payload as large as the cover holds comes back out of it, read back from a file, only with
+    // the key it was hidden with; nothing but the lowest bit of the color samples changes
+    #[test]
+    fn hide_in_low_bits() {
+        let mut rng = rng();
+        for color in [ColorType::Rgb8, ColorType::Rgba16] {
+            let key = rng.next_u64();
+            let original = Image {
+                format: ImageFormat::Jpeg,
+                ..random_image(&mut rng, 61, 23, color)
+            };
+            let mut payload = vec![0; stego_capacity(&original)];
+            rng.fill_bytes(&mut payload);
+            let mut img = original.clone();
+            embed(&mut img, &payload, key).unwrap();
+            assert_eq!(img.format, ImageFormat::Png);
+
+            let channels = color.channel_count() as usize;
+            let sample_size = color.bytes_per_pixel() as usize / channels;
+            let samples = img.pixels.chunks_exact(sample_size);
+            for (i, (sample, original)) in samples
+                .zip(original.pixels.chunks_exact(sample_size))
+                .enumerate()
+            {
+                let alpha = color.has_alpha() && i % channels == channels - 1;
+                let mask = if alpha { 0 } else { 1 };
+                assert_eq!(sample[0] & !mask, original[0] & !mask);
+                assert_eq!(sample[1..], original[1..]);
+            }
+
+            let written = load_image_from_bytes(&write_image_to_vec(&img).unwrap()).unwrap();
+            assert_eq!(extract(&written, key), Ok(payload.clone()));
+            assert_eq!(extract(&img, key ^ 1), Err(StegoError::NotFound));
+            assert_eq!(extract(&original, key), Err(StegoError::NotFound));
+            payload.push(0);
+            assert!(matches!(
+                embed(&mut img, &payload, key),
+                Err(StegoError::TooLarge { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use image::{ColorType, ImageFormat};
+use proptest::{collection::vec, prelude::*, sample::select};
+
+use crate::Image;
+
+// proptest strategies for the property tests of applications embedding the crate. The images are ones
+// a decoder could have given, so they survive encryption, `write_image_to_vec` and loading again:
+//
+//     proptest! {
+//         #[test]
+//         fn round_trip(img in arb_image(), key in arb_key()) {
+//             let mut encrypted = img.clone();
+//             encrypt_image(&mut encrypted, key);
+//             let bytes = write_image_to_vec(&encrypted).unwrap();
+//             let mut decrypted = load_image_from_bytes(&bytes).unwrap();
+//             decrypt_image(&mut decrypted, key).unwrap();
+//             prop_assert!(compare_images(&img, &decrypted).identical);
+//         }
+//     }
+
+// the largest side `arb_image` generates; small images keep a test run to seconds and shrink quickly
+pub const MAX_SIDE: u32 = 64;
+
+const COLORS: &[ColorType] = &[
+    ColorType::L8,
+    ColorType::La8,
+    ColorType::Rgb8,
+    ColorType::Rgba8,
+    ColorType::L16,
+    ColorType::La16,
+    ColorType::Rgb16,
+    ColorType::Rgba16,
+    ColorType::Rgb32F,
+    ColorType::Rgba32F,
+];
+
+// every color type the crate encrypts
+pub fn arb_color() -> impl Strategy<Value = ColorType> {
+    select(COLORS)
+}
+
+pub fn arb_key() -> impl Strategy<Value = u64> {
+    any::<u64>()
+}
+
+// an image of any color type, up to `MAX_SIDE` pixels a side
+pub fn arb_image() -> impl Strategy<Value = Image> {
+    arb_color().prop_flat_map(|color| arb_image_of(color, MAX_SIDE))
+}
+
+// an image of the given color type, of at least one and up to `max_side` pixels a side; float images
+// are TIFFs, the only format storing them, with samples in 0..=1 like a decoder gives, the others PNGs
+pub fn arb_image_of(color: ColorType, max_side: u32) -> impl Strategy<Value = Image> {
+    let side = 1..=max_side.max(1);
+    (side.clone(), side).prop_flat_map(move |(width, height)| {
+        let samples = (width * height) as usize * color.channel_count() as usize;
+        let (format, pixels) = match color {
+            ColorType::Rgb32F | ColorType::Rgba32F => (
+                ImageFormat::Tiff,
+                vec(any::<u16>(), samples)
+                    .prop_map(|samples| {
+                        samples
+                            .into_iter()
+                            .flat_map(|sample| (sample as f32 / u16::MAX as f32).to_le_bytes())
+                            .collect()
+                    })
+                    .boxed(),
+            ),
+            _ => {
+                let len = samples * (color.bytes_per_pixel() / color.channel_count()) as usize;
+                (ImageFormat::Png, vec(any::<u8>(), len).boxed())
+            }
+        };
+        pixels.prop_map(move |pixels| Image {
+            format,
+            pixels,
+            color,
+            width,
+            height,
+            header: None,
+            metadata: Vec::new(),
+            jpeg_segments: Vec::new(),
+        })
+    })
+}
@@ -0,0 +1,473 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use image::{ColorType, ImageFormat};
+use tiff::{
+    decoder::{ChunkType, Decoder, DecodingResult},
+    encoder::{colortype, TiffEncoder, TiffKind},
+    tags::Tag,
+};
+
+use crate::{
+    auth::{self, StreamingTag},
+    decrypt_band, digest, encrypt_band,
+    header::read_header_from,
+    key_check_iterations, Cipher, EncryptionHeader, Geometry, Image, ImageEncryptionError,
+    KeyFingerprint, Keystream, PermutationUnit, Rect, SealedDigest,
+};
+
+// about how many pixels a band of a streamed image holds, which bounds the memory a stream needs
+// along with the size of the strips or tiles of the input
+pub const BAND_PIXELS: u32 = 1 << 20;
+
+// the output is uncompressed, so anything this big needs the 64-bit offsets of BigTIFF
+const BIG_TIFF_BYTES: u64 = 1 << 31;
+
+fn u8_samples(result: DecodingResult) -> Result<Vec<u8>, ImageEncryptionError> {
+    match result {
+        DecodingResult::U8(samples) => Ok(samples),
+        _ => Err(ImageEncryptionError::Unsupported(
+            "only 8-bit samples can be streamed".to_string(),
+        )),
+    }
+}
+
+// hands out a TIFF a band of whole rows at a time, reading only the strips or tiles the band needs
+struct BandReader<R: Read + Seek> {
+    decoder: Decoder<R>,
+    width: u32,
+    height: u32,
+    color: ColorType,
+    band_rows: u32,
+    // the rows decoded so far, and the ones handed out in bands
+    decoded_rows: u32,
+    handed_rows: u32,
+    // decoded rows that haven't been handed out yet
+    pending: Vec<u8>,
+}
+
+impl<R: Read + Seek> BandReader<R> {
+    fn new(reader: R, band_rows: Option<u32>) -> Result<Self, ImageEncryptionError> {
+        let mut decoder = Decoder::new(reader)?;
+        let (width, height) = decoder.dimensions()?;
+        if decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)? == Some(2) {
+            return Err(ImageEncryptionError::Unsupported(
+                "TIFFs with separate color planes can't be streamed".to_string(),
+            ));
+        }
+        let color = match decoder.colortype()? {
+            tiff::ColorType::Gray(8) => ColorType::L8,
+            tiff::ColorType::RGB(8) => ColorType::Rgb8,
+            tiff::ColorType::RGBA(8) => ColorType::Rgba8,
+            color => {
+                return Err(ImageEncryptionError::Unsupported(format!(
+                    "only 8-bit gray, RGB and RGBA TIFFs can be streamed, not {:?}",
+                    color
+                )))
+            }
+        };
+        Ok(BandReader {
+            decoder,
+            width,
+            height,
+            color,
+            band_rows: band_rows
+                .unwrap_or(BAND_PIXELS / width.max(1))
+                .clamp(1, height.max(1)),
+            decoded_rows: 0,
+            handed_rows: 0,
+            pending: Vec::new(),
+        })
+    }
+
+    fn row_len(&self) -> usize {
+        self.width as usize * self.color.bytes_per_pixel() as usize
+    }
+
+    // decode the next strip, or the next row of tiles
+    fn decode_rows(&mut self) -> Result<(), ImageEncryptionError> {
+        let (chunk_width, chunk_height) = self.decoder.chunk_dimensions();
+        let chunk_row = self.decoded_rows / chunk_height;
+        let rows = chunk_height.min(self.height - self.decoded_rows) as usize;
+        let (row_len, bpp) = (self.row_len(), self.color.bytes_per_pixel() as usize);
+
+        match self.decoder.get_chunk_type() {
+            ChunkType::Strip => {
+                let samples = u8_samples(self.decoder.read_chunk(chunk_row)?)?;
+                let data = samples.get(..rows * row_len).ok_or_else(|| {
+                    ImageEncryptionError::Malformed(
+                        "a strip is shorter than it should be".to_string(),
+                    )
+                })?;
+                self.pending.extend_from_slice(data);
+            }
+            ChunkType::Tile => {
+                let start = self.pending.len();
+                self.pending.resize(start + rows * row_len, 0);
+                let across = self.width.div_ceil(chunk_width);
+                for tile_x in 0..across {
+                    let index = chunk_row * across + tile_x;
+                    let tile_row_len = self.decoder.chunk_data_dimensions(index).0 as usize * bpp;
+                    let samples = u8_samples(self.decoder.read_chunk(index)?)?;
+                    let x = (tile_x * chunk_width) as usize * bpp;
+                    for (y, row) in samples.chunks_exact(tile_row_len).take(rows).enumerate() {
+                        self.pending[start + y * row_len + x..][..tile_row_len]
+                            .copy_from_slice(row);
+                    }
+                }
+            }
+        }
+        self.decoded_rows += rows as u32;
+        Ok(())
+    }
+
+    fn next_band(&mut self) -> Result<Option<Vec<u8>>, ImageEncryptionError> {
+        let rows = self.band_rows.min(self.height - self.handed_rows);
+        if rows == 0 {
+            return Ok(None);
+        }
+        let len = rows as usize * self.row_len();
+        while self.pending.len() < len {
+            self.decode_rows()?;
+        }
+        let rest = self.pending.split_off(len);
+        self.handed_rows += rows;
+        Ok(Some(std::mem::replace(&mut self.pending, rest)))
+    }
+
+    // pass over the next band, without reading it when it is made of whole strips or rows of tiles
+    fn skip_band(&mut self) -> Result<(), ImageEncryptionError> {
+        let rows = self.band_rows.min(self.height - self.handed_rows);
+        let chunk_height = self.decoder.chunk_dimensions().1.max(1);
+        let whole_chunks =
+            rows.is_multiple_of(chunk_height) || self.handed_rows + rows == self.height;
+        if self.pending.is_empty() && whole_chunks {
+            self.decoded_rows += rows;
+            self.handed_rows += rows;
+            return Ok(());
+        }
+        self.next_band().map(drop)
+    }
+}
+
+type NextBand<'a> = dyn FnMut() -> Result<Option<Vec<u8>>, ImageEncryptionError> + 'a;
+
+fn write_strips<W: Write + Seek, K: TiffKind, C: colortype::ColorType<Inner = u8>>(
+    encoder: &mut TiffEncoder<W, K>,
+    (width, height): (u32, u32),
+    band_rows: u32,
+    next_band: &mut NextBand,
+) -> Result<(), ImageEncryptionError> {
+    let mut image = encoder.new_image::<C>(width, height)?;
+    image.rows_per_strip(band_rows)?;
+    while let Some(band) = next_band()? {
+        image.write_strip(&band)?;
+    }
+    image.finish()?;
+    Ok(())
+}
+
+fn write_tiff<W: Write + Seek, K: TiffKind>(
+    mut encoder: TiffEncoder<W, K>,
+    size: (u32, u32),
+    color: ColorType,
+    band_rows: u32,
+    next_band: &mut NextBand,
+) -> Result<(), ImageEncryptionError> {
+    match color {
+        ColorType::L8 => {
+            write_strips::<_, _, colortype::Gray8>(&mut encoder, size, band_rows, next_band)
+        }
+        ColorType::Rgb8 => {
+            write_strips::<_, _, colortype::RGB8>(&mut encoder, size, band_rows, next_band)
+        }
+        _ => write_strips::<_, _, colortype::RGBA8>(&mut encoder, size, band_rows, next_band),
+    }
+}
+
+// an uncompressed TIFF with one strip per band, so the output can be streamed again
+fn write_bands<W: Write + Seek>(
+    writer: W,
+    (width, height): (u32, u32),
+    color: ColorType,
+    band_rows: u32,
+    next_band: &mut NextBand,
+) -> Result<(), ImageEncryptionError> {
+    let bytes = width as u64 * height as u64 * color.bytes_per_pixel() as u64;
+    if bytes > BIG_TIFF_BYTES {
+        write_tiff(
+            TiffEncoder::new_big(writer)?,
+            (width, height),
+            color,
+            band_rows,
+            next_band,
+        )
+    } else {
+        write_tiff(
+            TiffEncoder::new(writer)?,
+            (width, height),
+            color,
+            band_rows,
+            next_band,
+        )
+    }
+}
+
+// encrypt an 8-bit gray, RGB or RGBA TIFF a band of rows at a time, so only a band and a strip
+// or a row of tiles of the input are ever in memory; every band is permuted and chained on its own
+// with a key of its own, so the permutation only moves pixels within their band.
+// the output is an uncompressed TIFF with the encryption header after it,
+// which `decrypt_image` decrypts just like any other encrypted image
+pub fn encrypt_stream<R: Read + Seek, W: Write + Seek>(
+    reader: R,
+    mut writer: W,
+    key: u64,
+) -> Result<(), ImageEncryptionError> {
+    let mut bands = BandReader::new(reader, None)?;
+    let (size, color, band_rows) = ((bands.width, bands.height), bands.color, bands.band_rows);
+
+    let mut digest = digest::digest_hasher(size.0, size.1, color);
+    let mut tag = StreamingTag::new(key, size.0, size.1, color);
+    let mut index = 0;
+    write_bands(&mut writer, size, color, band_rows, &mut || {
+        let Some(band) = bands.next_band()? else {
+            return Ok(None);
+        };
+        digest.update(&band);
+        let band = encrypt_band(&band, size.0, color, key, Keystream::Legacy, index);
+        index += 1;
+        tag.update(&band);
+        Ok(Some(band))
+    })?;
+
+    let iterations = key_check_iterations(size.0, size.1);
+    let mut header = EncryptionHeader {
+        cipher: Some(Cipher::Legacy),
+        original_format: Some(ImageFormat::Tiff),
+        dimensions: Some(size),
+        permutation_unit: Some(PermutationUnit::Pixel),
+        key_fingerprint: Some(KeyFingerprint::hardened(key, iterations)),
+        key_check_iterations: Some(iterations),
+        key_id: Some(KeyFingerprint::id(key)),
+        plaintext_digest: Some(SealedDigest::seal(digest::finish_digest(&digest), key)),
+        band_rows: Some(band_rows),
+        ..Default::default()
+    };
+    header.auth_tag = Some(tag.finish(&header));
+    writer.seek(SeekFrom::End(0))?;
+    writer.write_all(&header.to_bytes())?;
+    Ok(())
+}
+
+// decrypt a TIFF encrypted by `encrypt_stream` the same way, a band at a time, into a plain TIFF;
+// the ciphertext is authenticated in a first pass over the input, so nothing is written for the wrong key
+pub fn decrypt_stream<R: Read + Seek, W: Write + Seek>(
+    mut reader: R,
+    writer: W,
+    key: u64,
+) -> Result<(), ImageEncryptionError> {
+    let header = read_header_from(&mut reader)?.ok_or(ImageEncryptionError::NotEncrypted)?;
+    let band_rows = header.band_rows.ok_or_else(|| {
+        ImageEncryptionError::Unsupported("not encrypted as a stream".to_string())
+    })?;
+    let keystream = Keystream::of(&header);
+
+    // streams are always written with a tag, except by versions before the tag covered the header
+    match header.auth_tag {
+        Some(expected) => {
+            reader.seek(SeekFrom::Start(0))?;
+            let mut bands = BandReader::new(&mut reader, Some(band_rows))?;
+            let mut tag = StreamingTag::new(key, bands.width, bands.height, bands.color);
+            while let Some(band) = bands.next_band()? {
+                tag.update(&band);
+            }
+            if !auth::tags_match(&tag.finish(&header), &expected) {
+                return Err(ImageEncryptionError::AuthFailure);
+            }
+        }
+        None if !header.pixel_tag_only => return Err(ImageEncryptionError::AuthFailure),
+        None => {}
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    let mut bands = BandReader::new(&mut reader, Some(band_rows))?;
+    let (size, color) = ((bands.width, bands.height), bands.color);
+    let mut index = 0;
+    write_bands(writer, size, color, band_rows, &mut || {
+        let Some(band) = bands.next_band()? else {
+            return Ok(None);
+        };
+        let band = decrypt_band(&band, size.0, color, key, keystream, index);
+        index += 1;
+        Ok(Some(band))
+    })
+}
+
+// decrypt the pixels inside a rectangle of an image encrypted by `encrypt_stream`, reading and decrypting
+// only the bands the rectangle overlaps, so a crop of a huge scan costs about as much as the crop itself.
+// the key is checked against the header, but the tag covers every band, so it isn't: a band modified
+// in the file decrypts to noise instead of failing. every other cipher chains the pixels across the
+// whole image, so any one pixel depends on half of it on average, and those images can only be
+// decrypted whole
+pub fn decrypt_region<R: Read + Seek>(
+    mut reader: R,
+    rect: Rect,
+    key: u64,
+) -> Result<Image, ImageEncryptionError> {
+    let header = read_header_from(&mut reader)?.ok_or(ImageEncryptionError::NotEncrypted)?;
+    let band_rows = header.band_rows.ok_or_else(|| {
+        ImageEncryptionError::Unsupported(
+            "only images encrypted as a stream can be decrypted in part".to_string(),
+        )
+    })?;
+    if header.check_key(key) == Some(false) {
+        return Err(ImageEncryptionError::WrongKey);
+    }
+    let keystream = Keystream::of(&header);
+
+    reader.seek(SeekFrom::Start(0))?;
+    let mut bands = BandReader::new(&mut reader, Some(band_rows))?;
+    let (width, height, color) = (bands.width, bands.height, bands.color);
+    let rect = Geometry::from(rect).resolve(width, height).ok_or_else(|| {
+        ImageEncryptionError::Invalid(format!(
+            "the region {} is outside the {}x{} image",
+            rect, width, height
+        ))
+    })?;
+
+    let (row_len, bpp) = (bands.row_len(), color.bytes_per_pixel() as usize);
+    let (left, right) = (rect.x as usize * bpp, (rect.x + rect.width) as usize * bpp);
+    let mut pixels = Vec::with_capacity(rect.width as usize * rect.height as usize * bpp);
+    let (mut top, mut index) = (0, 0);
+    while top < rect.y + rect.height {
+        let rows = band_rows.min(height - top);
+        if top + rows <= rect.y {
+            bands.skip_band()?;
+        } else {
+            let band = bands.next_band()?.ok_or_else(|| {
+                ImageEncryptionError::Malformed("the image ends before its last band".to_string())
+            })?;
+            let band = decrypt_band(&band, width, color, key, keystream, index);
+            for (row, y) in band.chunks_exact(row_len).zip(top..) {
+                if (rect.y..rect.y + rect.height).contains(&y) {
+                    pixels.extend_from_slice(&row[left..right]);
+                }
+            }
+        }
+        top += rows;
+        index += 1;
+    }
+
+    Ok(Image {
+        format: ImageFormat::Tiff,
+        pixels,
+        color,
+        width: rect.width,
+        height: rect.height,
+        header: None,
+        metadata: Vec::new(),
+        jpeg_segments: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        encrypt_image,
+        test_util::{random_image, rng},
+        write_image_to_vec,
+    };
+
+    // a crop across bands decrypts to the same pixels as the crop of the original
+    #[test]
+    fn region_of_a_stream() {
+        let mut rng = rng();
+        // bands of 64 rows
+        let (width, height) = (BAND_PIXELS / 64, 200);
+        let mut original = random_image(&mut rng, width, height, ColorType::Rgb8);
+        original.format = ImageFormat::Tiff;
+        let mut encrypted = Cursor::new(Vec::new());
+        encrypt_stream(
+            Cursor::new(write_image_to_vec(&original).unwrap()),
+            &mut encrypted,
+            7,
+        )
+        .unwrap();
+
+        for rect in [
+            Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+            Rect {
+                x: 100,
+                y: 70,
+                width: 30,
+                height: 20,
+            },
+            Rect {
+                x: 5,
+                y: 50,
+                width: 3,
+                height: 100,
+            },
+            Rect {
+                x: width - 10,
+                y: 190,
+                width: 10,
+                height: 10,
+            },
+            // clipped to the image
+            Rect {
+                x: width - 1,
+                y: 199,
+                width: 10,
+                height: 10,
+            },
+        ] {
+            let region = decrypt_region(Cursor::new(encrypted.get_ref()), rect, 7).unwrap();
+            let rect = Geometry::from(rect).resolve(width, height).unwrap();
+            assert_eq!((region.width, region.height), (rect.width, rect.height));
+            assert!(region.pixels == original.crop_pixels(rect), "{}", rect);
+        }
+
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        let outside = Rect {
+            x: 0,
+            y: height,
+            width: 1,
+            height: 1,
+        };
+        let region = |bytes: &[u8], rect, key| decrypt_region(Cursor::new(bytes), rect, key);
+        assert!(matches!(
+            region(encrypted.get_ref(), rect, 8),
+            Err(ImageEncryptionError::WrongKey)
+        ));
+        assert!(matches!(
+            region(encrypted.get_ref(), outside, 7),
+            Err(ImageEncryptionError::Invalid(_))
+        ));
+
+        // an image chained as a whole can't be decrypted in part
+        let mut img = random_image(&mut rng, 20, 10, ColorType::Rgb8);
+        encrypt_image(&mut img, 7);
+        let bytes = write_image_to_vec(&img).unwrap();
+        assert!(matches!(
+            region(&bytes, rect, 7),
+            Err(ImageEncryptionError::Unsupported(_))
+        ));
+        assert!(matches!(
+            region(&write_image_to_vec(&original).unwrap(), rect, 7),
+            Err(ImageEncryptionError::NotEncrypted)
+        ));
+    }
+}
@@ -0,0 +1,61 @@
+use rand::RngCore;
+
+use crate::{blake3, kdf::Hmac};
+
+pub const TOKEN_LEN: usize = 16;
+
+// tags attached to an image at encryption time, stored in the header as HMAC tokens only,
+// so holders of the key can look for a tag without decrypting anything, while to everyone else
+// the tokens say nothing; they are salted per image, so files sharing a tag can't be linked either
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct SearchTags {
+    pub salt: [u8; TOKEN_LEN],
+    // sorted, so they don't give away the order the tags were given in
+    pub tokens: Vec<[u8; TOKEN_LEN]>,
+}
+
+// tags match whatever the case and surrounding whitespace they were typed with
+fn normalize(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+fn token(hmac: &Hmac, salt: &[u8; TOKEN_LEN], tag: &str) -> [u8; TOKEN_LEN] {
+    hmac.mac(&[
+        b"image_encryption search tag\0",
+        salt,
+        normalize(tag).as_bytes(),
+    ])[..TOKEN_LEN]
+        .try_into()
+        .unwrap()
+}
+
+fn tag_hmac(key: u64) -> Hmac {
+    Hmac::new(
+        &blake3::Hasher::new()
+            .update(b"image_encryption search tag key\0")
+            .update(&key.to_le_bytes())
+            .finalize(),
+    )
+}
+
+impl SearchTags {
+    pub(crate) fn seal(tags: &[String], key: u64) -> Self {
+        let mut salt = [0; TOKEN_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let hmac = tag_hmac(key);
+        let mut tokens = tags
+            .iter()
+            .map(|tag| token(&hmac, &salt, tag))
+            .collect::<Vec<_>>();
+        tokens.sort();
+        tokens.dedup();
+        SearchTags { salt, tokens }
+    }
+
+    // whether the image was tagged with this; with the wrong key nothing matches
+    pub fn contains(&self, key: u64, tag: &str) -> bool {
+        self.tokens
+            .contains(&token(&tag_hmac(key), &self.salt, tag))
+    }
+}
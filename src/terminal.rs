@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+
+use image::{DynamicImage, ImageOutputFormat, RgbaImage};
+
+use crate::{base64, Image};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphicsProtocol {
+    // the kitty terminal graphics protocol, also understood by wezterm and konsole
+    Kitty,
+    // DEC sixel graphics, understood by xterm, mlterm, foot and others
+    Sixel,
+}
+
+impl GraphicsProtocol {
+    // kitty sets TERM to xterm-kitty and exports its window id, everything else gets sixel
+    pub fn detect() -> Self {
+        let term = std::env::var("TERM").unwrap_or_default();
+        if term.contains("kitty") || std::env::var_os("KITTY_WINDOW_ID").is_some() {
+            GraphicsProtocol::Kitty
+        } else {
+            GraphicsProtocol::Sixel
+        }
+    }
+}
+
+// the image as RGBA, scaled down to fit in a max_size x max_size square if it is larger
+fn rgba(img: &Image, max_size: u32) -> RgbaImage {
+    let Some(image) = img.to_dynamic() else {
+        return RgbaImage::new(1, 1);
+    };
+    if image.width() > max_size || image.height() > max_size {
+        image.thumbnail(max_size, max_size).to_rgba8()
+    } else {
+        image.to_rgba8()
+    }
+}
+
+// the image as a PNG sent in base64 chunks of at most 4096 bytes, the most the protocol allows
+fn kitty(image: RgbaImage) -> Vec<u8> {
+    let mut png = Vec::new();
+    if DynamicImage::ImageRgba8(image)
+        .write_to(&mut std::io::Cursor::new(&mut png), ImageOutputFormat::Png)
+        .is_err()
+    {
+        return Vec::new();
+    }
+
+    let data = base64::encode(&png);
+    let chunks = data.as_bytes().chunks(4096).collect::<Vec<_>>();
+    let mut out = Vec::with_capacity(data.len() + chunks.len() * 32);
+    for (i, chunk) in chunks.iter().enumerate() {
+        // the first chunk carries the parameters, m=1 tells the terminal more chunks follow
+        let more = (i + 1 < chunks.len()) as u8;
+        if i == 0 {
+            out.extend_from_slice(format!("\x1b_Ga=T,f=100,m={};", more).as_bytes());
+        } else {
+            out.extend_from_slice(format!("\x1b_Gm={};", more).as_bytes());
+        }
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(b"\x1b\\");
+    }
+    out.push(b'\n');
+    out
+}
+
+// a sixel row draws 6 pixel rows at once, one character per column for each color used in it;
+// colors come from a fixed 6x6x6 cube, transparent pixels are drawn over black
+fn sixel(image: RgbaImage) -> Vec<u8> {
+    let level = |value: u8, alpha: u8| (value as u32 * alpha as u32 / 255 * 5 + 127) / 255;
+    let (width, height) = image.dimensions();
+
+    let mut out = format!("\x1bPq\"1;1;{};{}", width, height);
+    for i in 0..216 {
+        let (r, g, b) = (i / 36, i / 6 % 6, i % 6);
+        out += &format!("#{};2;{};{};{}", i, r * 20, g * 20, b * 20);
+    }
+
+    for band in (0..height).step_by(6) {
+        // for every color in the band, which of its 6 rows use it in each column
+        let mut columns = BTreeMap::<u32, Vec<u8>>::new();
+        for row in 0..6.min(height - band) {
+            for x in 0..width {
+                let [r, g, b, a] = image.get_pixel(x, band + row).0;
+                let color = level(r, a) * 36 + level(g, a) * 6 + level(b, a);
+                columns
+                    .entry(color)
+                    .or_insert_with(|| vec![0; width as usize])[x as usize] |= 1 << row;
+            }
+        }
+
+        for (color, bits) in columns {
+            out += &format!("#{}", color);
+            // runs of the same character are written as !<count><char>
+            let mut x = 0;
+            while x < bits.len() {
+                let run = bits[x..].iter().take_while(|&&b| b == bits[x]).count();
+                let ch = (63 + bits[x]) as char;
+                if run > 3 {
+                    out += &format!("!{}{}", run, ch);
+                } else {
+                    out.extend(std::iter::repeat_n(ch, run));
+                }
+                x += run;
+            }
+            // back to the start of the band for the next color
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out += "\x1b\\\n";
+    out.into_bytes()
+}
+
+// the escape sequences that draw the image in a terminal, scaled down to at most max_size pixels a side
+pub fn terminal_graphics(img: &Image, protocol: GraphicsProtocol, max_size: u32) -> Vec<u8> {
+    let image = rgba(img, max_size.max(1));
+    match protocol {
+        GraphicsProtocol::Kitty => kitty(image),
+        GraphicsProtocol::Sixel => sixel(image),
+    }
+}
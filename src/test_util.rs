@@ -0,0 +1,56 @@
+use image::{ColorType, ImageFormat};
+use rand::RngCore;
+
+use crate::{
+    compare_images, decrypt_image, encrypt_image_with, rng::Xoshiro256PlusPlus, EncryptOptions,
+    Image,
+};
+
+// fixtures the unit tests share
+
+// every color type the cipher takes
+pub(crate) const COLORS: [ColorType; 10] = [
+    ColorType::L8,
+    ColorType::La8,
+    ColorType::Rgb8,
+    ColorType::Rgba8,
+    ColorType::L16,
+    ColorType::La16,
+    ColorType::Rgb16,
+    ColorType::Rgba16,
+    ColorType::Rgb32F,
+    ColorType::Rgba32F,
+];
+
+// the same numbers on every run, so a failure can be reproduced
+pub(crate) fn rng() -> Xoshiro256PlusPlus {
+    Xoshiro256PlusPlus::seed_from_u64(0x0123_4567_89ab_cdef)
+}
+
+// a PNG of random pixels
+pub(crate) fn random_image(
+    rng: &mut impl RngCore,
+    width: u32,
+    height: u32,
+    color: ColorType,
+) -> Image {
+    let mut pixels = vec![0; (width * height) as usize * color.bytes_per_pixel() as usize];
+    rng.fill_bytes(&mut pixels);
+    Image {
+        format: ImageFormat::Png,
+        pixels,
+        color,
+        width,
+        height,
+        header: None,
+        metadata: Vec::new(),
+        jpeg_segments: Vec::new(),
+    }
+}
+
+// whether the image comes back exactly after being encrypted with the options and decrypted
+pub(crate) fn round_trips(original: &Image, key: u64, options: &EncryptOptions) -> bool {
+    let mut img = original.clone();
+    encrypt_image_with(&mut img, key, options);
+    decrypt_image(&mut img, key).is_ok() && compare_images(original, &img).identical
+}
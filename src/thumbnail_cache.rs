@@ -0,0 +1,166 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use image::ImageFormat;
+
+use crate::{
+    decrypt_image, decrypt_jpeg_dct, encode_image, encrypt_image_with, load_image, read_header,
+    thumbnail, write_file_atomic_with, Cipher, EncryptOptions, Image, ImageEncryptionError,
+    TempLocation,
+};
+
+// the directory the thumbnails of an album are kept in, inside the album
+pub const THUMBNAIL_DIR: &str = ".thumbnails";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    // the thumbnail was missing or older than its image, and was written
+    Updated,
+    UpToDate,
+    // the image is gone, so its thumbnail was deleted
+    Removed,
+}
+
+// what happened to the thumbnail of one image
+#[derive(Debug)]
+pub struct CacheEntry {
+    pub image: PathBuf,
+    pub thumbnail: PathBuf,
+    pub result: Result<CacheStatus, ImageEncryptionError>,
+}
+
+// where the thumbnail of an encrypted image is cached: always a PNG, so small previews don't lose more to JPEG
+pub fn thumbnail_path(image: impl AsRef<Path>) -> PathBuf {
+    let image = image.as_ref();
+    let mut name = image.file_name().unwrap_or_default().to_os_string();
+    name.push(".png");
+    image
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(THUMBNAIL_DIR)
+        .join(name)
+}
+
+fn is_fresh(image: &Path, cached: &Path, size: u32) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    let newer = matches!(
+        (modified(image), modified(cached)),
+        (Ok(image), Ok(cached)) if cached >= image
+    );
+    // a thumbnail made for another size is redone
+    newer
+        && read_header(cached)
+            .ok()
+            .flatten()
+            .and_then(|header| header.dimensions)
+            .is_some_and(|(width, height)| width.max(height) == size)
+}
+
+// the decrypted image, whichever way it was encrypted
+fn decrypt(path: &Path, key: u64, cipher: Option<Cipher>) -> Result<Image, ImageEncryptionError> {
+    if cipher == Some(Cipher::Dct) {
+        let plain = image::load_from_memory_with_format(
+            &decrypt_jpeg_dct(&fs::read(path)?, key)?,
+            ImageFormat::Jpeg,
+        )?;
+        return Ok(Image {
+            format: ImageFormat::Jpeg,
+            width: plain.width(),
+            height: plain.height(),
+            color: plain.color(),
+            pixels: crate::dynamic_bytes(plain),
+            header: None,
+            metadata: Vec::new(),
+            jpeg_segments: Vec::new(),
+        });
+    }
+    let mut img = load_image(path)?;
+    decrypt_image(&mut img, key)?;
+    Ok(img)
+}
+
+fn update_thumbnail(
+    image: &Path,
+    cached: &Path,
+    key: u64,
+    size: u32,
+) -> Result<CacheStatus, ImageEncryptionError> {
+    if is_fresh(image, cached, size) {
+        return Ok(CacheStatus::UpToDate);
+    }
+    let header = read_header(image)?.ok_or(ImageEncryptionError::NotEncrypted)?;
+    if header.check_key(key) == Some(false) {
+        return Err(ImageEncryptionError::WrongKey);
+    }
+
+    // the preview is encrypted with the same key and cipher as the image, so it is just as protected
+    let mut preview = thumbnail(&decrypt(image, key, header.cipher)?, size);
+    let options = EncryptOptions {
+        cipher: header.cipher.unwrap_or_default(),
+        ..Default::default()
+    };
+    encrypt_image_with(&mut preview, key, &options);
+    write_file_atomic_with(cached, &encode_image(&preview)?, &TempLocation::default())?;
+    Ok(CacheStatus::Updated)
+}
+
+// bring the thumbnail cache of a directory of encrypted images up to date: every encrypted image
+// gets a thumbnail of at most size x size pixels in `THUMBNAIL_DIR`, encrypted with the same key,
+// thumbnails older than their image are redone and those of deleted images are removed;
+// files that aren't encrypted images are left out, and one failure doesn't stop the rest
+pub fn update_thumbnail_cache(
+    dir: impl AsRef<Path>,
+    key: u64,
+    size: u32,
+) -> io::Result<Vec<CacheEntry>> {
+    let dir = dir.as_ref();
+    let cache_dir = dir.join(THUMBNAIL_DIR);
+    fs::create_dir_all(&cache_dir)?;
+
+    let mut images = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    images.retain(|path| path.is_file() && matches!(read_header(path), Ok(Some(_))));
+    images.sort();
+
+    let mut entries = images
+        .into_iter()
+        .map(|image| {
+            let cached = thumbnail_path(&image);
+            let result = update_thumbnail(&image, &cached, key, size.max(1));
+            CacheEntry {
+                image,
+                thumbnail: cached,
+                result,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut cached = fs::read_dir(&cache_dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    cached.sort();
+    for thumbnail in cached {
+        let Some(name) = thumbnail
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".png"))
+        else {
+            continue;
+        };
+        let image = dir.join(name);
+        if !image.exists() {
+            let result = fs::remove_file(&thumbnail)
+                .map(|_| CacheStatus::Removed)
+                .map_err(Into::into);
+            entries.push(CacheEntry {
+                image,
+                thumbnail,
+                result,
+            });
+        }
+    }
+    Ok(entries)
+}
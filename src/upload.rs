@@ -0,0 +1,253 @@
+use std::{error::Error, fmt, io, thread, time::Duration};
+
+use ureq::{Agent, AgentBuilder, ErrorKind};
+
+// the file is PUT in chunks over http or https, every chunk with a Content-Range, the way resumable
+// upload endpoints take it: they answer a partial upload with 308 and a Range header of the bytes they
+// have kept so far. After a chunk fails, an empty PUT of `bytes */total` asks for that Range again,
+// so the upload picks up from what the server actually has instead of what was last sent
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadOptions {
+    // size in bytes of every request body, except the last one
+    pub chunk_size: usize,
+    // how many times a failed chunk is resent before giving up
+    pub retries: u32,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        UploadOptions {
+            chunk_size: 4 * 1024 * 1024,
+            retries: 3,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    InvalidUrl(String),
+    UnsupportedScheme(String),
+    Io(io::Error),
+    // the server answered with something other than a success status
+    Status(u16),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::InvalidUrl(url) => write!(f, "invalid upload url {}", url),
+            UploadError::UnsupportedScheme(scheme) => {
+                write!(
+                    f,
+                    "unsupported upload url scheme {}, only http and https are supported",
+                    scheme
+                )
+            }
+            UploadError::Io(err) => write!(f, "upload failed: {}", err),
+            UploadError::Status(status) => write!(f, "upload rejected with status {}", status),
+        }
+    }
+}
+
+impl Error for UploadError {}
+
+impl From<io::Error> for UploadError {
+    fn from(err: io::Error) -> Self {
+        UploadError::Io(err)
+    }
+}
+
+fn check_url(url: &str) -> Result<(), UploadError> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| UploadError::InvalidUrl(url.to_string()))?;
+    if scheme != "http" && scheme != "https" {
+        return Err(UploadError::UnsupportedScheme(scheme.to_string()));
+    }
+    if rest.is_empty() || rest.starts_with('/') {
+        return Err(UploadError::InvalidUrl(url.to_string()));
+    }
+    Ok(())
+}
+
+// the number of bytes the server has of the upload, from the Range of its 308, like `bytes=0-1048575`
+fn received(range: &str) -> Option<usize> {
+    let last: usize = range.strip_prefix("bytes=0-")?.trim().parse().ok()?;
+    last.checked_add(1)
+}
+
+// send the chunk starting at `start`, or ask how much the server has if it is empty,
+// and return how many bytes the server has of the upload now
+fn put_chunk(
+    agent: &Agent,
+    url: &str,
+    chunk: &[u8],
+    start: usize,
+    total: usize,
+) -> Result<usize, UploadError> {
+    let range = if chunk.is_empty() {
+        format!("bytes */{}", total)
+    } else {
+        format!("bytes {}-{}/{}", start, start + chunk.len() - 1, total)
+    };
+    let response = agent
+        .put(url)
+        .set("Content-Type", "application/octet-stream")
+        .set("Content-Range", &range)
+        .send_bytes(chunk)
+        .map_err(|err| match err {
+            ureq::Error::Status(status, _) => UploadError::Status(status),
+            ureq::Error::Transport(err) if err.kind() == ErrorKind::InvalidUrl => {
+                UploadError::InvalidUrl(url.to_string())
+            }
+            ureq::Error::Transport(err) => UploadError::Io(io::Error::other(err)),
+        })?;
+
+    match response.status() {
+        // a server without a Range has kept none of it
+        308 => match response.header("Range") {
+            Some(range) => received(range)
+                .filter(|&received| received <= total)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "malformed Range in the response",
+                    )
+                    .into()
+                }),
+            None => Ok(0),
+        },
+        // servers that don't take resumable uploads keep every chunk they accept
+        status if (200..300).contains(&status) => Ok(start + chunk.len()),
+        status => Err(UploadError::Status(status)),
+    }
+}
+
+// upload the bytes to the url with one PUT request per chunk
+pub fn upload(url: &str, data: &[u8], options: &UploadOptions) -> Result<(), UploadError> {
+    check_url(url)?;
+    // a 308 is the server's answer to a partial upload, not a redirect to follow
+    let agent = AgentBuilder::new().timeout(TIMEOUT).redirects(0).build();
+    let chunk_size = options.chunk_size.max(1);
+
+    // an empty body is still sent once, so the server sees the file
+    let mut offset = 0;
+    let mut attempt = 0;
+    loop {
+        let end = data.len().min(offset + chunk_size);
+        let err = match put_chunk(&agent, url, &data[offset..end], offset, data.len()) {
+            Ok(received) if received == data.len() && end == data.len() => return Ok(()),
+            Ok(received) if received > offset => {
+                offset = received;
+                attempt = 0;
+                continue;
+            }
+            // the server took nothing of the chunk, which is no better than failing
+            Ok(_) => UploadError::Status(308),
+            Err(err) => err,
+        };
+        // neither of these goes away by sending the same chunk again
+        if matches!(
+            err,
+            UploadError::Status(400..=499) | UploadError::InvalidUrl(_)
+        ) || attempt >= options.retries
+        {
+            return Err(err);
+        }
+
+        // back off a little longer after every failure
+        thread::sleep(Duration::from_millis(500 << attempt.min(6)));
+        attempt += 1;
+        // go on from what the server kept, if it says; if it doesn't, the chunk is sent again
+        if let Ok(received) = put_chunk(&agent, url, &[], offset, data.len()) {
+            offset = received.min(data.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    use rand::RngCore;
+
+    use super::*;
+    use crate::test_util::rng;
+
+    // a resumable upload endpoint that keeps half of the second chunk and drops the connection,
+    // so the upload has to ask what it kept and go on from there
+    fn serve(listener: TcpListener) -> Vec<u8> {
+        let mut kept = Vec::new();
+        for (request, stream) in listener.incoming().enumerate() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let (mut range, mut len) = (String::new(), 0);
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_once(": ").unwrap_or((line, ""));
+                match name.to_ascii_lowercase().as_str() {
+                    "content-range" => range = value.to_string(),
+                    "content-length" => len = value.parse().unwrap(),
+                    _ => {}
+                }
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+
+            let (bytes, total) = range
+                .strip_prefix("bytes ")
+                .unwrap()
+                .split_once('/')
+                .unwrap();
+            let total: usize = total.parse().unwrap();
+            if let Some((start, _)) = bytes.split_once('-') {
+                assert_eq!(start.parse::<usize>().unwrap(), kept.len());
+                if request == 1 {
+                    kept.extend_from_slice(&body[..len / 2]);
+                    continue;
+                }
+                kept.extend_from_slice(&body);
+            }
+            let status = match kept.len() {
+                len if len == total => "200 OK\r\n".to_string(),
+                0 => "308 Resume Incomplete\r\n".to_string(),
+                len => format!("308 Resume Incomplete\r\nRange: bytes=0-{}\r\n", len - 1),
+            };
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {}Content-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            if kept.len() == total {
+                break;
+            }
+        }
+        kept
+    }
+
+    #[test]
+    fn resume_from_server_range() {
+        let mut data = vec![0; 3500];
+        rng().fill_bytes(&mut data);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/upload", listener.local_addr().unwrap());
+        let server = thread::spawn(move || serve(listener));
+
+        let options = UploadOptions {
+            chunk_size: 1000,
+            retries: 1,
+        };
+        upload(&url, &data, &options).unwrap();
+        assert_eq!(server.join().unwrap(), data);
+    }
+}
@@ -0,0 +1,56 @@
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    decrypt_image, encode_image, encrypt_image_with, load_image_from_bytes, parse_key, Cipher,
+    EncryptOptions, KeyFingerprint,
+};
+
+// the browser API, exported with wasm-bindgen: whole files go in and come out as bytes, so an image
+// can be encrypted on the client before it is uploaded. Keys are BigInts on the JavaScript side,
+// and errors are thrown as JavaScript errors with the message the CLI would print
+
+fn js_error(err: impl ToString) -> JsError {
+    JsError::new(&err.to_string())
+}
+
+fn encrypt_with(data: &[u8], key: u64, options: &EncryptOptions) -> Result<Vec<u8>, JsError> {
+    let mut img = load_image_from_bytes(data).map_err(js_error)?;
+    encrypt_image_with(&mut img, key, options);
+    encode_image(&img).map_err(js_error)
+}
+
+// encrypt an image file like `enc` does with the default options, giving back the encrypted file
+#[wasm_bindgen]
+pub fn encrypt_bytes(data: &[u8], key: u64) -> Result<Vec<u8>, JsError> {
+    encrypt_with(data, key, &EncryptOptions::default())
+}
+
+// the same with the ChaCha20 cipher and a random nonce, which unlike the default can't be broken
+#[wasm_bindgen]
+pub fn encrypt_bytes_chacha20(data: &[u8], key: u64) -> Result<Vec<u8>, JsError> {
+    let options = EncryptOptions {
+        cipher: Cipher::ChaCha20,
+        ..Default::default()
+    };
+    encrypt_with(data, key, &options)
+}
+
+// decrypt an encrypted file, giving back the image in the format it was encrypted from
+#[wasm_bindgen]
+pub fn decrypt_bytes(data: &[u8], key: u64) -> Result<Vec<u8>, JsError> {
+    let mut img = load_image_from_bytes(data).map_err(js_error)?;
+    decrypt_image(&mut img, key).map_err(js_error)?;
+    encode_image(&img).map_err(js_error)
+}
+
+// a key as it is typed on the command line: decimal, hex like `0x1f2e3d4c5b6a7988` or `base64:...`
+#[wasm_bindgen]
+pub fn key_from_text(text: &str) -> Result<u64, JsError> {
+    parse_key(text).map_err(js_error)
+}
+
+// the fingerprint of a key, to show which key a file is encrypted with without showing the key
+#[wasm_bindgen]
+pub fn key_fingerprint(key: u64) -> String {
+    KeyFingerprint::id(key).to_string()
+}
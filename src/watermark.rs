@@ -0,0 +1,118 @@
+use image::{GenericImage, GenericImageView, Rgba, RgbaImage};
+
+use crate::{
+    font::{draw_text, text_size},
+    Image,
+};
+
+#[derive(Debug, Clone)]
+pub enum WatermarkContent {
+    Text(String),
+    Overlay(Box<Image>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+// a visible mark blended onto the plaintext right before it gets encrypted,
+// so every decrypted copy carries it
+#[derive(Debug, Clone)]
+pub struct Watermark {
+    pub content: WatermarkContent,
+    pub position: WatermarkPosition,
+    // 0.0 leaves the image untouched, 1.0 draws the mark fully opaque
+    pub opacity: f32,
+}
+
+// render the watermark content as an RGBA layer sized relative to the image it goes on
+fn render_layer(content: &WatermarkContent, width: u32, height: u32) -> Option<RgbaImage> {
+    match content {
+        WatermarkContent::Text(text) => {
+            // text about a twentieth of the image height, with a dark outline so it reads on any background
+            let scale = (height / 20 / 8).max(1);
+            let (text_width, text_height) = text_size(text, scale);
+            let mut layer = RgbaImage::new(text_width + 2 * scale, text_height + 2 * scale);
+            for (dx, dy) in [(0, 1), (2, 1), (1, 0), (1, 2)] {
+                draw_text(
+                    &mut layer,
+                    text,
+                    (dx * scale) as i64,
+                    (dy * scale) as i64,
+                    scale,
+                    Rgba([0, 0, 0, 255]),
+                );
+            }
+            draw_text(
+                &mut layer,
+                text,
+                scale as i64,
+                scale as i64,
+                scale,
+                Rgba([255, 255, 255, 255]),
+            );
+            Some(layer)
+        }
+        WatermarkContent::Overlay(overlay) => {
+            let overlay = overlay.to_dynamic()?;
+            // overlays larger than the image are scaled down to fit in a quarter of it
+            if overlay.width() > width / 2 || overlay.height() > height / 2 {
+                Some(
+                    overlay
+                        .thumbnail((width / 2).max(1), (height / 2).max(1))
+                        .to_rgba8(),
+                )
+            } else {
+                Some(overlay.to_rgba8())
+            }
+        }
+    }
+}
+
+pub(crate) fn apply_watermark(img: &mut Image, watermark: &Watermark) {
+    let Some(mut image) = img.to_dynamic() else {
+        return;
+    };
+    let Some(layer) = render_layer(&watermark.content, img.width, img.height) else {
+        return;
+    };
+
+    let margin = (img.width.min(img.height) / 50) as i64;
+    let (free_x, free_y) = (
+        img.width as i64 - layer.width() as i64,
+        img.height as i64 - layer.height() as i64,
+    );
+    let (x, y) = match watermark.position {
+        WatermarkPosition::TopLeft => (margin, margin),
+        WatermarkPosition::TopRight => (free_x - margin, margin),
+        WatermarkPosition::BottomLeft => (margin, free_y - margin),
+        WatermarkPosition::BottomRight => (free_x - margin, free_y - margin),
+        WatermarkPosition::Center => (free_x / 2, free_y / 2),
+    };
+
+    let opacity = watermark.opacity.clamp(0.0, 1.0);
+    for (lx, ly, mark) in layer.enumerate_pixels() {
+        let (px, py) = (x + lx as i64, y + ly as i64);
+        if px < 0 || py < 0 || px >= img.width as i64 || py >= img.height as i64 {
+            continue;
+        }
+        let alpha = opacity * mark[3] as f32 / 255.0;
+        if alpha == 0.0 {
+            continue;
+        }
+
+        // blending through RGBA keeps the color type of the image, only the marked pixels are touched
+        let mut pixel = image.get_pixel(px as u32, py as u32);
+        for c in 0..3 {
+            pixel[c] = (pixel[c] as f32 * (1.0 - alpha) + mark[c] as f32 * alpha).round() as u8;
+        }
+        image.put_pixel(px as u32, py as u32, pixel);
+    }
+
+    img.set_dynamic(image);
+}
@@ -0,0 +1,47 @@
+use std::{env, fs, path::Path, process::Command};
+
+use image::RgbImage;
+
+// the program as a user runs it, printing its output
+fn run(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_image_encryption"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}: {:?}", args, output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn line<'a>(output: &'a str, prefix: &str) -> &'a str {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix(prefix))
+        .unwrap_or_else(|| panic!("no {:?} in {:?}", prefix, output))
+}
+
+// the fingerprint `keygen` shows for a key is the one `enc` and `info` show for every file
+// encrypted with it, whatever the size of the image
+#[test]
+fn keygen_matches_info() {
+    let dir = env::temp_dir().join(format!("image_encryption-keygen-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+    let keygen = run(&["keygen"]);
+    let key = line(&keygen, "key: ").split_whitespace().next().unwrap();
+    let fingerprint = line(&keygen, "key fingerprint: ");
+
+    for (width, height) in [(123, 77), (900, 700)] {
+        let name = format!("{}x{}.png", width, height);
+        RgbImage::from_fn(width, height, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .save(Path::new(&path(&name)))
+            .unwrap();
+        let encrypted = path(&format!("enc-{}", name));
+        let enc = run(&["enc", key, &path(&name), &encrypted]);
+        assert_eq!(line(&enc, "key fingerprint: "), fingerprint);
+        let info = run(&["info", &encrypted]);
+        assert_eq!(line(&info, "key fingerprint: "), fingerprint);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}